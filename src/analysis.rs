@@ -0,0 +1,722 @@
+//! Where the labels, constants and macros of a source file and the files it
+//! includes are declared and used, for the editor features of the language
+//! server.
+//!
+//! Declarations and references come from the syntax tree, including the
+//! branches of IF blocks that are not taken, while values and label addresses
+//! come from assembling the file.
+
+use crate::assembler::Assembler;
+use crate::ast::{self, DefKind, Operand, Statement, StatementType};
+use crate::diagnostic::{Diagnostics, SourceFile};
+use crate::expr::Expression;
+use crate::lexer::{self, Span};
+use crate::parser;
+use crate::symbols::SymbolValue;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DeclarationKind {
+    Label,
+    /// `EQU`, `RB`, `RW` and `RL`
+    Constant,
+    /// `=` and `SET`
+    Variable,
+    /// `EQUS`
+    String,
+    Macro,
+}
+
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    /// Full name, `Parent.local` for local labels
+    pub name: String,
+    pub kind: DeclarationKind,
+    pub file: String,
+    /// Span of the name
+    pub span: Span,
+    pub exported: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reference {
+    /// Full name of the referenced symbol
+    pub name: String,
+    pub file: String,
+    pub span: Span,
+}
+
+/// A name that is only put together when assembling, from macro arguments
+/// (`\1`), unique suffixes (`\@`) or interpolations (`{symbol}`), or a local
+/// label in a macro body, whose parent depends on where the macro is used.
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    /// The name as written, or a word of the value of an EQUS expression
+    pub text: String,
+    pub file: String,
+    pub span: Span,
+    /// What builds the name, like "macro `copy`"
+    pub origin: String,
+}
+
+/// A macro argument that the macro pastes into a longer name, like `Helper`
+/// in `mk Helper` when the body of `mk` declares `\1_x:`.
+#[derive(Debug, Clone)]
+pub struct PastedArgument {
+    /// Full name of the argument
+    pub name: String,
+    pub file: String,
+    /// Span of the argument in the call
+    pub span: Span,
+    /// The name built from it, like `Helper_x`
+    pub built: String,
+    pub macro_name: String,
+}
+
+impl NameTemplate {
+    /// Whether assembling can turn the template into `name`.
+    pub fn matches(&self, name: &str) -> bool {
+        // literal parts, a `None` between two of them stands for any text
+        let mut parts: Vec<Option<String>> = vec![];
+        let mut chars = self.text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                    parts.push(None);
+                }
+                '{' => {
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            break;
+                        }
+                    }
+                    parts.push(None);
+                }
+                c => match parts.last_mut() {
+                    Some(Some(literal)) => literal.push(c),
+                    _ => parts.push(Some(c.to_string())),
+                },
+            }
+        }
+
+        // a local label in a macro body matches any label with that local part
+        if self.text.starts_with('.') && !self.text.contains(['\\', '{']) {
+            return name.ends_with(&self.text);
+        }
+
+        return glob_matches(&parts, name);
+    }
+}
+
+fn glob_matches(parts: &[Option<String>], name: &str) -> bool {
+    return match parts.split_first() {
+        None => name.is_empty(),
+        Some((Some(literal), rest)) => name.strip_prefix(literal.as_str()).is_some_and(|name| glob_matches(rest, name)),
+        Some((None, rest)) => (0..=name.len())
+            .filter(|i| name.is_char_boundary(*i))
+            .any(|i| glob_matches(rest, &name[i..])),
+    };
+}
+
+pub struct Analysis {
+    /// The analyzed file first, then the files it includes
+    pub sources: Vec<SourceFile>,
+    /// Syntax tree of the analyzed file
+    pub ast: ast::Ast,
+    pub diagnostics: Diagnostics,
+    pub assembler: Assembler,
+    pub declarations: Vec<Declaration>,
+    pub references: Vec<Reference>,
+    pub templates: Vec<NameTemplate>,
+    pub pasted: Vec<PastedArgument>,
+}
+
+impl Analysis {
+    pub fn new(source: SourceFile, assembler: Assembler) -> Self {
+        return Self::with_diagnostics(source, assembler, Diagnostics::new());
+    }
+
+    /// Analysis reporting into `diagnostics`, which holds the warning settings.
+    pub fn with_diagnostics(source: SourceFile, mut assembler: Assembler, mut diagnostics: Diagnostics) -> Self {
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+
+        let mut sources = std::mem::take(&mut assembler.sources);
+        sources.insert(0, source);
+
+        let mut indexer = Indexer {
+            assembler: &assembler,
+            sources: &sources,
+            visited: vec![sources[0].name.clone()],
+            scope: None,
+            declarations: vec![],
+            references: vec![],
+            templates: vec![],
+            pasted: vec![],
+        };
+        indexer.index_statements(&ast.statements, &sources[0].name);
+
+        let declarations = indexer.declarations;
+        let references = indexer.references;
+        let templates = indexer.templates;
+        let pasted = indexer.pasted;
+
+        return Self {
+            sources,
+            ast,
+            diagnostics,
+            assembler,
+            declarations,
+            references,
+            templates,
+            pasted,
+        };
+    }
+
+    pub fn source(&self, file: &str) -> Option<&SourceFile> {
+        return self.sources.iter().find(|source| source.name == file);
+    }
+
+    /// Full name of the symbol declared or referenced at `offset`.
+    pub fn symbol_at(&self, file: &str, offset: usize) -> Option<&str> {
+        let contains = |f: &str, span: Span| f == file && span.start <= offset && offset <= span.end;
+
+        let declaration = self.declarations.iter().find(|d| contains(&d.file, d.span)).map(|d| d.name.as_str());
+        let reference = || self.references.iter().find(|r| contains(&r.file, r.span)).map(|r| r.name.as_str());
+
+        return declaration.or_else(reference);
+    }
+
+    pub fn declaration(&self, name: &str) -> Option<&Declaration> {
+        return self.declarations.iter().find(|d| d.name == name);
+    }
+
+    pub fn references_to<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Reference> {
+        return self.references.iter().filter(move |r| r.name == name);
+    }
+
+    /// The global label that local labels at `offset` belong to.
+    pub fn scope_at(&self, file: &str, offset: usize) -> Option<&str> {
+        return self.declarations.iter()
+            .rev()
+            .filter(|d| d.kind == DeclarationKind::Label && !d.name.contains('.'))
+            .find(|d| d.file == file && d.span.start <= offset)
+            .map(|d| d.name.as_str());
+    }
+
+    /// One line describing a symbol: the value of a constant, the bank and
+    /// address of a label.
+    pub fn describe(&self, name: &str) -> Option<String> {
+        let declaration = self.declaration(name);
+
+        if declaration.is_some_and(|d| d.kind == DeclarationKind::Macro) {
+            let arguments = self.assembler.macros.get(name).map(|m| highest_argument(&m.body)).unwrap_or(0);
+            return Some(format!("MACRO {} ({} argument(s))", name, arguments));
+        }
+
+        let symbol = match self.assembler.symbols.get(name) {
+            Some(symbol) => symbol,
+            None => return declaration.map(|_| format!("{} (not assembled)", name)),
+        };
+
+        return Some(match &symbol.value {
+            SymbolValue::Number(n) => match declaration.map(|d| d.kind) {
+                Some(DeclarationKind::Variable) => format!("DEF {} = ${:X} ({})", name, n, n),
+                _ => format!("DEF {} EQU ${:X} ({})", name, n, n),
+            },
+            SymbolValue::String(s) => format!("DEF {} EQUS \"{}\"", name, s.escape_debug()),
+            SymbolValue::Label(location) => format!("{}: {} in {} section \"{}\"", name, location, location.section_type, location.section),
+        });
+    }
+}
+
+// highest `\N` used in a macro body
+fn highest_argument(body: &str) -> usize {
+    let chars: Vec<char> = body.chars().collect();
+
+    return chars.windows(2)
+        .filter(|pair| pair[0] == '\\')
+        .filter_map(|pair| pair[1].to_digit(10))
+        .max()
+        .unwrap_or(0) as usize;
+}
+
+struct Indexer<'a> {
+    assembler: &'a Assembler,
+    sources: &'a [SourceFile],
+    visited: Vec<String>,
+    scope: Option<String>,
+    declarations: Vec<Declaration>,
+    references: Vec<Reference>,
+    templates: Vec<NameTemplate>,
+    pasted: Vec<PastedArgument>,
+}
+
+impl Indexer<'_> {
+    fn qualify(&self, name: &str) -> String {
+        return match &self.scope {
+            Some(scope) if name.starts_with('.') => format!("{}{}", scope, name),
+            _ => name.to_string(),
+        };
+    }
+
+    fn declare(&mut self, name: String, kind: DeclarationKind, file: &str, span: Span, exported: bool) {
+        self.declarations.push(Declaration {
+            name,
+            kind,
+            file: file.to_string(),
+            span,
+            exported,
+        });
+    }
+
+    fn index_statements(&mut self, statements: &[Box<dyn Statement>], file: &str) {
+        for statement in statements {
+            self.index_statement(statement.as_ref(), file);
+        }
+    }
+
+    fn index_statement(&mut self, statement: &dyn Statement, file: &str) {
+        let any = statement.as_any();
+
+        match statement.my_type() {
+            StatementType::Label => {
+                let label = any.downcast_ref::<ast::LabelStatement>().unwrap();
+                let name = self.qualify(&label.name);
+                if !label.name.contains('.') {
+                    self.scope = Some(name.clone());
+                }
+
+                let span = name_span(label.span, &label.name);
+                self.declare(name, DeclarationKind::Label, file, span, label.exported);
+            }
+            StatementType::Def => {
+                let def = any.downcast_ref::<ast::DefStatement>().unwrap();
+                let kind = match def.kind {
+                    DefKind::Set => DeclarationKind::Variable,
+                    DefKind::Equs => DeclarationKind::String,
+                    _ => DeclarationKind::Constant,
+                };
+
+                // a variable is declared where it is first set
+                let redefined = kind == DeclarationKind::Variable && self.declarations.iter().any(|d| d.name == def.name);
+                if redefined {
+                    self.add_reference(&def.name, file, def.name_span);
+                } else {
+                    self.declare(def.name.clone(), kind, file, def.name_span, false);
+                }
+
+                let origin = format!("EQUS `{}`", def.name);
+                match &def.expression {
+                    // the string is substituted as source text wherever the symbol is used
+                    Some(Expression::String(raw, span)) if def.kind == DefKind::Equs => {
+                        self.index_text(raw, span.start + 1, file, &origin, Quotes::Escaped);
+                    }
+                    Some(expression) if def.kind == DefKind::Equs => {
+                        self.index_expression(expression, file);
+                        self.index_equs_value(&def.name, file, def.span, &origin);
+                    }
+                    Some(expression) => self.index_expression(expression, file),
+                    None => {}
+                }
+            }
+            StatementType::Macro => {
+                let definition = any.downcast_ref::<ast::MacroStatement>().unwrap();
+                let span = name_span(definition.span, &definition.name);
+                self.declare(definition.name.clone(), DeclarationKind::Macro, file, span, false);
+
+                if let (Some(first), Some(last)) = (definition.tokens.first(), definition.tokens.last()) {
+                    let sources = self.sources;
+                    let source = sources.iter().find(|source| source.name == file).unwrap();
+                    let body = &source.text[first.span.start..last.span.end];
+                    self.index_text(body, first.span.start, file, &format!("macro `{}`", definition.name), Quotes::Plain);
+                }
+            }
+            StatementType::Rept => {
+                let rept = any.downcast_ref::<ast::ReptStatement>().unwrap();
+                self.index_expression(&rept.count, file);
+
+                if let (Some(first), Some(last)) = (rept.tokens.first(), rept.tokens.last()) {
+                    let sources = self.sources;
+                    let source = sources.iter().find(|source| source.name == file).unwrap();
+                    let body = &source.text[first.span.start..last.span.end];
+                    self.index_text(body, first.span.start, file, "a REPT body", Quotes::Plain);
+                }
+            }
+            StatementType::MacroCall => {
+                let call = any.downcast_ref::<ast::MacroCallStatement>().unwrap();
+                self.add_reference(&call.name, file, name_span(call.span, &call.name));
+
+                // arguments are pasted where the macro is used, so their local labels are known
+                let sources = self.sources;
+                let source = sources.iter().find(|source| source.name == file).unwrap();
+                let start = call.span.start + call.name.len();
+                let arguments = &source.text[start..call.span.end];
+                let scope = self.scope.clone();
+                self.index_text(arguments, start, file, &format!("arguments of `{}`", call.name), Quotes::Plain);
+                self.index_pasted_arguments(call, start, file);
+                self.scope = scope;
+            }
+            StatementType::Instruction => {
+                let instruction = any.downcast_ref::<ast::InstructionStatement>().unwrap();
+                for operand in &instruction.operands {
+                    match operand {
+                        Operand::Indirect(expression, _) | Operand::StackOffset(expression, _) | Operand::Immediate(expression) => {
+                            self.index_expression(expression, file);
+                        }
+                        Operand::Register(..) | Operand::IndirectRegister(..) => {}
+                    }
+                }
+            }
+            StatementType::Data => {
+                let data = any.downcast_ref::<ast::DataStatement>().unwrap();
+                for value in &data.values {
+                    self.index_expression(value, file);
+                }
+            }
+            StatementType::Section => {
+                let section = any.downcast_ref::<ast::SectionStatement>().unwrap();
+                for expression in [&section.address, &section.bank, &section.alignment].into_iter().flatten() {
+                    self.index_expression(expression, file);
+                }
+            }
+            StatementType::Incbin => {
+                let incbin = any.downcast_ref::<ast::IncbinStatement>().unwrap();
+                for expression in [&incbin.start, &incbin.length].into_iter().flatten() {
+                    self.index_expression(expression, file);
+                }
+            }
+            StatementType::Rs => {
+                let rs = any.downcast_ref::<ast::RsStatement>().unwrap();
+                if let Some(value) = &rs.value {
+                    self.index_expression(value, file);
+                }
+            }
+            StatementType::If => {
+                let conditional = any.downcast_ref::<ast::IfStatement>().unwrap();
+                for branch in &conditional.branches {
+                    if let Some(condition) = &branch.condition {
+                        self.index_expression(condition, file);
+                    }
+                    self.index_statements(&branch.statements, file);
+                }
+            }
+            StatementType::Test => {
+                let test = any.downcast_ref::<ast::TestStatement>().unwrap();
+                self.index_statements(&test.statements, file);
+            }
+            StatementType::Expect => {
+                let expect = any.downcast_ref::<ast::ExpectStatement>().unwrap();
+                if let ast::Operand::Indirect(expression, _) = &expect.target {
+                    self.index_expression(expression, file);
+                }
+                if let Some((_, value)) = &expect.comparison {
+                    self.index_expression(value, file);
+                }
+            }
+            StatementType::Include => {
+                let include = any.downcast_ref::<ast::IncludeStatement>().unwrap();
+                self.index_include(&include.path);
+            }
+            _ => {}
+        }
+    }
+
+    // only files that were included while assembling are indexed
+    fn index_include(&mut self, path: &str) {
+        let name = match self.assembler.find_file(path) {
+            Some(name) => name,
+            None => return,
+        };
+
+        if self.visited.contains(&name) {
+            return;
+        }
+        self.visited.push(name.clone());
+
+        let sources = self.sources;
+        let source = match sources.iter().find(|source| source.name == name) {
+            Some(source) => source,
+            None => return,
+        };
+
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        self.index_statements(&ast.statements, &name);
+    }
+
+    // the value of an EQUS built by an expression cannot be edited, so every
+    // name in it is a template
+    fn index_equs_value(&mut self, name: &str, file: &str, span: Span, origin: &str) {
+        let words = match self.assembler.symbols.get(name).map(|symbol| &symbol.value) {
+            Some(SymbolValue::String(value)) => words(value, Quotes::Plain).into_iter().map(|(start, end)| value[start..end].to_string()).collect(),
+            // not assembled, it could be anything
+            _ => vec!["{}".to_string()],
+        };
+
+        for text in words {
+            self.templates.push(NameTemplate {
+                text,
+                file: file.to_string(),
+                span,
+                origin: origin.to_string(),
+            });
+        }
+    }
+
+    /// Indexes the names in source text that is not parsed: macro and REPT
+    /// bodies, macro arguments and EQUS strings. Local labels belong to the current
+    /// scope in arguments only.
+    fn index_text(&mut self, text: &str, offset: usize, file: &str, origin: &str, quotes: Quotes) {
+        let locals_known = origin.starts_with("arguments");
+
+        for (start, end) in words(text, quotes) {
+            let word = &text[start..end];
+            let span = Span { start: offset + start, end: offset + end };
+
+            let dynamic = word.contains(['\\', '{']);
+            let single_argument = word.len() == 2 && word.starts_with('\\');
+            let interpolation = word.strip_prefix('{')
+                .and_then(|inner| inner.strip_suffix('}'))
+                .map(|inner| inner.split_once(':').map(|(_, name)| name).unwrap_or(inner))
+                .filter(|inner| !inner.contains(['\\', '{', '}']));
+
+            if let Some(name) = interpolation {
+                let start = span.end - 1 - name.len();
+                self.add_reference(name, file, Span { start, end: start + name.len() });
+            } else if single_argument {
+                continue;
+            } else if dynamic || (word.starts_with('.') && !locals_known) {
+                self.templates.push(NameTemplate {
+                    text: word.to_string(),
+                    file: file.to_string(),
+                    span,
+                    origin: origin.to_string(),
+                });
+            } else {
+                self.add_reference(word, file, span);
+            }
+        }
+    }
+
+    // arguments the macro body joins with other name characters, like `\1_x`
+    fn index_pasted_arguments(&mut self, call: &ast::MacroCallStatement, start: usize, file: &str) {
+        let body = match self.assembler.macros.get(&call.name) {
+            Some(definition) => &definition.body,
+            None => return,
+        };
+        let source = self.sources.iter().find(|source| source.name == file).unwrap();
+        let mut cursor = start;
+
+        for (index, argument) in call.arguments.iter().enumerate() {
+            let offset = match source.text[cursor..call.span.end].find(argument.as_str()) {
+                Some(offset) => cursor + offset,
+                None => continue,
+            };
+            cursor = offset + argument.len();
+
+            if words(argument, Quotes::Plain) != [(0, argument.len())] || argument.contains(['\\', '{']) {
+                continue;
+            }
+
+            let parameter = format!("\\{}", index + 1);
+            for (word_start, word_end) in words(body, Quotes::Plain) {
+                let word = &body[word_start..word_end];
+                if word == parameter || !word.contains(&parameter) {
+                    continue;
+                }
+
+                self.pasted.push(PastedArgument {
+                    name: self.qualify(argument),
+                    file: file.to_string(),
+                    span: Span { start: offset, end: cursor },
+                    built: word.replace(&parameter, argument),
+                    macro_name: call.name.clone(),
+                });
+            }
+        }
+    }
+
+    fn add_reference(&mut self, name: &str, file: &str, span: Span) {
+        self.references.push(Reference {
+            name: self.qualify(name),
+            file: file.to_string(),
+            span,
+        });
+    }
+
+    fn index_expression(&mut self, expression: &Expression, file: &str) {
+        match expression {
+            Expression::Symbol(name, span) if name != "@" => self.add_reference(name, file, *span),
+            Expression::Unary { operand, .. } => self.index_expression(operand, file),
+            Expression::Binary { left, right, .. } => {
+                self.index_expression(left, file);
+                self.index_expression(right, file);
+            }
+            Expression::Call { arguments, .. } => {
+                for argument in arguments {
+                    self.index_expression(argument, file);
+                }
+            }
+            Expression::String(raw, span) => {
+                // `{symbol}` and `{format:symbol}` interpolations
+                let mut rest = raw.as_str();
+                let mut offset = span.start + 1;
+
+                while let Some(open) = rest.find('{') {
+                    let close = match rest[open..].find('}') {
+                        Some(close) => open + close,
+                        None => break,
+                    };
+
+                    let inner = &rest[open + 1..close];
+                    let (start, name) = match inner.split_once(':') {
+                        Some((format, name)) => (open + 2 + format.len(), name),
+                        None => (open + 1, inner),
+                    };
+                    if !name.is_empty() {
+                        self.add_reference(name, file, Span { start: offset + start, end: offset + start + name.len() });
+                    }
+
+                    offset += close + 1;
+                    rest = &rest[close + 1..];
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Quotes {
+    /// Strings are delimited by `"`
+    Plain,
+    /// The text is itself in a string, so strings in it are delimited by `\"`
+    Escaped,
+}
+
+/// Start and end of the names in source text, skipping numbers, strings and
+/// comments. Names may contain macro arguments and interpolations.
+fn words(text: &str, quotes: Quotes) -> Vec<(usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut words = vec![];
+    let mut i = 0;
+
+    let is_quote = |i: usize| match quotes {
+        Quotes::Plain => bytes[i] == b'"',
+        Quotes::Escaped => bytes[i] == b'\\' && bytes.get(i + 1) == Some(&b'"'),
+    };
+    let is_name = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || c == b'#' || c == b'@' || c >= 0x80;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+
+        if is_quote(i) {
+            // skip the string
+            i += if quotes == Quotes::Escaped { 2 } else { 1 };
+            while i < bytes.len() && !is_quote(i) {
+                // interpolations are names in strings too
+                if bytes[i] == b'{' {
+                    if let Some(end) = text[i..].find('}') {
+                        words.push((i, i + end + 1));
+                        i += end + 1;
+                        continue;
+                    }
+                }
+                i += if bytes[i] == b'\\' && quotes == Quotes::Plain { 2 } else { 1 };
+            }
+            i += if quotes == Quotes::Escaped { 2 } else { 1 };
+        } else if c == b';' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_digit() || c == b'$' || c == b'%' || c == b'&' {
+            i += 1;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' || c == b'.' || c == b'\\' || c == b'{' || c >= 0x80 {
+            let start = i;
+            while i < bytes.len() {
+                if bytes[i] == b'\\' && i + 1 < bytes.len() && bytes[i + 1] != b'"' {
+                    i += 2;
+                } else if bytes[i] == b'{' {
+                    i += text[i..].find('}').map(|end| end + 1).unwrap_or(text.len() - i);
+                } else if is_name(bytes[i]) {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+
+            // `.` alone is not a name
+            let word = &text[start..i];
+            if i > start && word != "." && !word.starts_with("..") && !(word.starts_with('.') && word.len() > 1 && bytes[start + 1].is_ascii_digit()) {
+                words.push((start, i));
+            }
+            if i == start {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+
+    return words;
+}
+
+// the name is the first thing in the statement
+fn name_span(statement: Span, name: &str) -> Span {
+    return Span {
+        start: statement.start,
+        end: statement.start + name.len(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn analyze(text: &str) -> Analysis {
+        return Analysis::new(SourceFile::new("main.asm", text.to_string()), Assembler::with_time(UNIX_EPOCH));
+    }
+
+    #[test]
+    fn indexing_symbols() {
+        let text = concat!(
+            "DEF SCREEN EQU $9800\n",
+            "DEF NAME EQUS \"{d:SCREEN}\"\n",
+            "wait: MACRO\n",
+            "\tld a, \\1\n",
+            "ENDM\n",
+            "SECTION \"Main\", ROM0[$150]\n",
+            "Main::\n",
+            "\tld hl, SCREEN\n",
+            ".loop: wait 3\n",
+            "\tjr nz, .loop\n",
+            "IF 0\n",
+            "\tjp Main.loop\n",
+            "ENDC\n",
+        );
+        let analysis = analyze(text);
+        assert!(analysis.diagnostics.is_empty());
+
+        assert_eq!(analysis.symbol_at("main.asm", text.find("ld hl, SCREEN").unwrap() + 8), Some("SCREEN"));
+        assert_eq!(analysis.symbol_at("main.asm", text.find("SCREEN").unwrap()), Some("SCREEN"));
+        assert_eq!(analysis.references_to("SCREEN").count(), 2);
+        assert_eq!(analysis.references_to("Main.loop").count(), 2);
+        assert_eq!(analysis.references_to("wait").count(), 1);
+        assert_eq!(analysis.scope_at("main.asm", text.find("jr nz").unwrap()), Some("Main"));
+
+        let local = analysis.declaration("Main.loop").unwrap();
+        assert_eq!(&text[local.span.start..local.span.end], ".loop");
+        assert!(analysis.declaration("Main").unwrap().exported);
+
+        assert_eq!(analysis.describe("SCREEN"), Some("DEF SCREEN EQU $9800 (38912)".to_string()));
+        assert_eq!(analysis.describe("NAME"), Some("DEF NAME EQUS \"38912\"".to_string()));
+        assert_eq!(analysis.describe("Main.loop"), Some("Main.loop: $00:$0153 in ROM0 section \"Main\"".to_string()));
+        assert_eq!(analysis.describe("wait"), Some("MACRO wait (1 argument(s))".to_string()));
+    }
+}
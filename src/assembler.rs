@@ -0,0 +1,1762 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::ast::{self, DataKind, DefKind, Operand, Statement, StatementType};
+use crate::diagnostic::{Diagnostic, Diagnostics, SourceFile};
+use crate::dictionary::{self, CompressionOptions, Dictionary, Unit};
+use crate::expr::{self, Expression, ExpressionValue};
+use crate::lexer::{self, Span};
+use crate::symbols::{Definition, LabelLocation, SymbolError, SymbolKind, SymbolTable, SymbolValue};
+use crate::sm83::{self, Encoding, Immediate};
+use crate::parser;
+
+const E_SYMBOL_REDEFINED: &str = "E0020";
+const E_INVALID_DEFINE: &str = "E0021";
+const E_FILE_NOT_FOUND: &str = "E0022";
+const E_INCLUDE_DEPTH: &str = "E0023";
+const E_INCBIN_RANGE: &str = "E0024";
+const E_OUTSIDE_SECTION: &str = "E0025";
+const E_INVALID_INSTRUCTION: &str = "E0026";
+const E_UNDEFINED_MACRO: &str = "E0027";
+const E_LOCAL_LABEL_SCOPE: &str = "E0028";
+const E_VALUE_RANGE: &str = "E0029";
+const E_TEST_BLOCK: &str = "E0033";
+const E_CHARMAP: &str = "E0035";
+
+/// Warning for a `db` or `dw` value, or an 8 or 16 bit operand, cut to fit.
+pub const TRUNCATION: &str = "truncation";
+/// Warning for a character of a string that the current charmap does not
+/// map, when it maps others.
+pub const UNMAPPED_CHARACTER: &str = "unmapped-character";
+
+// same limit as rgbasm's default for -r
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+pub struct Assembler {
+    pub symbols: SymbolTable,
+    /// Directories searched for INCLUDE and INCBIN files after the current one.
+    pub include_paths: Vec<PathBuf>,
+    /// Record missing INCLUDE and INCBIN files as dependencies instead of
+    /// reporting them, for files that are generated by the build (`-MG`).
+    /// Assembling stops at the first missing file.
+    pub missing_files_allowed: bool,
+    /// Files read through INCLUDE, in the order they were first included.
+    pub sources: Vec<SourceFile>,
+    /// Contents to use instead of the files on disk, by canonical path, for
+    /// the unsaved buffers of an editor.
+    pub overlays: HashMap<PathBuf, String>,
+    pub macros: HashMap<String, Macro>,
+    /// Where each instruction of the assembled files was placed, except for
+    /// the instructions of macro expansions.
+    pub placements: Vec<Placement>,
+    /// The bytes output by each line, macro expansions included, for debug
+    /// information.
+    pub output_lines: Vec<OutputLine>,
+    /// Assemble the TEST blocks into sections of their own, for the `test`
+    /// command. They are skipped otherwise.
+    pub tests_enabled: bool,
+    /// The assembled TEST blocks, in the order they were met
+    pub tests: Vec<Test>,
+    /// Compress the strings `db` encodes through a charmap with a
+    /// dictionary, for `asm --compress-text`.
+    pub text_compression: Option<CompressionOptions>,
+    /// The dictionary of the compressed strings, chosen after a first pass
+    pub dictionary: Option<Dictionary>,
+    /// Strings of the compressed charmap met in the first pass, the first
+    /// one being where compression errors are reported
+    texts: Vec<Vec<Unit>>,
+    first_text: Option<Definition>,
+    /// Values of the `db` holding those strings
+    text_values: [bool; 256],
+    /// Index of the test being assembled
+    test: Option<usize>,
+    /// `jmp` instructions of the current pass, in the order they were met
+    jumps: Vec<Jump>,
+    /// Indices of the `jmp` instructions assembled as `jp`
+    long_jumps: HashSet<usize>,
+    dependencies: Vec<String>,
+    pub(crate) main_file: String,
+    include_depth: usize,
+    stopped: bool,
+    pub(crate) sections: Vec<Section>,
+    pub(crate) patches: Vec<Patch>,
+    section: Option<usize>,
+    /// Last global label, the parent of local labels
+    scope: Option<String>,
+    expansions: usize,
+    /// Invocation of the macro being expanded, where the symbols it defines
+    /// are reported
+    call_site: Option<Span>,
+    /// Macros being expanded, the innermost last, with `None` for the files
+    /// included from them
+    expansion_stack: Vec<Option<ExpansionFrame>>,
+}
+
+pub struct Macro {
+    /// Source text between MACRO and ENDM
+    pub body: String,
+    pub definition: Definition,
+    /// File and line the body starts on, the MACRO line, for the lines of
+    /// its expansions
+    pub body_start: (String, usize),
+}
+
+/// Bytes output by a line of source.
+#[derive(Debug, Clone)]
+pub struct OutputLine {
+    pub file: String,
+    pub line: usize,
+    /// Invocations of the macros the line was expanded from, the innermost
+    /// first
+    pub expansions: Vec<Expansion>,
+    pub location: LabelLocation,
+    pub size: i32,
+}
+
+/// An invocation of a macro, in a file or in the body of another macro.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    pub file: String,
+    pub line: usize,
+}
+
+struct ExpansionFrame {
+    invocation: Expansion,
+    body_start: (String, usize),
+}
+
+pub struct Placement {
+    pub file: String,
+    pub span: Span,
+    pub location: LabelLocation,
+    pub size: i32,
+    /// For `jmp`, whether it became a `jr`
+    pub relaxed: Option<bool>,
+}
+
+struct Jump {
+    /// Full name of the target label, `None` for other targets
+    target: Option<String>,
+    location: Option<LabelLocation>,
+}
+
+pub struct Section {
+    pub name: String,
+    pub section_type: String,
+    pub bank: Option<i32>,
+    pub address: Option<i32>,
+    /// `ALIGN[bits]`, the address being a multiple of 2 to the power of bits
+    pub alignment: Option<i32>,
+    pub data: Vec<u8>,
+    pub definition: Definition,
+}
+
+/// A TEST block, whose code is in a ROMX section named after it.
+pub struct Test {
+    pub name: String,
+    pub section: usize,
+    pub expectations: Vec<Expectation>,
+    pub definition: Definition,
+}
+
+/// An EXPECT, checked when its test gets to `offset` in the section.
+pub struct Expectation {
+    pub file: String,
+    pub span: Span,
+    pub offset: usize,
+    /// Register, condition or memory byte, with the symbols of an address
+    /// known when assembling replaced by their value
+    pub target: Operand,
+    pub comparison: Option<(String, Expression)>,
+}
+
+/// Value that could not be computed when assembling, written into its
+/// section once every section is placed.
+pub struct Patch {
+    pub file: String,
+    pub span: Span,
+    pub section: usize,
+    pub offset: usize,
+    /// Offset of the instruction or data holding the value, where `@` is
+    pub start: usize,
+    pub kind: PatchKind,
+    /// The expression with the symbols known when assembling replaced by
+    /// their value
+    pub expression: Expression,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PatchKind {
+    Byte,
+    Word,
+    Long,
+    /// `add sp, e8` and `ld hl, sp + e8`
+    Signed,
+    /// Target of `jr`, stored relative to the next instruction
+    Relative,
+    /// Address in $FF00-$FFFF of `ldh`
+    HighPage,
+}
+
+impl PatchKind {
+    pub fn size(&self) -> usize {
+        return match self {
+            PatchKind::Word => 2,
+            PatchKind::Long => 4,
+            _ => 1,
+        };
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        return Self::with_time(SystemTime::now());
+    }
+
+    /// An assembler whose date and time symbols are set from `now`, so the
+    /// output can be reproduced.
+    pub fn with_time(now: SystemTime) -> Self {
+        return Self {
+            symbols: SymbolTable::with_builtins(now),
+            include_paths: vec![],
+            missing_files_allowed: false,
+            sources: vec![],
+            overlays: HashMap::new(),
+            macros: HashMap::new(),
+            placements: vec![],
+            output_lines: vec![],
+            tests_enabled: false,
+            tests: vec![],
+            text_compression: None,
+            dictionary: None,
+            texts: vec![],
+            first_text: None,
+            text_values: [false; 256],
+            test: None,
+            jumps: vec![],
+            long_jumps: HashSet::new(),
+            dependencies: vec![],
+            main_file: String::new(),
+            include_depth: 0,
+            stopped: false,
+            sections: vec![],
+            patches: vec![],
+            section: None,
+            scope: None,
+            expansions: 0,
+            call_site: None,
+            expansion_stack: vec![],
+        };
+    }
+
+    /// Defines a string symbol like `-D NAME=VALUE` on the command line.
+    pub fn define(&mut self, name: &str, value: &str) -> Result<(), String> {
+        return self.symbols.define_command_line(name, value).map_err(|error| error.message);
+    }
+
+    pub fn assemble(&mut self, ast: &ast::Ast, source: &SourceFile, diagnostics: &mut Diagnostics) {
+        self.main_file = source.name.clone();
+        if source.name != "<stdin>" {
+            self.add_dependency(&source.name);
+        }
+
+        // every `jmp` starts as a `jr`, those out of reach become `jp` and the
+        // file is assembled again until all the remaining ones are in reach
+        let symbols = self.symbols.clone();
+        loop {
+            let mut pass_diagnostics = Diagnostics::new();
+            self.assemble_statements(&ast.statements, source, &mut pass_diagnostics);
+            self.output_dictionary(&mut pass_diagnostics);
+
+            // the strings are compressed in another pass once the dictionary is chosen
+            if !self.relax_jumps() && !self.choose_dictionary(&mut pass_diagnostics) {
+                for diagnostic in pass_diagnostics.iter() {
+                    diagnostics.push(diagnostic.clone());
+                }
+                return;
+            }
+
+            self.symbols = symbols.clone();
+            self.sources.clear();
+            self.macros.clear();
+            self.sections.clear();
+            self.patches.clear();
+            self.section = None;
+            self.scope = None;
+            self.expansions = 0;
+            self.stopped = false;
+            self.placements.clear();
+            self.output_lines.clear();
+            self.expansion_stack.clear();
+            self.tests.clear();
+            self.test = None;
+            self.jumps.clear();
+            self.texts.clear();
+            self.first_text = None;
+            self.text_values = [false; 256];
+        }
+    }
+
+    /// Turns the `jmp` instructions out of reach of `jr` with the current
+    /// layout into `jp`, returning whether there was any. Only targets in
+    /// the same section, or at fixed addresses in the same bank, can be
+    /// reached.
+    fn relax_jumps(&mut self) -> bool {
+        let mut grown = false;
+
+        for (index, jump) in self.jumps.iter().enumerate() {
+            if self.long_jumps.contains(&index) {
+                continue;
+            }
+
+            let target = jump.target.as_ref().and_then(|target| self.symbols.get(target)).map(|symbol| &symbol.value);
+            let offset = match (&jump.location, target) {
+                (Some(from), Some(SymbolValue::Label(to))) if from.section == to.section => Some(to.offset - (from.offset + 2)),
+                (Some(from), Some(SymbolValue::Label(to))) if from.bank.is_some() && from.bank == to.bank => {
+                    from.address.zip(to.address).map(|(from, to)| to - (from + 2))
+                }
+                _ => None,
+            };
+
+            if !offset.is_some_and(|offset| (-128..=127).contains(&offset)) {
+                self.long_jumps.insert(index);
+                grown = true;
+            }
+        }
+
+        return grown;
+    }
+
+    /// Chooses the dictionary of the strings met in the pass, returning
+    /// whether the file must be assembled again to compress them.
+    fn choose_dictionary(&mut self, diagnostics: &mut Diagnostics) -> bool {
+        let options = match &self.text_compression {
+            Some(options) if self.dictionary.is_none() && !diagnostics.has_errors() => options,
+            _ => return false,
+        };
+
+        // the values of the charmap are text as well, even where unused
+        let charmap = match self.symbols.charmaps.get(&options.charmap) {
+            Some(charmap) => charmap,
+            None => {
+                let message = format!("Cannot compress the strings of charmap `{}`, it is not defined", options.charmap);
+                diagnostics.push(Diagnostic::error(E_CHARMAP, &message, Span::default())
+                    .with_help("define it with NEWCHARMAP"));
+                return false;
+            }
+        };
+        let mut used = self.text_values;
+        for (_, values) in charmap.entries() {
+            for value in values {
+                used[*value as u8 as usize] = true;
+            }
+        }
+
+        return match Dictionary::build(&self.texts, &used, options) {
+            Ok(dictionary) => {
+                self.dictionary = Some(dictionary);
+                true
+            }
+            Err(message) => {
+                let mut error = Diagnostic::error(E_CHARMAP, &format!("Cannot compress the strings: {}", message), Span::default());
+                if let Some(first) = &self.first_text {
+                    error = Diagnostic::error(E_CHARMAP, &error.message, first.span)
+                        .with_label(&format!("first string of charmap `{}`", options.charmap));
+                    if first.file != self.main_file {
+                        error = error.with_file(&first.file);
+                    }
+                }
+                diagnostics.push(error);
+                false
+            }
+        };
+    }
+
+    /// Adds the section of the dictionary's table, once it is chosen.
+    fn output_dictionary(&mut self, diagnostics: &mut Diagnostics) {
+        let table = match &self.dictionary {
+            Some(dictionary) => dictionary.table(),
+            None => return,
+        };
+
+        self.sections.push(Section {
+            name: dictionary::TABLE_SECTION.to_string(),
+            section_type: "ROM0".to_string(),
+            bank: Some(0),
+            address: None,
+            alignment: None,
+            data: vec![],
+            definition: Definition {
+                file: self.main_file.clone(),
+                span: Span::default(),
+            },
+        });
+        self.section = Some(self.sections.len() - 1);
+
+        let location = self.current_location().unwrap();
+        if let Err(error) = self.symbols.define(dictionary::TABLE_LABEL, SymbolKind::Label, SymbolValue::Label(location), None) {
+            diagnostics.push(Diagnostic::error(E_SYMBOL_REDEFINED, &error.message, Span::default())
+                .with_note("the label of the table of compressed strings"));
+        }
+        self.sections.last_mut().unwrap().data = table;
+    }
+
+    /// Whether assembling stopped at a missing file, allowed by
+    /// `missing_files_allowed`, leaving the output incomplete.
+    pub fn stopped(&self) -> bool {
+        return self.stopped;
+    }
+
+    /// Every file the assembled file depends on: the file itself followed by
+    /// the INCLUDE and INCBIN files, without duplicates.
+    pub fn dependencies(&self) -> &[String] {
+        return &self.dependencies;
+    }
+
+    /// The sections in the order they were defined, with their bank and
+    /// address once `link::link` placed them.
+    pub fn sections(&self) -> &[Section] {
+        return &self.sections;
+    }
+
+    fn add_dependency(&mut self, path: &str) {
+        if !self.dependencies.iter().any(|d| d == path) {
+            self.dependencies.push(path.to_string());
+        }
+    }
+
+    fn assemble_statements(&mut self, statements: &[Box<dyn Statement>], source: &SourceFile, diagnostics: &mut Diagnostics) {
+        for statement in statements {
+            if self.stopped {
+                return;
+            }
+
+            let (line, _) = source.line_col(statement.span().start);
+            self.symbols.set_location(&source.name, line);
+            let start = self.current_location();
+            self.symbols.set_here(start.clone());
+
+            if let Err(diagnostic) = self.assemble_statement(statement.as_ref(), source, diagnostics) {
+                let mut diagnostic = *diagnostic;
+                if source.name != self.main_file {
+                    diagnostic = diagnostic.with_file(&source.name);
+                }
+                diagnostics.push(diagnostic);
+            }
+
+            let output = matches!(statement.my_type(), StatementType::Instruction | StatementType::Data | StatementType::Incbin);
+            if let (true, Some(start), Some(end)) = (output, start, self.current_location()) {
+                let size = end.offset - start.offset;
+                if start.section == end.section && size > 0 {
+                    self.record_output(start, size, source, statement.span());
+                }
+            }
+        }
+    }
+
+    fn assemble_statement(&mut self, statement: &dyn Statement, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<(), Box<Diagnostic>> {
+        match statement.my_type() {
+            StatementType::Def => {
+                let def = statement.as_any().downcast_ref::<ast::DefStatement>().unwrap();
+                return self.assemble_def(def, source);
+            }
+            StatementType::Include => {
+                let include = statement.as_any().downcast_ref::<ast::IncludeStatement>().unwrap();
+                return self.assemble_include(include, diagnostics);
+            }
+            StatementType::Incbin => {
+                let incbin = statement.as_any().downcast_ref::<ast::IncbinStatement>().unwrap();
+                return self.assemble_incbin(incbin);
+            }
+            StatementType::Rs => {
+                let rs = statement.as_any().downcast_ref::<ast::RsStatement>().unwrap();
+                let value = match &rs.value {
+                    Some(value) => expr::evaluate_number(value, &self.symbols)?,
+                    None => 0,
+                };
+                self.symbols.set_rs(value);
+            }
+            StatementType::Section => {
+                let section = statement.as_any().downcast_ref::<ast::SectionStatement>().unwrap();
+                return self.assemble_section(section, source);
+            }
+            StatementType::NewCharMap | StatementType::SetCharMap | StatementType::CharMap
+            | StatementType::PushCharMap | StatementType::PopCharMap => {
+                return self.assemble_char_map(statement);
+            }
+            StatementType::Test => {
+                let test = statement.as_any().downcast_ref::<ast::TestStatement>().unwrap();
+                if self.tests_enabled {
+                    return self.assemble_test(test, source, diagnostics);
+                }
+            }
+            StatementType::Expect => {
+                let expect = statement.as_any().downcast_ref::<ast::ExpectStatement>().unwrap();
+                return self.assemble_expect(expect, source);
+            }
+            StatementType::Label => {
+                let label = statement.as_any().downcast_ref::<ast::LabelStatement>().unwrap();
+                return self.assemble_label(label, source);
+            }
+            StatementType::Instruction => {
+                let instruction = statement.as_any().downcast_ref::<ast::InstructionStatement>().unwrap();
+                let (mnemonic, relaxed) = match instruction.mnemonic.as_str() {
+                    "jmp" => self.relax_jump(instruction)?,
+                    mnemonic => (mnemonic, None),
+                };
+                let encoding = sm83::encode(mnemonic, &instruction.operands).map_err(|message| {
+                    Diagnostic::error(E_INVALID_INSTRUCTION, &message, instruction.span)
+                        .with_label("no instruction takes these operands")
+                })?;
+                let bytes = self.instruction_bytes(&encoding, source, diagnostics)?;
+
+                // spans in macro expansions point into the expanded text
+                if let (Some(location), None) = (self.current_location(), self.call_site) {
+                    self.placements.push(Placement {
+                        file: source.name.clone(),
+                        span: instruction.span,
+                        location,
+                        size: bytes.len() as i32,
+                        relaxed,
+                    });
+                }
+
+                return self.output(&bytes, instruction.span);
+            }
+            StatementType::Data => {
+                let data = statement.as_any().downcast_ref::<ast::DataStatement>().unwrap();
+                let bytes = self.data_bytes(data, source, diagnostics)?;
+                return self.output(&bytes, data.span);
+            }
+            StatementType::Macro => {
+                let definition = statement.as_any().downcast_ref::<ast::MacroStatement>().unwrap();
+                return self.define_macro(definition, source);
+            }
+            StatementType::MacroCall => {
+                let call = statement.as_any().downcast_ref::<ast::MacroCallStatement>().unwrap();
+                return self.assemble_macro_call(call, source, diagnostics);
+            }
+            StatementType::Rept => {
+                let rept = statement.as_any().downcast_ref::<ast::ReptStatement>().unwrap();
+                return self.assemble_rept(rept, source, diagnostics);
+            }
+            StatementType::If => {
+                let conditional = statement.as_any().downcast_ref::<ast::IfStatement>().unwrap();
+
+                for branch in &conditional.branches {
+                    let taken = match &branch.condition {
+                        Some(condition) => expr::evaluate_number(condition, &self.symbols)? != 0,
+                        None => true,
+                    };
+
+                    if taken {
+                        self.assemble_statements(&branch.statements, source, diagnostics);
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        return Ok(());
+    }
+
+    fn assemble_char_map(&mut self, statement: &dyn Statement) -> Result<(), Box<Diagnostic>> {
+        let charmaps = &mut self.symbols.charmaps;
+        let result = match statement.my_type() {
+            StatementType::NewCharMap => {
+                let new = statement.as_any().downcast_ref::<ast::NewCharMapStatement>().unwrap();
+                charmaps.create(&new.names[0], new.names.get(1).map(|base| base.as_str()))
+            }
+            StatementType::SetCharMap => {
+                let set = statement.as_any().downcast_ref::<ast::SetCharMapStatement>().unwrap();
+                charmaps.select(&set.name)
+            }
+            StatementType::PushCharMap => {
+                charmaps.push();
+                Ok(())
+            }
+            StatementType::PopCharMap => charmaps.pop(),
+            _ => {
+                let map = statement.as_any().downcast_ref::<ast::CharMapStatement>().unwrap();
+                let key = match expr::evaluate(&map.key, &self.symbols)? {
+                    ExpressionValue::String(key) => key,
+                    ExpressionValue::Number(_) => {
+                        return Err(Box::new(Diagnostic::error(E_CHARMAP, "CHARMAP expects a string to map", map.key.span())
+                            .with_label("this is a number")));
+                    }
+                };
+                let values = map.values.iter()
+                    .map(|value| expr::evaluate_number(value, &self.symbols))
+                    .collect::<Result<Vec<i32>, Box<Diagnostic>>>()?;
+                self.symbols.charmaps.insert(&key, values)
+            }
+        };
+
+        return result.map_err(|message| Box::new(Diagnostic::error(E_CHARMAP, &message, statement.span())));
+    }
+
+    /// Instruction a `jmp` is assembled as in this pass, and whether it is
+    /// the shorter `jr`.
+    fn relax_jump(&mut self, jump: &ast::InstructionStatement) -> Result<(&'static str, Option<bool>), Box<Diagnostic>> {
+        if sm83::encode("jr", &jump.operands).is_err() {
+            return Err(Box::new(Diagnostic::error(E_INVALID_INSTRUCTION, "Invalid operands for `jmp`", jump.span)
+                .with_label("expected a label, after a condition for a conditional jump")
+                .with_help("use `jp hl` to jump to the address in `hl`")));
+        }
+
+        let target = match jump.operands.last() {
+            Some(ast::Operand::Immediate(Expression::Symbol(name, _))) if name.starts_with('.') => {
+                self.scope.as_ref().map(|scope| format!("{}{}", scope, name))
+            }
+            Some(ast::Operand::Immediate(Expression::Symbol(name, _))) => Some(name.clone()),
+            _ => None,
+        };
+
+        let index = self.jumps.len();
+        self.jumps.push(Jump {
+            target,
+            location: self.current_location(),
+        });
+
+        if self.long_jumps.contains(&index) {
+            return Ok(("jp", Some(false)));
+        }
+
+        return Ok(("jr", Some(true)));
+    }
+
+    fn instruction_bytes(&mut self, encoding: &Encoding, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<Vec<u8>, Box<Diagnostic>> {
+        let mut bytes = encoding.opcode.clone();
+
+        let (kind, expression) = match encoding.immediate {
+            Immediate::None => return Ok(bytes),
+            // bit numbers and vectors are part of the opcode, so they must be known now
+            Immediate::Bit(bit) => {
+                let value = expr::evaluate_number(bit, &self.symbols)?;
+                if !(0..8).contains(&value) {
+                    return Err(Box::new(Diagnostic::error(E_VALUE_RANGE, &format!("Bit number {} is not between 0 and 7", value), bit.span())
+                        .with_label("bits are numbered from 0 to 7")));
+                }
+                *bytes.last_mut().unwrap() |= (value as u8) << 3;
+                return Ok(bytes);
+            }
+            Immediate::Vector(vector) => {
+                let value = expr::evaluate_number(vector, &self.symbols)?;
+                if value & !0x38 != 0 {
+                    return Err(Box::new(Diagnostic::error(E_VALUE_RANGE, &format!("${:X} is not an `rst` vector", value), vector.span())
+                        .with_label("expected $00, $08, $10, $18, $20, $28, $30 or $38")));
+                }
+                bytes[0] |= value as u8;
+                return Ok(bytes);
+            }
+            Immediate::Byte(value) => (PatchKind::Byte, value),
+            Immediate::Word(value) => (PatchKind::Word, value),
+            Immediate::Signed(value) => (PatchKind::Signed, value),
+            Immediate::Relative(value) => (PatchKind::Relative, value),
+            Immediate::HighPage(value) => (PatchKind::HighPage, value),
+        };
+
+        let value = self.value(kind, expression, bytes.len(), source, diagnostics)?;
+        bytes.extend(value);
+
+        return Ok(bytes);
+    }
+
+    /// Bytes of a value `position` bytes after the current location, or a
+    /// placeholder and a patch when it is only known after placing the
+    /// sections.
+    fn value(&mut self, kind: PatchKind, expression: &Expression, position: usize, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<Vec<u8>, Box<Diagnostic>> {
+        let location = self.current_location();
+        let address = location.as_ref().and_then(|location| location.address).map(|address| address + position as i32);
+        let expression = resolve_known(expression, &self.symbols, self.scope.as_deref(), location.as_ref().and_then(|location| location.address));
+
+        // the offset of `jr` depends on where it ends up
+        if kind != PatchKind::Relative || address.is_some() {
+            if let Ok(value) = expr::evaluate_number(&expression, &self.symbols) {
+                if is_truncated(kind, value) {
+                    let mut warning = truncation_warning(kind, value, expression.span());
+                    if source.name != self.main_file {
+                        warning = warning.with_file(&source.name);
+                    }
+                    diagnostics.push(warning);
+                }
+
+                return encode_value(kind, value, address).map_err(|message| {
+                    Box::new(Diagnostic::error(E_VALUE_RANGE, &message, expression.span())
+                        .with_label("value out of range"))
+                });
+            }
+        }
+
+        if let (Some(section), Some(location)) = (self.section, location) {
+            self.patches.push(Patch {
+                file: source.name.clone(),
+                span: self.call_site.unwrap_or(expression.span()),
+                section,
+                offset: location.offset as usize + position,
+                start: location.offset as usize,
+                kind,
+                expression,
+            });
+        }
+
+        return Ok(vec![0; kind.size()]);
+    }
+
+
+    /// Looks for `path` in the current directory, then in each include path.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        return std::iter::once(PathBuf::from(path))
+            .chain(self.include_paths.iter().map(|directory| directory.join(path)))
+            .find(|candidate| candidate.is_file());
+    }
+
+    /// Name under which an INCLUDE or INCBIN of `path` is recorded, if the
+    /// file exists.
+    pub fn find_file(&self, path: &str) -> Option<String> {
+        return self.resolve(path).map(|path| display_path(&path));
+    }
+
+    fn read_file(&self, path: &Path) -> std::io::Result<String> {
+        let overlay = fs::canonicalize(path).ok().and_then(|path| self.overlays.get(&path));
+        if let Some(text) = overlay {
+            return Ok(text.clone());
+        }
+
+        return fs::read_to_string(path);
+    }
+
+    fn missing_file(&mut self, path: &str, span: Span) -> Result<(), Box<Diagnostic>> {
+        if self.missing_files_allowed {
+            self.add_dependency(path);
+            self.stopped = true;
+            return Ok(());
+        }
+
+        let mut diagnostic = Diagnostic::error(E_FILE_NOT_FOUND, &format!("Unable to open `{}`", path), span)
+            .with_label("file not found");
+        if !self.include_paths.is_empty() {
+            let paths: Vec<String> = self.include_paths.iter().map(|p| p.display().to_string()).collect();
+            diagnostic = diagnostic.with_note(&format!("searched the current directory and {}", paths.join(", ")));
+        }
+
+        return Err(Box::new(diagnostic));
+    }
+
+    fn assemble_include(&mut self, include: &ast::IncludeStatement, diagnostics: &mut Diagnostics) -> Result<(), Box<Diagnostic>> {
+        let path = match self.resolve(&include.path) {
+            Some(path) => path,
+            None => return self.missing_file(&include.path, include.span),
+        };
+
+        if self.include_depth >= MAX_INCLUDE_DEPTH {
+            return Err(Box::new(Diagnostic::error(E_INCLUDE_DEPTH, "Maximum INCLUDE depth exceeded", include.span)
+                .with_label(&format!("more than {} nested includes", MAX_INCLUDE_DEPTH))
+                .with_note("a file probably includes itself")));
+        }
+
+        let name = display_path(&path);
+        self.add_dependency(&name);
+
+        let text = self.read_file(&path).map_err(|error| {
+            Diagnostic::error(E_FILE_NOT_FOUND, &format!("Unable to read `{}`: {}", name, error), include.span)
+        })?;
+        let included = SourceFile::new(&name, text);
+
+        let mut parse_diagnostics = Diagnostics::new();
+        let included_ast = parser::parse_ast(lexer::lex_content(&included.text), &mut parse_diagnostics);
+        for diagnostic in parse_diagnostics.iter() {
+            diagnostics.push(diagnostic.clone().with_file(&name));
+        }
+
+        self.include_depth += 1;
+        self.expansion_stack.push(None);
+        self.assemble_statements(&included_ast.statements, &included, diagnostics);
+        self.expansion_stack.pop();
+        self.include_depth -= 1;
+
+        if !self.sources.iter().any(|source| source.name == name) {
+            self.sources.push(included);
+        }
+
+        return Ok(());
+    }
+
+    fn assemble_incbin(&mut self, incbin: &ast::IncbinStatement) -> Result<(), Box<Diagnostic>> {
+        let path = match self.resolve(&incbin.path) {
+            Some(path) => path,
+            None => return self.missing_file(&incbin.path, incbin.span),
+        };
+
+        let name = display_path(&path);
+        self.add_dependency(&name);
+
+        let contents = fs::read(&path).map_err(|error| {
+            Diagnostic::error(E_FILE_NOT_FOUND, &format!("Unable to read `{}`: {}", name, error), incbin.span)
+        })?;
+        let size = contents.len() as i64;
+
+        let start = match &incbin.start {
+            Some(start) => expr::evaluate_number(start, &self.symbols)? as i64,
+            None => 0,
+        };
+        let length = match &incbin.length {
+            Some(length) => expr::evaluate_number(length, &self.symbols)? as i64,
+            None => size - start.min(size),
+        };
+
+        if start < 0 || length < 0 || start + length > size {
+            let label = match incbin.length {
+                Some(_) => format!("{} byte(s) from offset {}", length, start),
+                None => format!("starts at offset {}", start),
+            };
+
+            return Err(Box::new(Diagnostic::error(E_INCBIN_RANGE, &format!("INCBIN range is outside of `{}`", name), incbin.span)
+                .with_label(&label)
+                .with_note(&format!("the file is {} byte(s) long", size))));
+        }
+
+        return self.output(&contents[start as usize..(start + length) as usize], incbin.span);
+    }
+
+    fn assemble_section(&mut self, section: &ast::SectionStatement, source: &SourceFile) -> Result<(), Box<Diagnostic>> {
+        let section_type = section.section_type.to_uppercase();
+        let address = match &section.address {
+            Some(address) => Some(expr::evaluate_number(address, &self.symbols)?),
+            None => None,
+        };
+        let bank = match &section.bank {
+            Some(bank) => Some(expr::evaluate_number(bank, &self.symbols)?),
+            // types with a single bank
+            None if ["ROM0", "WRAM0", "OAM", "HRAM"].contains(&section_type.as_str()) => Some(0),
+            None => None,
+        };
+        let alignment = match &section.alignment {
+            Some(alignment) => Some(expr::evaluate_number(alignment, &self.symbols)?),
+            None => None,
+        };
+
+        // going back to a section continues after its end
+        if let Some(index) = self.sections.iter().position(|s| s.name == section.name) {
+            self.section = Some(index);
+            return Ok(());
+        }
+
+        self.sections.push(Section {
+            name: section.name.clone(),
+            section_type,
+            bank,
+            address,
+            alignment,
+            data: vec![],
+            definition: Definition {
+                file: source.name.clone(),
+                span: self.call_site.unwrap_or(section.span),
+            },
+        });
+        self.section = Some(self.sections.len() - 1);
+
+        return Ok(());
+    }
+
+    fn assemble_test(&mut self, test: &ast::TestStatement, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<(), Box<Diagnostic>> {
+        let span = self.call_site.unwrap_or(test.span);
+
+        if self.test.is_some() {
+            return Err(Box::new(Diagnostic::error(E_TEST_BLOCK, "TEST blocks cannot be nested", span)
+                .with_label("inside another TEST block")));
+        }
+
+        if let Some(previous) = self.tests.iter().find(|t| t.name == test.name) {
+            let mut diagnostic = Diagnostic::error(E_TEST_BLOCK, &format!("Test `{}` is defined twice", test.name), span)
+                .with_label("second definition");
+            if previous.definition.file == source.name {
+                diagnostic = diagnostic.with_secondary(previous.definition.span, "first defined here");
+            }
+            return Err(Box::new(diagnostic));
+        }
+
+        let definition = Definition {
+            file: source.name.clone(),
+            span,
+        };
+        self.sections.push(Section {
+            name: format!("TEST \"{}\"", test.name),
+            section_type: "ROMX".to_string(),
+            bank: None,
+            address: None,
+            alignment: None,
+            data: vec![],
+            definition: definition.clone(),
+        });
+        self.tests.push(Test {
+            name: test.name.clone(),
+            section: self.sections.len() - 1,
+            expectations: vec![],
+            definition,
+        });
+
+        // the code of the test goes in its own section, where local labels
+        // belong to the test, then assembling goes on where it was
+        let name = self.sections[self.sections.len() - 1].name.clone();
+        let previous = (self.section, self.scope.replace(name));
+        self.section = Some(self.sections.len() - 1);
+        self.test = Some(self.tests.len() - 1);
+
+        self.assemble_statements(&test.statements, source, diagnostics);
+
+        self.test = None;
+        (self.section, self.scope) = previous;
+
+        return Ok(());
+    }
+
+    fn assemble_expect(&mut self, expect: &ast::ExpectStatement, source: &SourceFile) -> Result<(), Box<Diagnostic>> {
+        let span = self.call_site.unwrap_or(expect.span);
+        let test = match self.test {
+            Some(test) if self.section == Some(self.tests[test].section) => test,
+            _ => {
+                return Err(Box::new(Diagnostic::error(E_TEST_BLOCK, "EXPECT outside of a TEST block", span)
+                    .with_label("not checked by any test")
+                    .with_help("move it between TEST and ENDT")));
+            }
+        };
+
+        let resolve = |expression: &Expression| resolve_known(expression, &self.symbols, self.scope.as_deref(), None);
+        let target = match &expect.target {
+            Operand::Indirect(address, span) => Operand::Indirect(resolve(address), *span),
+            target => target.clone(),
+        };
+        let comparison = expect.comparison.as_ref().map(|(operator, value)| (operator.clone(), resolve(value)));
+
+        let offset = self.sections[self.tests[test].section].data.len();
+        self.tests[test].expectations.push(Expectation {
+            file: source.name.clone(),
+            span,
+            offset,
+            target,
+            comparison,
+        });
+
+        return Ok(());
+    }
+
+    fn record_output(&mut self, location: LabelLocation, size: i32, source: &SourceFile, span: Span) {
+        let (file, line) = self.source_position(source, span);
+        let expansions = self.expansion_stack.iter().rev()
+            .filter_map(|frame| frame.as_ref().map(|frame| frame.invocation.clone()))
+            .collect();
+
+        self.output_lines.push(OutputLine { file, line, expansions, location, size });
+    }
+
+    /// File and line of a span, in the body of the macro for the text of
+    /// its expansions.
+    fn source_position(&self, source: &SourceFile, span: Span) -> (String, usize) {
+        let (line, _) = source.line_col(span.start);
+
+        return match self.expansion_stack.last() {
+            Some(Some(frame)) => (frame.body_start.0.clone(), frame.body_start.1 + line - 1),
+            _ => (source.name.clone(), line),
+        };
+    }
+
+    fn current_location(&self) -> Option<LabelLocation> {
+        let section = &self.sections[self.section?];
+        let offset = section.data.len() as i32;
+
+        return Some(LabelLocation {
+            section: section.name.clone(),
+            section_type: section.section_type.clone(),
+            bank: section.bank,
+            offset,
+            address: section.address.map(|address| address + offset),
+        });
+    }
+
+    fn output(&mut self, bytes: &[u8], span: Span) -> Result<(), Box<Diagnostic>> {
+        let index = match self.section {
+            Some(index) => index,
+            None => {
+                return Err(Box::new(Diagnostic::error(E_OUTSIDE_SECTION, "Cannot output data outside of a SECTION", span)
+                    .with_help("add a SECTION directive before this line")));
+            }
+        };
+
+        self.sections[index].data.extend_from_slice(bytes);
+
+        return Ok(());
+    }
+
+    fn data_bytes(&mut self, data: &ast::DataStatement, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<Vec<u8>, Box<Diagnostic>> {
+        let kind = match data.kind {
+            DataKind::Db => PatchKind::Byte,
+            DataKind::Dw => PatchKind::Word,
+            DataKind::Dl => PatchKind::Long,
+            DataKind::Ds => return self.reserved_bytes(data, source, diagnostics),
+        };
+
+        // the strings of the compressed charmap are collected, then compressed
+        let compressed = kind == PatchKind::Byte
+            && self.text_compression.as_ref().is_some_and(|options| options.charmap == self.symbols.charmaps.current_name());
+
+        let mut bytes = vec![];
+        for value in &data.values {
+            // strings go through the charmap, then take one unit per value
+            let string = match value {
+                Expression::String(..) => Some(expr::evaluate(value, &self.symbols)?),
+                _ => expr::evaluate(value, &self.symbols).ok(),
+            };
+            if let Some(ExpressionValue::String(s)) = string {
+                let values = self.encode_string(&s, kind, value.span(), source, diagnostics);
+                if compressed {
+                    let units = self.text_units(&s);
+                    match &self.dictionary {
+                        Some(dictionary) => bytes.extend(dictionary.compress(&units)),
+                        None => {
+                            bytes.extend(values.iter().map(|value| *value as u8));
+                            self.texts.push(units);
+                            if self.first_text.is_none() {
+                                self.first_text = Some(Definition {
+                                    file: source.name.clone(),
+                                    span: self.call_site.unwrap_or(value.span()),
+                                });
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                for value in values {
+                    bytes.extend_from_slice(&value.to_le_bytes()[..kind.size()]);
+                }
+                continue;
+            }
+
+            let value = self.value(kind, value, bytes.len(), source, diagnostics)?;
+            bytes.extend(value);
+        }
+
+        if compressed && self.dictionary.is_none() {
+            for byte in &bytes {
+                self.text_values[*byte as usize] = true;
+            }
+        }
+
+        return Ok(bytes);
+    }
+
+    /// The units of a string in the current charmap, with their bytes.
+    fn text_units(&self, text: &str) -> Vec<Unit> {
+        return self.symbols.charmaps.current().units(text).into_iter().map(|(unit, values)| {
+            let bytes = match values {
+                Some(values) => values.iter().map(|value| *value as u8).collect(),
+                None => unit.as_bytes().to_vec(),
+            };
+            (unit.to_string(), bytes)
+        }).collect();
+    }
+
+    /// Values of a string with the current charmap, warning about the
+    /// characters it does not map and the values that do not fit.
+    fn encode_string(&self, text: &str, kind: PatchKind, span: Span, source: &SourceFile, diagnostics: &mut Diagnostics) -> Vec<i32> {
+        let charmap = self.symbols.charmaps.current();
+        let (values, unmapped) = charmap.encode(text);
+
+        let mut warnings = vec![];
+        if !charmap.is_empty() && !unmapped.is_empty() {
+            let mut characters: Vec<String> = vec![];
+            for c in unmapped.iter().map(|c| format!("`{}`", c.escape_debug())) {
+                if !characters.contains(&c) {
+                    characters.push(c);
+                }
+            }
+            let message = match characters.len() {
+                1 => format!("Character {} is not in charmap `{}`", characters[0], self.symbols.charmaps.current_name()),
+                _ => format!("Characters {} are not in charmap `{}`", characters.join(", "), self.symbols.charmaps.current_name()),
+            };
+            warnings.push(Diagnostic::warning(UNMAPPED_CHARACTER, &message, span)
+                .with_label("output as their UTF-8 bytes"));
+        }
+        if let Some(value) = values.iter().find(|value| is_truncated(kind, **value)) {
+            warnings.push(truncation_warning(kind, *value, span));
+        }
+
+        for mut warning in warnings {
+            if source.name != self.main_file {
+                warning = warning.with_file(&source.name);
+            }
+            diagnostics.push(warning);
+        }
+
+        return values;
+    }
+
+    // `ds count` filled with zeros, or `ds count, byte, ...` repeating the bytes
+    fn reserved_bytes(&mut self, data: &ast::DataStatement, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<Vec<u8>, Box<Diagnostic>> {
+        let count = match data.values.first() {
+            Some(count) => expr::evaluate_number(count, &self.symbols)?,
+            None => {
+                return Err(Box::new(Diagnostic::error(E_VALUE_RANGE, "Missing number of bytes to reserve", data.span)
+                    .with_label("expected `ds count` or `ds count, byte, ...`")));
+            }
+        };
+        if count < 0 {
+            return Err(Box::new(Diagnostic::error(E_VALUE_RANGE, "Cannot reserve a negative number of bytes", data.values[0].span())
+                .with_label(&format!("this is {}", count))));
+        }
+
+        let fill = &data.values[1..];
+        if fill.is_empty() {
+            return Ok(vec![0; count as usize]);
+        }
+
+        // a fill known now is repeated as is, large areas are often filled
+        let known: Option<Vec<u8>> = fill.iter()
+            .map(|value| expr::evaluate_number(value, &self.symbols).ok().filter(|value| !is_truncated(PatchKind::Byte, *value)))
+            .map(|value| value.map(|value| value as u8))
+            .collect();
+        if let Some(pattern) = known {
+            return Ok(pattern.iter().copied().cycle().take(count as usize).collect());
+        }
+
+        let mut bytes = vec![];
+        for i in 0..count as usize {
+            let value = self.value(PatchKind::Byte, &fill[i % fill.len()], i, source, diagnostics)?;
+            bytes.extend(value);
+        }
+
+        return Ok(bytes);
+    }
+
+    /// Full name of a label, with the parent of local labels.
+    fn label_name(&self, label: &ast::LabelStatement) -> Result<String, Box<Diagnostic>> {
+        if !label.name.starts_with('.') {
+            return Ok(label.name.clone());
+        }
+
+        return match &self.scope {
+            Some(scope) => Ok(format!("{}{}", scope, label.name)),
+            None => Err(Box::new(Diagnostic::error(E_LOCAL_LABEL_SCOPE, &format!("Local label `{}` has no parent label", label.name), label.span)
+                .with_label("defined before any global label"))),
+        };
+    }
+
+    fn assemble_label(&mut self, label: &ast::LabelStatement, source: &SourceFile) -> Result<(), Box<Diagnostic>> {
+        let location = match self.current_location() {
+            Some(location) => location,
+            None => {
+                let message = format!("Label `{}` created outside of a SECTION", label.name);
+                return Err(Box::new(Diagnostic::error(E_OUTSIDE_SECTION, &message, label.span)
+                    .with_help("add a SECTION directive before the label")));
+            }
+        };
+
+        let name = self.label_name(label)?;
+        if !label.name.contains('.') {
+            self.scope = Some(name.clone());
+        }
+
+        let definition = Definition {
+            file: source.name.clone(),
+            span: self.call_site.unwrap_or(label.span),
+        };
+
+        return self.symbols.define(&name, SymbolKind::Label, SymbolValue::Label(location), Some(definition))
+            .map_err(|error| Box::new(redefinition_error(error, label.span, source)));
+    }
+
+    fn define_macro(&mut self, definition: &ast::MacroStatement, source: &SourceFile) -> Result<(), Box<Diagnostic>> {
+        if let Some(existing) = self.macros.get(&definition.name) {
+            let error = SymbolError {
+                message: format!("Macro `{}` is already defined", definition.name),
+                previous: Some(existing.definition.clone()),
+            };
+            return Err(Box::new(redefinition_error(error, definition.span, source)));
+        }
+
+        let body_span = definition.tokens.first().map_or(definition.span, |token| token.span);
+        self.macros.insert(definition.name.clone(), Macro {
+            body: lexer::tokens_text(&definition.tokens),
+            definition: Definition {
+                file: source.name.clone(),
+                span: self.call_site.unwrap_or(definition.span),
+            },
+            body_start: self.source_position(source, body_span),
+        });
+
+        return Ok(());
+    }
+
+    fn assemble_macro_call(&mut self, call: &ast::MacroCallStatement, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<(), Box<Diagnostic>> {
+        let (body, body_start) = match self.macros.get(&call.name) {
+            Some(definition) => (definition.body.clone(), definition.body_start.clone()),
+            None => {
+                return Err(Box::new(Diagnostic::error(E_UNDEFINED_MACRO, &format!("Macro `{}` is not defined", call.name), call.span)
+                    .with_label("not an instruction, directive or macro")));
+            }
+        };
+
+        if self.include_depth >= MAX_INCLUDE_DEPTH {
+            return Err(Box::new(Diagnostic::error(E_INCLUDE_DEPTH, "Maximum macro expansion depth exceeded", call.span)
+                .with_label(&format!("more than {} nested expansions", MAX_INCLUDE_DEPTH))
+                .with_note("a macro probably invokes itself")));
+        }
+
+        self.expansions += 1;
+        let text = expand(&body, &call.arguments, self.expansions).map_err(|message| {
+            Diagnostic::error(E_UNDEFINED_MACRO, &message, call.span)
+                .with_label(&format!("called with {} argument(s)", call.arguments.len()))
+        })?;
+        self.symbols.enter_macro(call.arguments.len());
+        self.assemble_expansion(&call.name, text, body_start, call.span, source, diagnostics);
+        self.symbols.leave_macro();
+
+        return Ok(());
+    }
+
+    fn assemble_rept(&mut self, rept: &ast::ReptStatement, source: &SourceFile, diagnostics: &mut Diagnostics) -> Result<(), Box<Diagnostic>> {
+        let count = expr::evaluate_number(&rept.count, &self.symbols)?;
+        if count < 0 {
+            return Err(Box::new(Diagnostic::error(E_VALUE_RANGE, &format!("REPT count {} is negative", count), rept.count.span())
+                .with_label("expected 0 or more")));
+        }
+
+        let body = lexer::tokens_text(&rept.tokens);
+        let body_span = rept.tokens.first().map_or(rept.span, |token| token.span);
+        let body_start = self.source_position(source, body_span);
+        for _ in 0..count {
+            if self.stopped {
+                break;
+            }
+
+            // each repetition has its own `\@`
+            self.expansions += 1;
+            let text = expand(&body, &[], self.expansions).map_err(|message| {
+                Diagnostic::error(E_UNDEFINED_MACRO, &message, rept.span)
+                    .with_label("REPT has no arguments")
+            })?;
+            self.assemble_expansion("REPT", text, body_start.clone(), rept.span, source, diagnostics);
+        }
+
+        return Ok(());
+    }
+
+    /// Assembles the expanded body of a macro or a REPT, its lines counted
+    /// from `body_start` and its diagnostics reported at `span`, where it is
+    /// invoked.
+    fn assemble_expansion(&mut self, name: &str, text: String, body_start: (String, usize), span: Span, source: &SourceFile, diagnostics: &mut Diagnostics) {
+        let expansion = SourceFile::new(&source.name, text);
+
+        let mut expansion_diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&expansion.text), &mut expansion_diagnostics);
+
+        let (file, line) = self.source_position(source, span);
+        self.expansion_stack.push(Some(ExpansionFrame {
+            invocation: Expansion { name: name.to_string(), file, line },
+            body_start,
+        }));
+
+        let call_site = self.call_site.replace(self.call_site.unwrap_or(span));
+        self.include_depth += 1;
+        self.assemble_statements(&ast.statements, &expansion, &mut expansion_diagnostics);
+        self.include_depth -= 1;
+        self.call_site = call_site;
+        self.expansion_stack.pop();
+
+        // the expanded text is not in any file, so point at the invocation
+        let file = if source.name != self.main_file { Some(source.name.clone()) } else { None };
+        for diagnostic in expansion_diagnostics.iter() {
+            if diagnostic.file.is_some() && diagnostic.file != file {
+                diagnostics.push(diagnostic.clone());
+                continue;
+            }
+
+            let mut remapped = Diagnostic::new(diagnostic.severity, diagnostic.code, &diagnostic.message, span)
+                .with_label(&format!("in this expansion of `{}`", name));
+            remapped.notes = diagnostic.notes.clone();
+            remapped.help = diagnostic.help.clone();
+            remapped.file = file.clone();
+            diagnostics.push(remapped);
+        }
+    }
+
+    fn assemble_def(&mut self, def: &ast::DefStatement, source: &SourceFile) -> Result<(), Box<Diagnostic>> {
+        let (kind, value) = match def.kind {
+            DefKind::Equ | DefKind::Set => {
+                let expression = def.expression.as_ref().unwrap();
+                let value = expr::evaluate_number(expression, &self.symbols)?;
+                let kind = if def.kind == DefKind::Equ { SymbolKind::Constant } else { SymbolKind::Variable };
+
+                (kind, SymbolValue::Number(value))
+            }
+            DefKind::Equs => {
+                let expression = def.expression.as_ref().unwrap();
+
+                match expr::evaluate(expression, &self.symbols)? {
+                    ExpressionValue::String(s) => (SymbolKind::String, SymbolValue::String(s)),
+                    ExpressionValue::Number(_) => {
+                        return Err(Box::new(Diagnostic::error(E_INVALID_DEFINE, "EQUS expects a string", expression.span())
+                            .with_label("this is a number")
+                            .with_help("use EQU for numeric constants")));
+                    }
+                }
+            }
+            DefKind::Rb | DefKind::Rw | DefKind::Rl => {
+                let count = match &def.expression {
+                    Some(expression) => expr::evaluate_number(expression, &self.symbols)?,
+                    None => 1,
+                };
+                let size = match def.kind {
+                    DefKind::Rb => 1,
+                    DefKind::Rw => 2,
+                    _ => 4,
+                };
+
+                let offset = self.symbols.rs();
+                self.symbols.set_rs(offset.wrapping_add(count.wrapping_mul(size)));
+
+                (SymbolKind::Constant, SymbolValue::Number(offset))
+            }
+        };
+
+        let definition = Definition {
+            file: source.name.clone(),
+            span: self.call_site.unwrap_or(def.span),
+        };
+
+        return self.symbols.define(&def.name, kind, value, Some(definition))
+            .map_err(|error| Box::new(redefinition_error(error, def.span, source)));
+    }
+}
+
+/// The expression with the numbers and the labels at a fixed address replaced
+/// by their value, local labels by their name under `scope` and `@` by `here`
+/// when known, so it can be evaluated after assembling.
+pub fn resolve_known(expression: &Expression, symbols: &SymbolTable, scope: Option<&str>, here: Option<i32>) -> Expression {
+    return match expression {
+        Expression::Symbol(name, span) if name == "@" => match here {
+            Some(address) => Expression::Number(address, *span),
+            None => expression.clone(),
+        },
+        Expression::Symbol(name, span) => {
+            let name = match (scope, name.starts_with('.')) {
+                (Some(scope), true) => format!("{}{}", scope, name),
+                _ => name.clone(),
+            };
+
+            match symbols.value(&name) {
+                Ok(SymbolValue::Number(value)) => Expression::Number(value, *span),
+                Ok(SymbolValue::Label(LabelLocation { address: Some(address), .. })) => Expression::Number(address, *span),
+                _ => Expression::Symbol(name, *span),
+            }
+        }
+        Expression::Unary { operator, operand, span } => Expression::Unary {
+            operator: operator.clone(),
+            operand: Box::new(resolve_known(operand, symbols, scope, here)),
+            span: *span,
+        },
+        Expression::Binary { operator, left, right, span } => Expression::Binary {
+            operator: operator.clone(),
+            left: Box::new(resolve_known(left, symbols, scope, here)),
+            right: Box::new(resolve_known(right, symbols, scope, here)),
+            span: *span,
+        },
+        Expression::Call { name, arguments, span } if name != "DEF" => Expression::Call {
+            name: name.clone(),
+            arguments: arguments.iter().map(|argument| resolve_known(argument, symbols, scope, here)).collect(),
+            span: *span,
+        },
+        _ => expression.clone(),
+    };
+}
+
+/// Whether a value stored as `kind` loses bits, which is only a warning.
+pub fn is_truncated(kind: PatchKind, value: i32) -> bool {
+    return match kind {
+        PatchKind::Byte => !(-128..=255).contains(&value),
+        PatchKind::Word => !(-32768..=65535).contains(&value),
+        _ => false,
+    };
+}
+
+/// Bytes of `value` stored as `kind`, `address` being where it is stored.
+/// Bytes and words are truncated, other values must fit.
+pub fn encode_value(kind: PatchKind, value: i32, address: Option<i32>) -> Result<Vec<u8>, String> {
+    let (value, range, description) = match kind {
+        PatchKind::Byte | PatchKind::Word | PatchKind::Long => return Ok(value.to_le_bytes()[..kind.size()].to_vec()),
+        PatchKind::Signed => (value, -128..=127, "a signed byte"),
+        PatchKind::Relative => {
+            let next = address.ok_or("the address of `jr` is not known")? + 1;
+            let offset = value.wrapping_sub(next);
+            if !(-128..=127).contains(&offset) {
+                return Err(format!("Jump target is {} byte(s) away, out of reach of `jr`", offset));
+            }
+            (offset, -128..=127, "a signed byte")
+        }
+        // like RGBDS, `ldh [$40], a` is the same as `ldh [$FF40], a`
+        PatchKind::HighPage if (0xFF00..=0xFFFF).contains(&value) => (value & 0xFF, 0..=255, ""),
+        PatchKind::HighPage if (0..=0xFF).contains(&value) => (value, 0..=255, ""),
+        PatchKind::HighPage => return Err(format!("`ldh` expects an address in $FF00-$FFFF, found {}", value)),
+    };
+
+    if !range.contains(&value) {
+        return Err(format!("{} does not fit in {}", value, description));
+    }
+
+    return Ok(value.to_le_bytes()[..kind.size()].to_vec());
+}
+
+fn display_path(path: &Path) -> String {
+    return path.strip_prefix("./").unwrap_or(path).display().to_string();
+}
+
+/// Substitutes the arguments (`\1` to `\9`, `\#` for all of them) and the
+/// unique suffix (`\@`) in a macro body.
+fn expand(body: &str, arguments: &[String], unique: usize) -> Result<String, String> {
+    let mut text = String::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+
+        match chars.peek().copied() {
+            Some(digit @ '1'..='9') => {
+                chars.next();
+                let index = digit.to_digit(10).unwrap() as usize;
+                match arguments.get(index - 1) {
+                    Some(argument) => text += argument,
+                    None => return Err(format!("Macro argument `\\{}` is not defined", index)),
+                }
+            }
+            Some('#') => {
+                chars.next();
+                text += &arguments.join(", ");
+            }
+            Some('@') => {
+                chars.next();
+                text += &format!("_u{}", unique);
+            }
+            _ => text.push(c),
+        }
+    }
+
+    return Ok(text);
+}
+
+pub fn truncation_warning(kind: PatchKind, value: i32, span: Span) -> Diagnostic {
+    let bits = if kind == PatchKind::Word { 16 } else { 8 };
+
+    return Diagnostic::warning(TRUNCATION, &format!("{} does not fit in {} bits and is truncated", value, bits), span)
+        .with_label(&format!("stored as ${:0width$X}", value & ((1 << bits) - 1), width = bits / 4));
+}
+
+fn redefinition_error(error: SymbolError, span: Span, source: &SourceFile) -> Diagnostic {
+    let mut diagnostic = Diagnostic::error(E_SYMBOL_REDEFINED, &error.message, span);
+
+    match error.previous {
+        Some(previous) if previous.file == source.name => {
+            diagnostic = diagnostic.with_secondary(previous.span, "previously defined here");
+        }
+        Some(previous) => {
+            diagnostic = diagnostic.with_note(&format!("previously defined in {}", previous.file));
+        }
+        None => {}
+    }
+
+    return diagnostic;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer, parser};
+    use std::time::UNIX_EPOCH;
+
+    fn assemble(assembler: &mut Assembler, content: &str) -> Diagnostics {
+        let source = SourceFile::new("main.asm", content.to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+
+        return diagnostics;
+    }
+
+    #[test]
+    fn conditional_assembly_with_defines() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.define("DEBUG", "1").unwrap();
+        assembler.define("REGION", "JP").unwrap();
+
+        let diagnostics = assemble(&mut assembler, concat!(
+            "IF DEF(DEBUG) && DEBUG\n",
+            "    DEF LOG_LEVEL EQU 2\n",
+            "ELSE\n",
+            "    DEF LOG_LEVEL EQU 0\n",
+            "ENDC\n",
+            "IF !STRCMP(\"{REGION}\", \"JP\")\n",
+            "DEF TITLE EQUS \"ポケモン\"\n",
+            "ELIF __RGBDS_MAJOR__ > 0\n",
+            "DEF TITLE EQUS \"POKEMON\"\n",
+            "ENDC\n",
+            "DEF line = __LINE__\n",
+        ));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(assembler.symbols.value("LOG_LEVEL"), Ok(SymbolValue::Number(2)));
+        assert_eq!(assembler.symbols.value("TITLE"), Ok(SymbolValue::String("ポケモン".to_string())));
+        assert_eq!(assembler.symbols.value("line"), Ok(SymbolValue::Number(11)));
+    }
+
+    #[test]
+    fn rs_counters() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+
+        let diagnostics = assemble(&mut assembler, concat!(
+            "RSSET $C000\n",
+            "wPlayerX RB\n",
+            "wScore RW 2\n",
+            "DEF wEnd RB 0\n",
+            "RSRESET\n",
+            "DEF first RL\n",
+        ));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(assembler.symbols.value("wPlayerX"), Ok(SymbolValue::Number(0xC000)));
+        assert_eq!(assembler.symbols.value("wScore"), Ok(SymbolValue::Number(0xC001)));
+        assert_eq!(assembler.symbols.value("wEnd"), Ok(SymbolValue::Number(0xC005)));
+        assert_eq!(assembler.symbols.value("_RS"), Ok(SymbolValue::Number(4)));
+    }
+
+    #[test]
+    fn relaxing_jumps() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+
+        let diagnostics = assemble(&mut assembler, concat!(
+            "SECTION \"Code\", ROM0[$100]\n",
+            "Start:\n",
+            ".loop: jmp .loop\n",
+            "\tjmp Target\n",
+            "\tjmp Far\n",
+            "\tds 125\n",
+            "Target: jmp nz, Start\n",
+            "\tds 200\n",
+            "Far:\n",
+            "\tjmp [hl]\n",
+        ));
+
+        // `jmp Far` becoming a `jp` puts `Target` out of reach
+        let address = |name: &str| match assembler.symbols.value(name) {
+            Ok(SymbolValue::Label(location)) => location.address,
+            _ => None,
+        };
+        assert_eq!(address("Target"), Some(0x185));
+        assert_eq!(address("Far"), Some(0x250));
+
+        let relaxed: Vec<Option<bool>> = assembler.placements.iter().map(|placement| placement.relaxed).collect();
+        assert_eq!(relaxed, vec![Some(true), Some(false), Some(false), Some(false)]);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics.iter().next().unwrap().message, "Invalid operands for `jmp`");
+    }
+
+    #[test]
+    fn assembling_bytes() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        let diagnostics = assemble(&mut assembler, concat!(
+            "DEF rLCDC EQU $FF40\n",
+            "SECTION \"Code\", ROM0[$150]\n",
+            "Start:\n",
+            "\tldh a, [rLCDC]\n",
+            "\tbit 7, a\n",
+            ".wait: jr nz, .wait\n",
+            "\tcall Later\n",
+            "\trst $38\n",
+            "\tdb \"Hi\", 300, @ - Start\n",
+            "\tdw Later\n",
+            "\tds 3, 1, 2\n",
+            "Later: ret\n",
+            "\tldh a, [$C000]\n",
+        ));
+
+        assert_eq!(assembler.sections[0].data, vec![
+            0xF0, 0x40,
+            0xCB, 0x7F,
+            0x20, 0xFE,
+            0xCD, 0x00, 0x00,
+            0xFF,
+            b'H', b'i', 0x2C, 0x0A,
+            0x00, 0x00,
+            0x01, 0x02, 0x01,
+            0xC9,
+        ]);
+
+        // `Later` is only known after assembling
+        let patched: Vec<(usize, PatchKind)> = assembler.patches.iter().map(|patch| (patch.offset, patch.kind)).collect();
+        assert_eq!(patched, vec![(7, PatchKind::Word), (14, PatchKind::Word)]);
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "300 does not fit in 8 bits and is truncated",
+            "`ldh` expects an address in $FF00-$FFFF, found 49152",
+        ]);
+    }
+
+    #[test]
+    fn encoding_strings_with_charmaps() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        let diagnostics = assemble(&mut assembler, concat!(
+            "CHARMAP \"A\", $80\n",
+            "NEWCHARMAP text, main\n",
+            "CHARMAP \"<PLAYER>\", $52, $53\n",
+            "CHARMAP \"<\", 1 + 1\n",
+            "DEF NAME_LENGTH EQU CHARLEN(\"A<PLAYER>\")\n",
+            "SECTION \"Text\", ROM0\n",
+            "\tdb \"A<PLAYER><B\", NAME_LENGTH\n",
+            "PUSHC\n",
+            "SETCHARMAP main\n",
+            "\tdw \"A<\"\n",
+            "POPC\n",
+            "\tdb CHARSUB(\"A<PLAYER>\", 2)\n",
+            "POPC\n",
+            "SETCHARMAP menu\n",
+        ));
+
+        assert_eq!(assembler.sections[0].data, vec![0x80, 0x52, 0x53, 0x02, b'B', 0x02, 0x80, 0x00, b'<', 0x00, 0x52, 0x53]);
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Character `B` is not in charmap `text`",
+            "Character `<` is not in charmap `main`",
+            "POPC without a matching PUSHC",
+            "Charmap `menu` is not defined",
+        ]);
+    }
+
+    #[test]
+    fn recording_dependencies() {
+        let directory = std::env::temp_dir().join(format!("gbct-dependencies-{}", std::process::id()));
+        fs::create_dir_all(directory.join("inc")).unwrap();
+        fs::write(directory.join("inc/hardware.inc"), "DEF rLCDC EQU $FF40\nINCBIN \"font.1bpp\"\n").unwrap();
+        fs::write(directory.join("inc/font.1bpp"), [0u8; 8]).unwrap();
+
+        let include_path = directory.join("inc");
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.include_paths = vec![include_path.clone()];
+        let diagnostics = assemble(&mut assembler, "SECTION \"Font\", ROM0\nINCLUDE \"hardware.inc\"\nINCLUDE \"hardware.inc\"\n");
+
+        // the second include redefines rLCDC, reported in the included file
+        let diagnostic = diagnostics.iter().next().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostic.file, Some(display_path(&include_path.join("hardware.inc"))));
+        assert_eq!(assembler.dependencies(), &[
+            "main.asm".to_string(),
+            display_path(&include_path.join("hardware.inc")),
+            display_path(&include_path.join("font.1bpp")),
+        ]);
+
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.missing_files_allowed = true;
+        let diagnostics = assemble(&mut assembler, "INCLUDE \"generated.inc\"\nDEF X EQU UNDEFINED\n");
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(assembler.dependencies(), &["main.asm".to_string(), "generated.inc".to_string()]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reporting_redefinitions() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        let diagnostics = assemble(&mut assembler, "FOO EQU 1\nFOO EQU 2\n");
+
+        let diagnostic = diagnostics.iter().next().unwrap();
+        assert_eq!(diagnostic.message, "`FOO` is already defined");
+        assert_eq!(diagnostic.secondary[0].message, "previously defined here");
+    }
+
+    #[test]
+    fn laying_out_labels_and_macros() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        let diagnostics = assemble(&mut assembler, concat!(
+            "copy: MACRO\n",
+            "\tld hl, \\1\n",
+            ".loop\\@: ld [hl+], a\n",
+            "\tjr nz, .loop\\@\n",
+            "ENDM\n",
+            "SECTION \"Init\", ROM0[$150]\n",
+            "Init:\n",
+            "\tdb \"AB\", 1\n",
+            "\tcopy $C000\n",
+            ".end:\n",
+            "SECTION \"Code\", ROMX\n",
+            "Code: ds 4\n",
+            "After: nop\n",
+            "DEF size EQU After - Code\n",
+            "DEF start EQU Code\n",
+            "\tld [hl], [hl]\n",
+            "\tcopy\n",
+        ));
+
+        let location = |name: &str| match assembler.symbols.value(name) {
+            Ok(SymbolValue::Label(location)) => location.to_string(),
+            other => format!("{:?}", other),
+        };
+        assert_eq!(location("Init.end"), "$00:$0159");
+        assert_eq!(location("Init.loop_u1"), "$00:$0156");
+        assert_eq!(location("After"), "??:\"Code\"+$4");
+        assert_eq!(assembler.symbols.value("size"), Ok(SymbolValue::Number(4)));
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "`Code` is not constant at assembly time",
+            "Invalid operands for `ld`",
+            "Macro argument `\\1` is not defined",
+        ]);
+    }
+
+    #[test]
+    fn repeating_blocks() {
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        let diagnostics = assemble(&mut assembler, concat!(
+            "SECTION \"Code\", ROM0\n",
+            "Fill:\n",
+            "DEF n = 0\n",
+            "REPT 2\n",
+            ".next\\@: db n\n",
+            "DEF n = n + 1\n",
+            "\tREPT 2\n",
+            "\tnop\n",
+            "\tENDR\n",
+            "ENDR\n",
+            "REPT 0\n",
+            "\tdb $FF\n",
+            "ENDR\n",
+            "REPT -1\n",
+            "ENDR\n",
+        ));
+
+        let location = |name: &str| match assembler.symbols.value(name) {
+            Ok(SymbolValue::Label(location)) => location.to_string(),
+            other => format!("{:?}", other),
+        };
+        assert_eq!(assembler.sections()[0].data, [0, 0, 0, 1, 0, 0]);
+        assert_eq!(location("Fill.next_u4"), "$00:\"Code\"+$3");
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec!["REPT count -1 is negative"]);
+    }
+}
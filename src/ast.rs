@@ -1,24 +1,74 @@
+use std::any::Any;
 use std::fmt::{Debug, Formatter};
-use crate::lexer::Token;
+use crate::diagnostic::SourceFile;
+use crate::emit;
+use crate::expr::Expression;
+use crate::json::Value;
+use crate::lexer::{Span, Token, TokenType};
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum StatementType {
     Section,
     Include,
+    Incbin,
     If,
     Def,
     NewCharMap,
     CharMap,
     SetCharMap,
+    PushCharMap,
+    PopCharMap,
     Macro,
+    MacroCall,
+    Rept,
+    Label,
+    Instruction,
+    Data,
+    Rs,
+    Test,
+    Expect,
+    Error,
+}
+
+impl StatementType {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            StatementType::Section => "section",
+            StatementType::Include => "include",
+            StatementType::Incbin => "incbin",
+            StatementType::If => "if",
+            StatementType::Def => "def",
+            StatementType::NewCharMap => "new_char_map",
+            StatementType::CharMap => "char_map",
+            StatementType::SetCharMap => "set_char_map",
+            StatementType::PushCharMap => "push_char_map",
+            StatementType::PopCharMap => "pop_char_map",
+            StatementType::Macro => "macro",
+            StatementType::MacroCall => "macro_call",
+            StatementType::Rept => "rept",
+            StatementType::Label => "label",
+            StatementType::Instruction => "instruction",
+            StatementType::Data => "data",
+            StatementType::Rs => "rs",
+            StatementType::Test => "test",
+            StatementType::Expect => "expect",
+            StatementType::Error => "error",
+        };
+    }
 }
 
 pub trait Statement {
     fn my_type(&self) -> StatementType;
     fn to_string(&self) -> String;
+    fn span(&self) -> Span;
+    /// Statement specific fields for the JSON output, next to `kind` and `span`.
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)>;
+    fn as_any(&self) -> &dyn Any;
 }
 
 pub struct IncludeStatement {
-    pub path: String
+    pub path: String,
+    pub span: Span,
 }
 
 impl Statement for IncludeStatement {
@@ -29,11 +79,64 @@ impl Statement for IncludeStatement {
     fn to_string(&self) -> String {
         return "INCLUDE ".to_string() + "\"" + self.path.as_str() + "\"";
     }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![("path", self.path.as_str().into())];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// `INCBIN "path"[, start[, length]]`
+pub struct IncbinStatement {
+    pub path: String,
+    pub start: Option<Expression>,
+    pub length: Option<Expression>,
+    pub span: Span,
+}
+
+impl Statement for IncbinStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Incbin;
+    }
+
+    fn to_string(&self) -> String {
+        return "INCBIN ".to_string() + "\"" + self.path.as_str() + "\"";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let text = |e: &Option<Expression>| e.as_ref().map(|e| source.text[e.span().start..e.span().end].to_string());
+
+        return vec![
+            ("path", self.path.as_str().into()),
+            ("start", text(&self.start).into()),
+            ("length", text(&self.length).into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
 }
 
 pub struct SectionStatement {
     pub name: String,
     pub section_type: String,
+    /// Fixed address, `SECTION "name", ROM0[$100]`
+    pub address: Option<Expression>,
+    pub bank: Option<Expression>,
+    pub alignment: Option<Expression>,
+    pub span: Span,
 }
 
 impl Statement for SectionStatement {
@@ -44,9 +147,38 @@ impl Statement for SectionStatement {
     fn to_string(&self) -> String {
         return "SECTION ".to_string() + "\"" + self.name.as_str() + "\"";
     }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let text = |e: &Option<Expression>| e.as_ref().map(|e| source.text[e.span().start..e.span().end].to_string());
+
+        return vec![
+            ("name", self.name.as_str().into()),
+            ("section_type", self.section_type.as_str().into()),
+            ("address", text(&self.address).into()),
+            ("bank", text(&self.bank).into()),
+            ("alignment", text(&self.alignment).into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// One `IF`/`ELIF`/`ELSE` arm of a conditional block.
+pub struct ConditionalBranch {
+    /// `None` for the `ELSE` branch.
+    pub condition: Option<Expression>,
+    pub statements: Vec<Box<dyn Statement>>,
 }
 
 pub struct IfStatement {
+    pub branches: Vec<ConditionalBranch>,
+    pub span: Span,
 }
 
 impl Statement for IfStatement {
@@ -55,12 +187,40 @@ impl Statement for IfStatement {
     }
 
     fn to_string(&self) -> String {
-        return "IF ".to_string();
+        return "IF ".to_string() + self.branches.len().to_string().as_str() + " branch(es)";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let branches = self.branches.iter().map(|branch| {
+            let condition = match &branch.condition {
+                Some(condition) => Value::object(vec![
+                    ("text", source.text[condition.span().start..condition.span().end].into()),
+                    ("span", emit::span_to_json(condition.span(), source)),
+                ]),
+                None => Value::Null,
+            };
+
+            Value::object(vec![
+                ("condition", condition),
+                ("statements", emit::statements_to_json(&branch.statements, source)),
+            ])
+        }).collect();
+
+        return vec![("branches", Value::Array(branches))];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
     }
 }
 
 pub struct NewCharMapStatement {
-    pub names: Vec<String>
+    pub names: Vec<String>,
+    pub span: Span,
 }
 
 impl Statement for NewCharMapStatement {
@@ -71,25 +231,74 @@ impl Statement for NewCharMapStatement {
     fn to_string(&self) -> String {
         return "New Char Map ".to_string() + self.names.join(", ").as_str();
     }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![("names", self.names.clone().into())];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
 }
 
+/// `CHARMAP "key", value, ...`, the key being a string expression.
 pub struct CharMapStatement {
-    pub value: String,
-    pub number: i32
+    pub key: Expression,
+    pub values: Vec<Expression>,
+    pub span: Span,
 }
 
 impl Statement for CharMapStatement {
     fn my_type(&self) -> StatementType {
-        return StatementType::NewCharMap;
+        return StatementType::CharMap;
     }
 
     fn to_string(&self) -> String {
-        return "Char Map \"".to_string() + self.value.as_str() + "\" " + self.number.to_string().as_str();
+        let key = match &self.key {
+            Expression::String(raw, _) => raw.as_str(),
+            _ => "",
+        };
+
+        return "Char Map \"".to_string() + key + "\" " + self.values.len().to_string().as_str() + " value(s)";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let text = |expression: &Expression| source.text[expression.span().start..expression.span().end].to_string();
+        let values: Vec<String> = self.values.iter().map(text).collect();
+        // the fields of version 1, from when a key had a single number
+        let value = match &self.key {
+            Expression::String(raw, _) => Some(raw.as_str()),
+            _ => None,
+        };
+        let number = match self.values.first() {
+            Some(Expression::Number(number, _)) => Some(*number as i64),
+            _ => None,
+        };
+
+        return vec![
+            ("value", value.into()),
+            ("number", number.into()),
+            ("key", text(&self.key).into()),
+            ("values", values.into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
     }
 }
 
 pub struct SetCharMapStatement {
     pub name: String,
+    pub span: Span,
 }
 
 impl Statement for SetCharMapStatement {
@@ -100,11 +309,52 @@ impl Statement for SetCharMapStatement {
     fn to_string(&self) -> String {
         return "Set Char Map \"".to_string() + self.name.as_str() + "\"";
     }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![("name", self.name.as_str().into())];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DefKind {
+    Equ,
+    Set,
+    Equs,
+    Rb,
+    Rw,
+    Rl,
+}
+
+impl DefKind {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            DefKind::Equ => "equ",
+            DefKind::Set => "set",
+            DefKind::Equs => "equs",
+            DefKind::Rb => "rb",
+            DefKind::Rw => "rw",
+            DefKind::Rl => "rl",
+        };
+    }
 }
 
 pub struct DefStatement {
     pub name: String,
+    pub name_span: Span,
+    pub kind: DefKind,
+    /// Source text of the value.
     pub value: String,
+    /// `None` for RB, RW and RL without an explicit count.
+    pub expression: Option<Expression>,
+    pub span: Span,
 }
 
 impl Statement for DefStatement {
@@ -115,11 +365,61 @@ impl Statement for DefStatement {
     fn to_string(&self) -> String {
         return "DEF \"".to_string() + self.name.as_str() + "\" " + self.value.as_str();
     }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![
+            ("name", self.name.as_str().into()),
+            ("definition", self.kind.as_str().into()),
+            ("value", self.value.as_str().into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// `RSRESET` (without value) or `RSSET value`.
+pub struct RsStatement {
+    pub value: Option<Expression>,
+    pub span: Span,
+}
+
+impl Statement for RsStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Rs;
+    }
+
+    fn to_string(&self) -> String {
+        return match self.value {
+            Some(_) => "RSSET".to_string(),
+            None => "RSRESET".to_string(),
+        };
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let value = self.value.as_ref().map(|v| source.text[v.span().start..v.span().end].to_string());
+
+        return vec![("value", value.into())];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
 }
 
 pub struct MacroStatement {
     pub name: String,
     pub tokens: Vec<Token>,
+    pub span: Span,
 }
 
 impl Statement for MacroStatement {
@@ -130,6 +430,377 @@ impl Statement for MacroStatement {
     fn to_string(&self) -> String {
         return "Macro \"".to_string() + self.name.as_str() + "\"";
     }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let body: String = self.tokens.iter().map(|t| t.full_text()).collect();
+
+        return vec![
+            ("name", self.name.as_str().into()),
+            ("body", body.into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// Invocation of a macro, with the arguments as written.
+pub struct MacroCallStatement {
+    pub name: String,
+    pub arguments: Vec<String>,
+    pub span: Span,
+}
+
+impl Statement for MacroCallStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::MacroCall;
+    }
+
+    fn to_string(&self) -> String {
+        return "Macro call \"".to_string() + self.name.as_str() + "\" " + self.arguments.join(", ").as_str();
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![
+            ("name", self.name.as_str().into()),
+            ("arguments", self.arguments.clone().into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// `REPT count` up to its `ENDR`, the body assembled `count` times like a
+/// macro without arguments.
+pub struct ReptStatement {
+    pub count: Expression,
+    pub tokens: Vec<Token>,
+    pub span: Span,
+}
+
+impl Statement for ReptStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Rept;
+    }
+
+    fn to_string(&self) -> String {
+        // the first line break ends the line of REPT
+        let lines = self.tokens.iter().filter(|t| t.token_type == TokenType::LineBreak).count().saturating_sub(1);
+        return "Rept ".to_string() + lines.to_string().as_str() + " line(s)";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let body: String = self.tokens.iter().map(|t| t.full_text()).collect();
+
+        return vec![
+            ("count", source.text[self.count.span().start..self.count.span().end].into()),
+            ("body", body.into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// `Name:`, `Name::` (exported), `.local`, `.local:` or `Name.local:`
+pub struct LabelStatement {
+    /// Name as written, local labels start with or contain a `.`
+    pub name: String,
+    pub exported: bool,
+    pub span: Span,
+}
+
+impl LabelStatement {
+    pub fn is_local(&self) -> bool {
+        return self.name.contains('.');
+    }
+}
+
+impl Statement for LabelStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Label;
+    }
+
+    fn to_string(&self) -> String {
+        return "Label \"".to_string() + self.name.as_str() + "\"";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![
+            ("name", self.name.as_str().into()),
+            ("exported", self.exported.into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// Register or condition code in lowercase (`a`, `hl`, `sp`, `nz`, ...);
+    /// `c` is either until the instruction is encoded.
+    Register(String, Span),
+    /// `[bc]`, `[de]`, `[hl]`, `[hli]` (also `[hl+]`), `[hld]` (also `[hl-]`)
+    /// and `[c]` (also `[$ff00+c]`), with the register in lowercase.
+    IndirectRegister(String, Span),
+    /// `[n16]`
+    Indirect(Expression, Span),
+    /// `sp + e8` in `ld hl, sp + e8`
+    StackOffset(Expression, Span),
+    Immediate(Expression),
+}
+
+impl Operand {
+    pub fn span(&self) -> Span {
+        return match self {
+            Operand::Register(_, span) => *span,
+            Operand::IndirectRegister(_, span) => *span,
+            Operand::Indirect(_, span) => *span,
+            Operand::StackOffset(_, span) => *span,
+            Operand::Immediate(expression) => expression.span(),
+        };
+    }
+}
+
+pub struct InstructionStatement {
+    /// Lowercase mnemonic
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+    pub span: Span,
+}
+
+impl Statement for InstructionStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Instruction;
+    }
+
+    fn to_string(&self) -> String {
+        return "Instruction \"".to_string() + self.mnemonic.as_str() + "\" " + self.operands.len().to_string().as_str() + " operand(s)";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let operands: Vec<String> = self.operands.iter()
+            .map(|operand| source.text[operand.span().start..operand.span().end].to_string())
+            .collect();
+
+        return vec![
+            ("mnemonic", self.mnemonic.as_str().into()),
+            ("operands", operands.into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// PUSHC, saving the current charmap, or POPC, restoring it.
+pub struct CharMapStackStatement {
+    pub push: bool,
+    pub span: Span,
+}
+
+impl Statement for CharMapStackStatement {
+    fn my_type(&self) -> StatementType {
+        return if self.push { StatementType::PushCharMap } else { StatementType::PopCharMap };
+    }
+
+    fn to_string(&self) -> String {
+        return if self.push { "Push Char Map" } else { "Pop Char Map" }.to_string();
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DataKind {
+    Db,
+    Dw,
+    Dl,
+    Ds,
+}
+
+impl DataKind {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            DataKind::Db => "db",
+            DataKind::Dw => "dw",
+            DataKind::Dl => "dl",
+            DataKind::Ds => "ds",
+        };
+    }
+}
+
+/// `db`, `dw` and `dl` values, or the length and fill bytes of `ds`.
+pub struct DataStatement {
+    pub kind: DataKind,
+    pub values: Vec<Expression>,
+    pub span: Span,
+}
+
+impl Statement for DataStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Data;
+    }
+
+    fn to_string(&self) -> String {
+        return "Data \"".to_string() + self.kind.as_str() + "\" " + self.values.len().to_string().as_str() + " value(s)";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let values: Vec<String> = self.values.iter()
+            .map(|value| source.text[value.span().start..value.span().end].to_string())
+            .collect();
+
+        return vec![
+            ("directive", self.kind.as_str().into()),
+            ("values", values.into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// `TEST "name"` ... `ENDT`, code run by the `test` command and skipped
+/// otherwise.
+pub struct TestStatement {
+    pub name: String,
+    pub statements: Vec<Box<dyn Statement>>,
+    pub span: Span,
+}
+
+impl Statement for TestStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Test;
+    }
+
+    fn to_string(&self) -> String {
+        return "Test \"".to_string() + self.name.as_str() + "\" " + self.statements.len().to_string().as_str() + " statement(s)";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![
+            ("name", self.name.as_str().into()),
+            ("statements", emit::statements_to_json(&self.statements, source)),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// `EXPECT a == $0C`, `EXPECT [wCount] != 0` or `EXPECT nz`: a register or
+/// memory byte compared to a value, or a condition on the flags, checked
+/// when a TEST gets there.
+pub struct ExpectStatement {
+    pub target: Operand,
+    /// Comparison operator and value, `None` for a condition
+    pub comparison: Option<(String, Expression)>,
+    pub span: Span,
+}
+
+impl Statement for ExpectStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Expect;
+    }
+
+    fn to_string(&self) -> String {
+        return "Expect".to_string();
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, source: &SourceFile) -> Vec<(&'static str, Value)> {
+        let text = |span: Span| source.text[span.start..span.end].to_string();
+
+        return vec![
+            ("target", text(self.target.span()).into()),
+            ("operator", self.comparison.as_ref().map(|(operator, _)| operator.clone()).into()),
+            ("value", self.comparison.as_ref().map(|(_, value)| text(value.span())).into()),
+        ];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+}
+
+/// Placeholder for a statement that failed to parse, so the statements around
+/// it keep their place in the tree.
+pub struct ErrorStatement {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Statement for ErrorStatement {
+    fn my_type(&self) -> StatementType {
+        return StatementType::Error;
+    }
+
+    fn to_string(&self) -> String {
+        return "Error \"".to_string() + self.message.as_str() + "\"";
+    }
+
+    fn span(&self) -> Span {
+        return self.span;
+    }
+
+    fn json_fields(&self, _source: &SourceFile) -> Vec<(&'static str, Value)> {
+        return vec![("message", self.message.as_str().into())];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
 }
 
 pub struct Ast {
@@ -0,0 +1,202 @@
+//! Character maps, the values strings are encoded as by `db`, `dw` and
+//! `dl`, like the CHARMAP, NEWCHARMAP, SETCHARMAP, PUSHC and POPC of RGBDS.
+//!
+//! A string is split into units from left to right, each the longest key of
+//! the current charmap the rest of the string starts with, so `"<PLAYER>"`
+//! wins over `"<"`. A character that starts no key is a unit of its own and
+//! is output as its UTF-8 bytes.
+
+use std::collections::HashMap;
+
+/// The charmap every file starts with.
+pub const MAIN: &str = "main";
+
+/// Strings mapped to the values output for them.
+#[derive(Debug, Clone, Default)]
+pub struct CharMap {
+    entries: HashMap<String, Vec<i32>>,
+    /// Characters of the longest key
+    longest: usize,
+}
+
+impl CharMap {
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[i32]> {
+        return self.entries.get(key).map(|values| values.as_slice());
+    }
+
+    /// The keys and their values, in no particular order.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &[i32])> {
+        return self.entries.iter().map(|(key, values)| (key.as_str(), values.as_slice()));
+    }
+
+    /// Maps `key`, replacing its previous values.
+    pub fn insert(&mut self, key: &str, values: Vec<i32>) {
+        self.longest = self.longest.max(key.chars().count());
+        self.entries.insert(key.to_string(), values);
+    }
+
+    /// The units of `text` with their values, `None` for the characters
+    /// that are not mapped.
+    pub fn units<'a>(&self, text: &'a str) -> Vec<(&'a str, Option<&[i32]>)> {
+        let mut units = vec![];
+        let mut rest = text;
+
+        while let Some(first) = rest.chars().next() {
+            let ends: Vec<usize> = rest.char_indices().map(|(i, c)| i + c.len_utf8()).take(self.longest).collect();
+            let unit = ends.iter().rev()
+                .find_map(|&end| self.get(&rest[..end]).map(|values| (&rest[..end], Some(values))))
+                .unwrap_or((&rest[..first.len_utf8()], None));
+
+            units.push(unit);
+            rest = &rest[unit.0.len()..];
+        }
+
+        return units;
+    }
+
+    /// The values of `text`, with the UTF-8 bytes of the characters that
+    /// are not mapped, and those characters.
+    pub fn encode(&self, text: &str) -> (Vec<i32>, Vec<char>) {
+        let mut values = vec![];
+        let mut unmapped = vec![];
+
+        for (unit, unit_values) in self.units(text) {
+            match unit_values {
+                Some(unit_values) => values.extend_from_slice(unit_values),
+                None => {
+                    values.extend(unit.bytes().map(|byte| byte as i32));
+                    unmapped.push(unit.chars().next().unwrap());
+                }
+            }
+        }
+
+        return (values, unmapped);
+    }
+}
+
+/// The charmaps of a file by name, the current one and those saved by
+/// PUSHC.
+#[derive(Debug, Clone)]
+pub struct CharMaps {
+    maps: HashMap<String, CharMap>,
+    current: String,
+    stack: Vec<String>,
+}
+
+impl Default for CharMaps {
+    fn default() -> Self {
+        return Self {
+            maps: HashMap::from([(MAIN.to_string(), CharMap::default())]),
+            current: MAIN.to_string(),
+            stack: vec![],
+        };
+    }
+}
+
+impl CharMaps {
+    pub fn current(&self) -> &CharMap {
+        return &self.maps[&self.current];
+    }
+
+    pub fn current_name(&self) -> &str {
+        return &self.current;
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CharMap> {
+        return self.maps.get(name);
+    }
+
+    /// NEWCHARMAP: a charmap with the entries `base` has now, which becomes
+    /// the current one.
+    pub fn create(&mut self, name: &str, base: Option<&str>) -> Result<(), String> {
+        if self.maps.contains_key(name) {
+            return Err(format!("Charmap `{}` is already defined", name));
+        }
+
+        let map = match base {
+            Some(base) => self.maps.get(base).cloned().ok_or_else(|| format!("Charmap `{}` is not defined", base))?,
+            None => CharMap::default(),
+        };
+        self.maps.insert(name.to_string(), map);
+        self.current = name.to_string();
+
+        return Ok(());
+    }
+
+    /// SETCHARMAP
+    pub fn select(&mut self, name: &str) -> Result<(), String> {
+        if !self.maps.contains_key(name) {
+            return Err(format!("Charmap `{}` is not defined", name));
+        }
+        self.current = name.to_string();
+
+        return Ok(());
+    }
+
+    /// CHARMAP, in the current charmap.
+    pub fn insert(&mut self, key: &str, values: Vec<i32>) -> Result<(), String> {
+        if key.is_empty() {
+            return Err("Cannot map an empty string".to_string());
+        }
+        if values.is_empty() {
+            return Err(format!("No value to map \"{}\" to", key.escape_debug()));
+        }
+        self.maps.get_mut(&self.current).unwrap().insert(key, values);
+
+        return Ok(());
+    }
+
+    /// PUSHC
+    pub fn push(&mut self) {
+        self.stack.push(self.current.clone());
+    }
+
+    /// POPC
+    pub fn pop(&mut self) -> Result<(), String> {
+        self.current = self.stack.pop().ok_or("POPC without a matching PUSHC")?;
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_longest_keys_first() {
+        let mut maps = CharMaps::default();
+        maps.insert("<", vec![0x70]).unwrap();
+        maps.insert("<PLAYER>", vec![0x52, 0x53]).unwrap();
+        maps.insert("A", vec![0x80]).unwrap();
+
+        assert_eq!(maps.current().encode("A<PLAYER><P"), (vec![0x80, 0x52, 0x53, 0x70, 0x50], vec!['P']));
+        assert_eq!(maps.current().units("<PLAYER>é").iter().map(|unit| unit.0).collect::<Vec<_>>(), vec!["<PLAYER>", "é"]);
+        assert_eq!(maps.current().encode("é").0, vec![0xC3, 0xA9]);
+    }
+
+    #[test]
+    fn inheriting_and_stacking_charmaps() {
+        let mut maps = CharMaps::default();
+        maps.insert("A", vec![0x80]).unwrap();
+        maps.create("menu", Some(MAIN)).unwrap();
+        maps.insert("B", vec![0x81]).unwrap();
+        assert_eq!(maps.current().encode("AB").0, vec![0x80, 0x81]);
+
+        // later entries of the base are not inherited
+        maps.push();
+        maps.select(MAIN).unwrap();
+        maps.insert("C", vec![0x82]).unwrap();
+        assert_eq!(maps.current().get("B"), None);
+        maps.pop().unwrap();
+        assert_eq!((maps.current_name(), maps.current().get("C")), ("menu", None));
+
+        assert_eq!(maps.pop(), Err("POPC without a matching PUSHC".to_string()));
+        assert_eq!(maps.create("menu", None), Err("Charmap `menu` is already defined".to_string()));
+        assert_eq!(maps.create("text", Some("font")), Err("Charmap `font` is not defined".to_string()));
+    }
+}
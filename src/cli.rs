@@ -0,0 +1,639 @@
+use gameboy_compiler_toolchain::diagnostic::WarningSettings;
+use gameboy_compiler_toolchain::gfx::GfxOptions;
+use gameboy_compiler_toolchain::header::HeaderOptions;
+use std::ops::RangeInclusive;
+
+pub const EXIT_SUCCESS: i32 = 0;
+// the input was read but contained errors
+pub const EXIT_FAILURE: i32 = 1;
+// invalid command line
+pub const EXIT_USAGE: i32 = 2;
+// a file could not be read or written
+pub const EXIT_IO: i32 = 3;
+
+pub const USAGE: &str = "\
+Usage: gameboy-compiler-toolchain <command> [options] <input>...
+
+Commands:
+  lex       Print the tokens of a source file
+  parse     Print the syntax tree of a source file
+  check     Report errors and warnings without producing output
+  fmt       Reformat a source file
+  opt       Rewrite instructions into shorter or faster ones, reporting
+            each rewrite
+  asm       Assemble a source file and the files it includes into a ROM
+  link      Assemble source files on their own and link them together into
+            a ROM, their labels and constants being shared
+  fix       Fix up the header of a ROM: the Nintendo logo, the ROM size
+            and the checksums
+  disasm    Disassemble a ROM into source
+  test      Run the TEST blocks of a source file in the emulator
+  profile   Run a source file or a ROM in the emulator and report the
+            cycles taken by each routine
+  debug     Run a source file or a ROM in the emulator behind a GDB
+            remote stub listening on localhost
+  gfx       Convert a PNG or PPM image into tile data, and optionally a
+            tilemap, an attribute map and palettes
+  render    Draw tile data, from a file or a ROM, as a PNG or PPM image
+  lsp       Run a language server over standard input and output
+  rename    Rename a symbol in the inputs and the files they include:
+            rename <symbol> <new name> <input>...
+
+Options:
+  -o, --output <file>       Write the output to <file> instead of stdout
+  -I, --include <dir>       Add a directory to the include search path
+  -D, --define <name>[=<value>]
+                            Define a symbol before assembling
+  -M <file>                 Write the files the input depends on to <file>
+                            as make rules, unless the build fails
+  -MG                       Treat missing included files as generated ones,
+                            stopping without writing the ROM
+  -MP                       Add an empty rule for each dependency
+  -MT <target>              Target of the dependency rules (default: the
+                            output file)
+  -W<warning>               Enable a warning, -Wno-<warning> disables it,
+                            -Wall enables all of them, -Werror[=<warning>]
+                            turns warnings into errors
+  -w                        Disable all warnings
+      --emit <kinds>        Comma separated list of tokens, ast, diagnostics,
+                            and listing for check
+      --cycles              Report the minimum and maximum cycles of each
+                            routine with check
+      --sym <file>          Names of the addresses for disasm, profile and
+                            render, a .sym or debug information file
+                            (default: the .sym file next to the ROM, or
+                            else the .dbg file)
+      --debug-info <file>   Write the debug information of the ROM to <file>
+                            with asm, read it with debug (default: the .dbg
+                            file next to the ROM)
+      --compress-text <charmap>
+                            Compress the strings of a charmap with a
+                            dictionary of the sequences they repeat
+      --dictionary-size <bytes>
+                            Bytes the table of the dictionary may take
+                            (default: 256)
+      --dictionary-codes <first>-<last>
+                            Values of the entries of the dictionary
+                            (default: the most values the strings leave
+                            free)
+      --title <title>       Title of the ROM to write with fix
+      --cgb <mode>          CGB flag to write with fix, compatible or only
+      --sgb                 Enable the SGB functions with fix
+      --cartridge-type <byte>
+                            Cartridge type to write with fix, like $01 for
+                            MBC1
+      --ram-size <byte>     Cartridge RAM size code to write with fix
+      --frames <count>      Frames to profile for (default: 60)
+      --budget <routine>=<cycles>
+                            Report the calls of a routine that take more
+                            than <cycles> cycles when profiling
+      --port <port>         Port the debugger listens on (default: 2331)
+      --depth <bits>        Bits per pixel of the tiles of gfx and render,
+                            1 or 2 (default: 2)
+      --unique-tiles        Store each tile once with gfx
+      --mirror-tiles        Also store once the tiles that are flips of
+                            another with gfx, flipped by the attribute map
+      --tilemap <file>      The tile of each position of the image, written
+                            by gfx and read by render
+      --attrmap <file>      The CGB attributes of each position of the
+                            image, written by gfx and read by render
+      --palette <file>      The palettes of the image as RGB555, written by
+                            gfx and read by render
+      --columns <tiles>     Tiles per row of the image of render (default:
+                            16)
+      --at <location>       Label or BB:AAAA bank and address of the tiles
+                            to render in a ROM
+      --size <bytes>        Bytes of tiles to render from a ROM (default: up
+                            to the next label)
+      --format <format>     Output format, text (default) or json
+      --color <when>        auto (default), always or never
+  -h, --help                Print this help
+
+Use `-` as input to read from standard input.
+
+Exit status: 0 on success, 1 if the input had errors, 2 on invalid usage,
+3 if a file could not be read or written.
+";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    Lex,
+    Parse,
+    Check,
+    Fmt,
+    Opt,
+    Asm,
+    Link,
+    Fix,
+    Disasm,
+    Test,
+    Profile,
+    Debug,
+    Gfx,
+    Render,
+    Lsp,
+    Rename,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Command> {
+        return match name {
+            "lex" => Some(Command::Lex),
+            "parse" => Some(Command::Parse),
+            "check" => Some(Command::Check),
+            "fmt" => Some(Command::Fmt),
+            "opt" => Some(Command::Opt),
+            "asm" => Some(Command::Asm),
+            "link" => Some(Command::Link),
+            "fix" => Some(Command::Fix),
+            "disasm" => Some(Command::Disasm),
+            "test" => Some(Command::Test),
+            "profile" => Some(Command::Profile),
+            "debug" => Some(Command::Debug),
+            "gfx" => Some(Command::Gfx),
+            "render" => Some(Command::Render),
+            "lsp" => Some(Command::Lsp),
+            "rename" => Some(Command::Rename),
+            _ => None,
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Command::Lex => "lex",
+            Command::Parse => "parse",
+            Command::Check => "check",
+            Command::Fmt => "fmt",
+            Command::Opt => "opt",
+            Command::Asm => "asm",
+            Command::Link => "link",
+            Command::Fix => "fix",
+            Command::Disasm => "disasm",
+            Command::Test => "test",
+            Command::Profile => "profile",
+            Command::Debug => "debug",
+            Command::Gfx => "gfx",
+            Command::Render => "render",
+            Command::Lsp => "lsp",
+            Command::Rename => "rename",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub include_paths: Vec<String>,
+    /// `-M`, `-MG`, `-MP` and `-MT`
+    pub dependency_file: Option<String>,
+    pub missing_dependencies: bool,
+    pub phony_dependencies: bool,
+    pub dependency_targets: Vec<String>,
+    pub defines: Vec<(String, String)>,
+    pub warnings: WarningSettings,
+    pub emit: Vec<String>,
+    /// `--cycles`, the cycles of each routine for `check`
+    pub cycles: bool,
+    /// `--sym`, names for the disassembler and the profiler
+    pub symbol_file: Option<String>,
+    /// `--frames` and `--budget` for the profiler
+    pub frames: u32,
+    pub budgets: Vec<(String, u64)>,
+    /// `--port` of the debugger
+    pub port: u16,
+    /// `--debug-info`, written by the assembler and read by the debugger
+    pub debug_info: Option<String>,
+    /// `--depth`, `--unique-tiles` and `--mirror-tiles` of the converter,
+    /// and the files it writes besides the tiles
+    pub gfx: GfxOptions,
+    pub tilemap: Option<String>,
+    pub attrmap: Option<String>,
+    pub palette: Option<String>,
+    /// `--title`, `--cgb`, `--sgb`, `--cartridge-type` and `--ram-size` of
+    /// the header written by fix
+    pub header: HeaderOptions,
+    /// `--compress-text`, `--dictionary-size` and `--dictionary-codes`
+    pub compress_text: Option<String>,
+    pub dictionary_size: Option<usize>,
+    pub dictionary_codes: Option<RangeInclusive<u8>>,
+    /// `--columns`, `--at` and `--size` of the tiles to render
+    pub columns: usize,
+    pub at: Option<String>,
+    pub size: Option<usize>,
+    pub format: Format,
+    pub color: Color,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum CliError {
+    Help,
+    Usage(String),
+}
+
+fn usage_error(message: String) -> Result<Options, CliError> {
+    return Err(CliError::Usage(message));
+}
+
+/// Splits `-D NAME=VALUE`; a define without value is set to 1 like in rgbasm.
+pub fn parse_define(define: &str) -> Result<(String, String), String> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => (name, value),
+        None => (define, "1"),
+    };
+
+    let valid = name.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '#' || c == '@');
+
+    if !valid {
+        return Err(format!("invalid symbol name `{}` in define", name));
+    }
+
+    return Ok((name.to_string(), value.to_string()));
+}
+
+// decimal, or hex after `$` or `0x`
+fn parse_size(value: &str) -> Option<usize> {
+    return match value.strip_prefix('$').or_else(|| value.strip_prefix("0x")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+}
+
+pub fn parse_arguments(arguments: &[String]) -> Result<Options, CliError> {
+    let command = match arguments.first().map(|a| a.as_str()) {
+        None | Some("-h") | Some("--help") | Some("help") => return Err(CliError::Help),
+        Some(name) => match Command::from_name(name) {
+            Some(command) => command,
+            None => return usage_error(format!("unknown command `{}`", name)),
+        },
+    };
+
+    let mut options = Options {
+        command,
+        inputs: vec![],
+        output: None,
+        include_paths: vec![],
+        dependency_file: None,
+        missing_dependencies: false,
+        phony_dependencies: false,
+        dependency_targets: vec![],
+        defines: vec![],
+        warnings: WarningSettings::default(),
+        emit: vec![],
+        cycles: false,
+        symbol_file: None,
+        frames: 60,
+        budgets: vec![],
+        port: 2331,
+        debug_info: None,
+        gfx: GfxOptions::default(),
+        tilemap: None,
+        attrmap: None,
+        palette: None,
+        header: HeaderOptions::default(),
+        compress_text: None,
+        dictionary_size: None,
+        dictionary_codes: None,
+        columns: 16,
+        at: None,
+        size: None,
+        format: Format::Text,
+        color: Color::Auto,
+    };
+
+    let mut i = 1;
+    while i < arguments.len() {
+        let argument = arguments[i].as_str();
+        i += 1;
+
+        if argument == "-" || !argument.starts_with('-') {
+            options.inputs.push(argument.to_string());
+            continue;
+        }
+
+        if argument == "-h" || argument == "--help" {
+            return Err(CliError::Help);
+        }
+
+        if argument == "-w" {
+            options.warnings.all_disabled = true;
+            continue;
+        }
+
+        if argument == "-MG" {
+            options.missing_dependencies = true;
+            continue;
+        }
+
+        if argument == "-MP" {
+            options.phony_dependencies = true;
+            continue;
+        }
+
+        if argument == "--cycles" {
+            if command != Command::Check {
+                return usage_error("--cycles is only available with `check`".to_string());
+            }
+            options.cycles = true;
+            continue;
+        }
+
+        if argument == "--sgb" {
+            if command != Command::Fix {
+                return usage_error("--sgb is only available with `fix`".to_string());
+            }
+            options.header.sgb = true;
+            continue;
+        }
+
+        if argument == "--unique-tiles" || argument == "--mirror-tiles" {
+            if command != Command::Gfx {
+                return usage_error(format!("{} is only available with `gfx`", argument));
+            }
+            if argument == "--unique-tiles" {
+                options.gfx.unique_tiles = true;
+            } else {
+                options.gfx.mirror_tiles = true;
+            }
+            continue;
+        }
+
+        if let Some(flag) = argument.strip_prefix("-W") {
+            if let Err(message) = options.warnings.apply_flag(flag) {
+                return usage_error(message);
+            }
+            continue;
+        }
+
+        // options with a value, given either separately or attached (`-Iinc`, `--color=never`)
+        let (name, attached) = if let Some((name, value)) = argument.split_once('=').filter(|_| argument.starts_with("--")) {
+            (name, Some(value.to_string()))
+        } else if let Some(target) = argument.strip_prefix("-MT") {
+            ("-MT", Some(target.to_string()).filter(|value| !value.is_empty()))
+        } else if !argument.starts_with("--") && argument.len() > 2 {
+            (&argument[..2], Some(argument[2..].to_string()))
+        } else {
+            (argument, None)
+        };
+
+        let known = ["-o", "--output", "-I", "--include", "-M", "-MT", "-D", "--define", "--emit", "--sym", "--frames", "--budget", "--port", "--debug-info", "--depth", "--tilemap", "--attrmap", "--palette", "--columns", "--at", "--size", "--compress-text", "--dictionary-size", "--dictionary-codes", "--title", "--cgb", "--cartridge-type", "--ram-size", "--format", "--color"];
+        if !known.contains(&name) {
+            return usage_error(format!("unknown option `{}`", argument));
+        }
+
+        let header_option = ["--title", "--cgb", "--cartridge-type", "--ram-size"].contains(&name);
+        if header_option && command != Command::Fix {
+            return usage_error(format!("{} is only available with `fix`", name));
+        }
+
+        let value = match attached {
+            Some(value) => value,
+            None => match arguments.get(i) {
+                Some(value) => {
+                    i += 1;
+                    value.clone()
+                }
+                None => return usage_error(format!("{} expects a value", name)),
+            },
+        };
+
+        match name {
+            "-o" | "--output" => options.output = Some(value),
+            "-I" | "--include" => options.include_paths.push(value),
+            "-M" => options.dependency_file = Some(value),
+            "-MT" => options.dependency_targets.push(value),
+            "-D" | "--define" => match parse_define(&value) {
+                Ok(define) => options.defines.push(define),
+                Err(message) => return usage_error(message),
+            },
+            "--emit" => {
+                for kind in value.split(',') {
+                    if !["tokens", "ast", "diagnostics", "listing"].contains(&kind) {
+                        return usage_error(format!("unknown --emit kind `{}`, expected tokens, ast, diagnostics or listing", kind));
+                    }
+                    if kind == "listing" && command != Command::Check {
+                        return usage_error("--emit listing is only available with `check`".to_string());
+                    }
+                    options.emit.push(kind.to_string());
+                }
+            }
+            "--sym" => options.symbol_file = Some(value),
+            "--frames" => match value.parse() {
+                Ok(frames) => options.frames = frames,
+                Err(_) => return usage_error(format!("invalid --frames `{}`, expected a number of frames", value)),
+            },
+            "--budget" => match value.split_once('=').map(|(name, cycles)| (name, cycles.parse())) {
+                Some((name, Ok(cycles))) if !name.is_empty() => options.budgets.push((name.to_string(), cycles)),
+                _ => return usage_error(format!("invalid --budget `{}`, expected <routine>=<cycles>", value)),
+            },
+            "--debug-info" => options.debug_info = Some(value),
+            "--depth" => match value.as_str() {
+                "1" => options.gfx.depth = 1,
+                "2" => options.gfx.depth = 2,
+                _ => return usage_error(format!("invalid --depth `{}`, expected 1 or 2", value)),
+            },
+            "--tilemap" => options.tilemap = Some(value),
+            "--attrmap" => options.attrmap = Some(value),
+            "--palette" => options.palette = Some(value),
+            "--columns" => match value.parse() {
+                Ok(columns) if columns > 0 => options.columns = columns,
+                _ => return usage_error(format!("invalid --columns `{}`, expected a number of tiles", value)),
+            },
+            "--at" => options.at = Some(value),
+            "--compress-text" => options.compress_text = Some(value),
+            "--dictionary-size" => match parse_size(&value) {
+                Some(size) => options.dictionary_size = Some(size),
+                None => return usage_error(format!("invalid --dictionary-size `{}`, expected a number of bytes", value)),
+            },
+            "--dictionary-codes" => {
+                let codes = value.split_once('-')
+                    .and_then(|(first, last)| Some(u8::try_from(parse_size(first)?).ok()?..=u8::try_from(parse_size(last)?).ok()?))
+                    .filter(|codes| !codes.is_empty());
+                match codes {
+                    Some(codes) => options.dictionary_codes = Some(codes),
+                    None => return usage_error(format!("invalid --dictionary-codes `{}`, expected a range of bytes like $C0-$FF", value)),
+                }
+            }
+            "--title" => options.header.title = Some(value),
+            "--cgb" => match value.as_str() {
+                "compatible" => options.header.cgb_flag = Some(0x80),
+                "only" => options.header.cgb_flag = Some(0xC0),
+                _ => return usage_error(format!("invalid --cgb `{}`, expected compatible or only", value)),
+            },
+            "--cartridge-type" | "--ram-size" => match parse_size(&value).and_then(|byte| u8::try_from(byte).ok()) {
+                Some(byte) if name == "--cartridge-type" => options.header.cartridge_type = Some(byte),
+                Some(byte) => options.header.ram_size = Some(byte),
+                None => return usage_error(format!("invalid {} `{}`, expected a byte", name, value)),
+            },
+            "--size" => match parse_size(&value) {
+                Some(size) => options.size = Some(size),
+                None => return usage_error(format!("invalid --size `{}`, expected a number of bytes", value)),
+            },
+            "--port" => match value.parse() {
+                Ok(port) => options.port = port,
+                Err(_) => return usage_error(format!("invalid --port `{}`, expected a port number", value)),
+            },
+            "--format" => {
+                options.format = match value.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    _ => return usage_error(format!("unknown --format `{}`, expected text or json", value)),
+                };
+            }
+            _ => {
+                options.color = match value.as_str() {
+                    "auto" => Color::Auto,
+                    "always" => Color::Always,
+                    "never" => Color::Never,
+                    _ => return usage_error(format!("unknown --color `{}`, expected auto, always or never", value)),
+                };
+            }
+        }
+    }
+
+    // the language server gets its files from the editor
+    if options.inputs.is_empty() && command != Command::Lsp {
+        return usage_error(format!("`{}` expects an input file", command.name()));
+    }
+
+    if command == Command::Rename && options.inputs.len() < 3 {
+        return usage_error("`rename` expects a symbol, its new name and an input file".to_string());
+    }
+
+    if options.gfx.mirror_tiles && options.attrmap.is_none() {
+        return usage_error("--mirror-tiles needs --attrmap for the flips of the tiles".to_string());
+    }
+
+    if options.size.is_some() && options.at.is_none() {
+        return usage_error("--size needs --at for the tiles in the ROM".to_string());
+    }
+
+    if (options.dictionary_codes.is_some() || options.dictionary_size.is_some()) && options.compress_text.is_none() {
+        return usage_error("--dictionary-size and --dictionary-codes need --compress-text".to_string());
+    }
+
+    if options.dependency_file.is_some() && options.inputs.len() > 1 {
+        return usage_error("-M expects a single input file".to_string());
+    }
+
+    return Ok(options);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Options, CliError> {
+        let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+
+        return parse_arguments(&arguments);
+    }
+
+    #[test]
+    fn parsing_options() {
+        let options = parse(&["asm", "-o", "main.o", "-Iinclude", "-D", "DEBUG", "--define=REGION=JP", "-Wno-unused-label", "--color", "never", "main.asm"]).unwrap();
+
+        assert_eq!(options.command, Command::Asm);
+        assert_eq!(options.inputs, vec!["main.asm"]);
+        assert_eq!(options.output, Some("main.o".to_string()));
+        assert_eq!(options.include_paths, vec!["include"]);
+        assert_eq!(options.defines, vec![
+            ("DEBUG".to_string(), "1".to_string()),
+            ("REGION".to_string(), "JP".to_string()),
+        ]);
+        assert!(!options.warnings.is_enabled("unused-label", true));
+        assert_eq!(options.color, Color::Never);
+    }
+
+    #[test]
+    fn parsing_dependency_options() {
+        let options = parse(&["check", "-M", "main.d", "-MG", "-MP", "-MT", "build/main.o", "-MTmain.o", "main.asm"]).unwrap();
+
+        assert_eq!(options.dependency_file, Some("main.d".to_string()));
+        assert!(options.missing_dependencies);
+        assert!(options.phony_dependencies);
+        assert_eq!(options.dependency_targets, vec!["build/main.o", "main.o"]);
+        assert_eq!(parse(&["check", "-M", "a.d", "a.asm", "b.asm"]).unwrap_err(), CliError::Usage("-M expects a single input file".to_string()));
+    }
+
+    #[test]
+    fn rejecting_invalid_usage() {
+        assert_eq!(parse(&[]).unwrap_err(), CliError::Help);
+        assert_eq!(parse(&["build", "main.asm"]).unwrap_err(), CliError::Usage("unknown command `build`".to_string()));
+        assert_eq!(parse(&["lex"]).unwrap_err(), CliError::Usage("`lex` expects an input file".to_string()));
+        assert_eq!(parse(&["lex", "-o"]).unwrap_err(), CliError::Usage("-o expects a value".to_string()));
+        assert_eq!(parse(&["lex", "-D", "1X", "a.asm"]).unwrap_err(), CliError::Usage("invalid symbol name `1X` in define".to_string()));
+        assert!(parse(&["lex", "-", "--format", "json"]).is_ok());
+        assert!(parse(&["lsp", "-I", "include"]).is_ok());
+        assert_eq!(parse(&["rename", "Main", "main.asm"]).unwrap_err(), CliError::Usage("`rename` expects a symbol, its new name and an input file".to_string()));
+        assert_eq!(parse(&["profile", "--budget", "VBlank", "game.gb"]).unwrap_err(), CliError::Usage("invalid --budget `VBlank`, expected <routine>=<cycles>".to_string()));
+    }
+
+    #[test]
+    fn parsing_profiler_options() {
+        let options = parse(&["profile", "--frames=120", "--budget", "VBlank=1140", "game.gb"]).unwrap();
+
+        assert_eq!(options.command, Command::Profile);
+        assert_eq!(options.frames, 120);
+        assert_eq!(options.budgets, vec![("VBlank".to_string(), 1140)]);
+
+        assert_eq!(parse(&["debug", "--port", "3333", "main.asm"]).unwrap().port, 3333);
+        assert_eq!(parse(&["asm", "--debug-info=game.dbg", "main.asm"]).unwrap().debug_info, Some("game.dbg".to_string()));
+    }
+
+    #[test]
+    fn parsing_gfx_options() {
+        let options = parse(&["gfx", "--depth", "1", "--mirror-tiles", "--attrmap", "font.attrmap", "font.png"]).unwrap();
+        assert_eq!(options.gfx, GfxOptions { depth: 1, unique_tiles: false, mirror_tiles: true });
+        assert_eq!(options.attrmap, Some("font.attrmap".to_string()));
+
+        assert_eq!(parse(&["gfx", "--mirror-tiles", "font.png"]).unwrap_err(), CliError::Usage("--mirror-tiles needs --attrmap for the flips of the tiles".to_string()));
+        assert_eq!(parse(&["asm", "--unique-tiles", "main.asm"]).unwrap_err(), CliError::Usage("--unique-tiles is only available with `gfx`".to_string()));
+
+        let options = parse(&["render", "--at", "FontTiles", "--size", "$200", "--columns=32", "game.gb"]).unwrap();
+        assert_eq!((options.at, options.size, options.columns), (Some("FontTiles".to_string()), Some(0x200), 32));
+    }
+
+    #[test]
+    fn parsing_header_options() {
+        let options = parse(&["fix", "--title", "GAME", "--cgb=only", "--sgb", "--cartridge-type", "$1B", "--ram-size", "3", "game.gb"]).unwrap();
+        assert_eq!(options.header, HeaderOptions {
+            title: Some("GAME".to_string()),
+            cgb_flag: Some(0xC0),
+            sgb: true,
+            cartridge_type: Some(0x1B),
+            ram_size: Some(3),
+        });
+
+        assert_eq!(parse(&["asm", "--title", "GAME", "main.asm"]).unwrap_err(), CliError::Usage("--title is only available with `fix`".to_string()));
+        assert_eq!(parse(&["fix", "--cartridge-type", "$100", "game.gb"]).unwrap_err(), CliError::Usage("invalid --cartridge-type `$100`, expected a byte".to_string()));
+    }
+
+    #[test]
+    fn parsing_compression_options() {
+        let options = parse(&["asm", "--compress-text", "dialogue", "--dictionary-size", "0x200", "--dictionary-codes=$C0-$FF", "main.asm"]).unwrap();
+        assert_eq!(options.compress_text, Some("dialogue".to_string()));
+        assert_eq!((options.dictionary_size, options.dictionary_codes), (Some(0x200), Some(0xC0..=0xFF)));
+
+        assert_eq!(parse(&["asm", "--dictionary-codes", "$FF-$C0", "--compress-text", "dialogue", "main.asm"]).unwrap_err(),
+            CliError::Usage("invalid --dictionary-codes `$FF-$C0`, expected a range of bytes like $C0-$FF".to_string()));
+        assert_eq!(parse(&["asm", "--dictionary-size", "128", "main.asm"]).unwrap_err(),
+            CliError::Usage("--dictionary-size and --dictionary-codes need --compress-text".to_string()));
+        assert_eq!(parse(&["debug", "--port", "gdb", "main.asm"]).unwrap_err(), CliError::Usage("invalid --port `gdb`, expected a port number".to_string()));
+    }
+}
@@ -0,0 +1,357 @@
+//! Lossless concrete syntax tree.
+//!
+//! Every token of `lexer::lex_with_trivia`, with its whitespace and comments,
+//! is kept in the tree, so printing it gives back the source byte for byte.
+//! The parser builds the tree as it goes: each statement it parses becomes a
+//! node holding the tokens it consumed, and `SyntaxTree::statement` gives the
+//! typed AST statement of a node. Tokens outside of any statement (line
+//! breaks, `ELSE`/`ENDC` lines, the end of file) are children of the closest
+//! enclosing node.
+//!
+//! `fmt` reads the lines of a file from the tree and rename edits its tokens.
+
+use crate::ast::{self, Ast, Statement, StatementType};
+use crate::diagnostic::Diagnostics;
+use crate::lexer::{self, Span, Token};
+use crate::parser;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NodeKind {
+    File,
+    Statement(StatementType),
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(Token),
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    /// Span of the statement without the trivia around it, the whole file
+    /// for the root.
+    pub span: Span,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    /// Source text of the node, including all trivia.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        self.write_text(&mut text);
+
+        return text;
+    }
+
+    fn write_text(&self, text: &mut String) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.write_text(text),
+                SyntaxElement::Token(token) => *text += &token.full_text(),
+            }
+        }
+    }
+
+    /// All tokens below the node, in source order.
+    pub fn tokens(&self) -> Vec<&Token> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+
+        return tokens;
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a Token>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    fn tokens_mut(&mut self) -> Vec<&mut Token> {
+        let mut tokens = vec![];
+        for child in &mut self.children {
+            match child {
+                SyntaxElement::Node(node) => tokens.append(&mut node.tokens_mut()),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+
+        return tokens;
+    }
+
+    /// Direct child nodes.
+    pub fn nodes(&self) -> impl Iterator<Item = &SyntaxNode> {
+        return self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        });
+    }
+
+    /// The node and all the nodes below it, parents first.
+    pub fn descendants(&self) -> Vec<&SyntaxNode> {
+        let mut nodes = vec![self];
+        for node in self.nodes() {
+            nodes.append(&mut node.descendants());
+        }
+
+        return nodes;
+    }
+}
+
+pub struct SyntaxTree {
+    pub root: SyntaxNode,
+    pub ast: Ast,
+}
+
+impl SyntaxTree {
+    /// Parses `text` into the tree and the typed AST. Parse errors go to
+    /// `diagnostics`; the tree is lossless either way.
+    pub fn parse(text: &str, diagnostics: &mut Diagnostics) -> Self {
+        let (ast, root) = parser::parse_tree(lexer::lex_with_trivia(text), diagnostics);
+
+        return Self {
+            root,
+            ast,
+        };
+    }
+
+    /// The source text, identical to the parsed text until it is edited.
+    pub fn text(&self) -> String {
+        return self.root.text();
+    }
+
+    /// The typed statement `node` is a view of.
+    pub fn statement(&self, node: &SyntaxNode) -> Option<&dyn Statement> {
+        let kind = match node.kind {
+            NodeKind::Statement(kind) => kind,
+            NodeKind::File => return None,
+        };
+
+        return find_statement(&self.ast.statements, node.span, kind);
+    }
+
+    /// Replaces the source text at `span`, trivia included. The tokens it
+    /// touches become one token, which keeps its trivia when the span starts
+    /// and ends in tokens. Returns whether the span is in the tree. Spans keep
+    /// pointing into the parsed text, so edits are made from the last one.
+    pub fn replace(&mut self, span: Span, new_text: &str) -> bool {
+        let full_start = |token: &Token| token.leading_trivia.first().map_or(token.span.start, |trivia| trivia.span.start);
+        let full_end = |token: &Token| token.trailing_trivia.last().map_or(token.span.end, |trivia| trivia.span.end);
+
+        let mut tokens = self.root.tokens_mut();
+        let first = tokens.iter().position(|token| full_start(token) <= span.start && span.start < full_end(token));
+        let last = tokens.iter().position(|token| full_start(token) < span.end && span.end <= full_end(token));
+
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) if first <= last => (first, last),
+            _ => return false,
+        };
+
+        let in_tokens = (tokens[first].span.start..=tokens[first].span.end).contains(&span.start)
+            && (tokens[last].span.start..=tokens[last].span.end).contains(&span.end);
+        let (head, tail, trailing_trivia) = if in_tokens {
+            let head = tokens[first].literal[..span.start - tokens[first].span.start].to_string();
+            let tail = tokens[last].literal[span.end - tokens[last].span.start..].to_string();
+            (head, tail, std::mem::take(&mut tokens[last].trailing_trivia))
+        } else {
+            let head = tokens[first].full_text()[..span.start - full_start(tokens[first])].to_string();
+            let tail = tokens[last].full_text()[span.end - full_start(tokens[last])..].to_string();
+            tokens[first].leading_trivia.clear();
+            (head, tail, vec![])
+        };
+
+        for token in &mut tokens[first + 1..=last] {
+            token.literal.clear();
+            token.leading_trivia.clear();
+            token.trailing_trivia.clear();
+        }
+
+        let token = &mut tokens[first];
+        token.literal = head + new_text + &tail;
+        token.trailing_trivia = trailing_trivia;
+
+        return true;
+    }
+}
+
+// the statements are in source order, so only the last one starting before
+// the node can be it or contain it
+fn find_statement(statements: &[Box<dyn Statement>], span: Span, kind: StatementType) -> Option<&dyn Statement> {
+    let index = statements.partition_point(|statement| statement.span().start <= span.start).checked_sub(1)?;
+    let statement = statements[index].as_ref();
+
+    if statement.span() == span && statement.my_type() == kind {
+        return Some(statement);
+    }
+
+    if let Some(conditional) = statement.as_any().downcast_ref::<ast::IfStatement>() {
+        return conditional.branches.iter().find_map(|branch| find_statement(&branch.statements, span, kind));
+    }
+
+    if let Some(test) = statement.as_any().downcast_ref::<ast::TestStatement>() {
+        return find_statement(&test.statements, span, kind);
+    }
+
+    return None;
+}
+
+/// Builds the tree while the parser goes through the tokens: the tokens
+/// before a statement go to the enclosing node, the ones it consumes to a
+/// node of its own.
+pub(crate) struct TreeBuilder {
+    tokens: Vec<Token>,
+    // index of the first token not in the tree yet
+    emitted: usize,
+    // children of the nodes being built, the root first
+    stack: Vec<Vec<SyntaxElement>>,
+}
+
+impl TreeBuilder {
+    pub(crate) fn new(tokens: Vec<Token>) -> Self {
+        return Self {
+            tokens,
+            emitted: 0,
+            stack: vec![vec![]],
+        };
+    }
+
+    // a statement starts at token `index`
+    pub(crate) fn start(&mut self, index: usize) {
+        self.flush(index);
+        self.stack.push(vec![]);
+    }
+
+    // the statement started last ends before token `end`
+    pub(crate) fn finish(&mut self, end: usize, kind: StatementType, span: Span) {
+        self.flush(end);
+        let children = self.stack.pop().unwrap_or_default();
+        self.push(SyntaxElement::Node(SyntaxNode {
+            kind: NodeKind::Statement(kind),
+            span,
+            children,
+        }));
+    }
+
+    // the statement started at `start` failed and was skipped up to `end`,
+    // which may be before the tokens its nodes took
+    pub(crate) fn abandon(&mut self, start: usize, end: usize, span: Span) {
+        self.stack.pop();
+        self.emitted = start;
+        self.stack.push(vec![]);
+        self.finish(end, StatementType::Error, span);
+    }
+
+    pub(crate) fn build(mut self) -> SyntaxNode {
+        self.flush(self.tokens.len());
+        let end = self.tokens.last().map(|token| token.span.end).unwrap_or(0);
+
+        return SyntaxNode {
+            kind: NodeKind::File,
+            span: Span { start: 0, end },
+            children: self.stack.swap_remove(0),
+        };
+    }
+
+    fn flush(&mut self, end: usize) {
+        while self.emitted < end.min(self.tokens.len()) {
+            let token = self.tokens[self.emitted].clone();
+            self.push(SyntaxElement::Token(token));
+            self.emitted += 1;
+        }
+    }
+
+    fn push(&mut self, element: SyntaxElement) {
+        if let Some(children) = self.stack.last_mut() {
+            children.push(element);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(text: &str) {
+        let mut diagnostics = Diagnostics::new();
+        let tree = SyntaxTree::parse(text, &mut diagnostics);
+
+        assert_eq!(tree.text(), text, "{:?} does not round-trip", text);
+    }
+
+    #[test]
+    fn printing_reproduces_the_source() {
+        let inputs = [
+            "",
+            "\n\n",
+            "; only a comment",
+            "   \t  ",
+            "INCLUDE \"hardware.inc\"   ; trailing comment\r\n\tSECTION \"x\", ROM0[$100]\r\n",
+            "Main::\n\tld a, [hl+] ; load\n.loop:  jr nz, .loop\n\tdb \"a ; b\", \"\\\"\", 1\n",
+            "IF DEF(DEBUG)\n\tDEF X EQU 1\nELIF 0\n  ; nested comment\nELSE\nDEF X EQU 2\nENDC\n",
+            "my_macro: MACRO\n\tld a, \\1 ; body\nENDM\n\tmy_macro 3, (1, 2)\n",
+            "DEF TITLE EQUS \"ポケモン\" ; 名前\n",
+            // statements that do not parse are kept as well
+            "SECTION \"x\"\nIF 1\n@@ {} # '\nREPT 3\n\tnop\n",
+            "\"unterminated\n\tld a, ; missing\n\\\n",
+        ];
+
+        for input in inputs {
+            round_trip(input);
+        }
+    }
+
+    #[test]
+    fn round_trip_of_generated_sources() {
+        let fragments = [
+            "ld", " ", "\t", "a", ",", "[hl+]", "\n", "\r\n", "; c", "\"", "s p", ":", "::", ".", "IF",
+            "ENDC", "ELSE", "MACRO", "ENDM", "$FF", "%01", "&7", "+", "(", ")", "DEF", "EQU", "é",
+            "\\", "db", "SECTION", "INCLUDE", "x",
+        ];
+
+        // small linear congruential generator, so the inputs are reproducible
+        let mut seed: u64 = 0x2545F4914F6CDD1D;
+        for _ in 0..500 {
+            let mut text = String::new();
+            for _ in 0..24 {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                text += fragments[(seed >> 33) as usize % fragments.len()];
+            }
+
+            round_trip(&text);
+        }
+    }
+
+    #[test]
+    fn nodes_are_views_of_statements() {
+        let mut diagnostics = Diagnostics::new();
+        let tree = SyntaxTree::parse("IF 1 ; yes\n  Main: nop\nENDC\n", &mut diagnostics);
+
+        let conditional = tree.root.nodes().next().unwrap();
+        assert_eq!(conditional.kind, NodeKind::Statement(StatementType::If));
+        assert_eq!(conditional.text(), "IF 1 ; yes\n  Main: nop\nENDC");
+
+        let inner: Vec<&SyntaxNode> = conditional.nodes().collect();
+        assert_eq!(inner.len(), 2);
+        assert_eq!(inner[0].text(), "  Main: ");
+        assert_eq!(tree.statement(inner[1]).unwrap().to_string(), "Instruction \"nop\" 0 operand(s)");
+    }
+
+    #[test]
+    fn editing_tokens() {
+        let text = "Main: ; start\n\tld a, 0 ; clear\n\tjp Main\n";
+        let mut tree = SyntaxTree::parse(text, &mut Diagnostics::new());
+
+        assert!(tree.replace(Span { start: 35, end: 39 }, "Start"));
+        assert!(tree.replace(Span { start: 15, end: 22 }, "xor a"));
+        assert!(tree.replace(Span { start: 0, end: 4 }, "Start"));
+        assert!(!tree.replace(Span { start: 100, end: 104 }, "Nowhere"));
+
+        assert_eq!(tree.text(), "Start: ; start\n\txor a ; clear\n\tjp Start\n");
+        let instruction = tree.root.nodes().nth(1).unwrap();
+        assert_eq!(instruction.tokens()[0].trailing_trivia.iter().map(|trivia| trivia.text.as_str()).collect::<String>(), " ; clear");
+    }
+}
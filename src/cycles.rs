@@ -0,0 +1,604 @@
+//! Counting the cycles of routines without running them.
+//!
+//! A routine goes from a label that is not a local one to the next such
+//! label of its section. Its instructions are found by following the code
+//! from its label, decoded from the bytes of the linked sections, and each
+//! takes the cycles the encoder gives it, the not taken count for
+//! conditional branches that fall through. Calls, including `rst` and
+//! falling or jumping into the next routine, add the cycles of the routine
+//! they get to.
+//!
+//! A branch back to an earlier instruction makes a loop, which has no
+//! maximum unless the line of the branch has an `@loop <count>` comment
+//! telling how many times the loop body runs:
+//!
+//! ```text
+//!     ld b, 16
+//! .copy:
+//!     ld a, [hl+]
+//!     ld [de], a
+//!     inc de
+//!     dec b
+//!     jr nz, .copy ; @loop 16
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::assembler::{Assembler, Placement, Section};
+use crate::diagnostic::SourceFile;
+use crate::sm83;
+use crate::symbols::{Definition, SymbolValue};
+
+/// Minimum and maximum cycles of a routine. The maximum is `None` when a
+/// path has no bound, and the notes tell why.
+#[derive(Debug, Clone)]
+pub struct RoutineCycles {
+    pub name: String,
+    pub definition: Option<Definition>,
+    pub min: u64,
+    pub max: Option<u64>,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Cost {
+    min: u64,
+    max: Option<u64>,
+}
+
+impl Cost {
+    fn new(cycles: u64) -> Self {
+        return Self { min: cycles, max: Some(cycles) };
+    }
+
+    fn then(self, other: Cost) -> Cost {
+        return Cost {
+            min: self.min + other.min,
+            max: self.max.zip(other.max).map(|(a, b)| a + b),
+        };
+    }
+
+    // either of two paths
+    fn or(self, other: Cost) -> Cost {
+        return Cost {
+            min: self.min.min(other.min),
+            max: self.max.zip(other.max).map(|(a, b)| a.max(b)),
+        };
+    }
+
+    fn times(self, count: u64) -> Cost {
+        return Cost {
+            min: self.min * count,
+            max: self.max.map(|max| max * count),
+        };
+    }
+}
+
+fn or(path: Option<Cost>, other: Cost) -> Option<Cost> {
+    return Some(path.map_or(other, |path| path.or(other)));
+}
+
+struct Routine {
+    name: String,
+    definition: Option<Definition>,
+    section: usize,
+    start: usize,
+    end: usize,
+}
+
+struct Edge {
+    /// Node of the routine, `None` when leaving it
+    to: Option<usize>,
+    cost: Cost,
+}
+
+struct Node {
+    offset: usize,
+    edges: Vec<Edge>,
+    alive: bool,
+}
+
+/// Cycles of the routines of an assembled and linked file, in the order of
+/// their sections. Routines without any instruction, like tables, are left
+/// out.
+pub fn routine_cycles(assembler: &Assembler, sources: &[SourceFile]) -> Vec<RoutineCycles> {
+    let mut counter = Counter::new(assembler, sources);
+
+    let mut results = vec![];
+    for routine in 0..counter.routines.len() {
+        let (section, start, end) = (counter.routines[routine].section, counter.routines[routine].start, counter.routines[routine].end);
+        let section_name = &assembler.sections()[section].name;
+        let has_code = counter.lines.keys().any(|(name, offset)| name == section_name && (start..end).contains(offset));
+        if !has_code {
+            continue;
+        }
+
+        let (cost, notes) = counter.count(routine);
+        results.push(RoutineCycles {
+            name: counter.routines[routine].name.clone(),
+            definition: counter.routines[routine].definition.clone(),
+            min: cost.min,
+            max: cost.max,
+            notes,
+        });
+    }
+
+    return results;
+}
+
+struct Counter<'a> {
+    sections: &'a [Section],
+    routines: Vec<Routine>,
+    /// Routines by section and offset
+    starts: HashMap<(usize, usize), usize>,
+    /// Where the instructions come from, by section name and offset, as a
+    /// file, a line number and the text of the line
+    lines: HashMap<(String, usize), (String, usize, String)>,
+    counted: Vec<Option<(Cost, Vec<String>)>>,
+    counting: Vec<bool>,
+}
+
+impl<'a> Counter<'a> {
+    fn new(assembler: &'a Assembler, sources: &[SourceFile]) -> Self {
+        let sections = assembler.sections();
+        let indices: HashMap<&str, usize> = sections.iter().enumerate().map(|(i, section)| (section.name.as_str(), i)).collect();
+
+        let mut labels: BTreeMap<(usize, usize), (String, Option<Definition>)> = BTreeMap::new();
+        for symbol in assembler.symbols.iter() {
+            if let SymbolValue::Label(location) = &symbol.value {
+                if symbol.name.contains('.') {
+                    continue;
+                }
+                if let Some(&section) = indices.get(location.section.as_str()) {
+                    labels.entry((section, location.offset as usize)).or_insert((symbol.name.clone(), symbol.definition.clone()));
+                }
+            }
+        }
+
+        let mut routines: Vec<Routine> = vec![];
+        for ((section, start), (name, definition)) in labels {
+            if let Some(previous) = routines.last_mut().filter(|previous| previous.section == section) {
+                previous.end = start;
+            }
+            routines.push(Routine {
+                name,
+                definition,
+                section,
+                start,
+                end: sections[section].data.len(),
+            });
+        }
+
+        let starts = routines.iter().enumerate().map(|(i, routine)| ((routine.section, routine.start), i)).collect();
+        let count = routines.len();
+
+        return Self {
+            sections,
+            routines,
+            starts,
+            lines: instruction_lines(&assembler.placements, sources),
+            counted: vec![None; count],
+            counting: vec![false; count],
+        };
+    }
+
+    fn count(&mut self, routine: usize) -> (Cost, Vec<String>) {
+        if let Some(counted) = &self.counted[routine] {
+            return counted.clone();
+        }
+        if self.counting[routine] {
+            let note = format!("`{}` calls itself", self.routines[routine].name);
+            return (Cost { min: 0, max: None }, vec![note]);
+        }
+
+        self.counting[routine] = true;
+        let counted = self.count_paths(routine);
+        self.counting[routine] = false;
+        self.counted[routine] = Some(counted.clone());
+
+        return counted;
+    }
+
+    fn count_paths(&mut self, routine: usize) -> (Cost, Vec<String>) {
+        let (section, start) = (self.routines[routine].section, self.routines[routine].start);
+        let mut notes = vec![];
+
+        // the instructions reachable from the label, with the offsets they
+        // go to, `None` for leaving the routine
+        let mut instructions: BTreeMap<usize, Vec<(Option<usize>, Cost)>> = BTreeMap::new();
+        let mut pending = vec![start];
+        while let Some(offset) = pending.pop() {
+            if instructions.contains_key(&offset) {
+                continue;
+            }
+
+            let edges = self.instruction_edges(routine, offset, &mut notes);
+            for (to, _) in &edges {
+                if let Some(to) = to {
+                    pending.push(*to);
+                }
+            }
+            instructions.insert(offset, edges);
+        }
+
+        let indices: HashMap<usize, usize> = instructions.keys().enumerate().map(|(i, offset)| (*offset, i)).collect();
+        let mut nodes: Vec<Node> = instructions.into_iter().map(|(offset, edges)| Node {
+            offset,
+            edges: edges.into_iter().map(|(to, cost)| Edge { to: to.map(|to| indices[&to]), cost }).collect(),
+            alive: true,
+        }).collect();
+
+        // loops by their first instruction, with the last instruction that
+        // branches back to it and the count of the first `@loop` found
+        let mut loops: BTreeMap<usize, (usize, Option<u64>)> = BTreeMap::new();
+        for (i, node) in nodes.iter().enumerate() {
+            for edge in &node.edges {
+                if let Some(to) = edge.to.filter(|to| *to <= i) {
+                    let count = self.loop_count(section, node.offset);
+                    let entry = loops.entry(to).or_insert((i, count));
+                    entry.0 = entry.0.max(i);
+                    entry.1 = entry.1.or(count);
+                }
+            }
+        }
+
+        let mut loops: Vec<(usize, usize, Option<u64>)> = loops.into_iter().map(|(first, (last, count))| (first, last, count)).collect();
+        loops.sort_by_key(|(first, last, _)| last - first);
+
+        for (first, last, count) in loops {
+            if count.is_none() {
+                notes.push(format!("the loop at {} has no `@loop` count", self.location(section, nodes[last].offset)));
+            }
+            self.collapse_loop(&mut nodes, first, last, count, section, &mut notes);
+        }
+
+        // the longest and shortest paths, now that the instructions left
+        // only branch forward
+        let mut paths: Vec<Option<Cost>> = vec![None; nodes.len()];
+        let mut total: Option<Cost> = None;
+        if !nodes.is_empty() {
+            paths[0] = Some(Cost::new(0));
+        }
+
+        for i in 0..nodes.len() {
+            let path = match paths[i] {
+                Some(path) if nodes[i].alive => path,
+                _ => continue,
+            };
+            for edge in &nodes[i].edges {
+                let cost = path.then(edge.cost);
+                match edge.to {
+                    Some(to) if to > i => paths[to] = or(paths[to], cost),
+                    Some(_) => {}
+                    None => total = or(total, cost),
+                }
+            }
+        }
+
+        let total = total.unwrap_or_else(|| {
+            notes.push(format!("`{}` never returns", self.routines[routine].name));
+            Cost { min: 0, max: None }
+        });
+
+        let mut seen = HashSet::new();
+        notes.retain(|note| seen.insert(note.clone()));
+        return (total, notes);
+    }
+
+    // replaces the instructions of a loop by its first one, which gets the
+    // cycles of the whole loop to each place the loop can be left for
+    fn collapse_loop(&self, nodes: &mut [Node], first: usize, last: usize, count: Option<u64>, section: usize, notes: &mut Vec<String>) {
+        let inside = |i: usize| (first..=last).contains(&i);
+
+        let entered = nodes.iter().enumerate()
+            .filter(|(i, node)| node.alive && !inside(*i))
+            .any(|(_, node)| node.edges.iter().any(|edge| edge.to.is_some_and(|to| inside(to) && to != first)));
+        if entered {
+            notes.push(format!("the loop at {} is entered in the middle", self.location(section, nodes[last].offset)));
+        }
+
+        let mut paths: Vec<Option<Cost>> = vec![None; last + 1 - first];
+        paths[0] = Some(Cost::new(0));
+        let mut iteration: Option<Cost> = None;
+        // leaving the loop, from the instruction that branches back or from
+        // the middle
+        let mut exits: Vec<(Option<usize>, Cost, bool)> = vec![];
+
+        for i in first..=last {
+            let path = match paths[i - first] {
+                Some(path) if nodes[i].alive => path,
+                _ => continue,
+            };
+            let branches_back = nodes[i].edges.iter().any(|edge| edge.to == Some(first));
+            for edge in &nodes[i].edges {
+                let cost = path.then(edge.cost);
+                match edge.to {
+                    Some(to) if to == first => iteration = or(iteration, cost),
+                    Some(to) if inside(to) && to > i => paths[to - first] = or(paths[to - first], cost),
+                    Some(to) if inside(to) => {
+                        notes.push(format!("the loop at {} overlaps another one", self.location(section, nodes[last].offset)));
+                    }
+                    _ => exits.push((edge.to, cost, branches_back)),
+                }
+            }
+        }
+
+        // all but the last run of the body, which leaves the loop
+        let repeated = match (count, iteration) {
+            (Some(count), Some(iteration)) => iteration.times(count.max(1) - 1),
+            (None, Some(iteration)) => Cost { min: 0, max: None }.or(iteration),
+            _ => Cost::new(0),
+        };
+
+        let mut edges: Vec<Edge> = vec![];
+        for (to, cost, branches_back) in exits {
+            let mut cost = repeated.then(cost);
+            // leaving from the middle can happen in the first run
+            if !branches_back {
+                cost.min -= repeated.min;
+            }
+
+            match edges.iter_mut().find(|edge| edge.to == to) {
+                Some(edge) => edge.cost = edge.cost.or(cost),
+                None => edges.push(Edge { to, cost }),
+            }
+        }
+
+        for node in &mut nodes[first + 1..=last] {
+            node.alive = false;
+        }
+        nodes[first].edges = edges;
+    }
+
+    // where the instruction at an offset can go, with the cycles it takes
+    // to get there
+    fn instruction_edges(&mut self, routine: usize, offset: usize, notes: &mut Vec<String>) -> Vec<(Option<usize>, Cost)> {
+        let section = self.routines[routine].section;
+        let sections = self.sections;
+        let data = &sections[section].data;
+
+        let decoded = match sm83::decode(&data[offset.min(data.len())..]) {
+            Some(decoded) if offset + decoded.size <= data.len() => decoded,
+            _ => {
+                notes.push(format!("{} is not an instruction", self.location(section, offset)));
+                return vec![(None, Cost { min: 0, max: None })];
+            }
+        };
+
+        let bytes = &data[offset..offset + decoded.size];
+        let (taken, not_taken) = (decoded.cycles as u64, decoded.cycles_not_taken as u64);
+        let conditional = taken != not_taken;
+        let next = offset + decoded.size;
+
+        let mut edges = vec![];
+        match bytes[0] {
+            // jr, jp
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xC3 | 0xC2 | 0xCA | 0xD2 | 0xDA => {
+                let target = if decoded.size == 2 {
+                    Some((section, (next as isize + bytes[1] as i8 as isize) as usize))
+                } else {
+                    self.locate(section, u16::from_le_bytes([bytes[1], bytes[2]]))
+                };
+                edges.push(self.go_to(routine, target, Cost::new(taken), offset, notes));
+                if conditional {
+                    edges.push(self.go_to(routine, Some((section, next)), Cost::new(not_taken), offset, notes));
+                }
+            }
+            // call, rst
+            0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC | 0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                let target = match decoded.size {
+                    3 => u16::from_le_bytes([bytes[1], bytes[2]]),
+                    _ => (bytes[0] & 0x38) as u16,
+                };
+                let callee = self.locate(section, target).and_then(|target| self.starts.get(&target).copied());
+                let called = match callee {
+                    Some(callee) => {
+                        let (cost, _) = self.count(callee);
+                        if cost.max.is_none() {
+                            notes.push(format!("`{}` has no maximum", self.routines[callee].name));
+                        }
+                        cost
+                    }
+                    None => {
+                        notes.push(format!("{} calls ${:04X}, which is not a routine", self.location(section, offset), target));
+                        Cost { min: 0, max: None }
+                    }
+                };
+
+                edges.push(self.go_to(routine, Some((section, next)), Cost::new(taken).then(called), offset, notes));
+                if conditional {
+                    edges.push(self.go_to(routine, Some((section, next)), Cost::new(not_taken), offset, notes));
+                }
+            }
+            // ret, reti
+            0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                edges.push((None, Cost::new(taken)));
+                if conditional {
+                    edges.push(self.go_to(routine, Some((section, next)), Cost::new(not_taken), offset, notes));
+                }
+            }
+            // jp hl
+            0xE9 => {
+                notes.push(format!("{} jumps to hl", self.location(section, offset)));
+                edges.push((None, Cost { min: taken, max: None }));
+            }
+            _ => edges.push(self.go_to(routine, Some((section, next)), Cost::new(taken), offset, notes)),
+        }
+
+        return edges;
+    }
+
+    // an edge to an offset of the routine, or leaving it for the routine
+    // starting at the target
+    fn go_to(&mut self, routine: usize, target: Option<(usize, usize)>, cost: Cost, offset: usize, notes: &mut Vec<String>) -> (Option<usize>, Cost) {
+        let current = &self.routines[routine];
+        let section = current.section;
+
+        if let Some((target_section, target_offset)) = target {
+            if target_section == current.section && (current.start..current.end).contains(&target_offset) {
+                return (Some(target_offset), cost);
+            }
+            if let Some(&callee) = self.starts.get(&(target_section, target_offset)) {
+                let (called, _) = self.count(callee);
+                if called.max.is_none() {
+                    notes.push(format!("`{}` has no maximum", self.routines[callee].name));
+                }
+                return (None, cost.then(called));
+            }
+        }
+
+        notes.push(format!("{} leaves the routine for code that is not a routine", self.location(section, offset)));
+        return (None, Cost { min: cost.min, max: None });
+    }
+
+    // section and offset of an address, in the bank of `from` for ROMX
+    fn locate(&self, from: usize, address: u16) -> Option<(usize, usize)> {
+        let address = address as usize;
+        let bank = self.sections[from].bank;
+
+        let mut found = self.sections.iter().enumerate().filter(|(_, section)| {
+            return section.address.is_some_and(|start| (start as usize..start as usize + section.data.len()).contains(&address))
+                && (address < 0x4000 || !(0x4000..0x8000).contains(&address) || section.bank == bank || self.sections[from].section_type != "ROMX");
+        });
+
+        let (index, section) = found.next()?;
+        if found.next().is_some() {
+            // one of several banks
+            return None;
+        }
+        return Some((index, address - section.address? as usize));
+    }
+
+    fn loop_count(&self, section: usize, offset: usize) -> Option<u64> {
+        let (_, _, text) = self.lines.get(&(self.sections[section].name.clone(), offset))?;
+        let (_, comment) = text.split_once(';')?;
+        let (_, count) = comment.split_once("@loop")?;
+        let count = count.split_whitespace().next()?;
+
+        return match count.strip_prefix('$') {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => count.parse().ok(),
+        };
+    }
+
+    fn location(&self, section: usize, offset: usize) -> String {
+        return match self.lines.get(&(self.sections[section].name.clone(), offset)) {
+            Some((file, line, _)) => format!("{}:{}", file, line),
+            None => format!("\"{}\"+${:X}", self.sections[section].name, offset),
+        };
+    }
+}
+
+fn instruction_lines(placements: &[Placement], sources: &[SourceFile]) -> HashMap<(String, usize), (String, usize, String)> {
+    let mut lines = HashMap::new();
+
+    for placement in placements {
+        let source = match sources.iter().find(|source| source.name == placement.file) {
+            Some(source) => source,
+            None => continue,
+        };
+
+        let (line, _) = source.line_col(placement.span.start);
+        let start = source.text[..placement.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = source.text[placement.span.start..].find('\n').map_or(source.text.len(), |i| placement.span.start + i);
+        let text = source.text[start..end].to_string();
+        lines.insert((placement.location.section.clone(), placement.location.offset as usize), (source.name.clone(), line, text));
+    }
+
+    return lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Diagnostics;
+    use crate::{lexer, link, parser};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn counting_cycles() {
+        let source = SourceFile::new("main.asm", concat!(
+            "SECTION \"Code\", ROM0\n",
+            "Copy:\n",
+            "\tld b, 16\n",
+            ".copy:\n",
+            "\tld a, [hl+]\n",
+            "\tld [de], a\n",
+            "\tinc de\n",
+            "\tdec b\n",
+            "\tjr nz, .copy ; @loop 16\n",
+            "\tret\n",
+            "Clear:\n",
+            "\tld [hl+], a\n",
+            "\tdec c\n",
+            "\tjr nz, Clear\n",
+            "\tret\n",
+            "Update:\n",
+            "\tcall Copy\n",
+            "\tld a, [wFlag]\n",
+            "\tand a\n",
+            "\tret z\n",
+            "\tcall Clear\n",
+            "Wait:\n",
+            "\tld c, 4\n",
+            ".outer:\n",
+            "\tld b, 8\n",
+            ".inner:\n",
+            "\tdec b\n",
+            "\tjr nz, .inner ; @loop 8\n",
+            "\tdec c\n",
+            "\tjr nz, .outer ; @loop $4\n",
+            "\treti\n",
+            "Table:\n",
+            "\tdb 1, 2, 3\n",
+            "SECTION \"Work\", WRAM0\n",
+            "wFlag: db\n",
+        ).to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        let routines = routine_cycles(&assembler, &[source]);
+        let counts: Vec<(&str, u64, Option<u64>)> = routines.iter().map(|routine| (routine.name.as_str(), routine.min, routine.max)).collect();
+        // 15 times the body with the branch taken, then without
+        let copy = 2 + 15 * (2 + 2 + 2 + 1 + 3) + (2 + 2 + 2 + 1 + 2) + 4;
+        // the inner loop takes 7 * 4 + 3 cycles, the outer one 4 times
+        // that with the loading of b, and the decrements and branches
+        let wait = 2 + 3 * (2 + 31 + 1 + 3) + (2 + 31 + 1 + 2) + 4;
+        assert_eq!(counts, [
+            ("Copy", copy, Some(copy)),
+            ("Clear", 2 + 1 + 2 + 4, None),
+            // returning early, or calling `Clear` and falling into `Wait`
+            ("Update", 6 + copy + 4 + 1 + 5, None),
+            ("Wait", wait, Some(wait)),
+        ]);
+        assert_eq!(routines[1].notes, ["the loop at main.asm:14 has no `@loop` count"]);
+        assert_eq!(routines[2].notes, ["`Clear` has no maximum"]);
+    }
+
+    #[test]
+    fn counting_repeated_code() {
+        let source = SourceFile::new("main.asm", concat!(
+            "SECTION \"Code\", ROM0\n",
+            "Shift:\n",
+            "\tld a, [hl]\n",
+            "REPT 4\n",
+            "\tsrl a\n",
+            "ENDR\n",
+            "\tret\n",
+        ).to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        // the body counts once for each repetition
+        let routines = routine_cycles(&assembler, &[source]);
+        assert_eq!((routines[0].min, routines[0].max), (2 + 4 * 2 + 4, Some(2 + 4 * 2 + 4)));
+    }
+}
@@ -0,0 +1,360 @@
+//! Debug information of a linked ROM: the source line of every byte output
+//! by code or data, with the macros it was expanded from, and the labels
+//! with their scopes and sizes. The text format is described in
+//! docs/debug-info.md.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::assembler::Assembler;
+use crate::diagnostic::SourceFile;
+use crate::disasm::SymbolName;
+use crate::symbols::SymbolValue;
+
+/// First line of the format, with its version
+pub const HEADER: &str = "GBDEBUG 1";
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DebugInfo {
+    /// Sorted by bank and address
+    pub lines: Vec<LineInfo>,
+    /// Sorted by bank, address and name
+    pub symbols: Vec<SymbolInfo>,
+}
+
+/// Bytes output by a line of source.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LineInfo {
+    pub bank: i32,
+    pub address: u16,
+    pub size: u16,
+    pub file: String,
+    pub line: usize,
+    /// Invocations of the macros the line was expanded from, the innermost
+    /// first
+    pub expansions: Vec<MacroInvocation>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MacroInvocation {
+    pub name: String,
+    pub file: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Scope {
+    Global,
+    /// A local label, in the scope of the global label its name starts with
+    Local,
+}
+
+/// A label, with the bytes up to the next label of its scope or of a wider
+/// one, or to the end of its section.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SymbolInfo {
+    pub bank: i32,
+    pub address: u16,
+    pub size: u16,
+    pub scope: Scope,
+    pub name: String,
+    /// File and line of the definition, `None` for labels defined on the
+    /// command line
+    pub definition: Option<(String, usize)>,
+}
+
+impl DebugInfo {
+    /// The debug information of the sections of `assembler` as placed by
+    /// `link::link`.
+    pub fn new(assembler: &Assembler, sources: &[SourceFile]) -> Self {
+        let sections = assembler.sections();
+        let placed = |name: &str| {
+            let section = sections.iter().find(|section| section.name == name)?;
+            return Some((section.bank?, section.address?, section.data.len() as i32));
+        };
+
+        let mut lines = vec![];
+        for output in &assembler.output_lines {
+            if let Some((bank, address, _)) = placed(&output.location.section) {
+                lines.push(LineInfo {
+                    bank,
+                    address: (address + output.location.offset) as u16,
+                    size: output.size as u16,
+                    file: output.file.clone(),
+                    line: output.line,
+                    expansions: output.expansions.iter().map(|expansion| MacroInvocation {
+                        name: expansion.name.clone(),
+                        file: expansion.file.clone(),
+                        line: expansion.line,
+                    }).collect(),
+                });
+            }
+        }
+        lines.sort_by_key(|line| (line.bank, line.address));
+
+        // labels by section, to size them up to the next one
+        let mut labels: BTreeMap<&str, Vec<(i32, &str)>> = BTreeMap::new();
+        for symbol in assembler.symbols.iter() {
+            if let SymbolValue::Label(location) = &symbol.value {
+                labels.entry(location.section.as_str()).or_default().push((location.offset, symbol.name.as_str()));
+            }
+        }
+
+        let mut symbols = vec![];
+        for (section, mut offsets) in labels {
+            let (bank, address, length) = match placed(section) {
+                Some(section) => section,
+                None => continue,
+            };
+            offsets.sort();
+
+            for (i, (offset, name)) in offsets.iter().enumerate() {
+                let scope = if name.contains('.') { Scope::Local } else { Scope::Global };
+                let end = offsets[i + 1..].iter()
+                    .find(|(next, next_name)| *next > *offset && (scope == Scope::Local || !next_name.contains('.')))
+                    .map_or(length, |(next, _)| *next);
+                let definition = assembler.symbols.get(name)
+                    .and_then(|symbol| symbol.definition.as_ref())
+                    .and_then(|definition| {
+                        let source = sources.iter().find(|source| source.name == definition.file)?;
+                        return Some((source.name.clone(), source.line_col(definition.span.start).0));
+                    });
+
+                symbols.push(SymbolInfo {
+                    bank,
+                    address: (address + offset) as u16,
+                    size: (end - offset) as u16,
+                    scope,
+                    name: name.to_string(),
+                    definition,
+                });
+            }
+        }
+        symbols.sort_by(|a, b| (a.bank, a.address, &a.name).cmp(&(b.bank, b.address, &b.name)));
+
+        return Self { lines, symbols };
+    }
+
+    /// Reads the text format, failing at the first line that is not in it.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate()
+            .map(|(i, line)| (i + 1, line.split(';').next().unwrap_or("").trim()))
+            .filter(|(_, line)| !line.is_empty());
+
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            Some((number, line)) if line.starts_with("GBDEBUG ") => {
+                return Err(format!("line {}: unsupported version, expected `{}`", number, HEADER));
+            }
+            _ => return Err(format!("not debug information, the first line is not `{}`", HEADER)),
+        }
+
+        let mut info = DebugInfo::default();
+        let mut files: BTreeMap<usize, String> = BTreeMap::new();
+        for (number, line) in lines {
+            let error = |message: &str| format!("line {}: {}", number, message);
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+            match keyword {
+                "FILE" => {
+                    let (index, name) = rest.split_once(' ').ok_or_else(|| error("expected FILE <index> <path>"))?;
+                    let index = index.parse().map_err(|_| error(&format!("invalid file index `{}`", index)))?;
+                    files.insert(index, name.to_string());
+                }
+                "LINE" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    if fields.len() < 3 {
+                        return Err(error("expected LINE <bank>:<address> <size> <file>:<line>"));
+                    }
+                    let (bank, address) = parse_location(fields[0]).ok_or_else(|| error(&format!("invalid location `{}`", fields[0])))?;
+                    let size = parse_size(fields[1]).ok_or_else(|| error(&format!("invalid size `{}`", fields[1])))?;
+                    let (file, line) = parse_line(fields[2], &files).ok_or_else(|| error(&format!("invalid line `{}`", fields[2])))?;
+
+                    let mut expansions = vec![];
+                    for field in &fields[3..] {
+                        let invocation = field.split_once('@')
+                            .and_then(|(name, line)| Some((name, parse_line(line, &files)?)))
+                            .filter(|(name, _)| !name.is_empty());
+                        let (name, (file, line)) = invocation.ok_or_else(|| error(&format!("invalid macro invocation `{}`", field)))?;
+                        expansions.push(MacroInvocation { name: name.to_string(), file, line });
+                    }
+
+                    info.lines.push(LineInfo { bank, address, size, file, line, expansions });
+                }
+                "SYMBOL" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    if fields.len() != 5 {
+                        return Err(error("expected SYMBOL <bank>:<address> <size> <scope> <file>:<line> <name>"));
+                    }
+                    let (bank, address) = parse_location(fields[0]).ok_or_else(|| error(&format!("invalid location `{}`", fields[0])))?;
+                    let size = parse_size(fields[1]).ok_or_else(|| error(&format!("invalid size `{}`", fields[1])))?;
+                    let scope = match fields[2] {
+                        "global" => Scope::Global,
+                        "local" => Scope::Local,
+                        scope => return Err(error(&format!("unknown scope `{}`, expected global or local", scope))),
+                    };
+                    let definition = match fields[3] {
+                        "-" => None,
+                        line => Some(parse_line(line, &files).ok_or_else(|| error(&format!("invalid line `{}`", line)))?),
+                    };
+
+                    info.symbols.push(SymbolInfo { bank, address, size, scope, name: fields[4].to_string(), definition });
+                }
+                _ => return Err(error(&format!("unknown record `{}`", keyword))),
+            }
+        }
+
+        info.lines.sort_by_key(|line| (line.bank, line.address));
+        info.symbols.sort_by(|a, b| (a.bank, a.address, &a.name).cmp(&(b.bank, b.address, &b.name)));
+        return Ok(info);
+    }
+
+    /// The line that output the byte at an address of a bank.
+    pub fn line_at(&self, bank: i32, address: u16) -> Option<&LineInfo> {
+        let end = self.lines.partition_point(|line| (line.bank, line.address) <= (bank, address));
+        let line = self.lines[..end].last()?;
+
+        if line.bank == bank && address - line.address < line.size.max(1) {
+            return Some(line);
+        }
+        return None;
+    }
+
+    /// Bank and address of the first byte output by a line, not counting
+    /// the macros it invokes. The file is its path or the end of it.
+    pub fn address_of(&self, file: &str, line: usize) -> Option<(i32, u16)> {
+        let matches = |name: &str| name == file || name.ends_with(&format!("/{}", file));
+
+        return self.lines.iter()
+            .filter(|info| info.line == line && matches(&info.file))
+            .map(|info| (info.bank, info.address))
+            .min_by_key(|(bank, address)| (*address, *bank));
+    }
+
+    /// The labels as the names of a `.sym` file, for the disassembler.
+    pub fn symbol_names(&self) -> Vec<SymbolName> {
+        return self.symbols.iter().map(|symbol| SymbolName {
+            bank: symbol.bank as usize,
+            address: symbol.address as usize,
+            name: symbol.name.clone(),
+        }).collect();
+    }
+}
+
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // files numbered in the order they first appear
+        let mut files: Vec<String> = vec![];
+        let mut index = |file: &str| match files.iter().position(|name| *name == file) {
+            Some(index) => index,
+            None => {
+                files.push(file.to_string());
+                files.len() - 1
+            }
+        };
+
+        let mut records = String::new();
+        for line in &self.lines {
+            records += &format!("LINE {:02X}:{:04X} {} {}:{}", line.bank, line.address, line.size, index(&line.file), line.line);
+            for expansion in &line.expansions {
+                records += &format!(" {}@{}:{}", expansion.name, index(&expansion.file), expansion.line);
+            }
+            records.push('\n');
+        }
+        for symbol in &self.symbols {
+            let scope = match symbol.scope {
+                Scope::Global => "global",
+                Scope::Local => "local",
+            };
+            let definition = match &symbol.definition {
+                Some((file, line)) => format!("{}:{}", index(file), line),
+                None => "-".to_string(),
+            };
+            records += &format!("SYMBOL {:02X}:{:04X} {} {} {} {}\n", symbol.bank, symbol.address, symbol.size, scope, definition, symbol.name);
+        }
+
+        writeln!(f, "{}", HEADER)?;
+        for (i, file) in files.iter().enumerate() {
+            writeln!(f, "FILE {} {}", i, file)?;
+        }
+        return write!(f, "{}", records);
+    }
+}
+
+fn parse_location(text: &str) -> Option<(i32, u16)> {
+    let (bank, address) = text.split_once(':')?;
+    return Some((i32::from_str_radix(bank, 16).ok()?, u16::from_str_radix(address, 16).ok()?));
+}
+
+fn parse_size(text: &str) -> Option<u16> {
+    return text.parse().ok();
+}
+
+fn parse_line(text: &str, files: &BTreeMap<usize, String>) -> Option<(String, usize)> {
+    let (file, line) = text.split_once(':')?;
+    return Some((files.get(&file.parse().ok()?)?.clone(), line.parse().ok()?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Diagnostics;
+    use crate::{lexer, link, parser};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn writing_and_reading_debug_info() {
+        let source = SourceFile::new("main.asm", concat!(
+            "copy_byte: MACRO\n",
+            "\tld a, [hl+]\n",
+            "\tld [de], a\n",
+            "ENDM\n",
+            "copy_word: MACRO\n",
+            "\tcopy_byte\n",
+            "\tcopy_byte\n",
+            "ENDM\n",
+            "SECTION \"Main\", ROM0[$150]\n",
+            "Main:\n",
+            "\tld hl, Data\n",
+            ".copy:\n",
+            "\tcopy_word\n",
+            "\tjr .copy\n",
+            "SECTION \"Data\", ROMX[$4000], BANK[2]\n",
+            "Data: db 1, 2\n",
+            "\tdw 3\n",
+        ).to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        let info = DebugInfo::new(&assembler, &[source]);
+        let text = info.to_string();
+        assert_eq!(text, concat!(
+            "GBDEBUG 1\n",
+            "FILE 0 main.asm\n",
+            "LINE 00:0150 3 0:11\n",
+            "LINE 00:0153 1 0:2 copy_byte@0:6 copy_word@0:13\n",
+            "LINE 00:0154 1 0:3 copy_byte@0:6 copy_word@0:13\n",
+            "LINE 00:0155 1 0:2 copy_byte@0:7 copy_word@0:13\n",
+            "LINE 00:0156 1 0:3 copy_byte@0:7 copy_word@0:13\n",
+            "LINE 00:0157 2 0:14\n",
+            "LINE 02:4000 2 0:16\n",
+            "LINE 02:4002 2 0:17\n",
+            "SYMBOL 00:0150 9 global 0:10 Main\n",
+            "SYMBOL 00:0153 6 local 0:12 Main.copy\n",
+            "SYMBOL 02:4000 4 global 0:16 Data\n",
+        ));
+        assert_eq!(DebugInfo::parse(&text), Ok(info.clone()));
+
+        assert_eq!(info.line_at(0, 0x0158).map(|line| line.line), Some(14));
+        assert_eq!(info.line_at(2, 0x4003).map(|line| line.line), Some(17));
+        assert_eq!(info.line_at(1, 0x4000), None);
+        assert_eq!(info.address_of("main.asm", 2), Some((0, 0x0153)));
+
+        assert_eq!(DebugInfo::parse("GBDEBUG 1\nLINE 00:0150 3 0:11\n"), Err("line 2: invalid line `0:11`".to_string()));
+        assert!(DebugInfo::parse("00:0150 Main\n").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Make compatible dependency files, like the ones written by `rgbasm -M`.
+
+/// Escapes a path so make reads it as a single file name.
+pub fn escape(path: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in path.chars() {
+        match c {
+            ' ' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped += "$$",
+            _ => escaped.push(c),
+        }
+    }
+
+    return escaped;
+}
+
+/// One `targets: dependency` rule per dependency, the targets escaped like
+/// the dependencies. With `phony` every
+/// dependency but the first (the assembled file) also gets an empty rule
+/// (`-MP`), so make does not fail once an included file is deleted.
+pub fn make_rules(targets: &[String], dependencies: &[String], phony: bool) -> String {
+    let targets: Vec<String> = targets.iter().map(|target| escape(target)).collect();
+    let targets = targets.join(" ");
+    let mut out = String::new();
+
+    for dependency in dependencies {
+        out += &format!("{}: {}\n", targets, escape(dependency));
+    }
+
+    if phony {
+        for dependency in dependencies.iter().skip(1) {
+            out += &format!("\n{}:\n", escape(dependency));
+        }
+    }
+
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_rules() {
+        let dependencies = vec![
+            "main.asm".to_string(),
+            "inc/hardware.inc".to_string(),
+            "gfx/title screen.2bpp".to_string(),
+        ];
+
+        let expected = concat!(
+            "main.o: main.asm\n",
+            "main.o: inc/hardware.inc\n",
+            "main.o: gfx/title\\ screen.2bpp\n",
+            "\n",
+            "inc/hardware.inc:\n",
+            "\n",
+            "gfx/title\\ screen.2bpp:\n",
+        );
+
+        assert_eq!(make_rules(&["main.o".to_string()], &dependencies, true), expected);
+        assert_eq!(escape("$(x)#1"), "$$(x)\\#1");
+        assert_eq!(make_rules(&["build dir/$main.o".to_string()], &dependencies[..1], false), "build\\ dir/$$main.o: main.asm\n");
+    }
+}
@@ -0,0 +1,441 @@
+use std::fmt::Write;
+use crate::lexer::Span;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub primary: Label,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+    /// File the spans point into, `None` for the file being checked.
+    pub file: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: &str, span: Span) -> Self {
+        return Self {
+            severity,
+            code,
+            message: message.to_string(),
+            primary: Label {
+                span,
+                message: String::new(),
+            },
+            secondary: vec![],
+            notes: vec![],
+            help: None,
+            file: None,
+        };
+    }
+
+    pub fn error(code: &'static str, message: &str, span: Span) -> Self {
+        return Self::new(Severity::Error, code, message, span);
+    }
+
+    pub fn warning(code: &'static str, message: &str, span: Span) -> Self {
+        return Self::new(Severity::Warning, code, message, span);
+    }
+
+    pub fn with_label(mut self, message: &str) -> Self {
+        self.primary.message = message.to_string();
+        return self;
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: &str) -> Self {
+        self.secondary.push(Label {
+            span,
+            message: message.to_string(),
+        });
+        return self;
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_string());
+        return self;
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.help = Some(help.to_string());
+        return self;
+    }
+
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        return self;
+    }
+
+    pub fn is_error(&self) -> bool {
+        return self.severity == Severity::Error;
+    }
+}
+
+/// Warning controls given with `-w`, `-Werror`, `-W<name>`, `-Wno-<name>` and
+/// `-Werror=<name>`. Warnings are identified by their diagnostic code.
+#[derive(Debug, Clone, Default)]
+pub struct WarningSettings {
+    pub all_disabled: bool,
+    pub all_errors: bool,
+    enabled: Vec<String>,
+    disabled: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl WarningSettings {
+    /// Applies a flag given without its leading `-W`, e.g. `no-unused-label`.
+    pub fn apply_flag(&mut self, flag: &str) -> Result<(), String> {
+        if flag.is_empty() {
+            return Err("-W expects a warning name".to_string());
+        }
+
+        if flag == "error" {
+            self.all_errors = true;
+        } else if flag == "no-error" {
+            self.all_errors = false;
+        } else if let Some(name) = flag.strip_prefix("error=") {
+            self.enable(name);
+            self.errors.push(name.to_string());
+        } else if let Some(name) = flag.strip_prefix("no-error=") {
+            self.errors.retain(|n| n != name);
+        } else if let Some(name) = flag.strip_prefix("no-") {
+            self.enabled.retain(|n| n != name);
+            self.disabled.push(name.to_string());
+        } else {
+            self.enable(flag);
+        }
+
+        return Ok(());
+    }
+
+    fn enable(&mut self, name: &str) {
+        self.disabled.retain(|n| n != name);
+        self.enabled.push(name.to_string());
+    }
+
+    /// Whether a warning is reported, `default` being its state without flags.
+    /// `-Wall` enables the warnings that are off by default.
+    pub fn is_enabled(&self, name: &str, default: bool) -> bool {
+        if self.all_disabled || self.disabled.iter().any(|n| n == name) {
+            return false;
+        }
+
+        return default || self.enabled.iter().any(|n| n == name || n == "all");
+    }
+
+    /// Drops disabled warnings and promotes warnings turned into errors.
+    pub fn adjust(&self, mut diagnostic: Diagnostic) -> Option<Diagnostic> {
+        if diagnostic.severity != Severity::Warning {
+            return Some(diagnostic);
+        }
+
+        if !self.is_enabled(diagnostic.code, true) {
+            return None;
+        }
+
+        if self.all_errors || self.errors.iter().any(|n| n == diagnostic.code) {
+            diagnostic.severity = Severity::Error;
+            let note = format!("warning `{}` is treated as an error", diagnostic.code);
+            diagnostic.notes.push(note);
+        }
+
+        return Some(diagnostic);
+    }
+}
+
+/// Collects every diagnostic emitted while processing a file so they can be
+/// reported together instead of stopping at the first problem.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    diagnostics: Vec<Diagnostic>,
+    pub settings: WarningSettings,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn with_settings(settings: WarningSettings) -> Self {
+        return Self {
+            diagnostics: vec![],
+            settings,
+        };
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        if let Some(diagnostic) = self.settings.adjust(diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        return self.diagnostics.iter().any(|d| d.is_error());
+    }
+
+    pub fn error_count(&self) -> usize {
+        return self.diagnostics.iter().filter(|d| d.is_error()).count();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.diagnostics.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.diagnostics.is_empty();
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        return self.diagnostics.iter();
+    }
+}
+
+/// Source text of a single file together with the offsets of its lines, used
+/// to turn byte spans into line/column positions and snippets.
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, text: String) -> Self {
+        let mut line_starts = vec![0];
+        for (i, c) in text.char_indices() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+
+        return Self {
+            name: name.to_string(),
+            text,
+            line_starts,
+        };
+    }
+
+    /// Zero based line index of the given byte offset.
+    pub fn line_index(&self, offset: usize) -> usize {
+        return match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+    }
+
+    /// One based line and column (in characters) of the given byte offset.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len());
+        let line = self.line_index(offset);
+        let column = self.text[self.line_starts[line]..offset].chars().count();
+
+        return (line + 1, column + 1);
+    }
+
+    /// Text of the zero based line without its line break.
+    pub fn line_text(&self, line: usize) -> &str {
+        let start = self.line_starts[line];
+        let end = match self.line_starts.get(line + 1) {
+            Some(next) => next - 1,
+            None => self.text.len(),
+        };
+
+        return self.text[start..end].trim_end_matches('\r');
+    }
+
+    pub fn line_count(&self) -> usize {
+        return self.line_starts.len();
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+
+fn severity_color(severity: Severity) -> &'static str {
+    return match severity {
+        Severity::Error => "\x1b[1;31m",
+        Severity::Warning => "\x1b[1;33m",
+        Severity::Note => "\x1b[1;36m",
+    };
+}
+
+/// The source file a diagnostic belongs to: the one named by its `file`, or
+/// the first of `sources` (the file being checked).
+pub fn source_for<'a>(diagnostic: &Diagnostic, sources: &'a [SourceFile]) -> &'a SourceFile {
+    if let Some(file) = &diagnostic.file {
+        if let Some(source) = sources.iter().find(|source| &source.name == file) {
+            return source;
+        }
+    }
+
+    return &sources[0];
+}
+
+pub fn render(diagnostic: &Diagnostic, source: &SourceFile) -> String {
+    return render_with_color(diagnostic, source, false);
+}
+
+/// Renders the diagnostic like `render`, highlighting it with ANSI colors when
+/// `color` is set.
+pub fn render_with_color(diagnostic: &Diagnostic, source: &SourceFile, color: bool) -> String {
+    let paint = |code: &'static str| if color { code } else { "" };
+    let (reset, bold, blue) = (paint(RESET), paint(BOLD), paint(BLUE));
+    let severity = paint(severity_color(diagnostic.severity));
+
+    let mut out = String::new();
+    let (line, column) = source.line_col(diagnostic.primary.span.start);
+
+    let mut labels: Vec<(&Label, bool)> = vec![(&diagnostic.primary, true)];
+    for label in &diagnostic.secondary {
+        labels.push((label, false));
+    }
+    labels.sort_by_key(|(label, _)| label.span.start);
+
+    let max_line = labels.iter()
+        .map(|(label, _)| source.line_index(label.span.start) + 1)
+        .max()
+        .unwrap_or(line);
+    let gutter = max_line.to_string().len();
+    let pad = " ".repeat(gutter);
+
+    writeln!(out, "{}{}[{}]{}{}: {}{}", severity, diagnostic.severity.as_str(), diagnostic.code, reset, bold, diagnostic.message, reset).unwrap();
+    writeln!(out, "{}{}-->{} {}:{}:{}", pad, blue, reset, source.name, line, column).unwrap();
+    writeln!(out, "{}{} |{}", pad, blue, reset).unwrap();
+
+    let mut last_line = None;
+    for (label, is_primary) in labels {
+        let line_index = source.line_index(label.span.start.min(source.text.len()));
+        let text = source.line_text(line_index);
+
+        if last_line != Some(line_index) {
+            if let Some(previous) = last_line {
+                if line_index > previous + 1 {
+                    writeln!(out, "{}{} |{}", pad, blue, reset).unwrap();
+                }
+            }
+            writeln!(out, "{}{:>width$} |{} {}", blue, line_index + 1, reset, text, width = gutter).unwrap();
+            last_line = Some(line_index);
+        }
+
+        let marker_color = if is_primary { severity } else { blue };
+        writeln!(out, "{}{} |{} {}{}{}", pad, blue, reset, marker_color, underline(source, label, is_primary), reset).unwrap();
+    }
+
+    if !diagnostic.notes.is_empty() || diagnostic.help.is_some() {
+        writeln!(out, "{}{} |{}", pad, blue, reset).unwrap();
+    }
+    for note in &diagnostic.notes {
+        writeln!(out, "{}{} ={} {}note{}: {}", pad, blue, reset, bold, reset, note).unwrap();
+    }
+    if let Some(help) = &diagnostic.help {
+        writeln!(out, "{}{} ={} {}help{}: {}", pad, blue, reset, bold, reset, help).unwrap();
+    }
+
+    return out;
+}
+
+fn underline(source: &SourceFile, label: &Label, is_primary: bool) -> String {
+    let line_index = source.line_index(label.span.start.min(source.text.len()));
+    let line_start = source.line_starts[line_index];
+    let text = source.line_text(line_index);
+
+    let start = label.span.start.saturating_sub(line_start).min(text.len());
+    let end = label.span.end.saturating_sub(line_start).clamp(start, text.len());
+
+    // keep tabs so the marker lines up with the echoed source line
+    let mut marker: String = text[..start].chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let width = text[start..end].chars().count().max(1);
+    let symbol = if is_primary { "^" } else { "-" };
+    marker += &symbol.repeat(width);
+
+    if !label.message.is_empty() {
+        marker += " ";
+        marker += &label.message;
+    }
+
+    return marker;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_and_column_of_offsets() {
+        let source = SourceFile::new("test.asm", "INCLUDE foo\n\tSECTION bar\n".to_string());
+
+        assert_eq!(source.line_col(0), (1, 1));
+        assert_eq!(source.line_col(8), (1, 9));
+        assert_eq!(source.line_col(13), (2, 2));
+        assert_eq!(source.line_text(1), "\tSECTION bar");
+    }
+
+    #[test]
+    fn rendering_snippet_with_carets() {
+        let source = SourceFile::new("test.asm", "INCLUDE foo.asm\n".to_string());
+        let diagnostic = Diagnostic::error("E0002", "missing `\"` after INCLUDE", Span { start: 8, end: 11 })
+            .with_label("expected a quoted path")
+            .with_help("write the path as a string, e.g. INCLUDE \"foo.asm\"");
+
+        let expected = concat!(
+            "error[E0002]: missing `\"` after INCLUDE\n",
+            " --> test.asm:1:9\n",
+            "  |\n",
+            "1 | INCLUDE foo.asm\n",
+            "  |         ^^^ expected a quoted path\n",
+            "  |\n",
+            "  = help: write the path as a string, e.g. INCLUDE \"foo.asm\"\n",
+        );
+
+        assert_eq!(render(&diagnostic, &source), expected);
+    }
+
+    #[test]
+    fn adjusting_warnings() {
+        let mut settings = WarningSettings::default();
+        settings.apply_flag("no-unused-label").unwrap();
+        settings.apply_flag("error=shadowed-label").unwrap();
+
+        let span = Span { start: 0, end: 1 };
+
+        assert!(settings.adjust(Diagnostic::warning("unused-label", "unused", span)).is_none());
+        assert!(settings.adjust(Diagnostic::warning("shadowed-label", "shadowed", span)).unwrap().is_error());
+        assert!(!settings.adjust(Diagnostic::warning("other", "other", span)).unwrap().is_error());
+        assert!(!settings.is_enabled("off-by-default", false));
+
+        settings.apply_flag("off-by-default").unwrap();
+        assert!(settings.is_enabled("off-by-default", true));
+
+        settings.apply_flag("all").unwrap();
+        assert!(settings.is_enabled("prefer-jr", false));
+        assert!(!settings.is_enabled("unused-label", true));
+    }
+}
@@ -0,0 +1,352 @@
+//! Dictionary compression of text. The strings `db` encodes through one
+//! charmap are searched for the sequences of characters they repeat, the
+//! most profitable become the entries of a table, and each occurrence is
+//! replaced by the one byte code of its entry.
+//!
+//! Entries are chosen one at a time, the one saving the most bytes first,
+//! and replace their occurrences from left to right before the candidates
+//! are counted again for the next one. An entry may hold the codes of the
+//! entries chosen before it, so a repeat made of shorter ones is still
+//! found. Compressing a string replays the same replacements, so the
+//! strings come out exactly as the sizes were computed.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Label of the table, at its first byte.
+pub const TABLE_LABEL: &str = "TextDictionary";
+/// Name of the ROM0 section of the table.
+pub const TABLE_SECTION: &str = "Text Dictionary";
+/// Bytes the table takes without `--dictionary-size`
+pub const DEFAULT_TABLE_SIZE: usize = 256;
+/// Characters of the longest entry
+const MAX_ENTRY_UNITS: usize = 8;
+// the table starts with its first code and the number of entries
+const TABLE_HEADER_SIZE: usize = 2;
+
+/// What to compress, set by `asm --compress-text`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompressionOptions {
+    /// Charmap of the strings to compress
+    pub charmap: String,
+    /// Bytes the table may take, header included
+    pub table_size: usize,
+    /// Codes of the entries, by default the longest run of values the
+    /// strings and the charmap do not use
+    pub codes: Option<RangeInclusive<u8>>,
+}
+
+/// A unit of a string, a key of the charmap or a character it does not
+/// map, with its bytes.
+pub type Unit = (String, Vec<u8>);
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub code: u8,
+    /// Bytes of the entry in the table, with the codes of the earlier
+    /// entries it holds
+    pub bytes: Vec<u8>,
+    /// The characters the entry stands for, its entries expanded
+    pub text: String,
+    /// Occurrences replaced in the strings
+    pub uses: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    pub charmap: String,
+    pub entries: Vec<Entry>,
+    /// The units and codes of each entry
+    sequences: Vec<Vec<Token>>,
+    /// The number of units each entry stands for, its entries expanded
+    lengths: Vec<usize>,
+    unit_ids: HashMap<Vec<u8>, usize>,
+    /// Bytes of the strings before and after compression, table excluded
+    pub original_size: usize,
+    pub compressed_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+enum Token {
+    Unit(usize),
+    Code(u8),
+}
+
+impl Dictionary {
+    /// Chooses the entries for `strings`, the values in `used` being taken
+    /// by the strings and the charmap.
+    pub fn build(strings: &[Vec<Unit>], used: &[bool; 256], options: &CompressionOptions) -> Result<Dictionary, String> {
+        let codes = match &options.codes {
+            Some(codes) => {
+                if let Some(code) = codes.clone().find(|code| used[*code as usize]) {
+                    return Err(format!("code ${:02X} of the dictionary is used by the strings of charmap `{}`", code, options.charmap));
+                }
+                codes.clone()
+            }
+            None => free_codes(used).ok_or_else(|| format!("the strings of charmap `{}` leave no value free for the dictionary", options.charmap))?,
+        };
+        if options.table_size < TABLE_HEADER_SIZE {
+            return Err(format!("a table of {} bytes cannot hold its first code and number of entries", options.table_size));
+        }
+
+        let mut dictionary = Dictionary {
+            charmap: options.charmap.clone(),
+            entries: vec![],
+            sequences: vec![],
+            lengths: vec![],
+            unit_ids: HashMap::new(),
+            original_size: strings.iter().flatten().map(|unit| unit.1.len()).sum(),
+            compressed_size: 0,
+        };
+        let mut texts = vec![];
+        let mut unit_bytes: Vec<Vec<u8>> = vec![];
+        // the characters of each unit, as first written
+        let mut unit_texts: Vec<&str> = vec![];
+        for string in strings {
+            let mut tokens = vec![];
+            for (text, bytes) in string {
+                let id = *dictionary.unit_ids.entry(bytes.clone()).or_insert_with(|| {
+                    unit_bytes.push(bytes.clone());
+                    unit_texts.push(text);
+                    unit_bytes.len() - 1
+                });
+                tokens.push(Token::Unit(id));
+            }
+            texts.push(tokens);
+        }
+
+        let mut room = options.table_size - TABLE_HEADER_SIZE;
+        for code in codes {
+            let sequence = match best_sequence(&texts, &unit_bytes, room) {
+                Some(sequence) => sequence,
+                None => break,
+            };
+
+            let mut bytes = vec![];
+            let mut text = String::new();
+            let mut length = 0;
+            for token in &sequence {
+                match *token {
+                    Token::Unit(unit) => {
+                        bytes.extend_from_slice(&unit_bytes[unit]);
+                        text += unit_texts[unit];
+                        length += 1;
+                    }
+                    Token::Code(code) => {
+                        let index = dictionary.index(code);
+                        bytes.push(code);
+                        text += &dictionary.entries[index].text;
+                        length += dictionary.lengths[index];
+                    }
+                }
+            }
+            let uses = texts.iter_mut().map(|tokens| replace(tokens, &sequence, code)).sum();
+
+            room -= bytes.len() + 1;
+            dictionary.entries.push(Entry { code, bytes, text, uses });
+            dictionary.sequences.push(sequence);
+            dictionary.lengths.push(length);
+        }
+
+        dictionary.compressed_size = texts.iter().map(|tokens| {
+            tokens.iter().map(|token| match token {
+                Token::Unit(unit) => unit_bytes[*unit].len(),
+                Token::Code(_) => 1,
+            }).sum::<usize>()
+        }).sum();
+
+        return Ok(dictionary);
+    }
+
+    /// The table: the code of the first entry and the number of entries,
+    /// then each entry as its length followed by its bytes.
+    pub fn table(&self) -> Vec<u8> {
+        let first = self.entries.first().map_or(0, |entry| entry.code);
+        let mut table = vec![first, self.entries.len() as u8];
+        for entry in &self.entries {
+            table.push(entry.bytes.len() as u8);
+            table.extend_from_slice(&entry.bytes);
+        }
+
+        return table;
+    }
+
+    /// Bytes saved by the compression, the table included.
+    pub fn saved(&self) -> isize {
+        return self.original_size as isize - self.compressed_size as isize - self.table().len() as isize;
+    }
+
+    /// The bytes of a string with the entries in place of their
+    /// sequences.
+    pub fn compress(&self, string: &[Unit]) -> Vec<u8> {
+        // units that no entry uses cannot be replaced
+        let mut tokens: Vec<Token> = string.iter()
+            .map(|(_, bytes)| Token::Unit(self.unit_ids.get(bytes).copied().unwrap_or(usize::MAX)))
+            .collect();
+        for (entry, sequence) in self.entries.iter().zip(&self.sequences) {
+            replace(&mut tokens, sequence, entry.code);
+        }
+
+        let mut bytes = vec![];
+        let mut units = string.iter();
+        for token in tokens {
+            match token {
+                Token::Unit(_) => bytes.extend_from_slice(&units.next().unwrap().1),
+                Token::Code(code) => {
+                    units.nth(self.lengths[self.index(code)] - 1);
+                    bytes.push(code);
+                }
+            }
+        }
+
+        return bytes;
+    }
+
+    // the codes of the entries are consecutive
+    fn index(&self, code: u8) -> usize {
+        return (code - self.entries[0].code) as usize;
+    }
+}
+
+/// The longest run of values that are not used, the highest of the
+/// longest ones.
+fn free_codes(used: &[bool; 256]) -> Option<RangeInclusive<u8>> {
+    let mut best: Option<RangeInclusive<u8>> = None;
+    let mut run: Option<RangeInclusive<u8>> = None;
+
+    for value in (0..=u8::MAX).rev() {
+        if used[value as usize] {
+            run = None;
+            continue;
+        }
+
+        let current = value..=run.map_or(value, |run| *run.end());
+        if best.as_ref().is_none_or(|best| current.len() > best.len()) {
+            best = Some(current.clone());
+        }
+        run = Some(current);
+    }
+
+    return best;
+}
+
+/// The sequence of units and codes saving the most bytes as an entry that
+/// takes no more than `room` bytes of the table.
+fn best_sequence(texts: &[Vec<Token>], unit_bytes: &[Vec<u8>], room: usize) -> Option<Vec<Token>> {
+    // occurrences that do not overlap, with the string and the end of the last one
+    let mut counts: HashMap<&[Token], (usize, usize, usize)> = HashMap::new();
+
+    for (index, tokens) in texts.iter().enumerate() {
+        for start in 0..tokens.len() {
+            let mut size = 0;
+            for end in start..tokens.len().min(start + MAX_ENTRY_UNITS) {
+                size += match tokens[end] {
+                    Token::Unit(unit) => unit_bytes[unit].len(),
+                    Token::Code(_) => 1,
+                };
+                if size + 1 > room || size > u8::MAX as usize {
+                    break;
+                }
+
+                let count = counts.entry(&tokens[start..=end]).or_insert((0, usize::MAX, 0));
+                if count.1 != index || start >= count.2 {
+                    *count = (count.0 + 1, index, end + 1);
+                }
+            }
+        }
+    }
+
+    let mut best: Option<(&[Token], usize)> = None;
+    for (sequence, (count, _, _)) in counts {
+        let size: usize = sequence.iter().map(|token| match token {
+            Token::Unit(unit) => unit_bytes[*unit].len(),
+            Token::Code(_) => 1,
+        }).sum();
+        // each occurrence becomes a byte, the entry takes its bytes and its length
+        let saving = (count * (size - 1)).saturating_sub(size + 1);
+        let better = match best {
+            Some((best, best_saving)) => (saving, std::cmp::Reverse(sequence)) > (best_saving, std::cmp::Reverse(best)),
+            None => saving > 0,
+        };
+        if better {
+            best = Some((sequence, saving));
+        }
+    }
+
+    return best.map(|(sequence, _)| sequence.to_vec());
+}
+
+/// Start of the first occurrence of `sequence` in `tokens` from `from`.
+fn find(tokens: &[Token], sequence: &[Token], from: usize) -> Option<usize> {
+    return (from..(tokens.len() + 1).saturating_sub(sequence.len())).find(|&start| tokens[start..].starts_with(sequence));
+}
+
+/// Replaces the occurrences of `sequence` from left to right, returning
+/// how many there were.
+fn replace(tokens: &mut Vec<Token>, sequence: &[Token], code: u8) -> usize {
+    let mut count = 0;
+    let mut start = 0;
+
+    while let Some(found) = find(tokens, sequence, start) {
+        tokens.splice(found..found + sequence.len(), [Token::Code(code)]);
+        count += 1;
+        start = found + 1;
+    }
+
+    return count;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units(text: &str) -> Vec<Unit> {
+        return text.chars().map(|c| (c.to_string(), c.to_string().into_bytes())).collect();
+    }
+
+    #[test]
+    fn compressing_strings() {
+        let strings: Vec<Vec<Unit>> = ["the cat", "the hat", "the mat"].iter().map(|text| units(text)).collect();
+        let mut used = [false; 256];
+        for byte in strings.iter().flatten().flat_map(|unit| unit.1.iter()) {
+            used[*byte as usize] = true;
+        }
+        let options = CompressionOptions {
+            charmap: "text".to_string(),
+            table_size: 16,
+            codes: None,
+        };
+
+        // the values above `t` are free, "at" would save as much as it takes
+        let dictionary = Dictionary::build(&strings, &used, &options).unwrap();
+        assert_eq!(dictionary.entries, vec![Entry { code: 0x75, bytes: b"the ".to_vec(), text: "the ".to_string(), uses: 3 }]);
+        assert_eq!(dictionary.table(), vec![0x75, 1, 4, b't', b'h', b'e', b' ']);
+        assert_eq!(dictionary.compress(&units("the cat")), vec![0x75, b'c', b'a', b't']);
+        assert_eq!(dictionary.compress(&units("to bathe the")), b"to ba\x75the".to_vec());
+        assert_eq!((dictionary.original_size, dictionary.compressed_size, dictionary.saved()), (21, 12, 2));
+
+        let options = CompressionOptions { codes: Some(0x60..=0x6F), ..options };
+        assert_eq!(Dictionary::build(&strings, &used, &options).unwrap_err(), "code $61 of the dictionary is used by the strings of charmap `text`");
+    }
+
+    #[test]
+    fn nesting_entries() {
+        let strings: Vec<Vec<Unit>> = ["the cat, the cat", "the cat, the cat", "the hat", "the mat", "the rat"].iter().map(|text| units(text)).collect();
+        let mut used = [false; 256];
+        for byte in strings.iter().flatten().flat_map(|unit| unit.1.iter()) {
+            used[*byte as usize] = true;
+        }
+        let options = |table_size| CompressionOptions { charmap: "text".to_string(), table_size, codes: Some(0x80..=0x8F) };
+
+        // entries hold the codes of those before them, and more room saves more
+        let small = Dictionary::build(&strings, &used, &options(6)).unwrap();
+        let large = Dictionary::build(&strings, &used, &options(64)).unwrap();
+        assert_eq!(small.entries.iter().map(|entry| entry.text.as_str()).collect::<Vec<_>>(), ["the"]);
+        assert_eq!(large.entries.iter().map(|entry| entry.text.as_str()).collect::<Vec<_>>(), ["the ", "the cat", "the cat, the cat"]);
+        assert_eq!(large.table(), b"\x80\x03\x04the \x04\x80cat\x04\x81, \x81".to_vec());
+        assert_eq!((small.saved(), large.saved()), (8, 22));
+
+        assert_eq!(large.compress(&units("the cat, the cat")), vec![0x82]);
+        assert_eq!(large.compress(&units("the cat, the hat")), b"\x81, \x80hat".to_vec());
+    }
+}
@@ -0,0 +1,462 @@
+//! Turning a ROM back into source that assembles to the same bytes.
+//!
+//! Code is found by following jumps, calls and `rst`s from the entry point at
+//! $0100 and the interrupt vectors, unless a vector holds $00 or $FF like
+//! unused space usually does; every other byte is data. Jumps into
+//! $4000-$7FFF are followed from a switchable bank into the same bank, and
+//! from bank 0 only when the ROM has a single switchable bank, since which
+//! bank is mapped at that point is not known.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::ast::Operand;
+use crate::expr::Expression;
+use crate::format;
+use crate::link::BANK_SIZE;
+use crate::sm83::{self, Decoded};
+
+// the cartridge header, between the entry point and $0150
+const HEADER: std::ops::Range<usize> = 0x104..0x150;
+
+const VECTORS: [(usize, &str); 6] = [
+    (0x100, "Entry"),
+    (0x40, "VBlankInterrupt"),
+    (0x48, "StatInterrupt"),
+    (0x50, "TimerInterrupt"),
+    (0x58, "SerialInterrupt"),
+    (0x60, "JoypadInterrupt"),
+];
+
+// bytes of a `db` line, and the shortest run of one byte written as `ds`
+const BYTES_PER_LINE: usize = 16;
+const MIN_FILL: usize = 8;
+
+/// Name given to an address by a `.sym` file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SymbolName {
+    pub bank: usize,
+    pub address: usize,
+    pub name: String,
+}
+
+/// Reads the `BB:AAAA Name` lines of a `.sym` file, as written by RGBLINK
+/// and most emulators. Comments after `;` and other lines are skipped.
+pub fn parse_symbol_file(text: &str) -> Vec<SymbolName> {
+    let mut names = vec![];
+
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        let (location, name) = match line.split_once(char::is_whitespace) {
+            Some((location, name)) => (location, name.trim()),
+            None => continue,
+        };
+        let (bank, address) = match location.split_once(':') {
+            Some(location) => location,
+            None => continue,
+        };
+
+        if let (Ok(bank), Ok(address)) = (usize::from_str_radix(bank, 16), usize::from_str_radix(address, 16)) {
+            names.push(SymbolName {
+                bank,
+                address,
+                name: name.to_string(),
+            });
+        }
+    }
+
+    return names;
+}
+
+/// Source of a ROM, one fixed SECTION per bank, using `names` for the
+/// labels and constants. Fails when the size of the ROM is not one a linked
+/// ROM can have.
+pub fn disassemble(rom: &[u8], names: &[SymbolName]) -> Result<String, String> {
+    if rom.len() < 2 * BANK_SIZE || !rom.len().is_power_of_two() {
+        return Err(format!("a ROM is a power of two of at least 32 KiB, this one is {} bytes long", rom.len()));
+    }
+
+    let mut disassembler = Disassembler {
+        rom,
+        code: BTreeMap::new(),
+        covered: vec![false; rom.len()],
+        labels: BTreeMap::new(),
+        constants: BTreeMap::new(),
+    };
+    disassembler.name(names);
+    disassembler.trace();
+
+    return Ok(disassembler.source());
+}
+
+struct Disassembler<'a> {
+    rom: &'a [u8],
+    /// Instruction starting at each ROM offset
+    code: BTreeMap<usize, Decoded>,
+    /// ROM offsets that are part of an instruction
+    covered: Vec<bool>,
+    /// Label at each ROM offset
+    labels: BTreeMap<usize, String>,
+    /// Names of the addresses outside of the ROM
+    constants: BTreeMap<usize, String>,
+}
+
+impl Disassembler<'_> {
+    fn name(&mut self, names: &[SymbolName]) {
+        let mut used = HashSet::new();
+
+        for name in names {
+            if !is_valid_name(&name.name) || !used.insert(name.name.clone()) {
+                continue;
+            }
+
+            if name.address >= 0x8000 {
+                self.constants.entry(name.address).or_insert(name.name.clone());
+            } else if let Some(offset) = self.offset(name.bank, name.address) {
+                self.labels.entry(offset).or_insert(name.name.clone());
+            }
+        }
+    }
+
+    // ROM offset of an address in a bank
+    fn offset(&self, bank: usize, address: usize) -> Option<usize> {
+        let offset = match address {
+            0x0000..=0x3FFF if bank == 0 => address,
+            0x4000..=0x7FFF if bank > 0 => bank * BANK_SIZE + address - 0x4000,
+            _ => return None,
+        };
+
+        return Some(offset).filter(|offset| *offset < self.rom.len());
+    }
+
+    // ROM offset an address refers to from code in `bank`, when it is known
+    fn target(&self, bank: usize, address: usize) -> Option<usize> {
+        return match address {
+            0x0000..=0x3FFF => self.offset(0, address),
+            0x4000..=0x7FFF if bank > 0 => self.offset(bank, address),
+            0x4000..=0x7FFF if self.rom.len() == 2 * BANK_SIZE => self.offset(1, address),
+            _ => None,
+        };
+    }
+
+    fn trace(&mut self) {
+        let mut pending = vec![];
+        for (address, name) in VECTORS.iter().rev() {
+            if *address == 0x100 || (self.rom[*address] != 0x00 && self.rom[*address] != 0xFF) {
+                self.labels.entry(*address).or_insert(name.to_string());
+                pending.push(*address);
+            }
+        }
+
+        while let Some(mut offset) = pending.pop() {
+            let bank = offset / BANK_SIZE;
+            let bank_end = (bank + 1) * BANK_SIZE;
+
+            loop {
+                if self.code.contains_key(&offset) || self.covered[offset] || (bank == 0 && HEADER.contains(&offset)) {
+                    break;
+                }
+
+                let instruction = match sm83::decode(&self.rom[offset..bank_end]) {
+                    Some(instruction) => instruction,
+                    None => break,
+                };
+                let end = offset + instruction.size;
+                if (offset + 1..end).any(|inside| self.code.contains_key(&inside) || (bank == 0 && HEADER.contains(&inside))) {
+                    break;
+                }
+
+                let address = address_of(offset);
+                let (target, kind, ends) = match (instruction.mnemonic, instruction.operands.as_slice()) {
+                    ("jp", [Operand::Register(..)]) | ("ret" | "reti", []) => (None, "", true),
+                    ("jr", operands) => (number(operands.last()).map(|n| (address + instruction.size) as i32 + n), "Jump", operands.len() == 1),
+                    ("jp", operands) => (number(operands.last()), "Jump", operands.len() == 1),
+                    ("call", operands) => (number(operands.last()), "Call", false),
+                    ("rst", operands) => (number(operands.last()), "RST", false),
+                    _ => (None, "", false),
+                };
+
+                for inside in offset + 1..end {
+                    self.covered[inside] = true;
+                }
+                self.code.insert(offset, instruction);
+
+                if let Some(target) = target.and_then(|target| self.target(bank, target as usize)) {
+                    let name = match kind {
+                        "RST" => format!("RST_{:02X}", target),
+                        _ => format!("{}_{:02X}_{:04X}", kind, target / BANK_SIZE, address_of(target)),
+                    };
+                    self.labels.entry(target).or_insert(name);
+                    pending.push(target);
+                }
+
+                if ends {
+                    break;
+                }
+                offset = end;
+            }
+        }
+
+        // labels that would fall inside an instruction cannot be written
+        let covered = &self.covered;
+        self.labels.retain(|offset, _| !covered[*offset]);
+    }
+
+    fn source(&self) -> String {
+        let mut output = String::new();
+
+        for (address, name) in &self.constants {
+            output += &format!("DEF {} EQU ${:04X}\n", name, address);
+        }
+
+        for bank in 0..self.rom.len() / BANK_SIZE {
+            if !output.is_empty() {
+                output.push('\n');
+            }
+            if bank == 0 {
+                output += "SECTION \"ROM Bank $000\", ROM0[$0000]\n";
+            } else {
+                output += &format!("SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]\n", bank, bank);
+            }
+
+            let end = (bank + 1) * BANK_SIZE;
+            let mut offset = bank * BANK_SIZE;
+            while offset < end {
+                if let Some(label) = self.labels.get(&offset) {
+                    output += &format!("\n{}:\n", label);
+                }
+
+                if let Some(instruction) = self.code.get(&offset) {
+                    output += &format!("\t{}\n", self.instruction_text(offset, instruction));
+                    offset += instruction.size;
+                    continue;
+                }
+
+                // data up to the next instruction or label
+                let data_end = (offset + 1..end)
+                    .find(|next| self.code.contains_key(next) || self.labels.contains_key(next))
+                    .unwrap_or(end);
+                output += &data_text(&self.rom[offset..data_end]);
+                offset = data_end;
+            }
+        }
+
+        return output;
+    }
+
+    fn instruction_text(&self, offset: usize, instruction: &Decoded) -> String {
+        let bank = offset / BANK_SIZE;
+        let address = address_of(offset);
+
+        let operands: Vec<String> = instruction.operands.iter().map(|operand| match operand {
+            Operand::Register(name, _) => name.clone(),
+            Operand::IndirectRegister(name, _) => match name.as_str() {
+                "hli" => "[hl+]".to_string(),
+                "hld" => "[hl-]".to_string(),
+                _ => format!("[{}]", name),
+            },
+            Operand::Indirect(value, _) => format!("[{}]", self.address_text(bank, number_of(value), true)),
+            Operand::StackOffset(value, _) if number_of(value) < 0 => format!("sp - {}", -number_of(value)),
+            Operand::StackOffset(value, _) => format!("sp + {}", number_of(value)),
+            Operand::Immediate(value) => {
+                let value = number_of(value);
+                match instruction.mnemonic {
+                    "jr" => {
+                        let target = (address + instruction.size) as i32 + value;
+                        match self.target(bank, target as usize).and_then(|target| self.labels.get(&target)) {
+                            Some(label) => label.clone(),
+                            None => format!("@ {} {}", if value + 2 < 0 { "-" } else { "+" }, (value + 2).abs()),
+                        }
+                    }
+                    "jp" | "call" => self.address_text(bank, value, false),
+                    "rst" => format!("${:02X}", value),
+                    "bit" | "res" | "set" => value.to_string(),
+                    "add" => value.to_string(),
+                    _ if instruction.size == 3 => self.address_text(bank, value, true),
+                    _ => format!("${:02X}", value),
+                }
+            }
+        }).collect();
+
+        if operands.is_empty() {
+            return instruction.mnemonic.to_string();
+        }
+
+        return format!("{} {}", instruction.mnemonic, operands.join(", "));
+    }
+
+    // a 16 bit value as the label or constant at that address, the labels
+    // found by tracing the code only being used for jumps and calls
+    fn address_text(&self, bank: usize, value: i32, names_only: bool) -> String {
+        let address = value as usize;
+
+        let label = self.target(bank, address).and_then(|target| self.labels.get(&target));
+        let name = label.filter(|label| !names_only || !is_generated(label)).or(self.constants.get(&address));
+
+        return match name {
+            Some(name) => name.clone(),
+            None => format!("${:04X}", value),
+        };
+    }
+}
+
+fn address_of(offset: usize) -> usize {
+    return if offset < BANK_SIZE { offset } else { 0x4000 + offset % BANK_SIZE };
+}
+
+fn number(operand: Option<&Operand>) -> Option<i32> {
+    return match operand {
+        Some(Operand::Immediate(Expression::Number(value, _))) => Some(*value),
+        _ => None,
+    };
+}
+
+fn number_of(expression: &Expression) -> i32 {
+    return match expression {
+        Expression::Number(value, _) => *value,
+        _ => 0,
+    };
+}
+
+fn is_generated(label: &str) -> bool {
+    return ["Jump_", "Call_", "RST_"].iter().any(|prefix| label.starts_with(prefix))
+        || VECTORS.iter().any(|(_, name)| *name == label);
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let valid = name.split('.').all(|part| {
+        part.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '#' || c == '@')
+    });
+    let local = name.rsplit('.').next().unwrap_or("");
+    let reserved = sm83::is_mnemonic(local) || sm83::is_register(local)
+        || format::DIRECTIVES.contains(&local.to_lowercase().as_str());
+
+    return valid && !reserved;
+}
+
+// `db` lines, with long runs of one byte as `ds`
+fn data_text(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    let mut line: Vec<String> = vec![];
+
+    let flush = |line: &mut Vec<String>, output: &mut String| {
+        if !line.is_empty() {
+            *output += &format!("\tdb {}\n", line.join(", "));
+            line.clear();
+        }
+    };
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = bytes[i..].iter().take_while(|byte| **byte == bytes[i]).count();
+        if run >= MIN_FILL {
+            flush(&mut line, &mut output);
+            output += &format!("\tds {}, ${:02X}\n", run, bytes[i]);
+            i += run;
+            continue;
+        }
+
+        line.push(format!("${:02X}", bytes[i]));
+        if line.len() == BYTES_PER_LINE {
+            flush(&mut line, &mut output);
+        }
+        i += 1;
+    }
+    flush(&mut line, &mut output);
+
+    return output;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::diagnostic::{Diagnostics, SourceFile};
+    use crate::{lexer, link, parser};
+    use std::time::UNIX_EPOCH;
+
+    fn assemble(text: &str) -> Vec<u8> {
+        let source = SourceFile::new("main.asm", text.to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        let rom = link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        return rom;
+    }
+
+    #[test]
+    fn disassembling_roms() {
+        let rom = assemble(concat!(
+            "SECTION \"Entry\", ROM0[$100]\n",
+            "\tnop\n",
+            "\tjp Main\n",
+            "\tds $150 - @, $FF\n",
+            "Main:\n",
+            "\tld sp, $FFFE\n",
+            "\tcall Copy\n",
+            "\tldh [$FF40], a\n",
+            "\tld a, [wCount]\n",
+            "\tadd sp, -2\n",
+            "\tld hl, sp + 1\n",
+            "\tbit 3, [hl]\n",
+            ".idle: halt\n",
+            "\tjr .idle\n",
+            "Copy:\n",
+            "\tld a, [hl+]\n",
+            "\tjr nz, Copy\n",
+            "\tret\n",
+            "Data: db 1, 2, 3\n",
+            "\tds 10, $AB\n",
+            "SECTION \"VBlank\", ROM0[$40]\n",
+            "\treti\n",
+            "SECTION \"Bank\", ROMX[$4000], BANK[1]\n",
+            "\tdw Main\n",
+            "SECTION \"Work\", WRAM0[$C000]\n",
+            "wCount: ds 1\n",
+        ));
+
+        let names = parse_symbol_file(concat!(
+            "; generated\n",
+            "00:0150 Main\n",
+            "00:0161 Main.idle\n",
+            "00:0168 Data\n",
+            "00:C000 wCount\n",
+            "00:0151 Inside\n",
+            "00:0168 ld\n",
+        ));
+        assert_eq!(names[0], SymbolName { bank: 0, address: 0x150, name: "Main".to_string() });
+
+        let source = disassemble(&rom, &names).unwrap();
+        assert!(source.starts_with("DEF wCount EQU $C000\n\nSECTION \"ROM Bank $000\", ROM0[$0000]\n"));
+        assert!(source.contains("\nVBlankInterrupt:\n\treti\n"));
+        assert!(source.contains("\nEntry:\n\tnop\n\tjp Main\n\tds 76, $FF\n"));
+        assert!(source.contains(concat!(
+            "\nMain:\n",
+            "\tld sp, $FFFE\n",
+            "\tcall Call_00_0164\n",
+            "\tldh [$FF40], a\n",
+            "\tld a, [wCount]\n",
+            "\tadd sp, -2\n",
+            "\tld hl, sp + 1\n",
+            "\tbit 3, [hl]\n",
+            "\nMain.idle:\n",
+            "\thalt\n",
+            "\tjr Main.idle\n",
+            "\nCall_00_0164:\n",
+            "\tld a, [hl+]\n",
+            "\tjr nz, Call_00_0164\n",
+            "\tret\n",
+            "\nData:\n",
+            "\tdb $01, $02, $03\n",
+            "\tds 10, $AB\n",
+        )));
+        assert!(source.contains("SECTION \"ROM Bank $001\", ROMX[$4000], BANK[$1]\n\tdb $50, $01\n"));
+
+        assert_eq!(assemble(&source), rom);
+        assert!(disassemble(&rom[..0x4000], &[]).is_err());
+    }
+}
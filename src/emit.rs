@@ -0,0 +1,161 @@
+//! Machine readable representation of the lexer and parser output, of the
+//! layout chosen by the assembler and of the cycles of its routines.
+//!
+//! The layout is documented in `docs/json-output.md`. Fields are only ever
+//! added; removing or changing the meaning of one bumps `FORMAT_VERSION`.
+
+use crate::assembler::Placement;
+use crate::ast::{Ast, Statement};
+use crate::cycles::RoutineCycles;
+use crate::diagnostic::{self, Diagnostic, Diagnostics, Label, SourceFile};
+use crate::json::Value;
+use crate::lexer::{Span, Token};
+
+pub const FORMAT_VERSION: i64 = 1;
+
+pub fn document(source: &SourceFile, fields: Vec<(&str, Value)>) -> Value {
+    let mut all = vec![
+        ("version", Value::Number(FORMAT_VERSION)),
+        ("file", source.name.as_str().into()),
+    ];
+    all.extend(fields);
+
+    return Value::object(all);
+}
+
+pub fn position_to_json(offset: usize, source: &SourceFile) -> Value {
+    let (line, column) = source.line_col(offset);
+
+    return Value::object(vec![
+        ("offset", offset.into()),
+        ("line", line.into()),
+        ("column", column.into()),
+    ]);
+}
+
+pub fn span_to_json(span: Span, source: &SourceFile) -> Value {
+    return Value::object(vec![
+        ("start", position_to_json(span.start, source)),
+        ("end", position_to_json(span.end, source)),
+    ]);
+}
+
+pub fn tokens_to_json(tokens: &[Token], source: &SourceFile) -> Value {
+    return Value::Array(tokens.iter().map(|token| {
+        Value::object(vec![
+            ("kind", token.token_type.as_str().into()),
+            ("text", token.literal.as_str().into()),
+            ("span", span_to_json(token.span, source)),
+        ])
+    }).collect());
+}
+
+pub fn ast_to_json(ast: &Ast, source: &SourceFile) -> Value {
+    return statements_to_json(&ast.statements, source);
+}
+
+pub fn statements_to_json(statements: &[Box<dyn Statement>], source: &SourceFile) -> Value {
+    return Value::Array(statements.iter().map(|statement| {
+        let mut fields = vec![
+            ("kind", statement.my_type().as_str().into()),
+            ("span", span_to_json(statement.span(), source)),
+        ];
+        fields.extend(statement.json_fields(source));
+
+        Value::object(fields)
+    }).collect());
+}
+
+fn label_to_json(label: &Label, source: &SourceFile) -> Value {
+    return Value::object(vec![
+        ("span", span_to_json(label.span, source)),
+        ("message", label.message.as_str().into()),
+    ]);
+}
+
+pub fn diagnostic_to_json(diagnostic: &Diagnostic, source: &SourceFile) -> Value {
+    return Value::object(vec![
+        ("file", source.name.as_str().into()),
+        ("severity", diagnostic.severity.as_str().into()),
+        ("code", diagnostic.code.into()),
+        ("message", diagnostic.message.as_str().into()),
+        ("primary", label_to_json(&diagnostic.primary, source)),
+        ("secondary", Value::Array(diagnostic.secondary.iter().map(|l| label_to_json(l, source)).collect())),
+        ("notes", diagnostic.notes.clone().into()),
+        ("help", diagnostic.help.clone().into()),
+    ]);
+}
+
+/// `sources` starts with the file being checked, followed by the files it
+/// includes.
+pub fn diagnostics_to_json(diagnostics: &Diagnostics, sources: &[SourceFile]) -> Value {
+    return Value::Array(diagnostics.iter().map(|d| diagnostic_to_json(d, diagnostic::source_for(d, sources))).collect());
+}
+
+/// Where each instruction was placed, `sources` holding the files of the
+/// placements.
+pub fn listing_to_json(placements: &[Placement], sources: &[SourceFile]) -> Value {
+    return Value::Array(placements.iter().filter_map(|placement| {
+        let source = sources.iter().find(|source| source.name == placement.file)?;
+        let location = &placement.location;
+
+        Some(Value::object(vec![
+            ("file", placement.file.as_str().into()),
+            ("span", span_to_json(placement.span, source)),
+            ("section", location.section.as_str().into()),
+            ("bank", location.bank.map(i64::from).into()),
+            ("offset", Value::Number(location.offset as i64)),
+            ("address", location.address.map(i64::from).into()),
+            ("size", Value::Number(placement.size as i64)),
+            ("relaxed", placement.relaxed.into()),
+        ]))
+    }).collect());
+}
+
+pub fn cycles_to_json(routines: &[RoutineCycles], sources: &[SourceFile]) -> Value {
+    return Value::Array(routines.iter().map(|routine| {
+        let definition = routine.definition.as_ref()
+            .and_then(|definition| Some((definition, sources.iter().find(|source| source.name == definition.file)?)));
+
+        Value::object(vec![
+            ("name", routine.name.as_str().into()),
+            ("file", definition.map(|(definition, _)| definition.file.as_str()).into()),
+            ("span", definition.map_or(Value::Null, |(definition, source)| span_to_json(definition.span, source))),
+            ("min", Value::Number(routine.min as i64)),
+            ("max", routine.max.map(|max| max as i64).into()),
+            ("notes", Value::Array(routine.notes.iter().map(|note| note.as_str().into()).collect())),
+        ])
+    }).collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer, parser};
+
+    #[test]
+    fn ast_with_spans() {
+        let source = SourceFile::new("main.asm", "\nSETCHARMAP main ; comment\n".to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+
+        let json = ast_to_json(&ast, &source).to_string_compact();
+
+        assert_eq!(json, concat!(
+            r#"[{"kind":"set_char_map","#,
+            r#""span":{"start":{"offset":1,"line":2,"column":1},"end":{"offset":16,"line":2,"column":16}},"#,
+            r#""name":"main"}]"#,
+        ));
+    }
+
+    #[test]
+    fn char_map_fields_of_version_1() {
+        let source = SourceFile::new("main.asm", "CHARMAP \"<END>\", $50, 1\n".to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+
+        let json = ast_to_json(&ast, &source).to_string_compact();
+
+        assert!(json.ends_with(r#""value":"<END>","number":80,"key":"\"<END>\"","values":["$50","1"]}]"#), "{}", json);
+    }
+}
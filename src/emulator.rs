@@ -0,0 +1,1183 @@
+//! SM83 interpreter for running assembled routines without a full emulator.
+//!
+//! The CPU executes every instruction with its flags, and takes as many
+//! machine cycles as the encoder gives the instruction, the not taken count
+//! for conditional branches that fall through. Memory and the rest of the
+//! hardware are behind the `Bus` trait; `Memory` maps a ROM, one bank of
+//! each RAM and the PPU, without any other I/O besides the interrupt
+//! registers and an idle joypad.
+//!
+//! ```ignore
+//! let rom = link::link(&mut assembler, &mut diagnostics);
+//! let mut emulator = Emulator::new(rom);
+//! emulator.load_symbols(&assembler.symbols);
+//! emulator.cpu.registers.set("hl", 0xC000)?;
+//! let cycles = emulator.call("Multiply", 10_000)?;
+//! assert_eq!(emulator.cpu.registers.a, 12);
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::assembler::Section;
+use crate::image::Image;
+use crate::link::{self, BANK_SIZE};
+use crate::ppu::{self, Ppu};
+use crate::sm83;
+use crate::symbols::{SymbolTable, SymbolValue};
+
+pub const FLAG_Z: u8 = 0x80;
+pub const FLAG_N: u8 = 0x40;
+pub const FLAG_H: u8 = 0x20;
+pub const FLAG_C: u8 = 0x10;
+
+/// Interrupt enable and interrupt flag registers
+pub const IE: u16 = 0xFFFF;
+pub const IF: u16 = 0xFF0F;
+
+// pushed by `call`, the routine has returned once the CPU gets there with the
+// stack as it was before
+const RETURN_ADDRESS: u16 = 0xFFFF;
+
+/// The address space as seen by the CPU.
+pub trait Bus {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Advances the rest of the hardware by the cycles of an instruction.
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+/// A ROM with MBC5 style bank switching, one bank of VRAM, SRAM and each
+/// WRAM, and the PPU. Writes to $2000-$2FFF select the low 8 bits of the
+/// ROMX bank, writes to $3000-$3FFF its 9th bit; other writes to the ROM are
+/// ignored.
+pub struct Memory {
+    rom: Vec<u8>,
+    rom_bank: usize,
+    /// $8000-$FFFF
+    ram: Vec<u8>,
+    pub ppu: Ppu,
+}
+
+impl Memory {
+    /// Memory with `rom` mapped, padded to two banks.
+    pub fn new(mut rom: Vec<u8>) -> Self {
+        if rom.len() < 2 * BANK_SIZE {
+            rom.resize(2 * BANK_SIZE, 0);
+        }
+
+        return Self {
+            rom,
+            rom_bank: 1,
+            ram: vec![0; 0x8000],
+            ppu: Ppu::new(),
+        };
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        return &self.rom;
+    }
+
+    /// The bank mapped at $4000-$7FFF.
+    pub fn rom_bank(&self) -> usize {
+        return self.rom_bank;
+    }
+
+    /// Copies the bytes of a section placed by the linker to its address,
+    /// in its bank for ROMX.
+    pub fn load_section(&mut self, section: &Section) -> Result<(), String> {
+        let (bank, address) = match (section.bank, section.address) {
+            (Some(bank), Some(address)) => (bank as usize, address as usize),
+            _ => return Err(format!("Section `{}` is not placed", section.name)),
+        };
+
+        if link::is_rom(&section.section_type) {
+            let start = bank * BANK_SIZE + (address % BANK_SIZE);
+            let end = start + section.data.len();
+            if self.rom.len() < end {
+                self.rom.resize(end.next_power_of_two(), 0);
+            }
+
+            self.rom[start..end].copy_from_slice(&section.data);
+        } else {
+            for (i, byte) in section.data.iter().enumerate() {
+                self.write((address + i) as u16, *byte);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Writes a byte like the CPU does, except in the ROM, where it changes
+    /// the byte mapped at the address instead of switching banks.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.rom_bank * BANK_SIZE + address as usize - 0x4000,
+            _ => return self.write(address, value),
+        };
+
+        if let Some(byte) = self.rom.get_mut(offset) {
+            *byte = value;
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn read(&self, address: u16) -> u8 {
+        let address = address as usize;
+
+        return match address {
+            0x0000..=0x3FFF => self.rom[address],
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank * BANK_SIZE + address - 0x4000;
+                self.rom.get(offset).copied().unwrap_or(0xFF)
+            }
+            // echo of $C000-$DDFF
+            0xE000..=0xFDFF => self.ram[address - 0x2000 - 0x8000],
+            // no button is pressed
+            0xFF00 => 0xC0 | (self.ram[0x7F00] & 0x30) | 0x0F,
+            0xFF40..=0xFF4B => self.ppu.read(address as u16),
+            _ => self.ram[address - 0x8000],
+        };
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let address = address as usize;
+
+        match address {
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as usize,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as usize & 1) << 8),
+            0x0000..=0x7FFF => {}
+            0xE000..=0xFDFF => self.ram[address - 0x2000 - 0x8000] = value,
+            0xFF40..=0xFF4B => {
+                self.ppu.write(address as u16, value);
+
+                // OAM DMA, done at once
+                if address as u16 == ppu::DMA {
+                    let source = (value as u16) << 8;
+                    for i in 0..0xA0 {
+                        self.ram[0x7E00 + i] = self.read(source + i as u16);
+                    }
+                }
+            }
+            _ => self.ram[address - 0x8000] = value,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let interrupts = self.ppu.tick(cycles, &self.ram[..0x2000], &self.ram[0x7E00..0x7EA0]);
+        self.ram[IF as usize - 0x8000] |= interrupts;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    /// Z, N, H and C in the high 4 bits, the low 4 bits are always 0
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl Registers {
+    pub fn af(&self) -> u16 {
+        return u16::from_be_bytes([self.a, self.f]);
+    }
+
+    pub fn bc(&self) -> u16 {
+        return u16::from_be_bytes([self.b, self.c]);
+    }
+
+    pub fn de(&self) -> u16 {
+        return u16::from_be_bytes([self.d, self.e]);
+    }
+
+    pub fn hl(&self) -> u16 {
+        return u16::from_be_bytes([self.h, self.l]);
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        [self.a, self.f] = value.to_be_bytes();
+        self.f &= 0xF0;
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        [self.b, self.c] = value.to_be_bytes();
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        [self.d, self.e] = value.to_be_bytes();
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        [self.h, self.l] = value.to_be_bytes();
+    }
+
+    pub fn flag(&self, flag: u8) -> bool {
+        return self.f & flag != 0;
+    }
+
+    pub fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.f |= flag;
+        } else {
+            self.f &= !flag;
+        }
+    }
+
+    /// Value of a register or register pair by its name in source, like `a`
+    /// or `hl`.
+    pub fn get(&self, name: &str) -> Option<u16> {
+        return match name.to_lowercase().as_str() {
+            "a" => Some(self.a as u16),
+            "f" => Some(self.f as u16),
+            "b" => Some(self.b as u16),
+            "c" => Some(self.c as u16),
+            "d" => Some(self.d as u16),
+            "e" => Some(self.e as u16),
+            "h" => Some(self.h as u16),
+            "l" => Some(self.l as u16),
+            "af" => Some(self.af()),
+            "bc" => Some(self.bc()),
+            "de" => Some(self.de()),
+            "hl" => Some(self.hl()),
+            "sp" => Some(self.sp),
+            "pc" => Some(self.pc),
+            _ => None,
+        };
+    }
+
+    /// Sets a register or register pair by its name in source. The value must
+    /// fit in the register.
+    pub fn set(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let name = name.to_lowercase();
+        let byte = u8::try_from(value).ok();
+
+        match (name.as_str(), byte) {
+            ("a", Some(byte)) => self.a = byte,
+            ("f", Some(byte)) => self.f = byte & 0xF0,
+            ("b", Some(byte)) => self.b = byte,
+            ("c", Some(byte)) => self.c = byte,
+            ("d", Some(byte)) => self.d = byte,
+            ("e", Some(byte)) => self.e = byte,
+            ("h", Some(byte)) => self.h = byte,
+            ("l", Some(byte)) => self.l = byte,
+            ("a" | "f" | "b" | "c" | "d" | "e" | "h" | "l", None) => {
+                return Err(format!("${:X} does not fit in `{}`", value, name));
+            }
+            ("af", _) => self.set_af(value),
+            ("bc", _) => self.set_bc(value),
+            ("de", _) => self.set_de(value),
+            ("hl", _) => self.set_hl(value),
+            ("sp", _) => self.sp = value,
+            ("pc", _) => self.pc = value,
+            _ => return Err(format!("Unknown register `{}`", name)),
+        }
+
+        return Ok(());
+    }
+}
+
+pub struct Cpu {
+    pub registers: Registers,
+    /// Interrupt master enable
+    pub ime: bool,
+    /// `ei` enables interrupts after the next instruction
+    ime_pending: bool,
+    pub halted: bool,
+    /// Machine cycles since the CPU was created
+    pub cycles: u64,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Cpu {
+    /// A CPU in the state the DMG boot ROM leaves it in, at $0100.
+    pub fn new() -> Self {
+        let mut registers = Registers {
+            sp: 0xFFFE,
+            pc: 0x0100,
+            ..Registers::default()
+        };
+        registers.set_af(0x01B0);
+        registers.set_bc(0x0013);
+        registers.set_de(0x00D8);
+        registers.set_hl(0x014D);
+
+        return Self {
+            registers,
+            ime: false,
+            ime_pending: false,
+            halted: false,
+            cycles: 0,
+        };
+    }
+
+    /// Executes one instruction, or dispatches a pending interrupt, and
+    /// returns the machine cycles it took. A halted CPU idles for a cycle.
+    pub fn step(&mut self, bus: &mut impl Bus) -> Result<u32, String> {
+        let cycles = self.execute(bus)?;
+        self.cycles += cycles as u64;
+        bus.tick(cycles);
+
+        return Ok(cycles);
+    }
+
+    fn execute(&mut self, bus: &mut impl Bus) -> Result<u32, String> {
+        let pending = bus.read(IE) & bus.read(IF) & 0x1F;
+        if pending != 0 {
+            self.halted = false;
+
+            if self.ime {
+                let interrupt = pending.trailing_zeros() as u16;
+                bus.write(IF, bus.read(IF) & !(1 << interrupt));
+                self.ime = false;
+                self.push(bus, self.registers.pc);
+                self.registers.pc = 0x40 + 8 * interrupt;
+                return Ok(5);
+            }
+        }
+
+        if self.halted {
+            return Ok(1);
+        }
+
+        let enable_interrupts = self.ime_pending;
+        let address = self.registers.pc;
+        let opcode = self.fetch(bus);
+        let taken = match opcode {
+            0xCB => {
+                let opcode = self.fetch(bus);
+                self.execute_prefixed(bus, opcode);
+                true
+            }
+            _ => self.execute_opcode(bus, opcode).ok_or_else(|| {
+                format!("Illegal opcode ${:02X} at ${:04X}", opcode, address)
+            })?,
+        };
+
+        if enable_interrupts {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+
+        let index = match opcode {
+            0xCB => 0x100 + bus.read(address.wrapping_add(1)) as usize,
+            _ => opcode as usize,
+        };
+        let (cycles, cycles_not_taken) = timings()[index];
+
+        return Ok(if taken { cycles } else { cycles_not_taken } as u32);
+    }
+
+    // whether a conditional branch was taken, `None` for an illegal opcode
+    fn execute_opcode(&mut self, bus: &mut impl Bus, opcode: u8) -> Option<bool> {
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+
+        match opcode {
+            0x00 => {}
+            0x01 | 0x11 | 0x21 | 0x31 => {
+                let value = self.fetch_word(bus);
+                self.set_r16(y >> 1, value);
+            }
+            0x02 => bus.write(self.registers.bc(), self.registers.a),
+            0x12 => bus.write(self.registers.de(), self.registers.a),
+            0x22 | 0x32 => {
+                let hl = self.registers.hl();
+                bus.write(hl, self.registers.a);
+                self.registers.set_hl(if opcode == 0x22 { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
+            }
+            0x0A => self.registers.a = bus.read(self.registers.bc()),
+            0x1A => self.registers.a = bus.read(self.registers.de()),
+            0x2A | 0x3A => {
+                let hl = self.registers.hl();
+                self.registers.a = bus.read(hl);
+                self.registers.set_hl(if opcode == 0x2A { hl.wrapping_add(1) } else { hl.wrapping_sub(1) });
+            }
+            0x03 | 0x13 | 0x23 | 0x33 => self.set_r16(y >> 1, self.r16(y >> 1).wrapping_add(1)),
+            0x0B | 0x1B | 0x2B | 0x3B => self.set_r16(y >> 1, self.r16(y >> 1).wrapping_sub(1)),
+            0x09 | 0x19 | 0x29 | 0x39 => {
+                let hl = self.registers.hl();
+                let value = self.r16(y >> 1);
+                let (result, carry) = hl.overflowing_add(value);
+                self.registers.set_flag(FLAG_N, false);
+                self.registers.set_flag(FLAG_H, (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF);
+                self.registers.set_flag(FLAG_C, carry);
+                self.registers.set_hl(result);
+            }
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                let value = self.r8(bus, y);
+                let result = value.wrapping_add(1);
+                self.registers.set_flag(FLAG_Z, result == 0);
+                self.registers.set_flag(FLAG_N, false);
+                self.registers.set_flag(FLAG_H, value & 0x0F == 0x0F);
+                self.set_r8(bus, y, result);
+            }
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                let value = self.r8(bus, y);
+                let result = value.wrapping_sub(1);
+                self.registers.set_flag(FLAG_Z, result == 0);
+                self.registers.set_flag(FLAG_N, true);
+                self.registers.set_flag(FLAG_H, value & 0x0F == 0);
+                self.set_r8(bus, y, result);
+            }
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+                let value = self.fetch(bus);
+                self.set_r8(bus, y, value);
+            }
+            // rlca, rrca, rla and rra are the prefixed rotations of `a`
+            // that always clear Z
+            0x07 | 0x0F | 0x17 | 0x1F => {
+                self.registers.a = self.rotate(y, self.registers.a);
+                self.registers.set_flag(FLAG_Z, false);
+            }
+            0x08 => {
+                let address = self.fetch_word(bus);
+                let [low, high] = self.registers.sp.to_le_bytes();
+                bus.write(address, low);
+                bus.write(address.wrapping_add(1), high);
+            }
+            0x10 => {
+                self.fetch(bus);
+                self.halted = true;
+            }
+            0x18 => {
+                let offset = self.fetch(bus) as i8;
+                self.registers.pc = self.registers.pc.wrapping_add_signed(offset as i16);
+            }
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                let offset = self.fetch(bus) as i8;
+                let taken = self.condition(y - 4);
+                if taken {
+                    self.registers.pc = self.registers.pc.wrapping_add_signed(offset as i16);
+                }
+                return Some(taken);
+            }
+            0x27 => self.daa(),
+            0x2F => {
+                self.registers.a = !self.registers.a;
+                self.registers.set_flag(FLAG_N, true);
+                self.registers.set_flag(FLAG_H, true);
+            }
+            0x37 | 0x3F => {
+                let carry = opcode == 0x37 || !self.registers.flag(FLAG_C);
+                self.registers.set_flag(FLAG_N, false);
+                self.registers.set_flag(FLAG_H, false);
+                self.registers.set_flag(FLAG_C, carry);
+            }
+            0x76 => self.halted = true,
+            0x40..=0x7F => {
+                let value = self.r8(bus, z);
+                self.set_r8(bus, y, value);
+            }
+            0x80..=0xBF => {
+                let value = self.r8(bus, z);
+                self.alu(y, value);
+            }
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                let taken = self.condition(y);
+                if taken {
+                    self.registers.pc = self.pop(bus);
+                }
+                return Some(taken);
+            }
+            0xC9 => self.registers.pc = self.pop(bus),
+            0xD9 => {
+                self.registers.pc = self.pop(bus);
+                self.ime = true;
+            }
+            0xC1 | 0xD1 | 0xE1 => {
+                let value = self.pop(bus);
+                self.set_r16(y >> 1, value);
+            }
+            0xF1 => {
+                let value = self.pop(bus);
+                self.registers.set_af(value);
+            }
+            0xC5 | 0xD5 | 0xE5 => self.push(bus, self.r16(y >> 1)),
+            0xF5 => self.push(bus, self.registers.af()),
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                let address = self.fetch_word(bus);
+                let taken = self.condition(y);
+                if taken {
+                    self.registers.pc = address;
+                }
+                return Some(taken);
+            }
+            0xC3 => self.registers.pc = self.fetch_word(bus),
+            0xE9 => self.registers.pc = self.registers.hl(),
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                let address = self.fetch_word(bus);
+                let taken = self.condition(y);
+                if taken {
+                    self.push(bus, self.registers.pc);
+                    self.registers.pc = address;
+                }
+                return Some(taken);
+            }
+            0xCD => {
+                let address = self.fetch_word(bus);
+                self.push(bus, self.registers.pc);
+                self.registers.pc = address;
+            }
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+                let value = self.fetch(bus);
+                self.alu(y, value);
+            }
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                self.push(bus, self.registers.pc);
+                self.registers.pc = y as u16 * 8;
+            }
+            0xE0 => {
+                let address = 0xFF00 | self.fetch(bus) as u16;
+                bus.write(address, self.registers.a);
+            }
+            0xF0 => {
+                let address = 0xFF00 | self.fetch(bus) as u16;
+                self.registers.a = bus.read(address);
+            }
+            0xE2 => bus.write(0xFF00 | self.registers.c as u16, self.registers.a),
+            0xF2 => self.registers.a = bus.read(0xFF00 | self.registers.c as u16),
+            0xE8 => {
+                let offset = self.fetch(bus);
+                self.registers.sp = self.add_sp(offset);
+            }
+            0xF8 => {
+                let offset = self.fetch(bus);
+                let value = self.add_sp(offset);
+                self.registers.set_hl(value);
+            }
+            0xEA => {
+                let address = self.fetch_word(bus);
+                bus.write(address, self.registers.a);
+            }
+            0xFA => {
+                let address = self.fetch_word(bus);
+                self.registers.a = bus.read(address);
+            }
+            0xF9 => self.registers.sp = self.registers.hl(),
+            0xF3 => {
+                self.ime = false;
+                self.ime_pending = false;
+            }
+            0xFB => self.ime_pending = true,
+            _ => return None,
+        }
+
+        return Some(true);
+    }
+
+    fn execute_prefixed(&mut self, bus: &mut impl Bus, opcode: u8) {
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+        let value = self.r8(bus, z);
+
+        match opcode >> 6 {
+            0 => {
+                let result = self.rotate(y, value);
+                self.set_r8(bus, z, result);
+            }
+            1 => {
+                self.registers.set_flag(FLAG_Z, value & (1 << y) == 0);
+                self.registers.set_flag(FLAG_N, false);
+                self.registers.set_flag(FLAG_H, true);
+            }
+            2 => self.set_r8(bus, z, value & !(1 << y)),
+            _ => self.set_r8(bus, z, value | (1 << y)),
+        }
+    }
+
+    fn fetch(&mut self, bus: &impl Bus) -> u8 {
+        let value = bus.read(self.registers.pc);
+        self.registers.pc = self.registers.pc.wrapping_add(1);
+        return value;
+    }
+
+    fn fetch_word(&mut self, bus: &impl Bus) -> u16 {
+        let low = self.fetch(bus);
+        let high = self.fetch(bus);
+        return u16::from_le_bytes([low, high]);
+    }
+
+    fn push(&mut self, bus: &mut impl Bus, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        bus.write(self.registers.sp, high);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        bus.write(self.registers.sp, low);
+    }
+
+    fn pop(&mut self, bus: &impl Bus) -> u16 {
+        let low = bus.read(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        let high = bus.read(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        return u16::from_le_bytes([low, high]);
+    }
+
+    // b, c, d, e, h, l, [hl], a by their 3 bit index
+    fn r8(&self, bus: &impl Bus, index: u8) -> u8 {
+        let registers = &self.registers;
+
+        return match index {
+            0 => registers.b,
+            1 => registers.c,
+            2 => registers.d,
+            3 => registers.e,
+            4 => registers.h,
+            5 => registers.l,
+            6 => bus.read(registers.hl()),
+            _ => registers.a,
+        };
+    }
+
+    fn set_r8(&mut self, bus: &mut impl Bus, index: u8, value: u8) {
+        let registers = &mut self.registers;
+
+        match index {
+            0 => registers.b = value,
+            1 => registers.c = value,
+            2 => registers.d = value,
+            3 => registers.e = value,
+            4 => registers.h = value,
+            5 => registers.l = value,
+            6 => bus.write(registers.hl(), value),
+            _ => registers.a = value,
+        }
+    }
+
+    // bc, de, hl, sp by their 2 bit index
+    fn r16(&self, index: u8) -> u16 {
+        return match index {
+            0 => self.registers.bc(),
+            1 => self.registers.de(),
+            2 => self.registers.hl(),
+            _ => self.registers.sp,
+        };
+    }
+
+    fn set_r16(&mut self, index: u8, value: u16) {
+        match index {
+            0 => self.registers.set_bc(value),
+            1 => self.registers.set_de(value),
+            2 => self.registers.set_hl(value),
+            _ => self.registers.sp = value,
+        }
+    }
+
+    // nz, z, nc, c by their 2 bit index
+    fn condition(&self, index: u8) -> bool {
+        return match index & 3 {
+            0 => !self.registers.flag(FLAG_Z),
+            1 => self.registers.flag(FLAG_Z),
+            2 => !self.registers.flag(FLAG_C),
+            _ => self.registers.flag(FLAG_C),
+        };
+    }
+
+    // add, adc, sub, sbc, and, xor, or, cp by their 3 bit index
+    fn alu(&mut self, operation: u8, value: u8) {
+        let a = self.registers.a;
+        let carry = self.registers.flag(FLAG_C) as u8;
+
+        let (result, half, full) = match operation {
+            0 | 1 => {
+                let carry = if operation == 1 { carry } else { 0 };
+                let result = a as u16 + value as u16 + carry as u16;
+                (result as u8, (a & 0x0F) + (value & 0x0F) + carry > 0x0F, result > 0xFF)
+            }
+            2 | 3 | 7 => {
+                let carry = if operation == 3 { carry } else { 0 };
+                let result = a as i16 - value as i16 - carry as i16;
+                (result as u8, ((a & 0x0F) as i8 - (value & 0x0F) as i8 - carry as i8) < 0, result < 0)
+            }
+            4 => (a & value, true, false),
+            5 => (a ^ value, false, false),
+            _ => (a | value, false, false),
+        };
+
+        self.registers.set_flag(FLAG_Z, result == 0);
+        self.registers.set_flag(FLAG_N, matches!(operation, 2 | 3 | 7));
+        self.registers.set_flag(FLAG_H, half);
+        self.registers.set_flag(FLAG_C, full);
+
+        if operation != 7 {
+            self.registers.a = result;
+        }
+    }
+
+    // rlc, rrc, rl, rr, sla, sra, swap, srl by their 3 bit index
+    fn rotate(&mut self, operation: u8, value: u8) -> u8 {
+        let carry = self.registers.flag(FLAG_C) as u8;
+
+        let (result, carry) = match operation {
+            0 => (value.rotate_left(1), value & 0x80 != 0),
+            1 => (value.rotate_right(1), value & 1 != 0),
+            2 => ((value << 1) | carry, value & 0x80 != 0),
+            3 => ((value >> 1) | (carry << 7), value & 1 != 0),
+            4 => (value << 1, value & 0x80 != 0),
+            5 => ((value >> 1) | (value & 0x80), value & 1 != 0),
+            6 => (value.rotate_left(4), false),
+            _ => (value >> 1, value & 1 != 0),
+        };
+
+        self.registers.set_flag(FLAG_Z, result == 0);
+        self.registers.set_flag(FLAG_N, false);
+        self.registers.set_flag(FLAG_H, false);
+        self.registers.set_flag(FLAG_C, carry);
+
+        return result;
+    }
+
+    // `add sp, e8` and `ld hl, sp + e8`, with the flags of an 8 bit addition
+    // to the low byte
+    fn add_sp(&mut self, offset: u8) -> u16 {
+        let sp = self.registers.sp;
+        let low = sp as u8;
+
+        self.registers.set_flag(FLAG_Z, false);
+        self.registers.set_flag(FLAG_N, false);
+        self.registers.set_flag(FLAG_H, (low & 0x0F) + (offset & 0x0F) > 0x0F);
+        self.registers.set_flag(FLAG_C, low as u16 + offset as u16 > 0xFF);
+
+        return sp.wrapping_add_signed(offset as i8 as i16);
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.registers.a;
+        let mut carry = self.registers.flag(FLAG_C);
+
+        if self.registers.flag(FLAG_N) {
+            if self.registers.flag(FLAG_H) {
+                a = a.wrapping_sub(0x06);
+            }
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.registers.flag(FLAG_H) || a & 0x0F > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        }
+
+        self.registers.a = a;
+        self.registers.set_flag(FLAG_Z, a == 0);
+        self.registers.set_flag(FLAG_H, false);
+        self.registers.set_flag(FLAG_C, carry);
+    }
+}
+
+// cycles and not taken cycles of each opcode, then of each $CB opcode, from
+// the encoder
+fn timings() -> &'static [(u8, u8)] {
+    static TIMINGS: OnceLock<Vec<(u8, u8)>> = OnceLock::new();
+
+    return TIMINGS.get_or_init(|| {
+        return (0..0x200usize)
+            .map(|index| {
+                let bytes = match index {
+                    0x000..=0x0FF => [index as u8, 0, 0],
+                    _ => [0xCB, index as u8, 0],
+                };
+                return sm83::decode(&bytes).map_or((0, 0), |decoded| (decoded.cycles, decoded.cycles_not_taken));
+            })
+            .collect();
+    });
+}
+
+/// A CPU and its bus, with the addresses of the labels of the program for
+/// calling routines by name.
+pub struct Emulator<B: Bus = Memory> {
+    pub cpu: Cpu,
+    pub bus: B,
+    labels: HashMap<String, (i32, u16)>,
+}
+
+impl Emulator<Memory> {
+    /// An emulator running `rom` from $0100.
+    pub fn new(rom: Vec<u8>) -> Self {
+        return Self::with_bus(Memory::new(rom));
+    }
+
+    /// Runs until the PPU gets to VBlank `frames` times, or for as long as
+    /// that takes while the LCD is off. Returns the cycles run.
+    pub fn run_frames(&mut self, frames: u32) -> Result<u64, String> {
+        let mut elapsed = 0;
+        for _ in 0..frames {
+            let (start, counted) = (elapsed, self.bus.ppu.frames);
+            while self.bus.ppu.frames == counted && elapsed - start < ppu::FRAME_CYCLES as u64 {
+                elapsed += self.step()? as u64;
+            }
+        }
+
+        return Ok(elapsed);
+    }
+
+    /// The screen as last drawn by the PPU.
+    pub fn screenshot(&self) -> Image {
+        return self.bus.ppu.image();
+    }
+
+    /// Runs `frames` frames and compares the screen with a PPM or PNG
+    /// image, for catching changes to what a program draws.
+    pub fn compare_frames(&mut self, frames: u32, reference: &Path) -> Result<(), String> {
+        let bytes = fs::read(reference).map_err(|error| format!("Cannot read `{}`: {}", reference.display(), error))?;
+        let expected = Image::decode(&bytes).map_err(|error| format!("Cannot read `{}`: {}", reference.display(), error))?;
+
+        self.run_frames(frames)?;
+        return self.screenshot().compare(&expected)
+            .map_err(|error| format!("The screen after {} frame(s) does not match `{}`: {}", frames, reference.display(), error));
+    }
+}
+
+impl<B: Bus> Emulator<B> {
+    pub fn with_bus(bus: B) -> Self {
+        return Self {
+            cpu: Cpu::new(),
+            bus,
+            labels: HashMap::new(),
+        };
+    }
+
+    /// Makes the labels of `symbols` that have an address callable, which
+    /// they all have after `link::link`.
+    pub fn load_symbols(&mut self, symbols: &SymbolTable) {
+        for symbol in symbols.iter() {
+            if let SymbolValue::Label(location) = &symbol.value {
+                if let (Some(bank), Some(address)) = (location.bank, location.address) {
+                    self.labels.insert(symbol.name.clone(), (bank, address as u16));
+                }
+            }
+        }
+    }
+
+    /// Bank and address of a label.
+    pub fn label(&self, name: &str) -> Option<(i32, u16)> {
+        return self.labels.get(name).copied();
+    }
+
+    /// Names, banks and addresses of the labels, in no particular order.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, i32, u16)> {
+        return self.labels.iter().map(|(name, (bank, address))| (name.as_str(), *bank, *address));
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return self.bus.read(address);
+    }
+
+    pub fn read_word(&self, address: u16) -> u16 {
+        return u16::from_le_bytes([self.bus.read(address), self.bus.read(address.wrapping_add(1))]);
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.bus.write(address, value);
+    }
+
+    pub fn step(&mut self) -> Result<u32, String> {
+        return self.cpu.step(&mut self.bus);
+    }
+
+    /// Runs for at least `cycles` machine cycles, returning the cycles run.
+    pub fn run(&mut self, cycles: u64) -> Result<u64, String> {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            elapsed += self.step()? as u64;
+        }
+
+        return Ok(elapsed);
+    }
+
+    /// Calls the routine at a label, switching to its bank when it is in
+    /// ROMX, and runs until it returns. Returns the cycles from its first
+    /// instruction to its `ret`, or an error when it does not return within
+    /// `cycle_limit` cycles.
+    pub fn call(&mut self, label: &str, cycle_limit: u64) -> Result<u64, String> {
+        let (bank, address) = match self.label(label) {
+            Some(location) => location,
+            None => return Err(format!("Label `{}` is not defined", label)),
+        };
+
+        if (0x4000..0x8000).contains(&address) {
+            self.bus.write(0x2000, bank as u8);
+            self.bus.write(0x3000, (bank >> 8) as u8);
+        }
+
+        return self.call_routine(address, &format!("`{}`", label), cycle_limit);
+    }
+
+    /// Calls the routine at an address and runs until it returns, like `call`.
+    pub fn call_address(&mut self, address: u16, cycle_limit: u64) -> Result<u64, String> {
+        return self.call_routine(address, &format!("The routine at ${:04X}", address), cycle_limit);
+    }
+
+    fn call_routine(&mut self, address: u16, routine: &str, cycle_limit: u64) -> Result<u64, String> {
+        let sp = self.cpu.registers.sp;
+        self.cpu.push(&mut self.bus, RETURN_ADDRESS);
+        self.cpu.registers.pc = address;
+        self.cpu.halted = false;
+
+        let mut elapsed = 0;
+        while elapsed < cycle_limit {
+            elapsed += self.step()? as u64;
+
+            if self.cpu.registers.pc == RETURN_ADDRESS && self.cpu.registers.sp == sp {
+                return Ok(elapsed);
+            }
+        }
+
+        return Err(format!("{} did not return within {} cycles", routine, cycle_limit));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::diagnostic::{Diagnostics, SourceFile};
+    use crate::{lexer, parser};
+    use std::time::UNIX_EPOCH;
+
+    fn emulator(text: &str) -> Emulator {
+        let source = SourceFile::new("main.asm", text.to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        let rom = link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        let mut emulator = Emulator::new(rom);
+        emulator.load_symbols(&assembler.symbols);
+        return emulator;
+    }
+
+    #[test]
+    fn calling_routines() {
+        let mut emulator = emulator(concat!(
+            "SECTION \"Math\", ROM0\n",
+            "; a = b * c\n",
+            "Multiply:\n",
+            "\txor a\n",
+            "\tinc c\n",
+            ".loop:\n",
+            "\tdec c\n",
+            "\tret z\n",
+            "\tadd b\n",
+            "\tjr .loop\n",
+            "Copy:\n",
+            "\tld a, [hl+]\n",
+            "\tld [de], a\n",
+            "\tinc de\n",
+            "\tdec b\n",
+            "\tjr nz, Copy\n",
+            "\tret\n",
+            "Forever:\n",
+            "\tjr Forever\n",
+            "SECTION \"Far\", ROMX, BANK[2]\n",
+            "Far:\n",
+            "\tld hl, sp + 0\n",
+            "\tpush hl\n",
+            "\tpop bc\n",
+            "\tld a, $12\n",
+            "\tswap a\n",
+            "\tld [wResult], a\n",
+            "\tret\n",
+            "SECTION \"Work\", WRAM0\n",
+            "wResult: ds 1\n",
+        ));
+
+        emulator.cpu.registers.b = 3;
+        emulator.cpu.registers.c = 4;
+        // xor, inc, then 4 times dec, ret z, add, jr, then dec and ret z
+        assert_eq!(emulator.call("Multiply", 1000), Ok(1 + 1 + 4 * (1 + 2 + 1 + 3) + 1 + 5));
+        assert_eq!(emulator.cpu.registers.a, 12);
+        assert!(!emulator.cpu.registers.flag(FLAG_C));
+
+        emulator.cpu.registers.set("hl", 0x0000).unwrap();
+        emulator.cpu.registers.set("de", 0xC100).unwrap();
+        emulator.cpu.registers.set("b", 4).unwrap();
+        assert!(emulator.call("Copy", 1000).is_ok());
+        assert_eq!((0..4).map(|i| emulator.read(0xC100 + i)).collect::<Vec<_>>(), emulator.bus.rom()[..4]);
+        assert_eq!(emulator.cpu.registers.de(), 0xC104);
+
+        let sp = emulator.cpu.registers.sp;
+        assert!(emulator.call("Far", 1000).is_ok());
+        assert_eq!(emulator.cpu.registers.bc(), sp - 2);
+        assert_eq!(emulator.read(emulator.label("wResult").unwrap().1), 0x21);
+        assert_eq!(emulator.cpu.registers.sp, sp);
+
+        assert_eq!(emulator.call("Forever", 100), Err("`Forever` did not return within 100 cycles".to_string()));
+        assert!(emulator.call("Missing", 100).is_err());
+        assert_eq!(emulator.cpu.registers.set("a", 0x100), Err("$100 does not fit in `a`".to_string()));
+    }
+
+    #[test]
+    fn loading_sections() {
+        let source = SourceFile::new("main.asm", "SECTION \"Far\", ROMX, BANK[3]\nFar: ld a, 7\n\tret\n".to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+
+        let mut memory = Memory::new(vec![]);
+        assert!(memory.load_section(&assembler.sections()[0]).is_err());
+
+        link::link(&mut assembler, &mut diagnostics);
+        memory.load_section(&assembler.sections()[0]).unwrap();
+        assert_eq!(memory.rom().len(), 4 * BANK_SIZE);
+
+        let mut emulator = Emulator::with_bus(memory);
+        emulator.load_symbols(&assembler.symbols);
+        assert_eq!(emulator.call("Far", 100), Ok(2 + 4));
+        assert_eq!(emulator.cpu.registers.a, 7);
+    }
+
+    #[test]
+    fn computing_flags() {
+        let mut emulator = emulator(concat!(
+            "SECTION \"Code\", ROM0\n",
+            "Add: add b\n\tret\n",
+            "Sub: sub b\n\tret\n",
+            "Bcd: add b\n\tdaa\n\tret\n",
+            "AddHl: add hl, bc\n\tret\n",
+            "Rotate: rla\n\tret\n",
+            "Bit: bit 7, a\n\tret\n",
+            "Illegal: db $D3\n",
+        ));
+
+        let mut run = |label: &str, a: u8, b: u8, f: u8| {
+            let registers = &mut emulator.cpu.registers;
+            (registers.a, registers.b, registers.f) = (a, b, f);
+            emulator.call(label, 100).unwrap();
+            return (emulator.cpu.registers.a, emulator.cpu.registers.f);
+        };
+
+        assert_eq!(run("Add", 0x0F, 0x01, 0), (0x10, FLAG_H));
+        assert_eq!(run("Add", 0xFF, 0x01, 0), (0x00, FLAG_Z | FLAG_H | FLAG_C));
+        assert_eq!(run("Sub", 0x10, 0x01, 0), (0x0F, FLAG_N | FLAG_H));
+        assert_eq!(run("Sub", 0x01, 0x02, 0), (0xFF, FLAG_N | FLAG_H | FLAG_C));
+        assert_eq!(run("Bcd", 0x19, 0x28, 0), (0x47, 0));
+        assert_eq!(run("Bcd", 0x99, 0x01, 0), (0x00, FLAG_Z | FLAG_C));
+        assert_eq!(run("Rotate", 0x80, 0, FLAG_C), (0x01, FLAG_C));
+        assert_eq!(run("Bit", 0x7F, 0, FLAG_C), (0x7F, FLAG_Z | FLAG_H | FLAG_C));
+
+        emulator.cpu.registers.set_hl(0x8FFF);
+        emulator.cpu.registers.set_bc(0x0001);
+        emulator.cpu.registers.f = FLAG_Z;
+        emulator.call("AddHl", 100).unwrap();
+        assert_eq!((emulator.cpu.registers.hl(), emulator.cpu.registers.f), (0x9000, FLAG_Z | FLAG_H));
+
+        let address = emulator.label("Illegal").unwrap().1;
+        assert_eq!(emulator.call("Illegal", 100), Err(format!("Illegal opcode $D3 at ${:04X}", address)));
+    }
+
+    #[test]
+    fn timing_instructions() {
+        let cycles = |bytes: &[u8]| timings()[if bytes[0] == 0xCB { 0x100 + bytes[1] as usize } else { bytes[0] as usize }];
+
+        assert_eq!(cycles(&[0x00]), (1, 1));
+        assert_eq!(cycles(&[0xCD]), (6, 6));
+        assert_eq!(cycles(&[0xC0]), (5, 2));
+        assert_eq!(cycles(&[0x20]), (3, 2));
+        assert_eq!(cycles(&[0xC2]), (4, 3));
+        assert_eq!(cycles(&[0xC4]), (6, 3));
+        assert_eq!(cycles(&[0x36]), (3, 3));
+        assert_eq!(cycles(&[0x08]), (5, 5));
+        assert_eq!(cycles(&[0xE8]), (4, 4));
+        assert_eq!(cycles(&[0xF8]), (3, 3));
+        assert_eq!(cycles(&[0xE9]), (1, 1));
+        assert_eq!(cycles(&[0xFF]), (4, 4));
+        assert_eq!(cycles(&[0xCB, 0x46]), (3, 3));
+        assert_eq!(cycles(&[0xCB, 0x06]), (4, 4));
+    }
+
+    #[test]
+    fn dispatching_interrupts() {
+        let mut emulator = emulator(concat!(
+            "SECTION \"VBlank\", ROM0[$40]\n",
+            "\tinc d\n",
+            "\treti\n",
+            "SECTION \"Code\", ROM0\n",
+            "Wait:\n",
+            "\tei\n",
+            "\thalt\n",
+            "\tret\n",
+        ));
+
+        emulator.cpu.registers.d = 0;
+        emulator.write(IE, 0x01);
+        emulator.write(IF, 0x01);
+        assert!(emulator.call("Wait", 100).is_ok());
+        assert_eq!(emulator.cpu.registers.d, 1);
+        assert_eq!(emulator.read(IF), 0);
+        assert!(emulator.cpu.ime);
+    }
+
+    #[test]
+    fn comparing_frames() {
+        let mut emulator = emulator(concat!(
+            "SECTION \"VBlank\", ROM0[$40]\n",
+            "\tinc b\n",
+            "\treti\n",
+            "SECTION \"Entry\", ROM0[$100]\n",
+            "\tjp Start\n",
+            "SECTION \"Main\", ROM0[$150]\n",
+            "Start:\n",
+            "\tld b, 0\n",
+            "\tld a, %11100100\n",
+            "\tld [$FF47], a\n",
+            "\tld hl, $8010\n",
+            "\tld c, 16\n",
+            "\tld a, $FF\n",
+            ".tile:\n",
+            "\tld [hl+], a\n",
+            "\tdec c\n",
+            "\tjr nz, .tile\n",
+            "\tld a, 1\n",
+            "\tld [$9800], a\n",
+            "\tld [$FFFF], a\n",
+            "\tei\n",
+            ".wait:\n",
+            "\thalt\n",
+            "\tjr .wait\n",
+        ));
+
+        // a black tile in the top left corner of a white screen
+        let mut expected = Image::new(ppu::WIDTH, ppu::HEIGHT);
+        for i in 0..expected.pixels.len() {
+            let black = i % ppu::WIDTH < 8 && i / ppu::WIDTH < 8;
+            expected.pixels[i] = ppu::SHADES[if black { 3 } else { 0 }];
+        }
+        let reference = std::env::temp_dir().join(format!("frame-{}.ppm", std::process::id()));
+        fs::write(&reference, crate::image::encode_ppm(&expected)).unwrap();
+
+        assert_eq!(emulator.compare_frames(2, &reference), Ok(()));
+        // the interrupt of the second VBlank is yet to be dispatched
+        assert_eq!(emulator.cpu.registers.b, 1);
+
+        emulator.write(0x8010, 0x7F);
+        let error = emulator.compare_frames(1, &reference).unwrap_err();
+        fs::remove_file(&reference).unwrap();
+        assert!(error.ends_with("1 pixel(s) differ, the first at (0, 0) is #555555 instead of #000000"), "{}", error);
+    }
+}
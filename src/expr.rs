@@ -0,0 +1,679 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{self, Span, Token, TokenType};
+use crate::symbols::{LabelLocation, SymbolTable, SymbolValue};
+
+const E_EXPECTED_EXPRESSION: &str = "E0010";
+const E_UNTERMINATED_STRING: &str = "E0003";
+const E_UNDEFINED_SYMBOL: &str = "E0011";
+const E_DIVISION_BY_ZERO: &str = "E0012";
+const E_TYPE_MISMATCH: &str = "E0013";
+const E_BAD_FUNCTION_CALL: &str = "E0014";
+const E_BAD_INTERPOLATION: &str = "E0015";
+const E_NOT_CONSTANT: &str = "E0019";
+
+// EQUS symbols may expand to other EQUS symbols, but not forever
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(i32, Span),
+    /// Raw string contents between the quotes, escapes and `{}` interpolations
+    /// are resolved when evaluating.
+    String(String, Span),
+    Symbol(String, Span),
+    Unary {
+        operator: String,
+        operand: Box<Expression>,
+        span: Span,
+    },
+    Binary {
+        operator: String,
+        left: Box<Expression>,
+        right: Box<Expression>,
+        span: Span,
+    },
+    Call {
+        name: String,
+        arguments: Vec<Expression>,
+        span: Span,
+    },
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        return match self {
+            Expression::Number(_, span) => *span,
+            Expression::String(_, span) => *span,
+            Expression::Symbol(_, span) => *span,
+            Expression::Unary { span, .. } => *span,
+            Expression::Binary { span, .. } => *span,
+            Expression::Call { span, .. } => *span,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionValue {
+    Number(i32),
+    String(String),
+}
+
+impl ExpressionValue {
+    pub fn type_name(&self) -> &'static str {
+        return match self {
+            ExpressionValue::Number(_) => "number",
+            ExpressionValue::String(_) => "string",
+        };
+    }
+}
+
+/// Binary operators from the loosest to the tightest binding level, following
+/// the RGBDS precedence rules.
+const BINARY_LEVELS: [&[&str]; 7] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<", ">", "<=", ">="],
+    &["+", "-"],
+    &["&", "|", "^"],
+    &["<<", ">>"],
+    &["*", "/", "%"],
+];
+
+struct ExpressionParser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+/// Parses an expression at the start of `tokens`, returning it together with
+/// the number of tokens it used. Parsing stops before the first token that
+/// cannot continue the expression, like a comma or the end of the line.
+pub fn parse_expression(tokens: &[Token]) -> Result<(Expression, usize), Box<Diagnostic>> {
+    let mut parser = ExpressionParser {
+        tokens,
+        position: 0,
+    };
+
+    let expression = parser.parse_binary(0)?;
+
+    return Ok((expression, parser.position));
+}
+
+impl ExpressionParser<'_> {
+    fn skip_spaces(&mut self) {
+        while let Some(tok) = self.tokens.get(self.position) {
+            if tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab {
+                break;
+            }
+
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.skip_spaces();
+
+        return self.tokens.get(self.position);
+    }
+
+    fn end_span(&self) -> Span {
+        return match self.tokens.get(self.position) {
+            Some(tok) => tok.span,
+            None => {
+                let end = self.tokens.last().map(|tok| tok.span.end).unwrap_or(0);
+                Span {
+                    start: end,
+                    end,
+                }
+            }
+        };
+    }
+
+    fn peek_operator(&mut self, operators: &[&str]) -> Option<String> {
+        let tok = self.peek()?;
+        let is_operator = tok.token_type == TokenType::Operator || tok.token_type == TokenType::Slash;
+
+        if is_operator && operators.contains(&tok.literal.as_str()) {
+            return Some(tok.literal.clone());
+        }
+
+        return None;
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expression, Box<Diagnostic>> {
+        if level == BINARY_LEVELS.len() {
+            return self.parse_power();
+        }
+
+        let mut left = self.parse_binary(level + 1)?;
+
+        while let Some(operator) = self.peek_operator(BINARY_LEVELS[level]) {
+            self.position += 1;
+            let right = self.parse_binary(level + 1)?;
+            let span = left.span().to(right.span());
+
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+                span,
+            };
+        }
+
+        return Ok(left);
+    }
+
+    fn parse_power(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let base = self.parse_unary()?;
+
+        if self.peek_operator(&["**"]).is_some() {
+            self.position += 1;
+            // right associative
+            let exponent = self.parse_power()?;
+            let span = base.span().to(exponent.span());
+
+            return Ok(Expression::Binary {
+                operator: "**".to_string(),
+                left: Box::new(base),
+                right: Box::new(exponent),
+                span,
+            });
+        }
+
+        return Ok(base);
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        if let Some(operator) = self.peek_operator(&["-", "+", "~", "!"]) {
+            let start = self.tokens[self.position].span;
+            self.position += 1;
+            let operand = self.parse_unary()?;
+            let span = start.to(operand.span());
+
+            return Ok(Expression::Unary {
+                operator,
+                operand: Box::new(operand),
+                span,
+            });
+        }
+
+        return self.parse_primary();
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let tok = match self.peek() {
+            Some(tok) => tok.clone(),
+            None => {
+                return Err(Box::new(Diagnostic::error(E_EXPECTED_EXPRESSION, "Expected an expression", self.end_span())
+                    .with_label("expression missing here")));
+            }
+        };
+
+        match tok.token_type {
+            TokenType::Number => {
+                self.position += 1;
+
+                return match lexer::number_value(&tok.literal) {
+                    Some(value) => Ok(Expression::Number(value as i32, tok.span)),
+                    None => Err(Box::new(Diagnostic::error(E_EXPECTED_EXPRESSION, "Invalid number", tok.span)
+                        .with_label("no digits after the prefix"))),
+                };
+            }
+            TokenType::DoubleQuote => {
+                return self.parse_string();
+            }
+            TokenType::LeftParen => {
+                self.position += 1;
+                let inner = self.parse_binary(0)?;
+
+                return match self.peek() {
+                    Some(close) if close.token_type == TokenType::RightParen => {
+                        self.position += 1;
+                        Ok(inner)
+                    }
+                    _ => Err(Box::new(Diagnostic::error(E_EXPECTED_EXPRESSION, "Missing `)`", self.end_span())
+                        .with_label("expected `)`")
+                        .with_secondary(tok.span, "to close this `(`"))),
+                };
+            }
+            TokenType::Dot | TokenType::Identifier => {
+                return self.parse_symbol_or_call();
+            }
+            TokenType::Unknown if tok.literal == "@" => {
+                self.position += 1;
+
+                return Ok(Expression::Symbol(tok.literal.clone(), tok.span));
+            }
+            _ => {}
+        }
+
+        let message = format!("Expected an expression, found `{}`", tok.literal.escape_debug());
+
+        return Err(Box::new(Diagnostic::error(E_EXPECTED_EXPRESSION, &message, tok.span)
+            .with_label("expected a number, string or symbol")));
+    }
+
+    fn parse_symbol_or_call(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let start = self.tokens[self.position].span;
+        let mut name = String::new();
+        let mut end = start;
+
+        // symbols like `Label`, `.local` or `Label.local`
+        while let Some(tok) = self.tokens.get(self.position) {
+            if tok.token_type != TokenType::Identifier && tok.token_type != TokenType::Dot {
+                break;
+            }
+
+            name += &tok.literal;
+            end = tok.span;
+            self.position += 1;
+        }
+
+        let span = start.to(end);
+
+        let is_call = self.tokens.get(self.position).is_some_and(|tok| tok.token_type == TokenType::LeftParen);
+        if !is_call {
+            return Ok(Expression::Symbol(name, span));
+        }
+
+        // skip (
+        self.position += 1;
+        let mut arguments = vec![];
+
+        if self.peek().is_some_and(|tok| tok.token_type == TokenType::RightParen) {
+            self.position += 1;
+        } else {
+            loop {
+                arguments.push(self.parse_binary(0)?);
+
+                match self.peek() {
+                    Some(tok) if tok.token_type == TokenType::Comma => self.position += 1,
+                    Some(tok) if tok.token_type == TokenType::RightParen => {
+                        self.position += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(Box::new(Diagnostic::error(E_EXPECTED_EXPRESSION, "Missing `)` after arguments", self.end_span())
+                            .with_label("expected `,` or `)`")));
+                    }
+                }
+            }
+        }
+
+        let span = start.to(self.tokens[self.position - 1].span);
+
+        return Ok(Expression::Call {
+            name,
+            arguments,
+            span,
+        });
+    }
+
+    fn parse_string(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let open = self.tokens[self.position].span;
+        self.position += 1;
+
+        let mut raw = String::new();
+        let mut escaped = false;
+
+        while let Some(tok) = self.tokens.get(self.position) {
+            if tok.token_type == TokenType::LineBreak {
+                break;
+            }
+
+            self.position += 1;
+
+            if tok.token_type == TokenType::DoubleQuote && !escaped {
+                return Ok(Expression::String(raw, open.to(tok.span)));
+            }
+
+            escaped = tok.literal == "\\" && !escaped;
+            raw += &tok.literal;
+        }
+
+        return Err(Box::new(Diagnostic::error(E_UNTERMINATED_STRING, "Unterminated string", open)
+            .with_label("string starts here")));
+    }
+}
+
+pub fn evaluate(expression: &Expression, symbols: &SymbolTable) -> Result<ExpressionValue, Box<Diagnostic>> {
+    return evaluate_at_depth(expression, symbols, 0);
+}
+
+/// Evaluates the expression and requires the result to be a number.
+pub fn evaluate_number(expression: &Expression, symbols: &SymbolTable) -> Result<i32, Box<Diagnostic>> {
+    let value = evaluate(expression, symbols)?;
+
+    return expect_number(value, expression.span());
+}
+
+fn expect_number(value: ExpressionValue, span: Span) -> Result<i32, Box<Diagnostic>> {
+    return match value {
+        ExpressionValue::Number(n) => Ok(n),
+        ExpressionValue::String(_) => Err(Box::new(Diagnostic::error(E_TYPE_MISMATCH, "Expected a number, found a string", span)
+            .with_label("this is a string")
+            .with_help("compare strings with STRCMP, e.g. STRCMP(\"{REGION}\", \"JP\") == 0"))),
+    };
+}
+
+fn expect_string(value: ExpressionValue, span: Span) -> Result<String, Box<Diagnostic>> {
+    return match value {
+        ExpressionValue::String(s) => Ok(s),
+        ExpressionValue::Number(_) => Err(Box::new(Diagnostic::error(E_TYPE_MISMATCH, "Expected a string, found a number", span)
+            .with_label("this is a number"))),
+    };
+}
+
+fn evaluate_at_depth(expression: &Expression, symbols: &SymbolTable, depth: usize) -> Result<ExpressionValue, Box<Diagnostic>> {
+    match expression {
+        Expression::Number(n, _) => return Ok(ExpressionValue::Number(*n)),
+        Expression::String(raw, span) => {
+            return Ok(ExpressionValue::String(unescape(raw, *span, symbols)?));
+        }
+        Expression::Symbol(name, span) => {
+            return evaluate_symbol(name, *span, symbols, depth);
+        }
+        Expression::Unary { operator, operand, .. } => {
+            let value = evaluate_at_depth(operand, symbols, depth)?;
+            let n = expect_number(value, operand.span())?;
+
+            let result = match operator.as_str() {
+                "-" => n.wrapping_neg(),
+                "~" => !n,
+                "!" => (n == 0) as i32,
+                _ => n,
+            };
+
+            return Ok(ExpressionValue::Number(result));
+        }
+        Expression::Binary { operator, left, right, span } => {
+            // labels of the same section are a constant distance apart
+            if let (Expression::Symbol(a, _), Expression::Symbol(b, _), "-") = (left.as_ref(), right.as_ref(), operator.as_str()) {
+                if let (Ok(SymbolValue::Label(a)), Ok(SymbolValue::Label(b))) = (symbols.value(a), symbols.value(b)) {
+                    if a.section == b.section {
+                        return Ok(ExpressionValue::Number(a.offset.wrapping_sub(b.offset)));
+                    }
+                }
+            }
+
+            let l = expect_number(evaluate_at_depth(left, symbols, depth)?, left.span())?;
+
+            // short circuit like RGBDS so `DEF(X) && X > 2` works when X is undefined
+            if operator == "&&" && l == 0 {
+                return Ok(ExpressionValue::Number(0));
+            }
+            if operator == "||" && l != 0 {
+                return Ok(ExpressionValue::Number(1));
+            }
+
+            let r = expect_number(evaluate_at_depth(right, symbols, depth)?, right.span())?;
+
+            return Ok(ExpressionValue::Number(binary_operation(operator, l, r, *span, right.span())?));
+        }
+        Expression::Call { name, arguments, span } => {
+            return call_function(name, arguments, *span, symbols, depth);
+        }
+    }
+}
+
+fn binary_operation(operator: &str, l: i32, r: i32, span: Span, right_span: Span) -> Result<i32, Box<Diagnostic>> {
+    if (operator == "/" || operator == "%") && r == 0 {
+        return Err(Box::new(Diagnostic::error(E_DIVISION_BY_ZERO, "Division by zero", span)
+            .with_secondary(right_span, "this evaluates to 0")));
+    }
+
+    let result = match operator {
+        "||" => (l != 0 || r != 0) as i32,
+        "&&" => (l != 0 && r != 0) as i32,
+        "==" => (l == r) as i32,
+        "!=" => (l != r) as i32,
+        "<" => (l < r) as i32,
+        ">" => (l > r) as i32,
+        "<=" => (l <= r) as i32,
+        ">=" => (l >= r) as i32,
+        "+" => l.wrapping_add(r),
+        "-" => l.wrapping_sub(r),
+        "&" => l & r,
+        "|" => l | r,
+        "^" => l ^ r,
+        "<<" if (0..32).contains(&r) => l.wrapping_shl(r as u32),
+        "<<" => 0,
+        ">>" if (0..32).contains(&r) => l >> r,
+        ">>" => l >> 31,
+        "*" => l.wrapping_mul(r),
+        "/" => l.wrapping_div(r),
+        "%" => l.wrapping_rem(r),
+        "**" if r < 0 => 0,
+        "**" => l.wrapping_pow(r as u32),
+        _ => 0,
+    };
+
+    return Ok(result);
+}
+
+fn evaluate_symbol(name: &str, span: Span, symbols: &SymbolTable, depth: usize) -> Result<ExpressionValue, Box<Diagnostic>> {
+    let value = match symbols.value(name) {
+        Ok(value) => value,
+        Err(message) => {
+            return Err(Box::new(Diagnostic::error(E_UNDEFINED_SYMBOL, &message, span)
+                .with_label("not defined at this point")));
+        }
+    };
+
+    let text = match value {
+        SymbolValue::Number(n) => return Ok(ExpressionValue::Number(n)),
+        SymbolValue::Label(location) => return label_address(name, &location, span).map(ExpressionValue::Number),
+        SymbolValue::String(text) => text,
+    };
+
+    // string symbols behave like EQUS: their text is substituted and evaluated
+    let expansion_error = |diagnostic: Box<Diagnostic>| {
+        let message = format!("Cannot evaluate `{}`: {}", name, diagnostic.message);
+
+        return Box::new(Diagnostic::error(diagnostic.code, &message, span)
+            .with_label("while expanding this symbol")
+            .with_note(&format!("`{}` is the string \"{}\"", name, text.escape_debug())));
+    };
+
+    if depth >= MAX_EXPANSION_DEPTH {
+        return Err(expansion_error(Box::new(Diagnostic::error(E_UNDEFINED_SYMBOL, "expansion is nested too deeply", span))));
+    }
+
+    let tokens = lexer::lex_content(&text);
+    let (expression, used) = parse_expression(&tokens).map_err(expansion_error)?;
+
+    let rest = tokens[used..].iter().find(|tok| tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab);
+    if let Some(tok) = rest {
+        let message = format!("unexpected `{}`", tok.literal.escape_debug());
+        return Err(expansion_error(Box::new(Diagnostic::error(E_EXPECTED_EXPRESSION, &message, span))));
+    }
+
+    return evaluate_at_depth(&expression, symbols, depth + 1).map_err(expansion_error);
+}
+
+fn call_function(name: &str, arguments: &[Expression], span: Span, symbols: &SymbolTable, depth: usize) -> Result<ExpressionValue, Box<Diagnostic>> {
+    let function = name.to_uppercase();
+
+    let arity = match function.as_str() {
+        "DEF" | "HIGH" | "LOW" | "STRLEN" | "STRUPR" | "STRLWR" | "CHARLEN" => 1,
+        "STRCMP" | "CHARSUB" => 2,
+        "STRCAT" => arguments.len().max(1),
+        _ => {
+            let message = format!("Unknown function `{}`", name);
+            return Err(Box::new(Diagnostic::error(E_BAD_FUNCTION_CALL, &message, span)
+                .with_label("not a built-in function")));
+        }
+    };
+
+    if arguments.len() != arity {
+        let message = format!("{} expects {} argument(s), found {}", function, arity, arguments.len());
+        return Err(Box::new(Diagnostic::error(E_BAD_FUNCTION_CALL, &message, span)));
+    }
+
+    if function == "DEF" {
+        return match &arguments[0] {
+            Expression::Symbol(symbol, _) => Ok(ExpressionValue::Number(symbols.is_defined(symbol) as i32)),
+            other => Err(Box::new(Diagnostic::error(E_BAD_FUNCTION_CALL, "DEF expects a symbol name", other.span())
+                .with_label("not a symbol name"))),
+        };
+    }
+
+    let mut values = vec![];
+    for argument in arguments {
+        values.push((evaluate_at_depth(argument, symbols, depth)?, argument.span()));
+    }
+
+    let mut strings = vec![];
+    let mut numbers = vec![];
+
+    for (i, (value, argument_span)) in values.into_iter().enumerate() {
+        // CHARSUB takes a string and a position
+        let expects_number = function == "HIGH" || function == "LOW" || (function == "CHARSUB" && i == 1);
+        if expects_number {
+            numbers.push(expect_number(value, argument_span)?);
+        } else {
+            strings.push(expect_string(value, argument_span)?);
+        }
+    }
+
+    let result = match function.as_str() {
+        "HIGH" => ExpressionValue::Number((numbers[0] >> 8) & 0xFF),
+        "LOW" => ExpressionValue::Number(numbers[0] & 0xFF),
+        "STRLEN" => ExpressionValue::Number(strings[0].chars().count() as i32),
+        "STRUPR" => ExpressionValue::String(strings[0].to_uppercase()),
+        "STRLWR" => ExpressionValue::String(strings[0].to_lowercase()),
+        "STRCMP" => ExpressionValue::Number(strings[0].cmp(&strings[1]) as i32),
+        "CHARLEN" => ExpressionValue::Number(symbols.charmaps.current().units(&strings[0]).len() as i32),
+        // units are numbered from 1, there is none outside of the string
+        "CHARSUB" => {
+            let units = symbols.charmaps.current().units(&strings[0]);
+            let unit = usize::try_from(numbers[0] - 1).ok().and_then(|i| units.get(i));
+            ExpressionValue::String(unit.map_or("", |unit| unit.0).to_string())
+        }
+        _ => ExpressionValue::String(strings.concat()),
+    };
+
+    return Ok(result);
+}
+
+/// Resolves escape sequences and `{symbol}` interpolations of a raw string.
+fn unescape(raw: &str, span: Span, symbols: &SymbolTable) -> Result<String, Box<Diagnostic>> {
+    let mut result = String::new();
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('0') => result.push('\0'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else if c == '{' {
+            let mut inner = String::new();
+            let mut closed = false;
+
+            for c in chars.by_ref() {
+                if c == '}' {
+                    closed = true;
+                    break;
+                }
+                inner.push(c);
+            }
+
+            if !closed {
+                return Err(Box::new(Diagnostic::error(E_BAD_INTERPOLATION, "Missing `}` in string interpolation", span)));
+            }
+
+            result += &interpolate(&inner, span, symbols)?;
+        } else {
+            result.push(c);
+        }
+    }
+
+    return Ok(result);
+}
+
+fn interpolate(inner: &str, span: Span, symbols: &SymbolTable) -> Result<String, Box<Diagnostic>> {
+    let (format, name) = match inner.split_once(':') {
+        Some((format, name)) => (format, name),
+        None => ("", inner),
+    };
+
+    let value = match symbols.value(name) {
+        Ok(value) => value,
+        Err(message) => {
+            return Err(Box::new(Diagnostic::error(E_UNDEFINED_SYMBOL, &message, span)
+                .with_label("used in this string interpolation")));
+        }
+    };
+
+    let number = match value {
+        SymbolValue::String(text) => return Ok(text),
+        SymbolValue::Number(n) => n,
+        SymbolValue::Label(location) => label_address(name, &location, span)?,
+    };
+
+    return match format {
+        "" | "$" => Ok(format!("${:X}", number)),
+        "d" => Ok(number.to_string()),
+        "u" => Ok((number as u32).to_string()),
+        "x" => Ok(format!("{:x}", number)),
+        "X" => Ok(format!("{:X}", number)),
+        "b" => Ok(format!("{:b}", number)),
+        _ => {
+            let message = format!("Unknown interpolation format `{}`", format);
+            Err(Box::new(Diagnostic::error(E_BAD_INTERPOLATION, &message, span)))
+        }
+    };
+}
+
+fn label_address(name: &str, location: &LabelLocation, span: Span) -> Result<i32, Box<Diagnostic>> {
+    return location.address.ok_or_else(|| {
+        Box::new(Diagnostic::error(E_NOT_CONSTANT, &format!("`{}` is not constant at assembly time", name), span)
+            .with_label("the address of this label is decided when linking")
+            .with_note(&format!("section \"{}\" is not at a fixed address", location.section)))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolKind;
+
+    fn eval(text: &str, symbols: &SymbolTable) -> Result<ExpressionValue, String> {
+        let tokens = lexer::lex_content(text);
+        let (expression, _) = parse_expression(&tokens).map_err(|d| d.message)?;
+
+        return evaluate(&expression, symbols).map_err(|d| d.message);
+    }
+
+    #[test]
+    fn evaluating_with_precedence() {
+        let symbols = SymbolTable::new();
+
+        assert_eq!(eval("1 + 2 * 3", &symbols), Ok(ExpressionValue::Number(7)));
+        assert_eq!(eval("1 + $F0 & $0F", &symbols), Ok(ExpressionValue::Number(1)));
+        assert_eq!(eval("-(2 ** 3 ** 2) < 0 && !0", &symbols), Ok(ExpressionValue::Number(1)));
+        assert_eq!(eval("HIGH($1234) | LOW(%11) << 8", &symbols), Ok(ExpressionValue::Number(0x312)));
+        assert_eq!(eval("STRLEN(STRCAT(\"ab\", \"c\\\"\"))", &symbols), Ok(ExpressionValue::Number(4)));
+        assert!(eval("1 / (2 - 2)", &symbols).is_err());
+    }
+
+    #[test]
+    fn evaluating_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.define("DEBUG", SymbolKind::String, SymbolValue::String("1".to_string()), None).unwrap();
+        symbols.define("REGION", SymbolKind::String, SymbolValue::String("JP".to_string()), None).unwrap();
+        symbols.define("LIVES", SymbolKind::Constant, SymbolValue::Number(3), None).unwrap();
+
+        assert_eq!(eval("DEBUG + LIVES", &symbols), Ok(ExpressionValue::Number(4)));
+        assert_eq!(eval("DEF(MISSING) && MISSING", &symbols), Ok(ExpressionValue::Number(0)));
+        assert_eq!(eval("STRCMP(\"{REGION}\", \"JP\")", &symbols), Ok(ExpressionValue::Number(0)));
+        assert_eq!(eval("\"{d:LIVES} lives {LIVES}\"", &symbols), Ok(ExpressionValue::String("3 lives $3".to_string())));
+
+        let error = eval("REGION == 1", &symbols).unwrap_err();
+        assert_eq!(error, "Cannot evaluate `REGION`: Symbol `JP` is not defined");
+    }
+}
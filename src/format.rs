@@ -0,0 +1,405 @@
+//! Source formatter behind the `fmt` command.
+//!
+//! Formatting works line by line on the tokens of the concrete syntax tree,
+//! using the statements of its nodes to tell labels, instructions and
+//! directives apart:
+//!
+//! - labels, SECTION, INCLUDE, definitions, macro definitions and REPT start
+//!   at column 0, instructions, data, macro calls, INCBIN and EXPECT are indented
+//!   with a tab, conditional directives and comment lines keep whether they were
+//!   indented;
+//! - a label followed by a statement is split over two lines;
+//! - mnemonics, registers and data directives are lowercase, other directives
+//!   uppercase;
+//! - operands and trailing comments of consecutive instructions are aligned;
+//! - whitespace is collapsed to a single space, commas are followed by one;
+//! - comments and blank lines are kept, macro and REPT bodies are left
+//!   untouched.
+//!
+//! Formatting a formatted file does not change it.
+
+use std::collections::HashMap;
+
+use crate::ast::{self, Statement, StatementType};
+use crate::cst::SyntaxTree;
+use crate::diagnostic::Diagnostics;
+use crate::lexer::{Span, Token, TokenType, TriviaKind};
+use crate::sm83;
+
+const INDENT: &str = "\t";
+
+pub(crate) const DIRECTIVES: [&str; 28] = [
+    "include", "incbin", "section", "if", "elif", "else", "endc", "def", "macro", "endm",
+    "rept", "endr", "charmap", "newcharmap", "setcharmap", "pushc", "popc", "rsreset", "rsset", "equ",
+    "equs", "set", "rb", "rw", "rl", "test", "endt", "expect",
+];
+
+const SECTION_KEYWORDS: [&str; 10] = ["rom0", "romx", "vram", "sram", "wram0", "wramx", "oam", "hram", "bank", "align"];
+
+enum Line {
+    Blank,
+    /// Text including its indentation
+    Text {
+        text: String,
+        comment: Option<String>,
+    },
+    /// Instruction like line whose operands and comment are aligned with its
+    /// neighbours
+    Aligned {
+        mnemonic: String,
+        operands: String,
+        comment: Option<String>,
+        /// Macro names do not widen the mnemonic column
+        is_macro: bool,
+    },
+}
+
+/// Formats `text`, or returns `None` after adding the parse errors to
+/// `diagnostics` when the file does not parse.
+pub fn format_source(text: &str, diagnostics: &mut Diagnostics) -> Option<String> {
+    let mut parse_diagnostics = Diagnostics::new();
+    let tree = SyntaxTree::parse(text, &mut parse_diagnostics);
+    let failed = parse_diagnostics.has_errors();
+    for diagnostic in parse_diagnostics.iter() {
+        diagnostics.push(diagnostic.clone());
+    }
+    if failed {
+        return None;
+    }
+
+    let mut statements = HashMap::new();
+    let mut macro_bodies = vec![];
+    for node in tree.root.descendants() {
+        if let Some(statement) = tree.statement(node) {
+            statements.insert(node.span.start, statement);
+            macro_bodies.extend(body_span(statement));
+        }
+    }
+
+    let tokens: Vec<Token> = tree.root.tokens().into_iter().cloned().collect();
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.token_type == TokenType::LineBreak || token.token_type == TokenType::EOF {
+            format_line(&tokens[start..i], token, &statements, &macro_bodies, &mut lines);
+            start = i + 1;
+        }
+    }
+
+    // a single newline at the end of the file
+    while matches!(lines.last(), Some(Line::Blank)) {
+        lines.pop();
+    }
+
+    return Some(print_lines(&lines));
+}
+
+// the text of a macro or REPT body, which is left as it is
+fn body_span(statement: &dyn Statement) -> Option<Span> {
+    let tokens = match statement.my_type() {
+        StatementType::Macro => &statement.as_any().downcast_ref::<ast::MacroStatement>()?.tokens,
+        StatementType::Rept => &statement.as_any().downcast_ref::<ast::ReptStatement>()?.tokens,
+        _ => return None,
+    };
+
+    return Some(tokens.first()?.span.to(tokens.last()?.span));
+}
+
+fn format_line(tokens: &[Token], end: &Token, statements: &HashMap<usize, &dyn Statement>, macro_bodies: &[Span], lines: &mut Vec<Line>) {
+    let first = match tokens.first() {
+        Some(first) => first,
+        None => {
+            // blank or comment only line
+            let comment = end.leading_trivia.iter().find(|t| t.kind == TriviaKind::Comment);
+            lines.push(match comment {
+                Some(comment) => Line::Text {
+                    text: indentation(end) + comment.text.trim_end(),
+                    comment: None,
+                },
+                None => Line::Blank,
+            });
+            return;
+        }
+    };
+
+    if macro_bodies.iter().any(|body| body.start <= first.span.start && first.span.start < body.end && !is_keyword(first, "endm")) {
+        let text: String = tokens.iter().map(|t| t.full_text()).collect();
+        lines.push(Line::Text {
+            text: text.trim_end().to_string(),
+            comment: None,
+        });
+        return;
+    }
+
+    let comment = tokens.last()
+        .and_then(|last| last.trailing_trivia.iter().find(|t| t.kind == TriviaKind::Comment))
+        .map(|comment| comment.text.trim_end().to_string());
+
+    let mut rest = tokens;
+    if let Some(label) = statements.get(&first.span.start).filter(|s| s.my_type() == StatementType::Label) {
+        let count = tokens.iter().take_while(|t| t.span.end <= label.span().end).count();
+        let text: String = tokens[..count].iter().map(|t| t.literal.as_str()).collect();
+        rest = &tokens[count..];
+
+        lines.push(Line::Text {
+            text,
+            comment: if rest.is_empty() { comment.clone() } else { None },
+        });
+
+        if rest.is_empty() {
+            return;
+        }
+    }
+
+    let statement = statements.get(&rest[0].span.start).map(|s| s.my_type());
+    let keyword = rest[0].literal.to_lowercase();
+
+    let line = match statement {
+        Some(StatementType::Instruction) | Some(StatementType::Data) | Some(StatementType::MacroCall) => {
+            let lowercase = statement != Some(StatementType::MacroCall);
+            let mnemonic = if lowercase { keyword.clone() } else { rest[0].literal.clone() };
+
+            Line::Aligned {
+                mnemonic,
+                operands: render_tokens(&rest[1..], &|token| {
+                    let register = lowercase && token.token_type == TokenType::Identifier
+                        && (sm83::is_register(&token.literal) || ["hli", "hld"].contains(&token.literal.to_lowercase().as_str()));
+                    register.then(|| token.literal.to_lowercase())
+                }),
+                comment,
+                is_macro: !lowercase,
+            }
+        }
+        _ => {
+            let indented = match keyword.as_str() {
+                "incbin" | "expect" => true,
+                "if" | "elif" | "else" | "endc" => !rest[0].leading_trivia.is_empty(),
+                _ => false,
+            };
+            let is_section = statement == Some(StatementType::Section);
+
+            let text = render_tokens(rest, &|token| {
+                let name = token.literal.to_lowercase();
+                let keyword = token.token_type == TokenType::Identifier
+                    && (DIRECTIVES.contains(&name.as_str()) && is_directive_position(rest, token)
+                        || is_section && SECTION_KEYWORDS.contains(&name.as_str()));
+                keyword.then(|| token.literal.to_uppercase())
+            });
+
+            Line::Text {
+                text: if indented { INDENT.to_string() + &text } else { text },
+                comment,
+            }
+        }
+    };
+
+    lines.push(line);
+}
+
+// directive keywords are the first word of a line, or follow the symbol name
+// in `NAME EQU 1`, `DEF NAME EQU 1` and `NAME: MACRO`
+fn is_directive_position(tokens: &[Token], token: &Token) -> bool {
+    let index = tokens.iter().position(|t| t.span == token.span).unwrap_or(0);
+
+    if index == 0 {
+        return true;
+    }
+
+    let words: Vec<&Token> = tokens[..index].iter().filter(|t| t.token_type != TokenType::Colon).collect();
+    return match words.len() {
+        1 => true,
+        2 => is_keyword(words[0], "def"),
+        _ => false,
+    };
+}
+
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    return token.token_type == TokenType::Identifier && token.literal.eq_ignore_ascii_case(keyword);
+}
+
+fn indentation(token: &Token) -> String {
+    return match token.leading_trivia.first() {
+        Some(trivia) if trivia.kind == TriviaKind::Whitespace => INDENT.to_string(),
+        _ => String::new(),
+    };
+}
+
+/// Prints tokens with single spaces where the source had whitespace, one
+/// space after commas and none inside brackets; strings are kept as they are.
+fn render_tokens(tokens: &[Token], case: &dyn Fn(&Token) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut in_string = false;
+    let mut previous: Option<&Token> = None;
+
+    for token in tokens {
+        if !in_string {
+            if let Some(previous) = previous {
+                let spaced = !previous.trailing_trivia.is_empty() || previous.token_type == TokenType::Comma;
+                let closes = matches!(token.token_type, TokenType::Comma | TokenType::RightBracket | TokenType::RightParen);
+                let opens = matches!(previous.token_type, TokenType::LeftBracket | TokenType::LeftParen);
+
+                if spaced && !closes && !opens {
+                    out.push(' ');
+                }
+            }
+        }
+
+        if token.token_type == TokenType::DoubleQuote {
+            let escaped = in_string && previous.is_some_and(|p| p.literal == "\\" && p.span.end == token.span.start);
+            if !escaped {
+                in_string = !in_string;
+            }
+            out += &token.literal;
+        } else if in_string {
+            out += &token.literal;
+        } else {
+            out += &case(token).unwrap_or_else(|| token.literal.clone());
+        }
+
+        previous = Some(token);
+    }
+
+    return out;
+}
+
+fn print_lines(lines: &[Line]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        match &lines[i] {
+            Line::Blank => out.push('\n'),
+            Line::Text { text, comment } => {
+                out += text;
+                if let Some(comment) = comment {
+                    out += " ";
+                    out += comment;
+                }
+                out.push('\n');
+            }
+            Line::Aligned { .. } => {
+                let block_end = lines[i..].iter().position(|l| !matches!(l, Line::Aligned { .. })).map_or(lines.len(), |n| i + n);
+                print_block(&lines[i..block_end], &mut out);
+                i = block_end;
+                continue;
+            }
+        }
+
+        i += 1;
+    }
+
+    return out;
+}
+
+fn print_block(block: &[Line], out: &mut String) {
+    let lines: Vec<(&String, &String, &Option<String>)> = block.iter().filter_map(|line| match line {
+        Line::Aligned { mnemonic, operands, comment, .. } => Some((mnemonic, operands, comment)),
+        _ => None,
+    }).collect();
+
+    let mnemonic_width = block.iter()
+        .filter_map(|line| match line {
+            Line::Aligned { mnemonic, operands, is_macro: false, .. } if !operands.is_empty() => Some(mnemonic.chars().count()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let code: Vec<String> = lines.iter().map(|(mnemonic, operands, _)| {
+        if operands.is_empty() {
+            mnemonic.to_string()
+        } else {
+            format!("{:<width$} {}", mnemonic, operands, width = mnemonic_width)
+        }
+    }).collect();
+
+    let comment_column = code.iter().map(|text| text.chars().count()).max().unwrap_or(0) + 1;
+
+    for (text, (_, _, comment)) in code.iter().zip(lines) {
+        *out += INDENT;
+        *out += text;
+        if let Some(comment) = comment {
+            *out += &" ".repeat(comment_column - text.chars().count());
+            *out += comment;
+        }
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(text: &str) -> String {
+        let mut diagnostics = Diagnostics::new();
+        return format_source(text, &mut diagnostics).unwrap();
+    }
+
+    #[test]
+    fn formatting_instructions_and_directives() {
+        let input = concat!(
+            "include \"hardware.inc\"\n",
+            "def   SPEED  equ 2   ; pixels per frame\n",
+            "\n",
+            "section \"Main\",rom0 [$150]\n",
+            "Main::  LD A,[ HL+ ]\n",
+            "   call   Update ; move\n",
+            "  xor a\n",
+            "    ; wait for vblank\n",
+            ".wait   halt\n",
+            "  if DEF(DEBUG)\n",
+            "db 1 ,2,\"a ,  b\"\n",
+            "  endc\n",
+            "my_macro: macro\n",
+            "    ld a, \\1   ; kept\n",
+            "endm\n",
+            "\n\n",
+        );
+
+        let expected = concat!(
+            "INCLUDE \"hardware.inc\"\n",
+            "DEF SPEED EQU 2 ; pixels per frame\n",
+            "\n",
+            "SECTION \"Main\", ROM0 [$150]\n",
+            "Main::\n",
+            "\tld   a, [hl+]\n",
+            "\tcall Update   ; move\n",
+            "\txor  a\n",
+            "\t; wait for vblank\n",
+            ".wait\n",
+            "\thalt\n",
+            "\tIF DEF(DEBUG)\n",
+            "\tdb 1, 2, \"a ,  b\"\n",
+            "\tENDC\n",
+            "my_macro: MACRO\n",
+            "    ld a, \\1   ; kept\n",
+            "ENDM\n",
+        );
+
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let input = concat!(
+            "SECTION \"x\", ROMX, BANK[2]\n",
+            "Func:ld hl,sp+4 ;a\n",
+            "\tld [$ff00+c],a;b\n",
+            "\tjr nz,Func\n",
+            "  my_macro 1,(2, 3)   ,  \"x\"\n",
+            "no_newline: ; end",
+        );
+
+        let once = format(input);
+        assert_eq!(format(&once), once);
+        assert!(once.ends_with("no_newline: ; end\n"));
+    }
+
+    #[test]
+    fn refusing_invalid_input() {
+        let mut diagnostics = Diagnostics::new();
+
+        assert!(format_source("SECTION \"x\"\n", &mut diagnostics).is_none());
+        assert!(diagnostics.has_errors());
+    }
+}
@@ -0,0 +1,632 @@
+//! A GDB remote serial protocol stub in front of the emulator.
+//!
+//! GDB connects to it over a local TCP socket with `target remote`, and
+//! reads and writes the registers and memory, sets breakpoints and
+//! watchpoints, steps and continues. The registers are af, bc, de, hl, sp
+//! and pc, 16 bits each, in the order of GDB's z80 target. Addresses map
+//! back to source lines through the debug information of the program, for
+//! the `monitor` commands and the log of where the program stopped.
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::debuginfo::{DebugInfo, LineInfo};
+use crate::emulator::{Bus, Emulator, Memory};
+
+/// Largest packet taken, hex digits of `G` and `M` included
+pub const PACKET_SIZE: usize = 0x1000;
+
+const REGISTERS: [&str; 6] = ["af", "bc", "de", "hl", "sp", "pc"];
+
+// stop signals
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// instructions run between checks for an interrupt from GDB
+const INTERRUPT_CHECK: u32 = 10_000;
+
+const TARGET_XML: &str = concat!(
+    "<?xml version=\"1.0\"?>\n",
+    "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+    "<target version=\"1.0\">\n",
+    "  <feature name=\"org.gnu.gdb.z80.cpu\">\n",
+    "    <reg name=\"af\" bitsize=\"16\" type=\"int\"/>\n",
+    "    <reg name=\"bc\" bitsize=\"16\" type=\"data_ptr\"/>\n",
+    "    <reg name=\"de\" bitsize=\"16\" type=\"data_ptr\"/>\n",
+    "    <reg name=\"hl\" bitsize=\"16\" type=\"data_ptr\"/>\n",
+    "    <reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>\n",
+    "    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\n",
+    "  </feature>\n",
+    "</target>\n",
+);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+impl Watch {
+    fn name(self) -> &'static str {
+        return match self {
+            Watch::Write => "watch",
+            Watch::Read => "rwatch",
+            Watch::Access => "awatch",
+        };
+    }
+}
+
+/// `Memory` that notes the first access to a watched address.
+pub struct Watched {
+    pub memory: Memory,
+    watchpoints: Vec<(Watch, u16, u16)>,
+    hit: Cell<Option<(Watch, u16)>>,
+}
+
+impl Watched {
+    pub fn new(memory: Memory) -> Self {
+        return Self {
+            memory,
+            watchpoints: vec![],
+            hit: Cell::new(None),
+        };
+    }
+
+    fn watch(&self, address: u16, write: bool) {
+        if self.hit.get().is_some() {
+            return;
+        }
+
+        let watched = self.watchpoints.iter().find(|(watch, start, length)| {
+            let kind = match watch {
+                Watch::Write => write,
+                Watch::Read => !write,
+                Watch::Access => true,
+            };
+            kind && address.wrapping_sub(*start) < *length
+        });
+        if let Some((watch, _, _)) = watched {
+            self.hit.set(Some((*watch, address)));
+        }
+    }
+}
+
+impl Bus for Watched {
+    fn read(&self, address: u16) -> u8 {
+        self.watch(address, false);
+        return self.memory.read(address);
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.watch(address, true);
+        self.memory.write(address, value);
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.memory.tick(cycles);
+    }
+}
+
+/// The state of a debugging session, answering packets one at a time.
+pub struct Session {
+    pub emulator: Emulator<Watched>,
+    pub debug_info: DebugInfo,
+    breakpoints: BTreeSet<u16>,
+    /// Signal of the last stop
+    signal: u8,
+}
+
+impl Session {
+    pub fn new(emulator: Emulator<Watched>, debug_info: DebugInfo) -> Self {
+        return Self {
+            emulator,
+            debug_info,
+            breakpoints: BTreeSet::new(),
+            signal: SIGTRAP,
+        };
+    }
+
+    /// The program counter, with its source line when there is one.
+    pub fn location(&self) -> String {
+        let pc = self.emulator.cpu.registers.pc;
+        return match self.line(pc) {
+            Some(line) => format!("${:04X} ({})", pc, line_text(line)),
+            None => format!("${:04X}", pc),
+        };
+    }
+
+    fn register(&self, i: usize) -> u16 {
+        return self.emulator.cpu.registers.get(REGISTERS[i]).unwrap_or(0);
+    }
+
+    fn set_register(&mut self, i: usize, value: u16) {
+        // 16 bit registers take any value
+        let _ = self.emulator.cpu.registers.set(REGISTERS[i], value);
+    }
+
+    /// The line that output the byte at an address, in the banks mapped.
+    fn line(&self, address: u16) -> Option<&LineInfo> {
+        let bank = match address {
+            0x4000..=0x7FFF => self.emulator.bus.memory.rom_bank() as i32,
+            // the one WRAMX bank of `Memory`
+            0xD000..=0xDFFF => 1,
+            _ => 0,
+        };
+        return self.debug_info.line_at(bank, address);
+    }
+
+    /// The reply to the payload of a packet, `None` when the session ends.
+    /// `interrupted` is polled while the program runs, to stop it when GDB
+    /// asks to.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+
+        let reply = match command {
+            "?" => format!("S{:02x}", self.signal),
+            "g" => (0..REGISTERS.len()).map(|i| hex(&self.register(i).to_le_bytes())).collect(),
+            "G" => match unhex(arguments) {
+                Some(bytes) if bytes.len() >= 2 * REGISTERS.len() => {
+                    for i in 0..REGISTERS.len() {
+                        self.set_register(i, u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(arguments, 16).ok().filter(|i| *i < REGISTERS.len()) {
+                Some(i) => hex(&self.register(i).to_le_bytes()),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let register = arguments.split_once('=')
+                    .and_then(|(number, value)| Some((usize::from_str_radix(number, 16).ok()?, unhex(value)?)));
+                match register {
+                    Some((i, value)) if i < REGISTERS.len() && value.len() == 2 => {
+                        self.set_register(i, u16::from_le_bytes([value[0], value[1]]));
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match address_length(arguments) {
+                Some((address, length)) => {
+                    let memory = &self.emulator.bus.memory;
+                    let bytes: Vec<u8> = (0..length).map(|i| memory.read(address.wrapping_add(i))).collect();
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = arguments.split_once(':')
+                    .and_then(|(range, data)| Some((address_length(range)?, unhex(data)?)));
+                match write {
+                    Some(((address, length), data)) if data.len() == length as usize => {
+                        for (i, byte) in data.iter().enumerate() {
+                            self.emulator.bus.memory.poke(address.wrapping_add(i as u16), *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.set_point(command == "Z", arguments),
+            "c" | "s" => {
+                if let Some(address) = u16::from_str_radix(arguments, 16).ok().filter(|_| !arguments.is_empty()) {
+                    self.emulator.cpu.registers.pc = address;
+                }
+                self.resume(command == "s", interrupted)
+            }
+            "q" => self.query(arguments),
+            "H" | "T" => "OK".to_string(),
+            "D" | "k" => return None,
+            _ => String::new(),
+        };
+
+        return Some(reply);
+    }
+
+    fn set_point(&mut self, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next().unwrap_or("");
+        let address = fields.next().and_then(|address| u16::from_str_radix(address, 16).ok());
+        let length = fields.next().and_then(|length| u16::from_str_radix(length, 16).ok());
+        let (address, length) = match (address, length) {
+            (Some(address), Some(length)) => (address, length.max(1)),
+            _ => return "E01".to_string(),
+        };
+
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => Watch::Write,
+            "3" => Watch::Read,
+            "4" => Watch::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoints = &mut self.emulator.bus.watchpoints;
+        watchpoints.retain(|point| *point != (watch, address, length));
+        if insert {
+            watchpoints.push((watch, address, length));
+        }
+        return "OK".to_string();
+    }
+
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let mut steps = 0;
+
+        loop {
+            self.emulator.bus.hit.set(None);
+            if let Err(_message) = self.emulator.step() {
+                self.signal = SIGILL;
+                return format!("S{:02x}", self.signal);
+            }
+
+            self.signal = SIGTRAP;
+            if let Some((watch, address)) = self.emulator.bus.hit.take() {
+                return format!("T{:02x}{}:{:04x};", self.signal, watch.name(), address);
+            }
+            // a halted CPU stays at the instruction after the `halt`
+            let pc = self.emulator.cpu.registers.pc;
+            if step || (self.breakpoints.contains(&pc) && !self.emulator.cpu.halted) {
+                return format!("S{:02x}", self.signal);
+            }
+
+            steps += 1;
+            if steps % INTERRUPT_CHECK == 0 && interrupted() {
+                self.signal = SIGINT;
+                return format!("S{:02x}", self.signal);
+            }
+        }
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return match range.split_once(',') {
+                Some((offset, length)) => {
+                    let offset = usize::from_str_radix(offset, 16).unwrap_or(0).min(TARGET_XML.len());
+                    let length = usize::from_str_radix(length, 16).unwrap_or(0);
+                    let end = (offset + length).min(TARGET_XML.len());
+                    format!("{}{}", if end == TARGET_XML.len() { "l" } else { "m" }, &TARGET_XML[offset..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        if let Some(command) = query.strip_prefix("Rcmd,") {
+            return match unhex(command).and_then(|command| String::from_utf8(command).ok()) {
+                Some(command) => hex(self.monitor(&command).as_bytes()),
+                None => "E01".to_string(),
+            };
+        }
+
+        return match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        };
+    }
+
+    /// Output of a `monitor` command.
+    fn monitor(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+
+        return match (words.next(), words.next()) {
+            (Some("line"), address) => {
+                let address = match address {
+                    Some(address) => match self.parse_address(address) {
+                        Some(address) => address,
+                        None => return format!("Unknown address `{}`\n", address),
+                    },
+                    None => self.emulator.cpu.registers.pc,
+                };
+                match self.line(address) {
+                    Some(line) => format!("${:04X} is at {}\n", address, line_text(line)),
+                    None => format!("${:04X} was not output by a source line\n", address),
+                }
+            }
+            (Some("break"), Some(location)) => {
+                let line = location.rsplit_once(':').and_then(|(file, line)| Some((file, line.parse().ok()?)));
+                match line.and_then(|(file, line)| self.debug_info.address_of(file, line)) {
+                    Some((_, address)) => {
+                        self.breakpoints.insert(address);
+                        format!("Breakpoint at ${:04X}\n", address)
+                    }
+                    None => format!("No instruction at `{}`\n", location),
+                }
+            }
+            _ => concat!(
+                "monitor line [address]    source line of pc or an address\n",
+                "monitor break file:line   breakpoint at the first byte of a line\n",
+            ).to_string(),
+        };
+    }
+
+    // $-prefixed or plain hex, or a label
+    fn parse_address(&self, text: &str) -> Option<u16> {
+        if let Some(symbol) = self.debug_info.symbols.iter().find(|symbol| symbol.name == text) {
+            return Some(symbol.address);
+        }
+        let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+        return u16::from_str_radix(digits, 16).ok();
+    }
+}
+
+// `file:line`, followed by the macro invocations it comes from
+fn line_text(line: &LineInfo) -> String {
+    let mut text = format!("{}:{}", line.file, line.line);
+    for expansion in &line.expansions {
+        text += &format!(", in `{}` from {}:{}", expansion.name, expansion.file, expansion.line);
+    }
+    return text;
+}
+
+/// Answers the packets of GDB on `stream` until it detaches, kills the
+/// program or disconnects. `log` is given where the program stops.
+pub fn serve(session: &mut Session, stream: &mut TcpStream, log: &mut dyn FnMut(String)) -> io::Result<()> {
+    loop {
+        let packet = match read_packet(stream)? {
+            Some(Packet::Data(packet)) => packet,
+            Some(Packet::Interrupt) => {
+                session.signal = SIGINT;
+                write_packet(stream, &format!("S{:02x}", SIGINT))?;
+                continue;
+            }
+            None => return Ok(()),
+        };
+
+        let polling = stream.try_clone()?;
+        let mut interrupted = || interrupt_requested(&polling);
+        let reply = session.handle(&packet, &mut interrupted);
+
+        match reply {
+            Some(reply) => {
+                if packet.starts_with(['c', 's']) {
+                    log(format!("Stopped at {}", session.location()));
+                }
+                write_packet(stream, &reply)?;
+            }
+            None => {
+                if packet.starts_with('D') {
+                    write_packet(stream, "OK")?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+enum Packet {
+    Data(String),
+    Interrupt,
+}
+
+/// Reads the next packet, acknowledging it, `None` at the end of the stream.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<Packet>> {
+    loop {
+        let byte = match read_byte(stream)? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        match byte {
+            0x03 => return Ok(Some(Packet::Interrupt)),
+            b'$' => {}
+            // acknowledgements, and whatever comes between packets
+            _ => continue,
+        }
+
+        let mut data = vec![];
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) if data.len() < PACKET_SIZE => data.push(byte),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+
+        let mut digits = [0; 2];
+        stream.read_exact(&mut digits)?;
+        let expected = std::str::from_utf8(&digits).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        if expected != Some(checksum(&data)) {
+            stream.write_all(b"-")?;
+            continue;
+        }
+
+        stream.write_all(b"+")?;
+        return Ok(Some(Packet::Data(String::from_utf8_lossy(&unescape(&data)).into_owned())));
+    }
+}
+
+fn read_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    return match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    };
+}
+
+/// Sends a packet, again until GDB acknowledges it.
+fn write_packet(stream: &mut TcpStream, payload: &str) -> io::Result<()> {
+    let packet = frame(payload);
+    loop {
+        stream.write_all(packet.as_bytes())?;
+        match read_byte(stream)? {
+            Some(b'-') => continue,
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn interrupt_requested(stream: &TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let read = (&*stream).read(&mut byte);
+    let _ = stream.set_nonblocking(false);
+
+    return match read {
+        Ok(0) => true,
+        Ok(_) => byte[0] == 0x03,
+        Err(_) => false,
+    };
+}
+
+/// A payload as sent, with its checksum and the characters GDB treats
+/// specially escaped.
+pub fn frame(payload: &str) -> String {
+    let mut escaped = vec![];
+    for byte in payload.bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend([b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+
+    return format!("${}#{:02x}", String::from_utf8_lossy(&escaped), checksum(&escaped));
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    return data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut iter = data.iter();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'}' => bytes.extend(iter.next().map(|byte| byte ^ 0x20)),
+            _ => bytes.push(*byte),
+        }
+    }
+    return bytes;
+}
+
+fn address_length(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    return Some((u16::from_str_radix(address, 16).ok()?, length.min(PACKET_SIZE / 2) as u16));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::diagnostic::{Diagnostics, SourceFile};
+    use crate::{lexer, link, parser};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    fn session() -> Session {
+        let source = SourceFile::new("main.asm", concat!(
+            "SECTION \"Entry\", ROM0[$100]\n",
+            "\tjp Main\n",
+            "SECTION \"Main\", ROM0[$150]\n",
+            "Main:\n",
+            "\tld hl, wCounter\n",
+            ".loop:\n",
+            "\tinc [hl]\n",
+            "\tjr .loop\n",
+            "SECTION \"Counter\", WRAM0\n",
+            "wCounter: db\n",
+        ).to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        let rom = link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        let emulator = Emulator::with_bus(Watched::new(Memory::new(rom)));
+        return Session::new(emulator, DebugInfo::new(&assembler, &[source]));
+    }
+
+    #[test]
+    fn debugging_a_program() {
+        let mut session = session();
+        let mut running = || false;
+        let mut handle = |packet: &str| session.handle(packet, &mut running).unwrap();
+
+        assert_eq!(handle("?"), "S05");
+        assert_eq!(handle("p5"), "0001");
+        assert_eq!(handle("P3=34c0"), "OK");
+        assert_eq!(&handle("g")[12..], "34c0feff0001");
+        assert_eq!(handle("s"), "S05");
+        assert_eq!(handle("p5"), "5001");
+        assert_eq!(handle("m150,3"), "2100c0");
+        assert_eq!(handle("M150,1:00"), "OK");
+        assert_eq!(handle("m150,1"), "00");
+        assert_eq!(handle("M150,1:21"), "OK");
+
+        assert_eq!(handle("Z0,154,1"), "OK");
+        assert_eq!(handle("c"), "S05");
+        assert_eq!(handle("p5"), "5401");
+        assert_eq!(handle("mc000,1"), "01");
+        assert_eq!(handle("z0,154,1"), "OK");
+
+        assert_eq!(handle("Z2,c000,1"), "OK");
+        assert_eq!(handle("c"), "T05watch:c000;");
+        assert_eq!(handle("mc000,1"), "02");
+        assert_eq!(handle("z2,c000,1"), "OK");
+
+        let monitor = |command: &str| format!("qRcmd,{}", hex(command.as_bytes()));
+        assert_eq!(handle(&monitor("line Main")), hex(b"$0150 is at main.asm:5\n"));
+        assert_eq!(handle(&monitor("break main.asm:8")), hex(b"Breakpoint at $0154\n"));
+        assert_eq!(handle("c"), "S05");
+        assert_eq!(session.location(), "$0154 (main.asm:8)");
+        assert_eq!(session.handle("D", &mut running), None);
+    }
+
+    #[test]
+    fn framing_packets() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame("a$b"), "$a}\u{4}b#44");
+        assert_eq!(unescape(b"a}\x04b"), b"a$b");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"$?#3f+$p5#a5+$D#44+").unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut session = session();
+        serve(&mut session, &mut stream, &mut |_| {}).unwrap();
+        drop(stream);
+
+        // the client acknowledges the replies ahead of time
+        assert_eq!(client.join().unwrap(), format!("+{}+{}+{}", frame("S05"), frame("0001"), frame("OK")));
+    }
+}
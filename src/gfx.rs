@@ -0,0 +1,511 @@
+//! Converting images to the tile data, tilemaps, attribute maps and palettes
+//! of the PPU, in the layout INCBIN takes them, and drawing them back.
+//!
+//! The image is cut into 8x8 tiles, row by row from the top left corner, and
+//! each tile is stored with the color indices of its palette. The palettes
+//! are those of an indexed PNG image, in the order of its colors, or are
+//! made from the colors of the tiles: transparent first, then from the
+//! lightest to the darkest, which are the shades of the DMG.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::disasm::SymbolName;
+use crate::image::{Image, Palette};
+use crate::link::BANK_SIZE;
+use crate::ppu::SHADES;
+
+pub const TILE_SIZE: usize = 8;
+/// Palettes of the CGB
+pub const MAX_PALETTES: usize = 8;
+/// Tiles a tilemap addresses, 256 in each bank of VRAM of the CGB
+pub const MAX_TILES: usize = 512;
+
+// attribute bits
+const ATTRIBUTE_BANK: u8 = 0x08;
+const ATTRIBUTE_X_FLIP: u8 = 0x20;
+const ATTRIBUTE_Y_FLIP: u8 = 0x40;
+
+// written for the colors a palette does not use, and for transparency
+const UNUSED_COLOR: u16 = 0x7FFF;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GfxOptions {
+    /// Bits per pixel, 1 or 2
+    pub depth: u8,
+    /// Store each tile once, for the tilemap to reference it
+    pub unique_tiles: bool,
+    /// Also store once the tiles that are flips of another, flipped back by
+    /// their attributes
+    pub mirror_tiles: bool,
+}
+
+impl Default for GfxOptions {
+    fn default() -> Self {
+        return Self {
+            depth: 2,
+            unique_tiles: false,
+            mirror_tiles: false,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Graphics {
+    /// 8 bytes per tile row for 2 bits per pixel, the low bits first, 1 for 1
+    pub tiles: Vec<u8>,
+    pub tile_count: usize,
+    /// Index of the tile at each position of the image, the low 8 bits
+    pub tilemap: Vec<u8>,
+    /// CGB attributes of each position: palette, VRAM bank and flips
+    pub attrmap: Vec<u8>,
+    /// RGB555 colors, 4 per palette, little endian
+    pub palettes: Vec<u8>,
+    pub palette_count: usize,
+}
+
+/// Converts an image whose sides are multiples of 8. `palette` gives the
+/// colors of an indexed image by index, the pixels taking the index of the
+/// first one of their color.
+pub fn convert(image: &Image, palette: Option<&[[u8; 4]]>, options: &GfxOptions) -> Result<Graphics, String> {
+    if options.depth != 1 && options.depth != 2 {
+        return Err(format!("{} bits per pixel is not a depth of tiles, expected 1 or 2", options.depth));
+    }
+    if !image.width.is_multiple_of(TILE_SIZE) || !image.height.is_multiple_of(TILE_SIZE) || image.width == 0 || image.height == 0 {
+        return Err(format!("the image is {}x{}, its width and height must be multiples of 8", image.width, image.height));
+    }
+
+    let colors = 1 << options.depth;
+    let columns = image.width / TILE_SIZE;
+    let tiles: Vec<Vec<[u8; 4]>> = (0..columns * (image.height / TILE_SIZE)).map(|i| {
+        let (x0, y0) = (i % columns * TILE_SIZE, i / columns * TILE_SIZE);
+        return (0..TILE_SIZE * TILE_SIZE).map(|j| normalize(image.pixel(x0 + j % TILE_SIZE, y0 + j / TILE_SIZE))).collect();
+    }).collect();
+    let position = |tile: usize| (tile % columns * TILE_SIZE, tile / columns * TILE_SIZE);
+
+    // the palette and the color indices of each tile
+    let (palettes, indexed): (Vec<Palette>, Vec<(usize, Vec<u8>)>) = match palette {
+        Some(palette) => {
+            let palette: Vec<[u8; 4]> = palette.iter().map(|color| normalize(*color)).collect();
+            let mut indexed = vec![];
+            for (i, tile) in tiles.iter().enumerate() {
+                let indices: Vec<usize> = tile.iter().map(|color| palette.iter().position(|c| c == color).unwrap_or(0)).collect();
+                let used: BTreeSet<usize> = indices.iter().map(|index| index / colors).collect();
+                if used.len() > 1 {
+                    let (x, y) = position(i);
+                    let used: Vec<String> = used.iter().map(|palette| palette.to_string()).collect();
+                    return Err(format!("the tile at ({}, {}) has colors of palettes {}", x, y, used.join(" and ")));
+                }
+                let number = *used.first().unwrap();
+                indexed.push((number, indices.iter().map(|index| (index % colors) as u8).collect::<Vec<u8>>()));
+            }
+            (palette.chunks(colors).map(|colors| colors.to_vec()).collect(), indexed)
+        }
+        None => {
+            let palettes = pack_palettes(&tiles, colors, &position)?;
+            let indexed = tiles.iter().map(|tile| {
+                let set: BTreeSet<[u8; 4]> = tile.iter().copied().collect();
+                let number = palettes.iter().position(|palette| set.iter().all(|color| palette.contains(color))).unwrap();
+                let indices = tile.iter().map(|color| palettes[number].iter().position(|c| c == color).unwrap() as u8).collect();
+                return (number, indices);
+            }).collect();
+            (palettes, indexed)
+        }
+    };
+    if palettes.len() > MAX_PALETTES {
+        return Err(format!("the image needs {} palettes, more than the {} of the CGB", palettes.len(), MAX_PALETTES));
+    }
+
+    let mut graphics = Graphics {
+        palette_count: palettes.len(),
+        ..Graphics::default()
+    };
+    let mut stored: HashMap<Vec<u8>, usize> = HashMap::new();
+    for (palette, indices) in &indexed {
+        // the tile itself, then flipped horizontally, vertically and both ways
+        let flips: &[u8] = if options.mirror_tiles { &[0, ATTRIBUTE_X_FLIP, ATTRIBUTE_Y_FLIP, ATTRIBUTE_X_FLIP | ATTRIBUTE_Y_FLIP] } else { &[0] };
+        let found = flips.iter().find_map(|&flip| {
+            return stored.get(&encode_tile(&flipped(indices, flip), options.depth)).map(|index| (*index, flip));
+        });
+
+        let (index, flip) = match found.filter(|_| options.unique_tiles || options.mirror_tiles) {
+            Some(found) => found,
+            None => {
+                let data = encode_tile(indices, options.depth);
+                graphics.tiles.extend_from_slice(&data);
+                stored.entry(data).or_insert(graphics.tile_count);
+                graphics.tile_count += 1;
+                (graphics.tile_count - 1, 0)
+            }
+        };
+
+        let bank = if index >= 256 { ATTRIBUTE_BANK } else { 0 };
+        graphics.tilemap.push(index as u8);
+        graphics.attrmap.push(*palette as u8 | bank | flip);
+    }
+    if graphics.tile_count > MAX_TILES {
+        return Err(format!("the image has {} tiles, more than the {} a tilemap can address", graphics.tile_count, MAX_TILES));
+    }
+
+    for palette in &palettes {
+        for i in 0..4 {
+            let color = palette.get(i).filter(|color| color[3] != 0).map_or(UNUSED_COLOR, |color| rgb555(*color));
+            graphics.palettes.extend_from_slice(&color.to_le_bytes());
+        }
+    }
+
+    return Ok(graphics);
+}
+
+/// Groups the colors of the tiles into palettes of `colors` colors: the
+/// tiles with the most colors first, each in the first palette it fits in.
+fn pack_palettes(tiles: &[Vec<[u8; 4]>], colors: usize, position: &dyn Fn(usize) -> (usize, usize)) -> Result<Vec<Palette>, String> {
+    let mut sets: Vec<BTreeSet<[u8; 4]>> = vec![];
+    for (i, tile) in tiles.iter().enumerate() {
+        let set: BTreeSet<[u8; 4]> = tile.iter().copied().collect();
+        if set.len() > colors {
+            let (x, y) = position(i);
+            return Err(format!("the tile at ({}, {}) has {} colors, more than the {} of a palette", x, y, set.len(), colors));
+        }
+        if !sets.contains(&set) {
+            sets.push(set);
+        }
+    }
+    sets.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+
+    let mut palettes: Vec<BTreeSet<[u8; 4]>> = vec![];
+    for set in sets {
+        let fitting = palettes.iter().position(|palette| set.is_subset(palette))
+            .or_else(|| palettes.iter().position(|palette| palette.union(&set).count() <= colors));
+        match fitting {
+            Some(i) => palettes[i].extend(set),
+            None => palettes.push(set),
+        }
+    }
+
+    let palettes: Vec<Palette> = palettes.into_iter().map(|palette| {
+        let mut palette: Vec<[u8; 4]> = palette.into_iter().collect();
+        palette.sort_by_key(|color| (color[3] != 0, u32::MAX - lightness(*color), *color));
+        return palette;
+    }).collect();
+
+    // like rgbgfx, the shades of the DMG keep their index when they are the
+    // only colors, so black is 3 even without the grays
+    if let [palette] = palettes.as_slice() {
+        if let Some(shades) = dmg_shades(palette, colors) {
+            return Ok(vec![shades]);
+        }
+    }
+
+    return Ok(palettes);
+}
+
+/// The colors at the index of their shade, the gaps transparent, when they
+/// are all shades of the DMG that `colors` colors can index.
+fn dmg_shades(palette: &[[u8; 4]], colors: usize) -> Option<Palette> {
+    let mut shades = vec![[0, 0, 0, 0]; colors];
+    for color in palette {
+        // with 1 bit, white is 0 and black 1
+        let shade = SHADES.iter().position(|shade| shade == color)?;
+        if !(shade * (colors - 1)).is_multiple_of(3) {
+            return None;
+        }
+        shades[shade * (colors - 1) / 3] = *color;
+    }
+
+    return Some(shades);
+}
+
+// opaque colors are compared without their alpha, transparent ones are all
+// the same
+fn normalize(color: [u8; 4]) -> [u8; 4] {
+    if color[3] < 0x80 {
+        return [0, 0, 0, 0];
+    }
+    return [color[0], color[1], color[2], 0xFF];
+}
+
+fn lightness(color: [u8; 4]) -> u32 {
+    return 299 * color[0] as u32 + 587 * color[1] as u32 + 114 * color[2] as u32;
+}
+
+fn rgb555(color: [u8; 4]) -> u16 {
+    let channel = |value: u8| (value as u16 * 31 + 127) / 255;
+    return channel(color[0]) | channel(color[1]) << 5 | channel(color[2]) << 10;
+}
+
+fn flipped(indices: &[u8], flip: u8) -> Vec<u8> {
+    return (0..TILE_SIZE * TILE_SIZE).map(|i| {
+        let (mut x, mut y) = (i % TILE_SIZE, i / TILE_SIZE);
+        if flip & ATTRIBUTE_X_FLIP != 0 {
+            x = TILE_SIZE - 1 - x;
+        }
+        if flip & ATTRIBUTE_Y_FLIP != 0 {
+            y = TILE_SIZE - 1 - y;
+        }
+        return indices[y * TILE_SIZE + x];
+    }).collect();
+}
+
+/// The bytes of a tile from its 64 color indices, row by row.
+pub fn encode_tile(indices: &[u8], depth: u8) -> Vec<u8> {
+    let mut bytes = vec![];
+    for row in indices.chunks(TILE_SIZE) {
+        for plane in 0..depth {
+            let bits = row.iter().enumerate().fold(0, |byte, (x, index)| byte | ((index >> plane) & 1) << (7 - x));
+            bytes.push(bits);
+        }
+    }
+    return bytes;
+}
+
+/// The 64 color indices of a tile, row by row, from its bytes.
+pub fn decode_tile(bytes: &[u8], depth: u8) -> Vec<u8> {
+    let mut indices = vec![];
+    for row in bytes.chunks(depth as usize) {
+        for x in 0..TILE_SIZE {
+            let index = row.iter().enumerate().fold(0, |index, (plane, bits)| index | ((bits >> (7 - x)) & 1) << plane);
+            indices.push(index);
+        }
+    }
+    return indices;
+}
+
+/// How `render` lays out and colors tiles.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions<'a> {
+    pub depth: u8,
+    /// Tiles per row of the image, of the tilemap when there is one
+    pub columns: usize,
+    /// The tile of each position, all of the tiles in order without one
+    pub tilemap: Option<&'a [u8]>,
+    /// The palette, bank and flips of each position
+    pub attrmap: Option<&'a [u8]>,
+    /// RGB555 colors, 4 per palette, the shades of the DMG without them
+    pub palettes: Option<&'a [u8]>,
+}
+
+impl Default for RenderOptions<'_> {
+    fn default() -> Self {
+        return Self {
+            depth: 2,
+            columns: 16,
+            tilemap: None,
+            attrmap: None,
+            palettes: None,
+        };
+    }
+}
+
+/// Draws tile data as an image, the reverse of `convert`. Positions past
+/// the last one of a row that is not full are transparent.
+pub fn render(tiles: &[u8], options: &RenderOptions) -> Result<Image, String> {
+    if options.depth != 1 && options.depth != 2 {
+        return Err(format!("{} bits per pixel is not a depth of tiles, expected 1 or 2", options.depth));
+    }
+    let tile_size = TILE_SIZE * options.depth as usize;
+    if !tiles.len().is_multiple_of(tile_size) {
+        return Err(format!("{} bytes is not a whole number of tiles of {} bytes", tiles.len(), tile_size));
+    }
+    if options.columns == 0 {
+        return Err("an image needs at least one tile per row".to_string());
+    }
+
+    let tile_count = tiles.len() / tile_size;
+    let count = options.tilemap.map_or(tile_count, |tilemap| tilemap.len());
+    if let Some(attrmap) = options.attrmap.filter(|attrmap| attrmap.len() < count) {
+        return Err(format!("the attribute map has {} positions, fewer than the {} to draw", attrmap.len(), count));
+    }
+
+    // fewer positions than columns make a single row as wide as them
+    let columns = options.columns.min(count.max(1));
+    let mut image = Image::new(columns * TILE_SIZE, count.div_ceil(columns) * TILE_SIZE);
+    for position in 0..count {
+        let attributes = options.attrmap.map_or(0, |attrmap| attrmap[position]);
+        let tile = match options.tilemap {
+            Some(tilemap) => tilemap[position] as usize + if attributes & ATTRIBUTE_BANK != 0 { 256 } else { 0 },
+            None => position,
+        };
+        if tile >= tile_count {
+            return Err(format!("position {} of the tilemap is tile {}, there are {} tiles", position, tile, tile_count));
+        }
+
+        let indices = decode_tile(&tiles[tile * tile_size..(tile + 1) * tile_size], options.depth);
+        let flip = attributes & (ATTRIBUTE_X_FLIP | ATTRIBUTE_Y_FLIP);
+        let palette = (attributes & 0x07) as usize;
+        let (x0, y0) = (position % columns * TILE_SIZE, position / columns * TILE_SIZE);
+
+        for (i, index) in flipped(&indices, flip).into_iter().enumerate() {
+            let color = match options.palettes {
+                Some(palettes) => {
+                    let offset = 2 * (4 * palette + index as usize);
+                    let color = palettes.get(offset..offset + 2)
+                        .ok_or_else(|| format!("palette {} is not in the {} bytes of palettes", palette, palettes.len()))?;
+                    rgb888(u16::from_le_bytes([color[0], color[1]]))
+                }
+                // the darkest shade for the second color of 1 bit tiles
+                None => SHADES[if options.depth == 1 { index as usize * 3 } else { index as usize }],
+            };
+            image.set_pixel(x0 + i % TILE_SIZE, y0 + i / TILE_SIZE, color);
+        }
+    }
+
+    return Ok(image);
+}
+
+/// The bytes of a linked ROM at a label of `names`, or at a `BB:AAAA`
+/// bank and address. Without a size they go up to the next name in the
+/// bank, or to its end.
+pub fn rom_data<'a>(rom: &'a [u8], names: &[SymbolName], location: &str, size: Option<usize>) -> Result<&'a [u8], String> {
+    let parsed = location.split_once(':')
+        .and_then(|(bank, address)| Some((usize::from_str_radix(bank, 16).ok()?, usize::from_str_radix(address, 16).ok()?)));
+    let (bank, address) = match parsed {
+        Some(location) => location,
+        None => match names.iter().find(|name| name.name == location) {
+            Some(name) => (name.bank, name.address),
+            None => return Err(format!("`{}` is not a label of the ROM nor a bank and address", location)),
+        },
+    };
+    if address >= 2 * BANK_SIZE || (address < BANK_SIZE && bank != 0) {
+        return Err(format!("{:02X}:{:04X} is not in the ROM", bank, address));
+    }
+
+    let end_of_bank = BANK_SIZE - address % BANK_SIZE;
+    let size = size.unwrap_or_else(|| {
+        return names.iter()
+            .filter(|name| name.bank == bank && name.address > address && name.address < address + end_of_bank)
+            .map(|name| name.address - address)
+            .min()
+            .unwrap_or(end_of_bank);
+    });
+
+    let start = bank * BANK_SIZE + address % BANK_SIZE;
+    return rom.get(start..start + size)
+        .ok_or_else(|| format!("{} bytes at {:02X}:{:04X} go past the end of the ROM", size, bank, address));
+}
+
+fn rgb888(color: u16) -> [u8; 4] {
+    let channel = |shift: u16| ((((color >> shift) & 0x1F) * 255 + 15) / 31) as u8;
+    return [channel(0), channel(5), channel(10), 0xFF];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+    const GRAY: [u8; 4] = [0x80, 0x80, 0x80, 0xFF];
+    const BLACK: [u8; 4] = [0, 0, 0, 0xFF];
+    const RED: [u8; 4] = [0xFF, 0, 0, 0xFF];
+
+    // a tile with a diagonal of a color over another
+    fn draw_tile(image: &mut Image, x0: usize, background: [u8; 4], color: [u8; 4], mirrored: bool) {
+        for y in 0..8 {
+            for x in 0..8 {
+                let diagonal = if mirrored { 7 - x == y } else { x == y };
+                image.set_pixel(x0 + x, y, if diagonal { color } else { background });
+            }
+        }
+    }
+
+    #[test]
+    fn converting_tiles() {
+        let mut image = Image::new(32, 8);
+        draw_tile(&mut image, 0, WHITE, BLACK, false);
+        draw_tile(&mut image, 8, WHITE, GRAY, false);
+        draw_tile(&mut image, 16, WHITE, BLACK, false);
+        draw_tile(&mut image, 24, WHITE, BLACK, true);
+
+        let graphics = convert(&image, None, &GfxOptions::default()).unwrap();
+        assert_eq!((graphics.tile_count, graphics.tilemap.clone()), (4, vec![0, 1, 2, 3]));
+        // white, gray and black, the diagonal of the first tile in color 2
+        assert_eq!(graphics.palettes, [0xFF, 0x7F, 0x10, 0x42, 0x00, 0x00, 0xFF, 0x7F]);
+        assert_eq!(&graphics.tiles[..4], [0x00, 0x80, 0x00, 0x40]);
+        assert_eq!(&graphics.tiles[16..18], [0x80, 0x00]);
+
+        let unique = GfxOptions { unique_tiles: true, ..GfxOptions::default() };
+        let graphics = convert(&image, None, &unique).unwrap();
+        assert_eq!((graphics.tile_count, graphics.tilemap), (3, vec![0, 1, 0, 2]));
+
+        let mirror = GfxOptions { mirror_tiles: true, ..GfxOptions::default() };
+        let graphics = convert(&image, None, &mirror).unwrap();
+        assert_eq!((graphics.tile_count, graphics.tilemap), (2, vec![0, 1, 0, 0]));
+        assert_eq!(graphics.attrmap, [0, 0, 0, ATTRIBUTE_X_FLIP]);
+
+        let one_bit = GfxOptions { depth: 1, ..GfxOptions::default() };
+        // black and gray do not fit in a palette of 2 colors with white
+        assert_eq!(convert(&image, None, &one_bit).unwrap().palette_count, 2);
+    }
+
+    #[test]
+    fn rendering_tiles() {
+        let mut image = Image::new(16, 8);
+        draw_tile(&mut image, 0, WHITE, BLACK, false);
+        draw_tile(&mut image, 8, GRAY, BLACK, true);
+
+        let options = GfxOptions { mirror_tiles: true, ..GfxOptions::default() };
+        let graphics = convert(&image, None, &options).unwrap();
+        let rendered = render(&graphics.tiles, &RenderOptions {
+            columns: 2,
+            tilemap: Some(&graphics.tilemap),
+            attrmap: Some(&graphics.attrmap),
+            palettes: Some(&graphics.palettes),
+            ..RenderOptions::default()
+        }).unwrap();
+        // gray goes to 5 bits and back
+        let image = Image { pixels: image.pixels.iter().map(|c| if *c == GRAY { [0x84, 0x84, 0x84, 0xFF] } else { *c }).collect(), ..image };
+        assert_eq!(rendered, image);
+
+        // a sheet of the shades of the DMG, with the second row not full
+        let sheet = render(&[0xFF; 48], &RenderOptions { columns: 2, ..RenderOptions::default() }).unwrap();
+        assert_eq!((sheet.width, sheet.height), (16, 16));
+        assert_eq!((sheet.pixel(0, 8), sheet.pixel(8, 8)), (SHADES[3], [0, 0, 0, 0]));
+        assert!(render(&[0; 20], &RenderOptions::default()).is_err());
+
+        let names = [SymbolName { bank: 1, address: 0x4000, name: "Tiles".to_string() }, SymbolName { bank: 1, address: 0x4010, name: "Map".to_string() }];
+        let mut rom = vec![0; 2 * BANK_SIZE];
+        rom[BANK_SIZE] = 0x12;
+        assert_eq!(rom_data(&rom, &names, "Tiles", None).unwrap().len(), 16);
+        assert_eq!(rom_data(&rom, &names, "01:4000", Some(2)).unwrap(), [0x12, 0x00]);
+        assert_eq!(rom_data(&rom, &names, "Map", None).unwrap().len(), BANK_SIZE - 16);
+        assert!(rom_data(&rom, &names, "Font", None).is_err());
+    }
+
+    #[test]
+    fn keeping_dmg_shades() {
+        let mut image = Image::new(8, 8);
+        draw_tile(&mut image, 0, WHITE, BLACK, false);
+
+        // black is 3 without the grays, the indices in between unused
+        let graphics = convert(&image, None, &GfxOptions::default()).unwrap();
+        assert_eq!(&graphics.tiles[..2], [0x80, 0x80]);
+        assert_eq!(graphics.palettes, [0xFF, 0x7F, 0xFF, 0x7F, 0xFF, 0x7F, 0x00, 0x00]);
+
+        let one_bit = GfxOptions { depth: 1, ..GfxOptions::default() };
+        assert_eq!(convert(&image, None, &one_bit).unwrap().tiles[0], 0x80);
+    }
+
+    #[test]
+    fn splitting_palettes() {
+        let mut image = Image::new(16, 8);
+        draw_tile(&mut image, 0, WHITE, BLACK, false);
+        draw_tile(&mut image, 8, WHITE, RED, false);
+
+        let one_bit = GfxOptions { depth: 1, ..GfxOptions::default() };
+        let graphics = convert(&image, None, &one_bit).unwrap();
+        assert_eq!(graphics.palette_count, 2);
+        assert_eq!(graphics.attrmap, [0, 1]);
+        assert_eq!(&graphics.palettes[8..12], [0xFF, 0x7F, 0x1F, 0x00]);
+        assert_eq!(&graphics.tiles[..2], [0x80, 0x40]);
+
+        // the colors of an indexed image keep their indices
+        let palette = [WHITE, RED, BLACK, GRAY];
+        let graphics = convert(&image, Some(&palette), &GfxOptions::default()).unwrap();
+        assert_eq!(graphics.palette_count, 1);
+        assert_eq!(&graphics.tiles[..2], [0x00, 0x80]);
+        assert_eq!(&graphics.tiles[16..18], [0x80, 0x00]);
+
+        let error = convert(&image, Some(&palette), &one_bit).unwrap_err();
+        assert_eq!(error, "the tile at (0, 0) has colors of palettes 0 and 1");
+        assert!(convert(&Image::new(12, 8), None, &one_bit).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Fixing up the cartridge header of a ROM, between $0104 and $0150, the
+//! way the boot ROM and emulators expect it.
+//!
+//! The Nintendo logo, the ROM size and both checksums are always written;
+//! the title, the CGB and SGB flags, the cartridge type and the RAM size
+//! only when they are given.
+
+use std::ops::Range;
+
+use crate::link::BANK_SIZE;
+
+/// Logo the boot ROM compares before starting the cartridge
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+const LOGO: usize = 0x104;
+const TITLE: Range<usize> = 0x134..0x144;
+const CGB_FLAG: usize = 0x143;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HeaderOptions {
+    /// Up to 16 ASCII characters, 15 on the CGB where the last byte is its flag
+    pub title: Option<String>,
+    /// $80 for a game that also runs on the DMG, $C0 for the CGB only
+    pub cgb_flag: Option<u8>,
+    /// Enable the SGB functions
+    pub sgb: bool,
+    pub cartridge_type: Option<u8>,
+    pub ram_size: Option<u8>,
+}
+
+/// Writes the header of `rom`, first padding it with zeros to a power of two
+/// of at least 32 KiB like the linker outputs.
+pub fn fix(rom: &mut Vec<u8>, options: &HeaderOptions) -> Result<(), String> {
+    if rom.len() < HEADER_END {
+        return Err(format!("the ROM is {} byte(s) long, too short to hold a header", rom.len()));
+    }
+
+    let size = rom.len().max(2 * BANK_SIZE).next_power_of_two();
+    rom.resize(size, 0);
+
+    rom[LOGO..LOGO + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+
+    if let Some(title) = &options.title {
+        let room = if options.cgb_flag.is_some() { TITLE.len() - 1 } else { TITLE.len() };
+        if !title.is_ascii() || title.len() > room {
+            return Err(format!("the title `{}` is not made of at most {} ASCII characters", title, room));
+        }
+
+        rom[TITLE].fill(0);
+        rom[TITLE.start..TITLE.start + title.len()].copy_from_slice(title.as_bytes());
+    }
+
+    if let Some(flag) = options.cgb_flag {
+        rom[CGB_FLAG] = flag;
+    }
+    if options.sgb {
+        rom[SGB_FLAG] = 0x03;
+    }
+    if let Some(cartridge_type) = options.cartridge_type {
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+    }
+    if let Some(ram_size) = options.ram_size {
+        rom[RAM_SIZE] = ram_size;
+    }
+
+    // 32 KiB shifted left by the value
+    rom[ROM_SIZE] = (size / (2 * BANK_SIZE)).trailing_zeros() as u8;
+
+    rom[HEADER_CHECKSUM] = header_checksum(rom);
+    let checksum = global_checksum(rom);
+    rom[GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    return Ok(());
+}
+
+/// Checksum of $0134-$014C the boot ROM verifies.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    return rom[TITLE.start..HEADER_CHECKSUM].iter().fold(0u8, |checksum, byte| checksum.wrapping_sub(*byte).wrapping_sub(1));
+}
+
+/// Sum of every byte but the checksum itself, which nothing verifies.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    return rom.iter()
+        .enumerate()
+        .filter(|(offset, _)| !(GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2).contains(offset))
+        .fold(0u16, |checksum, (_, byte)| checksum.wrapping_add(*byte as u16));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixing_the_header() {
+        let mut rom = vec![0; 0x150];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+
+        let options = HeaderOptions {
+            title: Some("GAME".to_string()),
+            cgb_flag: Some(0x80),
+            sgb: true,
+            cartridge_type: Some(0x01),
+            ram_size: None,
+        };
+        fix(&mut rom, &options).unwrap();
+
+        assert_eq!(rom.len(), 2 * BANK_SIZE);
+        assert_eq!(rom[0x104..0x134], NINTENDO_LOGO);
+        assert_eq!(rom[0x134..0x144], *b"GAME\0\0\0\0\0\0\0\0\0\0\0\x80");
+        assert_eq!(rom[0x146..0x14A], [0x03, 0x01, 0x00, 0x00]);
+        assert_eq!(rom[0x14D], 0x49);
+
+        let sum = rom.iter().map(|byte| *byte as u16).sum::<u16>() - rom[0x14E] as u16 - rom[0x14F] as u16;
+        assert_eq!(u16::from_be_bytes([rom[0x14E], rom[0x14F]]), sum);
+    }
+
+    #[test]
+    fn rejecting_invalid_headers() {
+        let long_title = HeaderOptions { title: Some("SIXTEEN CHARS..!".to_string()), cgb_flag: Some(0xC0), ..HeaderOptions::default() };
+
+        assert_eq!(fix(&mut vec![0; 0x100], &HeaderOptions::default()), Err("the ROM is 256 byte(s) long, too short to hold a header".to_string()));
+        assert_eq!(fix(&mut vec![0; 0x150], &long_title), Err("the title `SIXTEEN CHARS..!` is not made of at most 15 ASCII characters".to_string()));
+
+        let mut rom = vec![0; 3 * BANK_SIZE];
+        fix(&mut rom, &HeaderOptions::default()).unwrap();
+        assert_eq!((rom.len(), rom[0x148]), (4 * BANK_SIZE, 1));
+    }
+}
@@ -0,0 +1,699 @@
+//! Reading and writing PPM and PNG images.
+//!
+//! PPM is read in its binary (P6, P5) and text (P3, P2) forms and written as
+//! P6. PNG is read in every color type and bit depth, interlaced or not, and
+//! written as 8 bit RGB or RGBA with uncompressed deflate blocks, which
+//! every PNG reader accepts.
+
+pub const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Colors of an indexed image, by index
+pub type Palette = Vec<[u8; 4]>;
+
+/// Pixels as RGBA, row by row from the top left corner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Image {
+    /// A transparent black image.
+    pub fn new(width: usize, height: usize) -> Self {
+        return Self {
+            width,
+            height,
+            pixels: vec![[0, 0, 0, 0]; width * height],
+        };
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        return self.pixels[y * self.width + x];
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    /// Whether the image is PNG or PPM is told by its first bytes.
+    pub fn decode(bytes: &[u8]) -> Result<Image, String> {
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            return decode_png(bytes);
+        }
+        if bytes.first() == Some(&b'P') {
+            return decode_ppm(bytes);
+        }
+
+        return Err("not a PNG or PPM image".to_string());
+    }
+
+    /// Like `decode`, with the palette of an indexed PNG image, in the order
+    /// of its indices.
+    pub fn decode_with_palette(bytes: &[u8]) -> Result<(Image, Option<Palette>), String> {
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            return decode_png_with_palette(bytes);
+        }
+        return Ok((Image::decode(bytes)?, None));
+    }
+
+    /// Where two images of the same size differ, as a message naming the
+    /// first pixel that does.
+    pub fn compare(&self, expected: &Image) -> Result<(), String> {
+        if (self.width, self.height) != (expected.width, expected.height) {
+            return Err(format!(
+                "the image is {}x{} instead of {}x{}",
+                self.width, self.height, expected.width, expected.height,
+            ));
+        }
+
+        let differing: Vec<usize> = (0..self.pixels.len()).filter(|&i| self.pixels[i] != expected.pixels[i]).collect();
+        let first = match differing.first() {
+            Some(first) => *first,
+            None => return Ok(()),
+        };
+
+        let hex = |color: [u8; 4]| format!("#{:02X}{:02X}{:02X}", color[0], color[1], color[2]);
+        return Err(format!(
+            "{} pixel(s) differ, the first at ({}, {}) is {} instead of {}",
+            differing.len(), first % self.width, first / self.width, hex(self.pixels[first]), hex(expected.pixels[first]),
+        ));
+    }
+}
+
+pub fn decode_ppm(bytes: &[u8]) -> Result<Image, String> {
+    let mut position = 0;
+
+    // header fields are separated by whitespace, with `#` comments
+    let field = |position: &mut usize| -> Result<String, String> {
+        loop {
+            match bytes.get(*position) {
+                Some(b'#') => {
+                    while bytes.get(*position).is_some_and(|&b| b != b'\n') {
+                        *position += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => *position += 1,
+                Some(_) => break,
+                None => return Err("the PPM image ends in its header".to_string()),
+            }
+        }
+
+        let start = *position;
+        while bytes.get(*position).is_some_and(|b| !b.is_ascii_whitespace()) {
+            *position += 1;
+        }
+        return Ok(String::from_utf8_lossy(&bytes[start..*position]).to_string());
+    };
+
+    let magic = field(&mut position)?;
+    let channels = match magic.as_str() {
+        "P3" | "P6" => 3,
+        "P2" | "P5" => 1,
+        _ => return Err(format!("unsupported PPM format `{}`", magic)),
+    };
+
+    let number = |position: &mut usize, name: &str| -> Result<usize, String> {
+        let text = field(position)?;
+        return text.parse().map_err(|_| format!("invalid PPM {} `{}`", name, text));
+    };
+    let width = number(&mut position, "width")?;
+    let height = number(&mut position, "height")?;
+    let maximum = number(&mut position, "maximum value")?;
+    if !(1..=65535).contains(&maximum) {
+        return Err(format!("invalid PPM maximum value {}", maximum));
+    }
+
+    let count = width * height * channels;
+    let samples: Vec<usize> = if magic == "P3" || magic == "P2" {
+        let mut samples = Vec::with_capacity(count);
+        for _ in 0..count {
+            samples.push(number(&mut position, "value")?);
+        }
+        samples
+    } else {
+        // a single whitespace byte separates the header from the samples
+        let data = &bytes[(position + 1).min(bytes.len())..];
+        let size = if maximum > 255 { 2 } else { 1 };
+        if data.len() < count * size {
+            return Err("the PPM image is missing pixels".to_string());
+        }
+        (0..count).map(|i| {
+            return match size {
+                2 => u16::from_be_bytes([data[2 * i], data[2 * i + 1]]) as usize,
+                _ => data[i] as usize,
+            };
+        }).collect()
+    };
+
+    let scale = |sample: usize| (sample.min(maximum) * 255 / maximum) as u8;
+    let pixels = samples.chunks(channels).map(|pixel| {
+        return match pixel {
+            [r, g, b] => [scale(*r), scale(*g), scale(*b), 255],
+            _ => [scale(pixel[0]), scale(pixel[0]), scale(pixel[0]), 255],
+        };
+    }).collect();
+
+    return Ok(Image {
+        width,
+        height,
+        pixels,
+    });
+}
+
+/// P6, without the alpha channel.
+pub fn encode_ppm(image: &Image) -> Vec<u8> {
+    let mut bytes = format!("P6\n{} {}\n255\n", image.width, image.height).into_bytes();
+    for pixel in &image.pixels {
+        bytes.extend(&pixel[..3]);
+    }
+
+    return bytes;
+}
+
+pub fn decode_png(bytes: &[u8]) -> Result<Image, String> {
+    return decode_png_with_palette(bytes).map(|(image, _)| image);
+}
+
+fn decode_png_with_palette(bytes: &[u8]) -> Result<(Image, Option<Palette>), String> {
+    if !bytes.starts_with(&PNG_SIGNATURE) {
+        return Err("not a PNG image".to_string());
+    }
+
+    let mut header = None;
+    let mut palette: Vec<[u8; 4]> = vec![];
+    let mut transparency: Vec<u8> = vec![];
+    let mut data = vec![];
+
+    let mut position = PNG_SIGNATURE.len();
+    loop {
+        if position + 12 > bytes.len() {
+            return Err("the PNG image ends before its IEND chunk".to_string());
+        }
+
+        let length = u32::from_be_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+        let end = position + 8 + length;
+        if end + 4 > bytes.len() {
+            return Err("the PNG image ends in a chunk".to_string());
+        }
+
+        let kind = &bytes[position + 4..position + 8];
+        let content = &bytes[position + 8..end];
+        let crc = u32::from_be_bytes(bytes[end..end + 4].try_into().unwrap());
+        if crc32(&bytes[position + 4..end]) != crc {
+            return Err(format!("the PNG {} chunk is corrupted", String::from_utf8_lossy(kind)));
+        }
+        position = end + 4;
+
+        match kind {
+            b"IHDR" if length == 13 => {
+                let number = |i: usize| u32::from_be_bytes(content[i..i + 4].try_into().unwrap()) as usize;
+                header = Some(PngHeader {
+                    width: number(0),
+                    height: number(4),
+                    depth: content[8],
+                    color_type: content[9],
+                    interlaced: content[12] == 1,
+                });
+            }
+            b"PLTE" => palette = content.chunks_exact(3).map(|c| [c[0], c[1], c[2], 255]).collect(),
+            b"tRNS" => transparency = content.to_vec(),
+            b"IDAT" => data.extend_from_slice(content),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or("the PNG image has no IHDR chunk")?;
+    let channels = match (header.color_type, header.depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => 1,
+        (3, 1 | 2 | 4 | 8) => 1,
+        (4, 8 | 16) => 2,
+        (2, 8 | 16) => 3,
+        (6, 8 | 16) => 4,
+        _ => return Err(format!("invalid PNG color type {} with depth {}", header.color_type, header.depth)),
+    };
+    for (i, alpha) in transparency.iter().enumerate() {
+        if header.color_type == 3 && i < palette.len() {
+            palette[i][3] = *alpha;
+        }
+    }
+
+    let raw = zlib_decompress(&data)?;
+    let bits = channels * header.depth as usize;
+    let mut image = Image::new(header.width, header.height);
+
+    // the 7 passes of Adam7 as x, y, x step and y step, or a single pass
+    let passes: &[(usize, usize, usize, usize)] = if header.interlaced {
+        &[(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)]
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+
+    let mut offset = 0;
+    for &(x0, y0, dx, dy) in passes {
+        let width = header.width.saturating_sub(x0).div_ceil(dx);
+        let height = header.height.saturating_sub(y0).div_ceil(dy);
+        if width == 0 || height == 0 {
+            continue;
+        }
+
+        let stride = (width * bits).div_ceil(8);
+        let size = (stride + 1) * height;
+        if raw.len() < offset + size {
+            return Err("the PNG image is missing pixels".to_string());
+        }
+
+        let rows = unfilter(&raw[offset..offset + size], stride, bits.div_ceil(8))?;
+        offset += size;
+
+        for (y, row) in rows.chunks(stride).enumerate() {
+            for x in 0..width {
+                let color = png_pixel(row, x, &header, channels, &palette, &transparency)?;
+                image.set_pixel(x0 + x * dx, y0 + y * dy, color);
+            }
+        }
+    }
+
+    let palette = if header.color_type == 3 { Some(palette) } else { None };
+    return Ok((image, palette));
+}
+
+struct PngHeader {
+    width: usize,
+    height: usize,
+    depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+// removes the filter byte of each row, returning the rows one after the other
+fn unfilter(data: &[u8], stride: usize, pixel_size: usize) -> Result<Vec<u8>, String> {
+    let mut rows = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; stride];
+
+    for line in data.chunks(stride + 1) {
+        let filter = line[0];
+        let mut row = line[1..].to_vec();
+
+        for i in 0..stride {
+            let left = if i >= pixel_size { row[i - pixel_size] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= pixel_size { previous[i - pixel_size] } else { 0 };
+
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(format!("invalid PNG filter {}", filter)),
+            };
+            row[i] = row[i].wrapping_add(predicted);
+        }
+
+        rows.extend_from_slice(&row);
+        previous = row;
+    }
+
+    return Ok(rows);
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (a, b, c) = ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+
+    if a <= b && a <= c {
+        return left;
+    }
+    if b <= c {
+        return up;
+    }
+    return up_left;
+}
+
+fn png_pixel(row: &[u8], x: usize, header: &PngHeader, channels: usize, palette: &[[u8; 4]], transparency: &[u8]) -> Result<[u8; 4], String> {
+    let depth = header.depth as usize;
+
+    // samples scaled to 8 bits, 16 bit ones by their high byte
+    let sample = |index: usize| -> u8 {
+        return match depth {
+            16 => row[index * 2],
+            8 => row[index],
+            _ => {
+                let bit = index * depth;
+                let value = (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8;
+                (value as usize * 255 / ((1 << depth) - 1)) as u8
+            }
+        };
+    };
+    let raw = |index: usize| -> u16 {
+        return match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth;
+                ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8) as u16
+            }
+        };
+    };
+    // the color that tRNS makes transparent, for gray and RGB images
+    let key = |i: usize| transparency.get(2 * i..2 * i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+    let first = x * channels;
+    return match header.color_type {
+        0 => {
+            let gray = sample(first);
+            let alpha = if key(0) == Some(raw(first)) { 0 } else { 255 };
+            Ok([gray, gray, gray, alpha])
+        }
+        3 => {
+            let index = raw(first) as usize;
+            palette.get(index).copied().ok_or(format!("PNG palette index {} out of range", index))
+        }
+        4 => Ok([sample(first), sample(first), sample(first), sample(first + 1)]),
+        2 => {
+            let transparent = (0..3).all(|i| key(i) == Some(raw(first + i)));
+            Ok([sample(first), sample(first + 1), sample(first + 2), if transparent { 0 } else { 255 }])
+        }
+        _ => Ok([sample(first), sample(first + 1), sample(first + 2), sample(first + 3)]),
+    };
+}
+
+/// 8 bit RGB, or RGBA when a pixel is not opaque.
+pub fn encode_png(image: &Image) -> Vec<u8> {
+    let opaque = image.pixels.iter().all(|pixel| pixel[3] == 255);
+    let channels = if opaque { 3 } else { 4 };
+
+    let mut raw = Vec::with_capacity((image.width * channels + 1) * image.height);
+    for row in image.pixels.chunks(image.width.max(1)).take(image.height) {
+        raw.push(0);
+        for pixel in row {
+            raw.extend_from_slice(&pixel[..channels]);
+        }
+    }
+
+    let mut header = vec![];
+    header.extend((image.width as u32).to_be_bytes());
+    header.extend((image.height as u32).to_be_bytes());
+    header.extend([8, if opaque { 2 } else { 6 }, 0, 0, 0]);
+
+    let mut bytes = PNG_SIGNATURE.to_vec();
+    for (kind, content) in [(b"IHDR", header), (b"IDAT", zlib_store(&raw)), (b"IEND", vec![])] {
+        bytes.extend((content.len() as u32).to_be_bytes());
+        let start = bytes.len();
+        bytes.extend(kind);
+        bytes.extend(&content);
+        let crc = crc32(&bytes[start..]);
+        bytes.extend(crc.to_be_bytes());
+    }
+
+    return bytes;
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    return !crc;
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    return (b << 16) | a;
+}
+
+// zlib stream of uncompressed deflate blocks
+fn zlib_store(data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(0xFFFF).collect() };
+
+    for (i, block) in blocks.iter().enumerate() {
+        bytes.push((i == blocks.len() - 1) as u8);
+        bytes.extend((block.len() as u16).to_le_bytes());
+        bytes.extend((!(block.len() as u16)).to_le_bytes());
+        bytes.extend_from_slice(block);
+    }
+    bytes.extend(adler32(data).to_be_bytes());
+
+    return bytes;
+}
+
+pub fn zlib_decompress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.len() < 6 || bytes[0] & 0x0F != 8 || !u16::from_be_bytes([bytes[0], bytes[1]]).is_multiple_of(31) || bytes[1] & 0x20 != 0 {
+        return Err("invalid zlib header".to_string());
+    }
+
+    let data = inflate(&bytes[2..])?;
+    let checksum = bytes.len().checked_sub(4).map(|end| u32::from_be_bytes(bytes[end..].try_into().unwrap()));
+    if checksum != Some(adler32(&data)) {
+        return Err("zlib checksum mismatch".to_string());
+    }
+
+    return Ok(data);
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// order of the code lengths of the code length alphabet in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// In bits
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u8) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.bytes.get(self.position / 8).ok_or("the deflate data ends early")?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+
+        return Ok(value);
+    }
+}
+
+// canonical Huffman code as the number of codes of each length and the
+// symbols ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[s as usize] != 0).collect();
+        symbols.sort_by_key(|&s| lengths[s as usize]);
+
+        return Self { counts, symbols };
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        return Err("invalid deflate code".to_string());
+    }
+}
+
+/// Decompresses raw deflate data.
+pub fn inflate(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { bytes, position: 0 };
+    let mut output = vec![];
+
+    loop {
+        let last = reader.bits(1)? == 1;
+
+        match reader.bits(2)? {
+            0 => {
+                let start = reader.position.div_ceil(8);
+                let header = bytes.get(start..start + 4).ok_or("the deflate data ends early")?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let block = bytes.get(start + 4..start + 4 + length).ok_or("the deflate data ends early")?;
+                output.extend_from_slice(block);
+                reader.position = (start + 4 + length) * 8;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut reader, &literals, &distances, &mut output)?;
+            }
+            2 => {
+                let literal_count = reader.bits(5)? as usize + 257;
+                let distance_count = reader.bits(5)? as usize + 1;
+                let code_length_count = reader.bits(4)? as usize + 4;
+
+                let mut code_lengths = [0u8; 19];
+                for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+                    code_lengths[index] = reader.bits(3)? as u8;
+                }
+                let code_lengths = Huffman::new(&code_lengths);
+
+                let mut lengths = vec![];
+                while lengths.len() < literal_count + distance_count {
+                    let symbol = code_lengths.decode(&mut reader)?;
+                    let (value, repeat) = match symbol {
+                        0..=15 => (symbol as u8, 1),
+                        16 => (*lengths.last().ok_or("invalid deflate code lengths")?, 3 + reader.bits(2)?),
+                        17 => (0, 3 + reader.bits(3)?),
+                        _ => (0, 11 + reader.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(value, repeat as usize));
+                }
+                if lengths.len() > literal_count + distance_count {
+                    return Err("invalid deflate code lengths".to_string());
+                }
+
+                let literals = Huffman::new(&lengths[..literal_count]);
+                let distances = Huffman::new(&lengths[literal_count..]);
+                inflate_block(&mut reader, &literals, &distances, &mut output)?;
+            }
+            _ => return Err("invalid deflate block type".to_string()),
+        }
+
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+fn inflate_block(reader: &mut BitReader<'_>, literals: &Huffman, distances: &Huffman, output: &mut Vec<u8>) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASES.len() {
+            return Err("invalid deflate length".to_string());
+        }
+        let length = LENGTH_BASES[index] as usize + reader.bits(LENGTH_EXTRA_BITS[index])? as usize;
+
+        let index = distances.decode(reader)? as usize;
+        if index >= DISTANCE_BASES.len() {
+            return Err("invalid deflate distance".to_string());
+        }
+        let distance = DISTANCE_BASES[index] as usize + reader.bits(DISTANCE_EXTRA_BITS[index])? as usize;
+        if distance > output.len() {
+            return Err("invalid deflate distance".to_string());
+        }
+
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_png() {
+        // 5x3, 2 bit palette with a transparent first color, rows filtered
+        // with none, up and Paeth
+        let indexed = Image::decode(&[
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00,
+            0x00, 0x05, 0x00, 0x00, 0x00, 0x03, 0x02, 0x03, 0x00, 0x00, 0x00, 0x26, 0x58, 0x2D, 0x6B, 0x00, 0x00, 0x00,
+            0x0C, 0x50, 0x4C, 0x54, 0x45, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFB,
+            0x00, 0x60, 0xF6, 0x00, 0x00, 0x00, 0x01, 0x74, 0x52, 0x4E, 0x53, 0x00, 0x40, 0xE6, 0xD8, 0x66, 0x00, 0x00,
+            0x00, 0x11, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0x90, 0x76, 0x60, 0xBA, 0xEA, 0xC0, 0x52, 0x73, 0x05,
+            0x00, 0x09, 0xAE, 0x02, 0xC7, 0x31, 0x73, 0xEE, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE,
+            0x42, 0x60, 0x82,
+        ]).unwrap();
+        let colors = [[255, 0, 0, 0], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 255, 255]];
+        let indices = [0, 1, 2, 3, 1, 3, 3, 0, 0, 2, 1, 2, 3, 0, 1];
+        assert_eq!((indexed.width, indexed.height), (5, 3));
+        assert_eq!(indexed.pixels, indices.map(|i| colors[i]));
+
+        // 3x3 RGB interlaced with Adam7
+        let interlaced = Image::decode(&[
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00,
+            0x00, 0x03, 0x00, 0x00, 0x00, 0x03, 0x08, 0x02, 0x00, 0x00, 0x01, 0xAE, 0x4D, 0x12, 0x7E, 0x00, 0x00, 0x00,
+            0x24, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0x64, 0x60, 0x60, 0x60, 0x5C, 0xC0, 0x10, 0xC0, 0xC8, 0x70,
+            0x22, 0x00, 0x44, 0x05, 0x30, 0x68, 0x30, 0x6B, 0x9C, 0x48, 0x61, 0x64, 0x48, 0xD1, 0x00, 0x32, 0x81, 0x08,
+            0x00, 0x65, 0x72, 0x06, 0x49, 0xB7, 0x4D, 0xFE, 0xA3, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE,
+            0x42, 0x60, 0x82,
+        ]).unwrap();
+        for (i, pixel) in interlaced.pixels.iter().enumerate() {
+            let (x, y) = (i % 3, i / 3);
+            assert_eq!(*pixel, [x as u8 * 80, y as u8 * 100, (x + y) as u8 * 40, 255]);
+        }
+
+        assert_eq!(decode_png(&encode_png(&indexed)).unwrap(), indexed);
+    }
+
+    #[test]
+    fn inflating_dynamic_blocks() {
+        let text: Vec<String> = (0..60).map(|i| format!("the quick brown fox {} jumps over the lazy dog", i % 7)).collect();
+        let data = zlib_decompress(&[
+            0x78, 0xDA, 0xED, 0xD0, 0xB7, 0x11, 0x80, 0x40, 0x10, 0x04, 0xC1, 0x54, 0x36, 0x04, 0x74, 0x3E, 0x88, 0x47,
+            0xC3, 0xC1, 0xF3, 0xC8, 0xE8, 0xA1, 0x30, 0xF1, 0xCE, 0xC0, 0x5B, 0x73, 0xAA, 0xC6, 0x6A, 0x57, 0x1B, 0xCC,
+            0x6B, 0x93, 0x77, 0xC8, 0xAC, 0xEC, 0x23, 0x4A, 0x39, 0xE0, 0xA1, 0x5D, 0x87, 0x69, 0x81, 0x6C, 0xC6, 0xC2,
+            0x3D, 0x43, 0x9F, 0x5E, 0x27, 0x0A, 0xA9, 0xDE, 0xF8, 0xDE, 0xBE, 0xEA, 0x0E, 0x54, 0x77, 0xA8, 0xBA, 0x23,
+            0xD5, 0x1D, 0xAB, 0xEE, 0x44, 0x75, 0x53, 0x90, 0x82, 0x14, 0xA4, 0x20, 0x05, 0x29, 0x48, 0x41, 0x0A, 0x52,
+            0xF0, 0x67, 0xC1, 0x1B, 0x0D, 0xF1, 0xD9, 0x58,
+        ]).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), text.join(" "));
+    }
+
+    #[test]
+    fn reading_ppm() {
+        let text = Image::decode(b"P3\n# a comment\n2 1\n15\n15 0 0  0 0 15\n").unwrap();
+        assert_eq!(text.pixels, [[255, 0, 0, 255], [0, 0, 255, 255]]);
+        assert_eq!(decode_ppm(&encode_ppm(&text)).unwrap(), text);
+
+        let mut other = text.clone();
+        other.set_pixel(1, 0, [0, 0, 0, 255]);
+        assert_eq!(other.compare(&text).unwrap_err(), "1 pixel(s) differ, the first at (1, 0) is #000000 instead of #0000FF");
+    }
+}
@@ -0,0 +1,420 @@
+use std::fmt::Write;
+
+/// Minimal JSON document model. Objects keep their keys in insertion order so
+/// the emitted output is stable between runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object(fields: Vec<(&str, Value)>) -> Value {
+        return Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    }
+
+    pub fn string(s: &str) -> Value {
+        return Value::String(s.to_string());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        if let Value::Object(fields) = self {
+            return fields.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+        }
+
+        return None;
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        return match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        };
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        return match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        };
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        return match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        };
+    }
+
+    pub fn to_string_pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, Some(0));
+
+        return out;
+    }
+
+    pub fn to_string_compact(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, None);
+
+        return out;
+    }
+
+    fn write(&self, out: &mut String, indent: Option<usize>) {
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) => write!(out, "{}", n).unwrap(),
+            Value::String(s) => write_string(out, s),
+            Value::Array(items) => {
+                if items.is_empty() {
+                    out.push_str("[]");
+                    return;
+                }
+
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    new_line(out, indent.map(|level| level + 1));
+                    item.write(out, indent.map(|level| level + 1));
+                }
+                new_line(out, indent);
+                out.push(']');
+            }
+            Value::Object(fields) => {
+                if fields.is_empty() {
+                    out.push_str("{}");
+                    return;
+                }
+
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    new_line(out, indent.map(|level| level + 1));
+                    write_string(out, key);
+                    out.push(':');
+                    if indent.is_some() {
+                        out.push(' ');
+                    }
+                    value.write(out, indent.map(|level| level + 1));
+                }
+                new_line(out, indent);
+                out.push('}');
+            }
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        return Value::String(s.to_string());
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        return Value::String(s);
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        return Value::Number(n);
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        return Value::Number(n as i64);
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        return Value::Bool(b);
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        return match o {
+            Some(v) => v.into(),
+            None => Value::Null,
+        };
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        return Value::Array(items.into_iter().map(|i| i.into()).collect());
+    }
+}
+
+fn new_line(out: &mut String, indent: Option<usize>) {
+    if let Some(level) = indent {
+        out.push('\n');
+        for _ in 0..level {
+            out.push_str("  ");
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Parses a JSON document. Numbers with a fraction or an exponent are
+/// truncated to integers.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+    };
+
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.position < parser.chars.len() {
+        return Err(format!("unexpected `{}` after the document", parser.chars[parser.position]));
+    }
+
+    return Ok(value);
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.position).copied();
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        return match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+            None => Err(format!("expected `{}`, found the end of the document", expected)),
+        };
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+
+        return match self.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => self.parse_string().map(Value::String),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => {
+                for (word, value) in [("null", Value::Null), ("true", Value::Bool(true)), ("false", Value::Bool(false))] {
+                    let end = self.position + word.len();
+                    if end <= self.chars.len() && self.chars[self.position..end].iter().copied().eq(word.chars()) {
+                        self.position = end;
+                        return Ok(value);
+                    }
+                }
+
+                Err(format!("unexpected `{}`", self.chars[self.position]))
+            }
+            None => Err("unexpected end of the document".to_string()),
+        };
+    }
+
+    fn parse_object(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut fields = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Value::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err("expected a key".to_string());
+            }
+            let key = self.parse_string()?;
+            self.expect(':')?;
+            fields.push((key, self.parse_value()?));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.position += 1,
+                _ => break,
+            }
+        }
+
+        self.expect('}')?;
+
+        return Ok(Value::Object(fields));
+    }
+
+    fn parse_array(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut items = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Value::Array(items));
+        }
+
+        loop {
+            items.push(self.parse_value()?);
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.position += 1,
+                _ => break,
+            }
+        }
+
+        self.expect(']')?;
+
+        return Ok(Value::Array(items));
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut s = String::new();
+
+        loop {
+            let c = self.peek().ok_or("unterminated string")?;
+            self.position += 1;
+
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = self.peek().ok_or("unterminated string")?;
+                    self.position += 1;
+
+                    match escaped {
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            let mut code = self.parse_hex()?;
+                            // surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && self.chars[self.position..].starts_with(&['\\', 'u']) {
+                                self.position += 2;
+                                let low = self.parse_hex()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            s.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                        }
+                        other => s.push(other),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_hex(&mut self) -> Result<u32, String> {
+        let end = self.position + 4;
+        if end > self.chars.len() {
+            return Err("unterminated string".to_string());
+        }
+
+        let digits: String = self.chars[self.position..end].iter().collect();
+        self.position = end;
+
+        return u32::from_str_radix(&digits, 16).map_err(|_| format!("invalid escape `\\u{}`", digits));
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+            self.position += 1;
+        }
+
+        let text: String = self.chars[start..self.position].iter().collect();
+        if let Ok(n) = text.parse::<i64>() {
+            return Ok(Value::Number(n));
+        }
+
+        return match text.parse::<f64>() {
+            Ok(n) => Ok(Value::Number(n as i64)),
+            Err(_) => Err(format!("invalid number `{}`", text)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_values() {
+        let value = Value::object(vec![
+            ("kind", "include".into()),
+            ("path", "a \"b\"\n".into()),
+            ("items", vec![1i64, 2].into()),
+            ("help", Value::Null),
+        ]);
+
+        assert_eq!(value.to_string_compact(), r#"{"kind":"include","path":"a \"b\"\n","items":[1,2],"help":null}"#);
+        assert_eq!(value.to_string_pretty(), concat!(
+            "{\n",
+            "  \"kind\": \"include\",\n",
+            "  \"path\": \"a \\\"b\\\"\\n\",\n",
+            "  \"items\": [\n",
+            "    1,\n",
+            "    2\n",
+            "  ],\n",
+            "  \"help\": null\n",
+            "}",
+        ));
+    }
+
+    #[test]
+    fn parsing_values() {
+        let text = r#" {"id": 1, "params": {"text": "a\"\n\u00e9\ud83d\ude00", "list": [true, null, -2.5e1]}, "empty": {}} "#;
+        let value = parse(text).unwrap();
+
+        assert_eq!(value.get("id").and_then(Value::as_i64), Some(1));
+        let params = value.get("params").unwrap();
+        assert_eq!(params.get("text").and_then(Value::as_str), Some("a\"\né😀"));
+        assert_eq!(params.get("list").and_then(Value::as_array), Some(&[Value::Bool(true), Value::Null, Value::Number(-25)][..]));
+        assert_eq!(parse(&value.to_string_compact()), Ok(value));
+
+        assert!(parse("{\"a\": }").is_err());
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("1 2").is_err());
+    }
+}
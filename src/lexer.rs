@@ -12,6 +12,11 @@ pub enum TokenType {
     Dot,
     Colon,
     SemiColon,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Operator,
 
     Number,
     Identifier,
@@ -19,10 +24,105 @@ pub enum TokenType {
     EOF,
 }
 
+impl TokenType {
+    /// Stable name used in the machine readable output.
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            TokenType::Unknown => "unknown",
+            TokenType::Space => "space",
+            TokenType::Tab => "tab",
+            TokenType::LineBreak => "line_break",
+            TokenType::Slash => "slash",
+            TokenType::DoubleQuote => "double_quote",
+            TokenType::Comma => "comma",
+            TokenType::Dot => "dot",
+            TokenType::Colon => "colon",
+            TokenType::SemiColon => "semicolon",
+            TokenType::LeftParen => "left_paren",
+            TokenType::RightParen => "right_paren",
+            TokenType::LeftBracket => "left_bracket",
+            TokenType::RightBracket => "right_bracket",
+            TokenType::Operator => "operator",
+            TokenType::Number => "number",
+            TokenType::Identifier => "identifier",
+            TokenType::EOF => "eof",
+        };
+    }
+}
+
+/// Byte offsets into the lexed source, `end` being exclusive.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn to(&self, other: Span) -> Span {
+        return Span {
+            start: self.start,
+            end: other.end,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TriviaKind {
+    /// Run of spaces and tabs
+    Whitespace,
+    /// `;` up to the end of the line
+    Comment,
+}
+
+/// Source text without meaning for the parser, kept next to the tokens so
+/// tools can reprint a file with its comments and layout.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub literal: String,
     pub token_type: TokenType,
+    pub span: Span,
+    /// Trivia in front of the token, only ever at the start of a line.
+    pub leading_trivia: Vec<Trivia>,
+    /// Trivia after the token up to the end of its line.
+    pub trailing_trivia: Vec<Trivia>,
+}
+
+impl Token {
+    pub fn new(literal: &str, token_type: TokenType, span: Span) -> Self {
+        return Self {
+            literal: literal.to_string(),
+            token_type,
+            span,
+            leading_trivia: vec![],
+            trailing_trivia: vec![],
+        };
+    }
+
+    /// The token with its leading and trailing trivia, as found in the source.
+    pub fn full_text(&self) -> String {
+        let mut text = String::new();
+
+        for trivia in &self.leading_trivia {
+            text += &trivia.text;
+        }
+        text += &self.literal;
+        for trivia in &self.trailing_trivia {
+            text += &trivia.text;
+        }
+
+        return text;
+    }
+
+    pub fn is_trivia(&self) -> bool {
+        return self.token_type == TokenType::Space || self.token_type == TokenType::Tab;
+    }
 }
 
 #[derive(Debug)]
@@ -36,6 +136,10 @@ pub struct Lexer {
     pub position: usize,
     // current reading position in input (after current char)
     pub read_position: usize,
+    // byte offset of the current char
+    pub offset: usize,
+    // byte offset of the char after the current one
+    pub read_offset: usize,
     // current char under examination
     pub ch: Option<char>,
 }
@@ -50,6 +154,8 @@ impl Lexer {
             input_size,
             position: 0,
             read_position: 0,
+            offset: 0,
+            read_offset: 0,
             ch: None,
         };
 
@@ -59,157 +165,295 @@ impl Lexer {
     }
 
     pub fn retrieve_next_token(&mut self) -> Result<Token, LexingError> {
-        self.read_char();
-
         if let Some(c) = self.ch {
-            return match c {
-                '\n' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::LineBreak,
-                }),
-                ' ' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::Space,
-                }),
-                '\t' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::Tab,
-                }),
-                '"' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::DoubleQuote,
-                }),
-                '.' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::Dot,
-                }),
-                ',' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::Comma,
-                }),
-                '/' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::Slash,
-                }),
-                ';' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::SemiColon,
-                }),
-                ':' => Ok(Token {
-                    literal: c.to_string(),
-                    token_type: TokenType::Colon,
-                }),
-                '$' => Ok(Token {
-                    literal: self.read_number(),
-                    token_type: TokenType::Number,
-                }),
-                _ => {
-                    return if c.is_alphabetic() {
-                        let identifier = self.read_identifier();
+            let start = self.offset;
 
-                        Ok(Token {
-                            literal: identifier,
-                            token_type: TokenType::Identifier,
-                        })
+            let (literal, token_type) = match c {
+                '\n' => (self.read_single(), TokenType::LineBreak),
+                '\r' if self.peek_char() == Some('\n') => {
+                    let literal = self.read_single();
+                    (literal + &self.read_single(), TokenType::LineBreak)
+                }
+                ' ' => (self.read_single(), TokenType::Space),
+                '\t' => (self.read_single(), TokenType::Tab),
+                '"' => (self.read_single(), TokenType::DoubleQuote),
+                '.' => (self.read_single(), TokenType::Dot),
+                ',' => (self.read_single(), TokenType::Comma),
+                '/' => (self.read_single(), TokenType::Slash),
+                ';' => (self.read_single(), TokenType::SemiColon),
+                ':' => (self.read_single(), TokenType::Colon),
+                '(' => (self.read_single(), TokenType::LeftParen),
+                ')' => (self.read_single(), TokenType::RightParen),
+                '[' => (self.read_single(), TokenType::LeftBracket),
+                ']' => (self.read_single(), TokenType::RightBracket),
+                '$' => (self.read_number(16), TokenType::Number),
+                '%' if self.peek_char().is_some_and(|n| n == '0' || n == '1') => (self.read_number(2), TokenType::Number),
+                '&' if self.peek_char().is_some_and(|n| n.is_digit(8)) => (self.read_number(8), TokenType::Number),
+                '+' | '-' | '*' | '%' | '&' | '|' | '^' | '~' | '!' | '=' | '<' | '>' => {
+                    (self.read_operator(), TokenType::Operator)
+                }
+                _ => {
+                    if c.is_ascii_digit() {
+                        (self.read_decimal(), TokenType::Number)
+                    } else if c.is_alphabetic() || c == '_' {
+                        (self.read_identifier(), TokenType::Identifier)
                     } else {
-                        Ok(Token {
-                            literal: c.to_string(),
-                            token_type: TokenType::Unknown,
-                        })
-                    };
+                        (self.read_single(), TokenType::Unknown)
+                    }
                 }
             };
+
+            return Ok(Token::new(&literal, token_type, Span {
+                start,
+                end: self.offset,
+            }));
         }
+
         return Err(LexingError {});
     }
 
+    fn read_single(&mut self) -> String {
+        let literal = self.ch.unwrap().to_string();
+        self.read_char();
+
+        return literal;
+    }
+
     fn read_identifier(&mut self) -> String {
-        let mut identifier = self.ch.unwrap().to_string();
-        let mut changed = false;
+        let mut identifier = String::new();
 
-        while let Some(c) = self.peek_char() {
+        while let Some(c) = self.ch {
             if !c.is_alphanumeric() && c != '_' {
                 break;
             }
 
-            changed = true;
+            identifier.push(c);
             self.read_char();
-            identifier += &*self.ch.unwrap().to_string();
         }
 
-        if changed {
-            self.read_char();
+        return identifier;
+    }
+
+    fn read_number(&mut self, radix: u32) -> String {
+        // keep the `$`, `%` or `&` prefix so literals always match the source text
+        let mut identifier = self.read_single();
 
-            if self.ch.is_some() {
-                identifier += &*self.ch.unwrap().to_string();
+        while let Some(c) = self.ch {
+            if !c.is_digit(radix) {
+                break;
             }
+
+            identifier.push(c);
+            self.read_char();
         }
 
         return identifier;
     }
 
-    fn read_number(&mut self) -> String {
+    fn read_decimal(&mut self) -> String {
+        let radix = match (self.ch, self.peek_char()) {
+            (Some('0'), Some('x' | 'X')) => 16,
+            (Some('0'), Some('b' | 'B')) => 2,
+            (Some('0'), Some('o' | 'O')) => 8,
+            _ => 10,
+        };
+
+        if radix != 10 {
+            let mut identifier = self.read_single();
+            identifier += &self.read_number(radix);
+
+            return identifier;
+        }
+
         let mut identifier = String::new();
 
-        while let Some(c) = self.peek_char() {
-            if !c.is_ascii_hexdigit() {
+        while let Some(c) = self.ch {
+            if !c.is_ascii_digit() {
                 break;
             }
 
+            identifier.push(c);
             self.read_char();
-
-            identifier += &*self.ch.unwrap().to_string();
         }
 
-        self.read_char();
+        return identifier;
+    }
+
+    fn read_operator(&mut self) -> String {
+        let c = self.ch.unwrap();
+        let pair = self.peek_char().map(|n| format!("{}{}", c, n));
 
-        if self.ch.is_some() {
-            identifier += &*self.ch.unwrap().to_string();
+        let doubled = ["**", "==", "!=", "<=", ">=", "<<", ">>", "&&", "||"];
+        if let Some(pair) = pair.filter(|p| doubled.contains(&p.as_str())) {
+            self.read_char();
+            self.read_char();
+
+            return pair;
         }
 
-        return identifier;
+        return self.read_single();
     }
 
     fn peek_char(&self) -> Option<char> {
-        let result = if self.read_position >= self.input_size {
-            None
-        } else {
-            Some(self.input[self.read_position])
-        };
-
-        return result;
+        return self.input.get(self.read_position).copied();
     }
 
     fn read_char(&mut self) {
         if self.read_position >= self.input_size {
             self.ch = None
         } else {
-            self.ch = Some(self.input[self.position]);
+            self.ch = Some(self.input[self.read_position]);
         }
 
         self.position = self.read_position;
         self.read_position += 1;
+
+        self.offset = self.read_offset;
+        if let Some(c) = self.ch {
+            self.read_offset += c.len_utf8();
+        }
     }
 }
 
-pub fn lex_content(content: Vec<String>) -> Vec<Token> {
-    let mut c: String = "".to_string();
+pub fn lex_content(content: &str) -> Vec<Token> {
+    let mut lexer = Lexer::new(content.to_string());
+    let mut tokens: Vec<Token> = vec![];
 
-    for x in content {
-        c += &*x;
-        c += "\n";
+    while let Ok(token) = lexer.retrieve_next_token() {
+        tokens.push(token);
     }
 
-    let mut lexer = Lexer::new(c);
+    return tokens;
+}
+
+/// Lexes `content` with spaces, tabs and comments folded into the trivia of
+/// the surrounding tokens, except inside strings where spaces are kept as
+/// tokens. The last token is always an `EOF` token holding the trivia after
+/// the last line break.
+pub fn lex_with_trivia(content: &str) -> Vec<Token> {
     let mut tokens: Vec<Token> = vec![];
+    let mut pending: Vec<Trivia> = vec![];
+    let mut in_string = false;
+    let mut in_comment = false;
+
+    for token in lex_content(content) {
+        if in_comment && token.token_type != TokenType::LineBreak {
+            let comment = pending.last_mut().unwrap();
+            comment.text += &token.literal;
+            comment.span.end = token.span.end;
+            continue;
+        }
+        in_comment = false;
+
+        if !in_string && token.token_type == TokenType::SemiColon {
+            pending.push(Trivia {
+                kind: TriviaKind::Comment,
+                text: token.literal.clone(),
+                span: token.span,
+            });
+            in_comment = true;
+            continue;
+        }
 
-    while let Ok(token) = lexer.retrieve_next_token() {
+        if !in_string && token.is_trivia() {
+            match pending.last_mut() {
+                Some(whitespace) if whitespace.kind == TriviaKind::Whitespace => {
+                    whitespace.text += &token.literal;
+                    whitespace.span.end = token.span.end;
+                }
+                _ => pending.push(Trivia {
+                    kind: TriviaKind::Whitespace,
+                    text: token.literal.clone(),
+                    span: token.span,
+                }),
+            }
+            continue;
+        }
+
+        if token.token_type == TokenType::DoubleQuote {
+            let escaped = in_string && tokens.last().is_some_and(|t| t.literal == "\\" && t.span.end == token.span.start);
+            if !escaped {
+                in_string = !in_string;
+            }
+        } else if token.token_type == TokenType::LineBreak {
+            in_string = false;
+        }
+
+        attach_trivia(&mut tokens, &mut pending);
+        let mut token = token;
+        token.leading_trivia = std::mem::take(&mut pending);
         tokens.push(token);
     }
 
+    attach_trivia(&mut tokens, &mut pending);
+    let end = content.len();
+    let mut eof = Token::new("", TokenType::EOF, Span {
+        start: end,
+        end,
+    });
+    eof.leading_trivia = pending;
+    tokens.push(eof);
+
     return tokens;
 }
 
+// trivia after a token on the same line trails it, the rest leads the next token
+fn attach_trivia(tokens: &mut [Token], pending: &mut Vec<Trivia>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    if let Some(previous) = tokens.last_mut() {
+        if previous.token_type != TokenType::LineBreak {
+            previous.trailing_trivia.append(pending);
+        }
+    }
+}
+
+/// Source text of consecutive tokens, including the trivia between them but
+/// not the leading trivia of the first or the trailing trivia of the last.
+pub fn tokens_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 {
+            for trivia in &token.leading_trivia {
+                text += &trivia.text;
+            }
+        }
+        text += &token.literal;
+        if i + 1 < tokens.len() {
+            for trivia in &token.trailing_trivia {
+                text += &trivia.text;
+            }
+        }
+    }
+
+    return text;
+}
+
+/// Value of a number literal as produced by the lexer (`42`, `$2A`, `%101010`,
+/// `&52`, `0x2A`, `0b101010` or `0o52`).
+pub fn number_value(literal: &str) -> Option<i64> {
+    let (digits, radix) = if let Some(digits) = literal.strip_prefix('$') {
+        (digits, 16)
+    } else if let Some(digits) = literal.strip_prefix('%') {
+        (digits, 2)
+    } else if let Some(digits) = literal.strip_prefix('&') {
+        (digits, 8)
+    } else if let Some(digits) = literal.strip_prefix("0x").or_else(|| literal.strip_prefix("0X")) {
+        (digits, 16)
+    } else if let Some(digits) = literal.strip_prefix("0b").or_else(|| literal.strip_prefix("0B")) {
+        (digits, 2)
+    } else if let Some(digits) = literal.strip_prefix("0o").or_else(|| literal.strip_prefix("0O")) {
+        (digits, 8)
+    } else {
+        (literal, 10)
+    };
+
+    return i64::from_str_radix(digits, radix).ok();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,54 +466,93 @@ mod tests {
             Token {
                 literal: "INCLUDE".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: " ".to_string(),
                 token_type: TokenType::Space,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "\"".to_string(),
                 token_type: TokenType::DoubleQuote,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "foo".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: ".".to_string(),
                 token_type: TokenType::Dot,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "asm".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "\"".to_string(),
                 token_type: TokenType::DoubleQuote,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "\n".to_string(),
                 token_type: TokenType::LineBreak,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: ";".to_string(),
                 token_type: TokenType::SemiColon,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: " ".to_string(),
                 token_type: TokenType::Space,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "simple".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: " ".to_string(),
                 token_type: TokenType::Space,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "comment".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
         ];
 
@@ -286,48 +569,78 @@ mod tests {
 
     #[test]
     fn lexing_macro() {
-        let mut l = Lexer::new(concat!("foo: MACRO\nsetcharmap no_ngrams\nENDM").to_string());
+        let mut l = Lexer::new("foo: MACRO\nsetcharmap no_ngrams\nENDM".to_string());
 
         let expected_tokens = vec![
             Token {
                 literal: "foo".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: ":".to_string(),
                 token_type: TokenType::Colon,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: " ".to_string(),
                 token_type: TokenType::Space,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "MACRO".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "\n".to_string(),
                 token_type: TokenType::LineBreak,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "setcharmap".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: " ".to_string(),
                 token_type: TokenType::Space,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "no_ngrams".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "\n".to_string(),
                 token_type: TokenType::LineBreak,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
             Token {
                 literal: "ENDM".to_string(),
                 token_type: TokenType::Identifier,
+                span: Span::default(),
+                leading_trivia: vec![],
+                trailing_trivia: vec![],
             },
         ];
 
@@ -342,11 +655,67 @@ mod tests {
         validate_tokens(expected_tokens, output_tokens);
     }
 
+    #[test]
+    fn lexing_spans() {
+        let tokens = lex_content("ld a, $1F ; é\n");
+        let spans: Vec<(usize, usize)> = tokens.iter().map(|t| (t.span.start, t.span.end)).collect();
+
+        assert_eq!(spans, vec![(0, 2), (2, 3), (3, 4), (4, 5), (5, 6), (6, 9), (9, 10), (10, 11), (11, 12), (12, 14), (14, 15)]);
+        assert_eq!(tokens[5].literal, "$1F");
+    }
+
+    #[test]
+    fn lexing_numbers_and_operators() {
+        let tokens = lex_content("(%101 & &17) << 2 >= $1F % 0x10 - 42");
+        let significant: Vec<(&str, TokenType)> = tokens.iter()
+            .filter(|t| t.token_type != TokenType::Space)
+            .map(|t| (t.literal.as_str(), t.token_type.clone()))
+            .collect();
+
+        assert_eq!(significant, vec![
+            ("(", TokenType::LeftParen),
+            ("%101", TokenType::Number),
+            ("&", TokenType::Operator),
+            ("&17", TokenType::Number),
+            (")", TokenType::RightParen),
+            ("<<", TokenType::Operator),
+            ("2", TokenType::Number),
+            (">=", TokenType::Operator),
+            ("$1F", TokenType::Number),
+            ("%", TokenType::Operator),
+            ("0x10", TokenType::Number),
+            ("-", TokenType::Operator),
+            ("42", TokenType::Number),
+        ]);
+        assert_eq!(number_value("%101"), Some(5));
+        assert_eq!(number_value("&17"), Some(15));
+        assert_eq!(number_value("0x10"), Some(16));
+        assert_eq!(number_value("$"), None);
+    }
+
+    #[test]
+    fn lexing_with_trivia() {
+        let content = "\tld a, \"; b \" ; load\r\n; only a comment\nEND ";
+        let tokens = lex_with_trivia(content);
+
+        let literals: Vec<&str> = tokens.iter().map(|t| t.literal.as_str()).collect();
+        assert_eq!(literals, vec!["ld", "a", ",", "\"", ";", " ", "b", " ", "\"", "\r\n", "\n", "END", ""]);
+
+        assert_eq!(tokens[0].leading_trivia[0].text, "\t");
+        assert_eq!(tokens[8].trailing_trivia.iter().map(|t| t.kind).collect::<Vec<_>>(), vec![TriviaKind::Whitespace, TriviaKind::Comment]);
+        assert_eq!(tokens[8].trailing_trivia[1].text, "; load");
+        assert_eq!(tokens[10].leading_trivia[0].text, "; only a comment");
+        assert_eq!(tokens[11].trailing_trivia[0].text, " ");
+
+        let printed: String = tokens.iter().map(|t| t.full_text()).collect();
+        assert_eq!(printed, content);
+    }
+
     fn validate_tokens(expected_tokens: Vec<Token>, output_tokens: Vec<Token>) {
         assert_eq!(expected_tokens.len(), output_tokens.len());
         for i in 0..expected_tokens.len() {
             let exp_tok: Token = expected_tokens[i].clone();
-            let output_tok: Token = expected_tokens[i].clone();
+            let output_tok: Token = output_tokens[i].clone();
 
             assert_eq!(exp_tok.literal, output_tok.literal);
             assert_eq!(exp_tok.token_type, output_tok.token_type);
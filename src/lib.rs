@@ -0,0 +1,36 @@
+#![allow(clippy::needless_return)]
+
+// lexer (tokens) > ast (expressions/statements) > parser
+
+pub mod analysis;
+pub mod assembler;
+pub mod ast;
+pub mod charmap;
+pub mod cst;
+pub mod cycles;
+pub mod debuginfo;
+pub mod depfile;
+pub mod diagnostic;
+pub mod dictionary;
+pub mod disasm;
+pub mod emit;
+pub mod emulator;
+pub mod expr;
+pub mod format;
+pub mod gfx;
+pub mod gdb;
+pub mod header;
+pub mod image;
+pub mod json;
+pub mod lexer;
+pub mod link;
+pub mod lint;
+pub mod lsp;
+pub mod parser;
+pub mod peephole;
+pub mod ppu;
+pub mod profiler;
+pub mod rename;
+pub mod sm83;
+pub mod symbols;
+pub mod testing;
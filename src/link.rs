@@ -0,0 +1,378 @@
+//! Placing the sections of an assembled file in the address space and
+//! writing the ROM.
+//!
+//! Sections at a fixed address are placed first, then the others in the
+//! order they were defined, each at the lowest address of the first bank
+//! where it fits. The values the assembler could not compute are written
+//! once every label has an address.
+//!
+//! Files assembled on their own are merged first to be linked together, their
+//! labels and constants being visible from the other files.
+
+use std::ops::RangeInclusive;
+
+use crate::assembler::{self, Assembler};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::expr;
+use crate::symbols::SymbolKind;
+
+const E_SYMBOL_REDEFINED: &str = "E0020";
+const E_SECTION_PLACEMENT: &str = "E0030";
+const E_SECTION_OVERLAP: &str = "E0031";
+const E_VALUE_RANGE: &str = "E0029";
+const E_SECTION_REDEFINED: &str = "E0036";
+
+pub const BANK_SIZE: usize = 0x4000;
+
+/// Addresses and banks the sections of a type can be placed in.
+pub struct Region {
+    pub start: i32,
+    pub size: i32,
+    pub banks: RangeInclusive<i32>,
+}
+
+pub fn region(section_type: &str) -> Option<Region> {
+    let (start, size, banks) = match section_type {
+        "ROM0" => (0x0000, 0x4000, 0..=0),
+        "ROMX" => (0x4000, 0x4000, 1..=511),
+        "VRAM" => (0x8000, 0x2000, 0..=1),
+        "SRAM" => (0xA000, 0x2000, 0..=15),
+        "WRAM0" => (0xC000, 0x1000, 0..=0),
+        "WRAMX" => (0xD000, 0x1000, 1..=7),
+        "OAM" => (0xFE00, 0xA0, 0..=0),
+        "HRAM" => (0xFF80, 0x7F, 0..=0),
+        _ => return None,
+    };
+
+    return Some(Region { start, size, banks });
+}
+
+pub fn is_rom(section_type: &str) -> bool {
+    return section_type == "ROM0" || section_type == "ROMX";
+}
+
+// a placed section: its type, bank and addresses
+struct Placed {
+    section: usize,
+    section_type: String,
+    bank: i32,
+    start: i32,
+    end: i32,
+}
+
+/// Joins files assembled on their own into the first of them, to be linked
+/// together. Their sections keep their names, so two files defining the same
+/// section, label or constant is an error.
+pub fn merge(modules: Vec<Assembler>, diagnostics: &mut Diagnostics) -> Assembler {
+    let mut modules = modules.into_iter();
+    let mut merged = modules.next().unwrap_or_default();
+
+    for mut module in modules {
+        let first_section = merged.sections.len();
+
+        for section in module.sections.drain(..) {
+            if let Some(existing) = merged.sections.iter().find(|other| other.name == section.name) {
+                let diagnostic = Diagnostic::error(E_SECTION_REDEFINED, &format!("Section `{}` is already defined", section.name), section.definition.span)
+                    .with_note(&format!("previously defined in {}", existing.definition.file));
+                diagnostics.push(in_file(&merged, diagnostic, &section.definition.file));
+            }
+            merged.sections.push(section);
+        }
+
+        for mut patch in module.patches.drain(..) {
+            patch.section += first_section;
+            merged.patches.push(patch);
+        }
+
+        // variables, strings and built-in symbols belong to the file
+        let symbols = module.symbols.iter().filter(|symbol| symbol.kind == SymbolKind::Label || symbol.kind == SymbolKind::Constant);
+        for symbol in symbols {
+            let result = merged.symbols.define(&symbol.name, symbol.kind, symbol.value.clone(), symbol.definition.clone());
+
+            if let (Err(error), Some(definition)) = (result, &symbol.definition) {
+                let mut diagnostic = Diagnostic::error(E_SYMBOL_REDEFINED, &error.message, definition.span);
+                if let Some(previous) = error.previous {
+                    diagnostic = diagnostic.with_note(&format!("previously defined in {}", previous.file));
+                }
+                diagnostics.push(in_file(&merged, diagnostic, &definition.file));
+            }
+        }
+
+        merged.placements.append(&mut module.placements);
+        merged.output_lines.append(&mut module.output_lines);
+    }
+
+    return merged;
+}
+
+/// Places the sections of `assembler`, gives their labels an address and
+/// fills in the values left for later. Returns the ROM, a power of two of at
+/// least 32 KiB holding the ROM0 and ROMX sections with zeros in between,
+/// which is only complete when no error was reported.
+pub fn link(assembler: &mut Assembler, diagnostics: &mut Diagnostics) -> Vec<u8> {
+    let mut order: Vec<usize> = (0..assembler.sections.len()).collect();
+    order.sort_by_key(|&index| assembler.sections[index].address.is_none());
+
+    let mut placed: Vec<Placed> = vec![];
+    for index in order {
+        match place(assembler, index, &placed) {
+            Ok(section) => placed.push(section),
+            Err(diagnostic) => diagnostics.push(in_file(assembler, *diagnostic, &assembler.sections[index].definition.file)),
+        }
+    }
+
+    for section in &placed {
+        let name = assembler.sections[section.section].name.clone();
+        assembler.symbols.place_section(&name, section.bank, section.start);
+        assembler.sections[section.section].bank = Some(section.bank);
+        assembler.sections[section.section].address = Some(section.start);
+    }
+
+    let patches = std::mem::take(&mut assembler.patches);
+    for patch in &patches {
+        // the sections that could not be placed were already reported
+        let start = match placed.iter().find(|section| section.section == patch.section) {
+            Some(section) => section.start,
+            None => continue,
+        };
+
+        let address = start + patch.offset as i32;
+        let expression = assembler::resolve_known(&patch.expression, &assembler.symbols, None, Some(start + patch.start as i32));
+        let result = expr::evaluate_number(&expression, &assembler.symbols).and_then(|value| {
+            if assembler::is_truncated(patch.kind, value) {
+                let warning = assembler::truncation_warning(patch.kind, value, patch.span);
+                diagnostics.push(in_file(assembler, warning, &patch.file));
+            }
+
+            return assembler::encode_value(patch.kind, value, Some(address)).map_err(|message| {
+                Box::new(Diagnostic::error(E_VALUE_RANGE, &message, patch.span).with_label("value out of range"))
+            });
+        });
+
+        match result {
+            Ok(bytes) => {
+                let data = &mut assembler.sections[patch.section].data;
+                data[patch.offset..patch.offset + bytes.len()].copy_from_slice(&bytes);
+            }
+            Err(mut diagnostic) => {
+                // in a macro expansion, the spans point into the expanded text
+                let span = diagnostic.primary.span;
+                if span.start < patch.span.start || span.end > patch.span.end {
+                    diagnostic.primary.span = patch.span;
+                    diagnostic.secondary.clear();
+                }
+                diagnostics.push(in_file(assembler, *diagnostic, &patch.file));
+            }
+        }
+    }
+    assembler.patches = patches;
+
+    let banks = placed.iter()
+        .filter(|section| is_rom(&section.section_type))
+        .map(|section| section.bank as usize + 1)
+        .max()
+        .unwrap_or(0)
+        .max(2)
+        .next_power_of_two();
+
+    let mut rom = vec![0; banks * BANK_SIZE];
+    for section in placed.iter().filter(|section| is_rom(&section.section_type)) {
+        let data = &assembler.sections[section.section].data;
+        let offset = section.bank as usize * BANK_SIZE + (section.start as usize % BANK_SIZE);
+        rom[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    return rom;
+}
+
+fn place(assembler: &Assembler, index: usize, placed: &[Placed]) -> Result<Placed, Box<Diagnostic>> {
+    let section = &assembler.sections[index];
+    let span = section.definition.span;
+
+    let region = region(&section.section_type).ok_or_else(|| {
+        Diagnostic::error(E_SECTION_PLACEMENT, &format!("Unknown section type `{}`", section.section_type), span)
+            .with_label("expected ROM0, ROMX, VRAM, SRAM, WRAM0, WRAMX, OAM or HRAM")
+    })?;
+    let end = region.start + region.size;
+    let size = section.data.len() as i32;
+
+    let banks: Vec<i32> = match section.bank {
+        Some(bank) if !region.banks.contains(&bank) => {
+            let message = format!("{} has no bank {}", section.section_type, bank);
+            return Err(Box::new(Diagnostic::error(E_SECTION_PLACEMENT, &message, span)
+                .with_label(&format!("banks go from {} to {}", region.banks.start(), region.banks.end()))));
+        }
+        Some(bank) => vec![bank],
+        None => region.banks.clone().collect(),
+    };
+
+    if let Some(address) = section.address {
+        if address < region.start || address + size > end {
+            let message = format!("Section `{}` does not fit at ${:04X}", section.name, address);
+            return Err(Box::new(Diagnostic::error(E_SECTION_PLACEMENT, &message, span)
+                .with_label(&format!("{} byte(s) long", size))
+                .with_note(&format!("{} goes from ${:04X} to ${:04X}", section.section_type, region.start, end - 1))));
+        }
+    }
+
+    let alignment = 1 << section.alignment.unwrap_or(0).clamp(0, 16);
+    let overlapping = |bank: i32, start: i32| placed.iter().find(|other| {
+        other.section_type == section.section_type && other.bank == bank && start < other.end && other.start < start + size
+    });
+
+    let mut last_overlap = None;
+    for bank in banks {
+        let mut start = match section.address {
+            Some(address) => address,
+            None => align(region.start, alignment),
+        };
+
+        while start + size <= end {
+            match overlapping(bank, start) {
+                Some(other) if section.address.is_none() => start = align(other.end, alignment),
+                Some(other) => {
+                    last_overlap = Some(other);
+                    break;
+                }
+                None => {
+                    return Ok(Placed {
+                        section: index,
+                        section_type: section.section_type.clone(),
+                        bank,
+                        start,
+                        end: start + size,
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(other) = last_overlap {
+        let other_section = &assembler.sections[other.section];
+        let message = format!("Section `{}` overlaps section `{}`", section.name, other_section.name);
+        let note = format!("`{}` takes ${:02X}:${:04X}-${:04X}", other_section.name, other.bank, other.start, other.end - 1);
+        return Err(Box::new(Diagnostic::error(E_SECTION_OVERLAP, &message, span)
+            .with_label(&format!("{} byte(s) from ${:04X}", size, section.address.unwrap_or(0)))
+            .with_note(&note)));
+    }
+
+    let message = format!("No room left for section `{}` ({} byte(s))", section.name, size);
+    return Err(Box::new(Diagnostic::error(E_SECTION_PLACEMENT, &message, span)
+        .with_label(&format!("every {} bank it can go in is full", section.section_type))));
+}
+
+fn align(address: i32, alignment: i32) -> i32 {
+    return (address + alignment - 1) / alignment * alignment;
+}
+
+fn in_file(assembler: &Assembler, diagnostic: Diagnostic, file: &str) -> Diagnostic {
+    if file != assembler.main_file {
+        return diagnostic.with_file(file);
+    }
+
+    return diagnostic;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::SourceFile;
+    use crate::{lexer, parser};
+    use std::time::UNIX_EPOCH;
+
+    fn assemble(name: &str, text: &str, diagnostics: &mut Diagnostics) -> Assembler {
+        let source = SourceFile::new(name, text.to_string());
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), diagnostics);
+
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, diagnostics);
+
+        return assembler;
+    }
+
+    fn link_source(text: &str) -> (Assembler, Vec<u8>, Diagnostics) {
+        let mut diagnostics = Diagnostics::new();
+        let mut assembler = assemble("main.asm", text, &mut diagnostics);
+        let rom = link(&mut assembler, &mut diagnostics);
+
+        return (assembler, rom, diagnostics);
+    }
+
+    #[test]
+    fn placing_sections() {
+        let (assembler, rom, diagnostics) = link_source(concat!(
+            "SECTION \"Entry\", ROM0[$100]\n",
+            "\tjp Main\n",
+            "SECTION \"Main\", ROM0\n",
+            "Main: jr Main\n",
+            "\tld a, [wValue]\n",
+            "SECTION \"Aligned\", ROM0, ALIGN[8]\n",
+            "Table: db HIGH(Table)\n",
+            "SECTION \"Far\", ROMX, BANK[3]\n",
+            "Far: dw Main, Far\n",
+            "SECTION \"Variables\", WRAM0\n",
+            "wValue: ds 1\n",
+        ));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(rom.len(), 4 * BANK_SIZE);
+        assert_eq!(rom[0x100..0x103], [0xC3, 0x00, 0x00]);
+        assert_eq!(rom[0x000..0x005], [0x18, 0xFE, 0xFA, 0x00, 0xC0]);
+        assert_eq!(rom[0x200], 0x02);
+        assert_eq!(rom[3 * BANK_SIZE..3 * BANK_SIZE + 4], [0x00, 0x00, 0x00, 0x40]);
+        assert_eq!(assembler.sections[2].address, Some(0x200));
+    }
+
+    #[test]
+    fn reporting_placement_errors() {
+        let (_, _, diagnostics) = link_source(concat!(
+            "SECTION \"A\", ROM0[$100]\n",
+            "\tds 4\n",
+            "SECTION \"B\", ROM0[$102]\n",
+            "\tjr Far\n",
+            "SECTION \"C\", ROMX[$7FFF]\n",
+            "\tdw 0\n",
+            "SECTION \"D\", HRAM\n",
+            "\tds $80\n",
+            "SECTION \"E\", ROM0[$200]\n",
+            "\tjr Far\n",
+            "\tds 200\n",
+            "Far:\n",
+        ));
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Section `B` overlaps section `A`",
+            "Section `C` does not fit at $7FFF",
+            "No room left for section `D` (128 byte(s))",
+            "Jump target is 200 byte(s) away, out of reach of `jr`",
+        ]);
+    }
+
+    #[test]
+    fn linking_files_together() {
+        let mut diagnostics = Diagnostics::new();
+        let modules = vec![
+            assemble("main.asm", "SECTION \"Main\", ROM0[$150]\nMain: call Helper\n\tdb COUNT\n", &mut diagnostics),
+            assemble("helper.asm", "DEF COUNT EQU 3\nSECTION \"Helper\", ROM0\nHelper: ret\n", &mut diagnostics),
+        ];
+        let mut assembler = merge(modules, &mut diagnostics);
+        let rom = link(&mut assembler, &mut diagnostics);
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(rom[0x150..0x154], [0xCD, 0x00, 0x00, 0x03]);
+        assert_eq!(rom[0], 0xC9);
+
+        let modules = vec![
+            assemble("main.asm", "SECTION \"Main\", ROM0\nMain: ret\n", &mut diagnostics),
+            assemble("other.asm", "SECTION \"Main\", ROMX\nMain: ret\n", &mut diagnostics),
+        ];
+        merge(modules, &mut diagnostics);
+
+        let errors: Vec<(&str, Option<&str>)> = diagnostics.iter().map(|d| (d.message.as_str(), d.file.as_deref())).collect();
+        assert_eq!(errors, vec![
+            ("Section `Main` is already defined", Some("other.asm")),
+            ("`Main` is already defined", Some("other.asm")),
+        ]);
+    }
+}
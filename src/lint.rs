@@ -0,0 +1,369 @@
+//! Warnings of the `check` command about code that assembles but is probably
+//! wrong, or could be smaller and faster.
+//!
+//! Every warning has a stable name, used as its diagnostic code, to enable it
+//! with `-W<name>` and disable it with `-Wno-<name>`. A `; nolint` comment
+//! silences the warnings of its line, `; nolint(<name>, ...)` only the named
+//! ones; on a line of its own the comment applies to the next line.
+//!
+//! Only the checked file is linted, not the files it includes.
+
+use crate::analysis::{Analysis, DeclarationKind};
+use crate::ast::{self, Operand, Statement, StatementType};
+use crate::diagnostic::{Diagnostic, SourceFile, WarningSettings};
+use crate::expr::Expression;
+use crate::lexer::{self, Span, TriviaKind};
+use crate::sm83;
+use crate::symbols::SymbolValue;
+
+pub const UNUSED_LABEL: &str = "unused-label";
+pub const UNUSED_CONSTANT: &str = "unused-constant";
+pub const PREFER_JR: &str = "prefer-jr";
+pub const PREFER_XOR_A: &str = "prefer-xor-a";
+pub const PREFER_AND_A: &str = "prefer-and-a";
+pub const UNREACHABLE_CODE: &str = "unreachable-code";
+pub const SHADOWED_LABEL: &str = "shadowed-label";
+pub const ROM_WRITE: &str = "rom-write";
+
+/// Every warning with whether it is enabled without flags. The suggestions
+/// to use a shorter instruction are off by default as they change flags or
+/// depend on the distance to the target.
+pub const WARNINGS: [(&str, bool); 8] = [
+    (UNUSED_LABEL, true),
+    (UNUSED_CONSTANT, true),
+    (PREFER_JR, false),
+    (PREFER_XOR_A, false),
+    (PREFER_AND_A, false),
+    (UNREACHABLE_CODE, true),
+    (SHADOWED_LABEL, true),
+    (ROM_WRITE, true),
+];
+
+/// Warnings about the analyzed file that are enabled in `settings` and not
+/// silenced by a comment.
+pub fn lint(analysis: &Analysis, settings: &WarningSettings) -> Vec<Diagnostic> {
+    let source = &analysis.sources[0];
+    let linter = Linter {
+        analysis,
+        file: &source.name,
+    };
+
+    let mut warnings = vec![];
+    linter.lint_declarations(&mut warnings);
+    linter.lint_statements(&analysis.ast.statements, &mut warnings);
+
+    let suppressions = suppressions(source);
+    warnings.retain(|warning| {
+        let enabled = WARNINGS.iter().any(|(name, default)| *name == warning.code && settings.is_enabled(name, *default));
+        let line = source.line_index(warning.primary.span.start);
+        let suppressed = suppressions.iter().any(|(l, names)| {
+            *l == line && names.as_ref().is_none_or(|names| names.iter().any(|name| name == warning.code))
+        });
+
+        enabled && !suppressed
+    });
+    warnings.sort_by_key(|warning| warning.primary.span.start);
+
+    return warnings;
+}
+
+// lines with a `nolint` comment and the warnings it names, `None` for all
+fn suppressions(source: &SourceFile) -> Vec<(usize, Option<Vec<String>>)> {
+    let mut suppressions = vec![];
+
+    for token in lexer::lex_with_trivia(&source.text) {
+        for trivia in token.leading_trivia.iter().chain(&token.trailing_trivia) {
+            if trivia.kind != TriviaKind::Comment {
+                continue;
+            }
+
+            let comment = trivia.text.trim_start_matches(';').trim();
+            let names = match comment.strip_prefix("nolint") {
+                Some("") => None,
+                Some(rest) => match rest.trim().strip_prefix('(').and_then(|rest| rest.strip_suffix(')')) {
+                    Some(names) => Some(names.split(',').map(|name| name.trim().to_string()).collect()),
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            let line = source.line_index(trivia.span.start);
+            let line_start = source.text[..trivia.span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+            let alone = source.text[line_start..trivia.span.start].trim().is_empty();
+
+            if alone {
+                suppressions.push((line + 1, names.clone()));
+            }
+            suppressions.push((line, names));
+        }
+    }
+
+    return suppressions;
+}
+
+struct Linter<'a> {
+    analysis: &'a Analysis,
+    file: &'a str,
+}
+
+impl Linter<'_> {
+    fn lint_declarations(&self, warnings: &mut Vec<Diagnostic>) {
+        let analysis = self.analysis;
+
+        for declaration in analysis.declarations.iter().filter(|d| d.file == self.file) {
+            let unused = !declaration.exported
+                && analysis.references_to(&declaration.name).next().is_none()
+                && !analysis.templates.iter().any(|template| template.matches(&declaration.name));
+
+            match declaration.kind {
+                DeclarationKind::Label if unused => {
+                    warnings.push(Diagnostic::warning(UNUSED_LABEL, &format!("Label `{}` is never used", declaration.name), declaration.span)
+                        .with_label("no instruction or expression refers to it")
+                        .with_help("export it with `::` if it is used by another file"));
+                }
+                DeclarationKind::Constant | DeclarationKind::Variable | DeclarationKind::String if unused => {
+                    warnings.push(Diagnostic::warning(UNUSED_CONSTANT, &format!("Constant `{}` is never used", declaration.name), declaration.span)
+                        .with_label("defined here"));
+                }
+                _ => {}
+            }
+
+            // a local label named like a global symbol
+            let local = match declaration.name.split_once('.') {
+                Some((_, local)) if declaration.kind == DeclarationKind::Label => local,
+                _ => continue,
+            };
+
+            if let Some(global) = analysis.declaration(local) {
+                let message = format!("Local label `.{}` has the same name as `{}`", local, global.name);
+                let mut warning = Diagnostic::warning(SHADOWED_LABEL, &message, declaration.span)
+                    .with_label(&format!("`{}` and `.{}` are different symbols", local, local))
+                    .with_help("rename the local label");
+                if global.file == self.file {
+                    warning = warning.with_secondary(global.span, "the other one is declared here");
+                }
+                warnings.push(warning);
+            }
+        }
+    }
+
+    fn lint_statements(&self, statements: &[Box<dyn Statement>], warnings: &mut Vec<Diagnostic>) {
+        // the last unconditional jump or return, until something else can be
+        // jumped to
+        let mut jump: Option<&ast::InstructionStatement> = None;
+
+        for statement in statements {
+            if statement.my_type() == StatementType::If {
+                let conditional = statement.as_any().downcast_ref::<ast::IfStatement>().unwrap();
+                for branch in &conditional.branches {
+                    self.lint_statements(&branch.statements, warnings);
+                }
+            }
+            if statement.my_type() == StatementType::Test {
+                let test = statement.as_any().downcast_ref::<ast::TestStatement>().unwrap();
+                self.lint_statements(&test.statements, warnings);
+            }
+
+            let instruction = match statement.my_type() {
+                StatementType::Instruction => statement.as_any().downcast_ref::<ast::InstructionStatement>().unwrap(),
+                StatementType::Def | StatementType::Rs | StatementType::Macro => continue,
+                _ => {
+                    jump = None;
+                    continue;
+                }
+            };
+
+            if let Some(jump) = jump.take() {
+                warnings.push(Diagnostic::warning(UNREACHABLE_CODE, "Unreachable instruction", instruction.span)
+                    .with_label("nothing jumps here")
+                    .with_secondary(jump.span, &format!("execution never continues after this `{}`", jump.mnemonic))
+                    .with_help("add a label if this is the target of a jump"));
+            }
+
+            let unconditional = match instruction.mnemonic.as_str() {
+                "jp" | "jr" => instruction.operands.len() == 1,
+                "ret" => instruction.operands.is_empty(),
+                "reti" => true,
+                _ => false,
+            };
+            if unconditional {
+                jump = Some(instruction);
+            }
+
+            self.lint_instruction(instruction, warnings);
+        }
+    }
+
+    fn lint_instruction(&self, instruction: &ast::InstructionStatement, warnings: &mut Vec<Diagnostic>) {
+        let operands = &instruction.operands;
+        let is_zero = |operand: &Operand| matches!(operand, Operand::Immediate(Expression::Number(0, _)));
+        let is_a = |operand: &Operand| matches!(operand, Operand::Register(register, _) if register == "a");
+
+        match instruction.mnemonic.as_str() {
+            "jp" => {
+                if let Some(distance) = self.jump_distance(instruction) {
+                    if (-128..=127).contains(&distance) {
+                        warnings.push(Diagnostic::warning(PREFER_JR, "`jp` to a label in reach of `jr`", instruction.span)
+                            .with_label(&format!("`jr` would jump {} byte(s)", distance))
+                            .with_help("`jr` is one byte shorter and one cycle faster when taken"));
+                    }
+                }
+            }
+            "ld" if operands.len() == 2 && is_a(&operands[0]) && is_zero(&operands[1]) => {
+                warnings.push(Diagnostic::warning(PREFER_XOR_A, "`ld a, 0` can be `xor a`", instruction.span)
+                    .with_label("2 bytes and 2 cycles")
+                    .with_help("`xor a` takes 1 byte and 1 cycle but sets the flags, Z to 1 and the others to 0"));
+            }
+            "cp" if operands.last().is_some_and(is_zero) && (operands.len() == 1 || is_a(&operands[0])) => {
+                warnings.push(Diagnostic::warning(PREFER_AND_A, "`cp 0` can be `and a`", instruction.span)
+                    .with_label("2 bytes and 2 cycles")
+                    .with_help("`and a` takes 1 byte and 1 cycle and sets Z the same way, but H to 1 and N to 0"));
+            }
+            "ld" if operands.len() == 2 => {
+                if let Operand::Indirect(address, span) = &operands[0] {
+                    self.lint_rom_write(address, *span, warnings);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // offset of a `jr` replacing the `jp`, when the target label is in the
+    // same section
+    fn jump_distance(&self, instruction: &ast::InstructionStatement) -> Option<i32> {
+        let target = match instruction.operands.last()? {
+            Operand::Immediate(Expression::Symbol(_, span)) => self.label(*span)?,
+            _ => return None,
+        };
+
+        let placement = self.analysis.assembler.placements.iter()
+            .find(|p| p.file == self.file && p.span == instruction.span)?;
+        let location = &placement.location;
+
+        if location.section != target.section {
+            return None;
+        }
+
+        return Some(sm83::relaxed_offset(location.offset, target.offset));
+    }
+
+    fn label(&self, span: Span) -> Option<&crate::symbols::LabelLocation> {
+        let name = self.analysis.symbol_at(self.file, span.start)?;
+
+        return match &self.analysis.assembler.symbols.get(name)?.value {
+            SymbolValue::Label(location) => Some(location),
+            _ => None,
+        };
+    }
+
+    fn lint_rom_write(&self, address: &Expression, span: Span, warnings: &mut Vec<Diagnostic>) {
+        let mut symbols = vec![];
+        symbol_spans(address, &mut symbols);
+
+        for symbol in symbols {
+            let location = match self.label(symbol) {
+                Some(location) if location.section_type == "ROM0" || location.section_type == "ROMX" => location,
+                _ => continue,
+            };
+
+            let name = self.analysis.symbol_at(self.file, symbol.start).unwrap_or("");
+            let message = format!("Write to `{}`, which is in ROM", name);
+            warnings.push(Diagnostic::warning(ROM_WRITE, &message, span)
+                .with_label(&format!("in {} section \"{}\"", location.section_type, location.section))
+                .with_note("writing to ROM does not change it, the cartridge takes it as a bank switch")
+                .with_help("place variables in a WRAM0, WRAMX or HRAM section"));
+            return;
+        }
+    }
+}
+
+fn symbol_spans(expression: &Expression, spans: &mut Vec<Span>) {
+    match expression {
+        Expression::Symbol(_, span) => spans.push(*span),
+        Expression::Unary { operand, .. } => symbol_spans(operand, spans),
+        Expression::Binary { left, right, .. } => {
+            symbol_spans(left, spans);
+            symbol_spans(right, spans);
+        }
+        Expression::Call { arguments, .. } => {
+            for argument in arguments {
+                symbol_spans(argument, spans);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use std::time::UNIX_EPOCH;
+
+    fn lint_text(text: &str, flags: &[&str]) -> Vec<(&'static str, usize)> {
+        let source = SourceFile::new("main.asm", text.to_string());
+        let analysis = Analysis::new(source, Assembler::with_time(UNIX_EPOCH));
+        assert!(!analysis.diagnostics.has_errors());
+
+        let mut settings = WarningSettings::default();
+        for flag in flags {
+            settings.apply_flag(flag).unwrap();
+        }
+
+        return lint(&analysis, &settings).iter()
+            .map(|warning| (warning.code, analysis.sources[0].line_col(warning.primary.span.start).0))
+            .collect();
+    }
+
+    #[test]
+    fn reporting_warnings() {
+        let text = concat!(
+            "DEF UNUSED EQU 1\n",
+            "DEF USED EQU 2\n",
+            "SECTION \"Code\", ROM0\n",
+            "Main::\n",
+            "\tld a, USED\n",
+            "\tld [Data], a\n",
+            ".loop: jp .loop\n",
+            "\tnop\n",
+            "\tld a, 0\n",
+            "\tcp 0\n",
+            "Wait: ret\n",
+            "Other::\n",
+            ".Wait: jp Wait\n",
+            "SECTION \"Data\", ROM0\n",
+            "Data: db 1\n",
+        );
+
+        assert_eq!(lint_text(text, &[]), vec![
+            (UNUSED_CONSTANT, 1),
+            (ROM_WRITE, 6),
+            (UNREACHABLE_CODE, 8),
+            (UNUSED_LABEL, 13),
+            (SHADOWED_LABEL, 13),
+        ]);
+
+        assert_eq!(lint_text(text, &["all", "no-unused-label", "no-shadowed-label"]), vec![
+            (UNUSED_CONSTANT, 1),
+            (ROM_WRITE, 6),
+            (PREFER_JR, 7),
+            (UNREACHABLE_CODE, 8),
+            (PREFER_XOR_A, 9),
+            (PREFER_AND_A, 10),
+            (PREFER_JR, 13),
+        ]);
+    }
+
+    #[test]
+    fn suppressing_warnings() {
+        let text = concat!(
+            "DEF W EQU 1 ; nolint\n",
+            "DEF X EQU 1 ; nolint(unused-label)\n",
+            "; nolint(unused-constant, rom-write)\n",
+            "DEF Y EQU 1\n",
+            "DEF Z EQU 1 ; no lint\n",
+        );
+
+        assert_eq!(lint_text(text, &[]), vec![(UNUSED_CONSTANT, 2), (UNUSED_CONSTANT, 5)]);
+    }
+}
@@ -0,0 +1,879 @@
+//! Language server for editors, speaking the Language Server Protocol over
+//! standard input and output.
+//!
+//! Every request analyzes the document again from its last known text, with
+//! the other open documents used instead of their files on disk. References
+//! and renames also analyze the `.asm` files of the workspace including the
+//! document. Only full document synchronization is supported.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::analysis::{Analysis, Declaration, DeclarationKind};
+use crate::assembler::Assembler;
+use crate::diagnostic::{Diagnostic, Severity, SourceFile, WarningSettings};
+use crate::format;
+use crate::json::{self, Value};
+use crate::lexer::Span;
+use crate::{lint, rename, sm83};
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+
+// LSP enumerations
+const SYNC_FULL: i64 = 1;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_VARIABLE: i64 = 13;
+const SYMBOL_CONSTANT: i64 = 14;
+const SYMBOL_STRING: i64 = 15;
+const SYMBOL_OPERATOR: i64 = 25;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_REFERENCE: i64 = 18;
+const COMPLETION_CONSTANT: i64 = 21;
+
+const DATA_DIRECTIVES: [&str; 4] = ["db", "dw", "dl", "ds"];
+
+pub struct Server {
+    /// Text of the open documents by URI
+    documents: HashMap<String, String>,
+    include_paths: Vec<PathBuf>,
+    root: Option<PathBuf>,
+    shutdown_requested: bool,
+    exit_code: Option<i32>,
+}
+
+impl Server {
+    /// A server searching INCLUDE and INCBIN files in the workspace and then
+    /// in `include_paths`.
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        return Self {
+            documents: HashMap::new(),
+            include_paths,
+            root: None,
+            shutdown_requested: false,
+            exit_code: None,
+        };
+    }
+
+    /// Exit status once the client asked the server to exit: 0 after a
+    /// shutdown request, 1 otherwise.
+    pub fn exit_code(&self) -> Option<i32> {
+        return self.exit_code;
+    }
+
+    /// Handles one message from the client and returns the messages to send
+    /// back: the response to a request and any notification.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Value::Null);
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.handle_notification(method, params),
+        };
+
+        let result = match method {
+            "initialize" => Ok(self.initialize(params)),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => self.with_position(params, definition),
+            "textDocument/references" => {
+                let include_declaration = params.get("context")
+                    .and_then(|context| context.get("includeDeclaration"))
+                    .is_some_and(|value| *value == Value::Bool(true));
+                self.with_workspace_position(params, |analyses, file, offset| {
+                    Ok(references(analyses, file, offset, include_declaration))
+                })
+            }
+            "textDocument/rename" => {
+                let new_name = params.get("newName").and_then(Value::as_str).unwrap_or("");
+                self.with_workspace_position(params, |analyses, file, offset| rename_symbol(analyses, file, offset, new_name))
+            }
+            "textDocument/hover" => self.with_position(params, hover),
+            "textDocument/completion" => self.with_position(params, completion),
+            "textDocument/documentSymbol" => match self.analyze(params) {
+                Some((analysis, file)) => Ok(document_symbols(&analysis, &file)),
+                None => Err(unknown_document()),
+            },
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method `{}`", method))),
+        };
+
+        let response = match result {
+            Ok(result) => Value::object(vec![
+                ("jsonrpc", "2.0".into()),
+                ("id", id),
+                ("result", result),
+            ]),
+            Err((code, message)) => error_response(id, code, &message),
+        };
+
+        return vec![response];
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let document = params.get("textDocument");
+        let uri = document.and_then(|d| d.get("uri")).and_then(Value::as_str).unwrap_or("").to_string();
+
+        match method {
+            "exit" => {
+                self.exit_code = Some(if self.shutdown_requested { 0 } else { 1 });
+                return vec![];
+            }
+            "textDocument/didOpen" => {
+                let text = document.and_then(|d| d.get("text")).and_then(Value::as_str).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+            }
+            "textDocument/didChange" => {
+                // with full synchronization the last change holds the whole text
+                let changes = params.get("contentChanges").and_then(Value::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|change| change.get("text")).and_then(Value::as_str) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+            }
+            "textDocument/didSave" => {}
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, vec![])];
+            }
+            _ => return vec![],
+        }
+
+        let diagnostics = match self.analyze(params) {
+            Some((analysis, _)) => {
+                let source = &analysis.sources[0];
+                let warnings = if analysis.diagnostics.has_errors() {
+                    vec![]
+                } else {
+                    lint::lint(&analysis, &WarningSettings::default())
+                };
+
+                analysis.diagnostics.iter()
+                    .chain(&warnings)
+                    .filter(|d| d.file.is_none())
+                    .map(|d| diagnostic_to_lsp(d, source, &uri))
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        return vec![publish_diagnostics(&uri, diagnostics)];
+    }
+
+    fn initialize(&mut self, params: &Value) -> Value {
+        let root_uri = params.get("rootUri").and_then(Value::as_str);
+        let root_path = params.get("rootPath").and_then(Value::as_str);
+        self.root = root_uri.and_then(uri_to_path).or_else(|| root_path.map(PathBuf::from));
+
+        let capabilities = Value::object(vec![
+            ("textDocumentSync", Value::Number(SYNC_FULL)),
+            ("definitionProvider", true.into()),
+            ("referencesProvider", true.into()),
+            ("renameProvider", true.into()),
+            ("hoverProvider", true.into()),
+            ("documentSymbolProvider", true.into()),
+            ("completionProvider", Value::object(vec![("triggerCharacters", vec!["."].into())])),
+        ]);
+
+        return Value::object(vec![
+            ("capabilities", capabilities),
+            ("serverInfo", Value::object(vec![
+                ("name", env!("CARGO_PKG_NAME").into()),
+                ("version", env!("CARGO_PKG_VERSION").into()),
+            ])),
+        ]);
+    }
+
+    /// Analyzes the document of `params`, returning the analysis and the name
+    /// of the document in it.
+    fn analyze(&self, params: &Value) -> Option<(Analysis, String)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let text = self.documents.get(uri)?;
+        let path = uri_to_path(uri)?;
+
+        let name = path.display().to_string();
+        let analysis = Analysis::new(SourceFile::new(&name, text.clone()), self.assembler());
+
+        return Some((analysis, name));
+    }
+
+    /// Analyzes the document of `params` and the `.asm` files of the
+    /// workspace that include it, the document's analysis coming first.
+    fn analyze_workspace(&self, params: &Value) -> Option<(Vec<Analysis>, String)> {
+        let (analysis, name) = self.analyze(params)?;
+        let mut analyses = vec![analysis];
+
+        let (root, document) = match (&self.root, fs::canonicalize(&name)) {
+            (Some(root), Ok(document)) => (root, document),
+            _ => return Some((analyses, name)),
+        };
+
+        let mut files = vec![];
+        find_sources(root, &mut files);
+
+        for path in files {
+            if fs::canonicalize(&path).ok().as_ref() == Some(&document) {
+                continue;
+            }
+
+            let text = match self.documents.get(&path_to_uri(&path)) {
+                Some(text) => text.clone(),
+                None => match fs::read_to_string(&path) {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                },
+            };
+
+            let analysis = Analysis::new(SourceFile::new(&path.display().to_string(), text), self.assembler());
+            let includes_document = analysis.sources[1..].iter()
+                .any(|source| fs::canonicalize(&source.name).ok().as_ref() == Some(&document));
+            if includes_document {
+                analyses.push(analysis);
+            }
+        }
+
+        return Some((analyses, name));
+    }
+
+    // searching the workspace and the include paths, with the open documents
+    // instead of their files
+    fn assembler(&self) -> Assembler {
+        let mut assembler = Assembler::new();
+        if let Some(root) = &self.root {
+            assembler.include_paths.push(root.clone());
+        }
+        assembler.include_paths.extend(self.include_paths.iter().cloned());
+
+        for (other, text) in &self.documents {
+            if let Some(other) = uri_to_path(other).and_then(|path| fs::canonicalize(path).ok()) {
+                assembler.overlays.insert(other, text.clone());
+            }
+        }
+
+        return assembler;
+    }
+
+    fn with_position<F>(&self, params: &Value, request: F) -> Result<Value, (i64, String)>
+    where
+        F: Fn(&Analysis, &str, usize) -> Value,
+    {
+        let (line, character) = position_param(params)?;
+        let (analysis, file) = self.analyze(params).ok_or_else(unknown_document)?;
+        let offset = offset_at(&analysis.sources[0].text, line, character);
+
+        return Ok(request(&analysis, &file, offset));
+    }
+
+    fn with_workspace_position<F>(&self, params: &Value, request: F) -> Result<Value, (i64, String)>
+    where
+        F: Fn(&[Analysis], &str, usize) -> Result<Value, (i64, String)>,
+    {
+        let (line, character) = position_param(params)?;
+        let (analyses, file) = self.analyze_workspace(params).ok_or_else(unknown_document)?;
+        let offset = offset_at(&analyses[0].sources[0].text, line, character);
+
+        return request(&analyses, &file, offset);
+    }
+}
+
+fn position_param(params: &Value) -> Result<(usize, usize), (i64, String)> {
+    let position = params.get("position");
+    let line = position.and_then(|p| p.get("line")).and_then(Value::as_i64);
+    let character = position.and_then(|p| p.get("character")).and_then(Value::as_i64);
+
+    return match (line, character) {
+        (Some(line), Some(character)) => Ok((line as usize, character as usize)),
+        _ => Err((INVALID_PARAMS, "missing position".to_string())),
+    };
+}
+
+// `.asm` files under `directory`, skipping hidden directories
+fn find_sources(directory: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(directory) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+
+    for path in entries {
+        let hidden = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with('.'));
+        if path.is_dir() && !hidden {
+            find_sources(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "asm") {
+            files.push(path);
+        }
+    }
+}
+
+fn unknown_document() -> (i64, String) {
+    return (INVALID_PARAMS, "the document is not open".to_string());
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    return Value::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        ("error", Value::object(vec![
+            ("code", code.into()),
+            ("message", message.into()),
+        ])),
+    ]);
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    return Value::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        ("params", Value::object(vec![
+            ("uri", uri.into()),
+            ("diagnostics", Value::Array(diagnostics)),
+        ])),
+    ]);
+}
+
+fn diagnostic_to_lsp(diagnostic: &Diagnostic, source: &SourceFile, uri: &str) -> Value {
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+        Severity::Note => 3,
+    };
+
+    let mut message = diagnostic.message.clone();
+    for note in &diagnostic.notes {
+        message += &format!("\nnote: {}", note);
+    }
+    if let Some(help) = &diagnostic.help {
+        message += &format!("\nhelp: {}", help);
+    }
+
+    let related: Vec<Value> = diagnostic.secondary.iter()
+        .map(|label| Value::object(vec![
+            ("location", location(uri, &source.text, label.span)),
+            ("message", label.message.as_str().into()),
+        ]))
+        .collect();
+
+    return Value::object(vec![
+        ("range", range(&source.text, diagnostic.primary.span)),
+        ("severity", Value::Number(severity)),
+        ("code", diagnostic.code.into()),
+        ("source", env!("CARGO_PKG_NAME").into()),
+        ("message", message.into()),
+        ("relatedInformation", Value::Array(related)),
+    ]);
+}
+
+fn declaration_location(analysis: &Analysis, declaration: &Declaration) -> Option<Value> {
+    let source = analysis.source(&declaration.file)?;
+
+    return Some(location(&file_uri(&declaration.file), &source.text, declaration.span));
+}
+
+// the analyzed document has an absolute name, included files the name they
+// were found under
+fn file_uri(name: &str) -> String {
+    let path = Path::new(name);
+    if path.is_absolute() {
+        return path_to_uri(path);
+    }
+
+    let absolute = fs::canonicalize(path)
+        .or_else(|_| std::env::current_dir().map(|directory| directory.join(path)))
+        .unwrap_or_else(|_| path.to_path_buf());
+
+    return path_to_uri(&absolute);
+}
+
+fn definition(analysis: &Analysis, file: &str, offset: usize) -> Value {
+    return analysis.symbol_at(file, offset)
+        .and_then(|name| analysis.declaration(name))
+        .and_then(|declaration| declaration_location(analysis, declaration))
+        .unwrap_or(Value::Null);
+}
+
+fn references(analyses: &[Analysis], file: &str, offset: usize, include_declaration: bool) -> Value {
+    let name = match analyses[0].symbol_at(file, offset) {
+        Some(name) => name,
+        None => return Value::Null,
+    };
+
+    let mut locations = vec![];
+    for analysis in analyses {
+        if include_declaration {
+            locations.extend(analysis.declaration(name).and_then(|d| declaration_location(analysis, d)));
+        }
+
+        for reference in analysis.references_to(name) {
+            if let Some(source) = analysis.source(&reference.file) {
+                locations.push(location(&file_uri(&reference.file), &source.text, reference.span));
+            }
+        }
+    }
+
+    // files included by several analyses are seen more than once
+    let mut unique = vec![];
+    for location in locations {
+        if !unique.contains(&location) {
+            unique.push(location);
+        }
+    }
+
+    return Value::Array(unique);
+}
+
+fn rename_symbol(analyses: &[Analysis], file: &str, offset: usize, new_name: &str) -> Result<Value, (i64, String)> {
+    let name = analyses[0].symbol_at(file, offset)
+        .ok_or_else(|| (REQUEST_FAILED, "there is no symbol to rename here".to_string()))?;
+    let edits = rename::rename(analyses, name, new_name).map_err(|message| (REQUEST_FAILED, message))?;
+
+    let mut changes: Vec<(String, Value)> = vec![];
+    for edit in &edits {
+        let source = match analyses.iter().find_map(|analysis| analysis.source(&edit.file)) {
+            Some(source) => source,
+            None => continue,
+        };
+
+        let uri = file_uri(&edit.file);
+        let text_edit = Value::object(vec![
+            ("range", range(&source.text, edit.span)),
+            ("newText", edit.new_text.as_str().into()),
+        ]);
+
+        match changes.iter_mut().find(|(other, _)| *other == uri) {
+            Some((_, Value::Array(text_edits))) if text_edits.contains(&text_edit) => {}
+            Some((_, Value::Array(text_edits))) => text_edits.push(text_edit),
+            _ => changes.push((uri, Value::Array(vec![text_edit]))),
+        }
+    }
+
+    return Ok(Value::object(vec![("changes", Value::Object(changes))]));
+}
+
+fn hover(analysis: &Analysis, file: &str, offset: usize) -> Value {
+    let description = analysis.symbol_at(file, offset).and_then(|name| analysis.describe(name));
+
+    return match description {
+        Some(description) => Value::object(vec![
+            ("contents", Value::object(vec![
+                ("kind", "markdown".into()),
+                ("value", format!("```asm\n{}\n```", description).into()),
+            ])),
+        ]),
+        None => Value::Null,
+    };
+}
+
+fn document_symbols(analysis: &Analysis, file: &str) -> Value {
+    let source = &analysis.sources[0];
+    let uri = path_to_uri(Path::new(file));
+
+    let symbols: Vec<Value> = analysis.declarations.iter()
+        .filter(|declaration| declaration.file == file)
+        .map(|declaration| {
+            let kind = match declaration.kind {
+                DeclarationKind::Label => SYMBOL_FUNCTION,
+                DeclarationKind::Constant => SYMBOL_CONSTANT,
+                DeclarationKind::Variable => SYMBOL_VARIABLE,
+                DeclarationKind::String => SYMBOL_STRING,
+                DeclarationKind::Macro => SYMBOL_OPERATOR,
+            };
+
+            let mut fields = vec![
+                ("name", declaration.name.as_str().into()),
+                ("kind", Value::Number(kind)),
+                ("location", location(&uri, &source.text, declaration.span)),
+            ];
+            if let Some((parent, _)) = declaration.name.split_once('.') {
+                fields.push(("containerName", parent.into()));
+            }
+
+            Value::object(fields)
+        })
+        .collect();
+
+    return Value::Array(symbols);
+}
+
+fn completion(analysis: &Analysis, file: &str, offset: usize) -> Value {
+    let item = |label: String, kind: i64, detail: Option<String>| {
+        let mut fields = vec![
+            ("label", label.into()),
+            ("kind", Value::Number(kind)),
+        ];
+        if let Some(detail) = detail {
+            fields.push(("detail", detail.into()));
+        }
+
+        return Value::object(fields);
+    };
+
+    let mut items = vec![];
+
+    for mnemonic in sm83::MNEMONICS.iter().chain(&sm83::PSEUDO_MNEMONICS) {
+        items.push(item(mnemonic.to_string(), COMPLETION_KEYWORD, Some("instruction".to_string())));
+    }
+    for directive in format::DIRECTIVES.iter().chain(DATA_DIRECTIVES.iter()) {
+        items.push(item(directive.to_uppercase(), COMPLETION_KEYWORD, Some("directive".to_string())));
+    }
+
+    // global symbols, and the local labels of the current global label
+    let scope = analysis.scope_at(file, offset).map(|scope| format!("{}.", scope));
+    for declaration in &analysis.declarations {
+        let label = match &scope {
+            Some(scope) if declaration.name.starts_with(scope.as_str()) => declaration.name[scope.len() - 1..].to_string(),
+            _ if declaration.name.contains('.') => continue,
+            _ => declaration.name.clone(),
+        };
+
+        let kind = match declaration.kind {
+            DeclarationKind::Label => COMPLETION_REFERENCE,
+            DeclarationKind::Constant | DeclarationKind::String => COMPLETION_CONSTANT,
+            DeclarationKind::Variable => COMPLETION_VARIABLE,
+            DeclarationKind::Macro => COMPLETION_FUNCTION,
+        };
+
+        items.push(item(label, kind, analysis.describe(&declaration.name)));
+    }
+
+    return Value::Array(items);
+}
+
+fn location(uri: &str, text: &str, span: Span) -> Value {
+    return Value::object(vec![
+        ("uri", uri.into()),
+        ("range", range(text, span)),
+    ]);
+}
+
+fn range(text: &str, span: Span) -> Value {
+    return Value::object(vec![
+        ("start", position(text, span.start)),
+        ("end", position(text, span.end)),
+    ]);
+}
+
+/// LSP position of a byte offset: the line and the UTF-16 code unit in it.
+fn position(text: &str, offset: usize) -> Value {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = text[..line_start].matches('\n').count();
+    let character: usize = text[line_start..offset].chars().map(char::len_utf16).sum();
+
+    return Value::object(vec![
+        ("line", line.into()),
+        ("character", character.into()),
+    ]);
+}
+
+/// Byte offset of an LSP position, clamped to the end of its line.
+fn offset_at(text: &str, line: usize, character: usize) -> usize {
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+
+    return text.len();
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%' && i + 2 < bytes.len())
+            .then(|| std::str::from_utf8(&bytes[i + 1..i + 3]).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    return String::from_utf8(decoded).ok().map(PathBuf::from);
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+
+    for byte in path.display().to_string().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri += &format!("%{:02X}", byte);
+        }
+    }
+
+    return uri;
+}
+
+/// Reads one message framed by a `Content-Length` header, `None` at the end
+/// of the input.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header"))?;
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+
+    return String::from_utf8(content)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message is not UTF-8"));
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let content = message.to_string_compact();
+    write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+
+    return output.flush();
+}
+
+/// Serves requests until the client sends `exit` or closes the input, and
+/// returns the exit status.
+pub fn run(input: &mut impl BufRead, output: &mut impl Write, server: &mut Server) -> io::Result<i32> {
+    while let Some(content) = read_message(input)? {
+        let responses = match json::parse(&content) {
+            Ok(message) => server.handle(&message),
+            Err(message) => vec![error_response(Value::Null, PARSE_ERROR, &message)],
+        };
+
+        for response in &responses {
+            write_message(output, response)?;
+        }
+
+        if let Some(code) = server.exit_code() {
+            return Ok(code);
+        }
+    }
+
+    return Ok(if server.shutdown_requested { 0 } else { 1 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///project/main%20file.asm";
+
+    fn request(server: &mut Server, method: &str, params: Value) -> Value {
+        let message = Value::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", 1i64.into()),
+            ("method", method.into()),
+            ("params", params),
+        ]);
+
+        return server.handle(&message).remove(0).get("result").unwrap().clone();
+    }
+
+    fn at(line: i64, character: i64) -> Value {
+        return Value::object(vec![
+            ("textDocument", Value::object(vec![("uri", URI.into())])),
+            ("position", Value::object(vec![("line", line.into()), ("character", character.into())])),
+            ("context", Value::object(vec![("includeDeclaration", true.into())])),
+        ]);
+    }
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        return server.handle(&Value::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/didOpen".into()),
+            ("params", Value::object(vec![
+                ("textDocument", Value::object(vec![
+                    ("uri", URI.into()),
+                    ("languageId", "rgbasm".into()),
+                    ("version", 1i64.into()),
+                    ("text", text.into()),
+                ])),
+            ])),
+        ]));
+    }
+
+    #[test]
+    fn answering_requests() {
+        let mut server = Server::new(vec![]);
+        let text = concat!(
+            "DEF SPEED EQU 3 ; é\n",
+            "SECTION \"Main\", ROM0[$150]\n",
+            "Main:\n",
+            "\tld a, SPEED\n",
+            ".wait: jr .wait\n",
+            "\tld b, UNKNOWN\n",
+        );
+
+        let notifications = open(&mut server, text);
+        let diagnostics = notifications[0].get("params").unwrap().get("diagnostics").unwrap().as_array().unwrap();
+        let codes: Vec<&str> = diagnostics.iter().map(|d| d.get("code").unwrap().as_str().unwrap()).collect();
+        assert_eq!(codes, vec!["unused-label", "unreachable-code"]);
+
+        let definition = request(&mut server, "textDocument/definition", at(3, 8));
+        assert_eq!(definition.to_string_compact(), format!(
+            r#"{{"uri":"{}","range":{{"start":{{"line":0,"character":4}},"end":{{"line":0,"character":9}}}}}}"#, URI));
+
+        let references = request(&mut server, "textDocument/references", at(4, 11));
+        assert_eq!(references.as_array().unwrap().len(), 2);
+
+        let mut params = at(4, 1);
+        if let Value::Object(fields) = &mut params {
+            fields.push(("newName".to_string(), ".loop".into()));
+        }
+        let rename = request(&mut server, "textDocument/rename", params.clone());
+        let edits = rename.get("changes").unwrap().get(URI).unwrap().as_array().unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].get("newText").and_then(Value::as_str), Some(".loop"));
+
+        if let Value::Object(fields) = &mut params {
+            fields.last_mut().unwrap().1 = "ld".into();
+        }
+        let message = Value::object(vec![("id", 3i64.into()), ("method", "textDocument/rename".into()), ("params", params)]);
+        let response = server.handle(&message);
+        assert_eq!(response[0].get("error").unwrap().get("code"), Some(&Value::Number(REQUEST_FAILED)));
+
+        let hover = request(&mut server, "textDocument/hover", at(4, 1));
+        let contents = hover.get("contents").unwrap().get("value").unwrap();
+        assert_eq!(contents.as_str(), Some("```asm\nMain.wait: $00:$0152 in ROM0 section \"Main\"\n```"));
+
+        let symbols = request(&mut server, "textDocument/documentSymbol", at(0, 0));
+        let names: Vec<&str> = symbols.as_array().unwrap().iter().map(|s| s.get("name").unwrap().as_str().unwrap()).collect();
+        assert_eq!(names, vec!["SPEED", "Main", "Main.wait"]);
+
+        let completion = request(&mut server, "textDocument/completion", at(5, 1));
+        let labels: Vec<&str> = completion.as_array().unwrap().iter().map(|s| s.get("label").unwrap().as_str().unwrap()).collect();
+        assert!(labels.contains(&"ld") && labels.contains(&"SECTION") && labels.contains(&".wait") && labels.contains(&"SPEED"));
+
+        let response = server.handle(&json::parse(r#"{"jsonrpc":"2.0","id":2,"method":"workspace/symbol"}"#).unwrap());
+        assert_eq!(response[0].get("error").unwrap().get("code"), Some(&Value::Number(METHOD_NOT_FOUND)));
+    }
+
+    #[test]
+    fn searching_the_workspace() {
+        let directory = std::env::temp_dir().join(format!("gbct-workspace-{}", std::process::id()));
+        fs::create_dir_all(directory.join("src")).unwrap();
+        fs::write(directory.join("src/main.asm"), "INCLUDE \"lib.inc\"\nSECTION \"x\", ROM0\n\tcall Helper\n").unwrap();
+        fs::write(directory.join("other.asm"), "SECTION \"y\", ROM0\nHelper: ret\n").unwrap();
+        fs::write(directory.join("src/lib.inc"), "SECTION \"z\", ROM0\nHelper: ret\n").unwrap();
+
+        let mut server = Server::new(vec![directory.join("src")]);
+        let root = path_to_uri(&directory);
+        request(&mut server, "initialize", Value::object(vec![("rootUri", root.as_str().into())]));
+
+        let uri = path_to_uri(&directory.join("src/lib.inc"));
+        server.handle(&Value::object(vec![
+            ("method", "textDocument/didOpen".into()),
+            ("params", Value::object(vec![
+                ("textDocument", Value::object(vec![
+                    ("uri", uri.as_str().into()),
+                    ("text", "SECTION \"z\", ROM0\nHelper: ret\n".into()),
+                ])),
+            ])),
+        ]));
+
+        let params = Value::object(vec![
+            ("textDocument", Value::object(vec![("uri", uri.as_str().into())])),
+            ("position", Value::object(vec![("line", 1i64.into()), ("character", 0i64.into())])),
+            ("context", Value::object(vec![("includeDeclaration", false.into())])),
+            ("newName", "Assist".into()),
+        ]);
+
+        let references = request(&mut server, "textDocument/references", params.clone());
+        let uris: Vec<&str> = references.as_array().unwrap().iter().map(|l| l.get("uri").unwrap().as_str().unwrap()).collect();
+        assert_eq!(uris, vec![path_to_uri(&fs::canonicalize(directory.join("src/main.asm")).unwrap())]);
+
+        let rename = request(&mut server, "textDocument/rename", params);
+        let Value::Object(changes) = rename.get("changes").unwrap() else { panic!() };
+        assert_eq!(changes.len(), 2);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn publishing_diagnostics() {
+        let mut server = Server::new(vec![]);
+        let notifications = open(&mut server, "SECTION \"x\", ROM0\n\tld [hl], [hl]\n");
+
+        let params = notifications[0].get("params").unwrap();
+        assert_eq!(params.get("uri").and_then(Value::as_str), Some(URI));
+        let diagnostic = &params.get("diagnostics").unwrap().as_array().unwrap()[0];
+        assert_eq!(diagnostic.get("code").and_then(Value::as_str), Some("E0026"));
+        assert_eq!(diagnostic.get("range").unwrap().get("start").unwrap().get("line"), Some(&Value::Number(1)));
+    }
+
+    #[test]
+    fn framing_messages() {
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"rootUri":null}}"#;
+        let shutdown = r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#;
+        let exit = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let mut input = String::new();
+        for message in [initialize, "{broken", shutdown, exit] {
+            input += &format!("Content-Length: {}\r\n\r\n{}", message.len(), message);
+        }
+
+        let mut output = vec![];
+        let code = run(&mut input.as_bytes(), &mut output, &mut Server::new(vec![])).unwrap();
+        assert_eq!(code, 0);
+
+        let mut output = output.as_slice();
+        let first = json::parse(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert!(first.get("result").unwrap().get("capabilities").is_some());
+        let second = json::parse(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(second.get("error").unwrap().get("code"), Some(&Value::Number(PARSE_ERROR)));
+        let third = json::parse(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(third.get("result"), Some(&Value::Null));
+        assert_eq!(read_message(&mut output).unwrap(), None);
+    }
+
+    #[test]
+    fn converting_positions() {
+        let text = "a\n😀b\n";
+        assert_eq!(offset_at(text, 1, 2), 6);
+        assert_eq!(position(text, 6).to_string_compact(), r#"{"line":1,"character":2}"#);
+        assert_eq!(offset_at(text, 0, 10), 1);
+        assert_eq!(uri_to_path("file:///a%20b/%C3%A9.asm"), Some(PathBuf::from("/a b/é.asm")));
+        assert_eq!(path_to_uri(Path::new("/a b/é.asm")), "file:///a%20b/%C3%A9.asm");
+    }
+}
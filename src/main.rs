@@ -1,36 +1,1051 @@
-mod ast;
-mod lexer;
-mod parser;
+#![allow(clippy::needless_return)]
+
+mod cli;
 
 use std::env;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::time::Instant;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use gameboy_compiler_toolchain::diagnostic::{self, Diagnostics, SourceFile};
+use gameboy_compiler_toolchain::assembler::{Assembler, Placement};
+use gameboy_compiler_toolchain::analysis::Analysis;
+use gameboy_compiler_toolchain::cycles::{self, RoutineCycles};
+use gameboy_compiler_toolchain::disasm::SymbolName;
+use gameboy_compiler_toolchain::emulator::{Emulator, Memory};
+use gameboy_compiler_toolchain::debuginfo::{self, DebugInfo};
+use gameboy_compiler_toolchain::dictionary::{self, CompressionOptions, Dictionary};
+use gameboy_compiler_toolchain::gdb::{self, Session, Watched};
+use gameboy_compiler_toolchain::gfx::RenderOptions;
+use gameboy_compiler_toolchain::image::{self, Image};
+use gameboy_compiler_toolchain::profiler::Profiler;
+use gameboy_compiler_toolchain::symbols::SymbolValue;
+use gameboy_compiler_toolchain::{depfile, disasm, emit, format, gfx, header, lexer, link, lint, lsp, parser, peephole, rename, testing};
 
-// lexer (tokens) > ast (expressions/statements) > parser
+use cli::{CliError, Color, Command, Format, Options, EXIT_FAILURE, EXIT_IO, EXIT_SUCCESS, EXIT_USAGE};
 
 fn main() {
-    let arguments: Vec<String> = env::args().collect();
-    if arguments.len() == 1 {
-        println!("Path is missing as argument");
-        return;
+    let arguments: Vec<String> = env::args().skip(1).collect();
+
+    let options = match cli::parse_arguments(&arguments) {
+        Ok(options) => options,
+        Err(CliError::Help) => {
+            print!("{}", cli::USAGE);
+            process::exit(EXIT_SUCCESS);
+        }
+        Err(CliError::Usage(message)) => {
+            eprintln!("error: {}", message);
+            eprintln!("Run with --help for usage");
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    process::exit(run(&options));
+}
+
+fn run(options: &Options) -> i32 {
+    return match options.command {
+        Command::Lex | Command::Parse | Command::Check => run_frontend(options),
+        Command::Fmt => run_format(options),
+        Command::Lsp => run_lsp(options),
+        Command::Rename => run_rename(options),
+        Command::Opt => run_optimize(options),
+        Command::Asm => run_assemble(options),
+        Command::Link => run_link(options),
+        Command::Fix => run_fix(options),
+        Command::Disasm => run_disassemble(options),
+        Command::Test => run_tests(options),
+        Command::Profile => run_profile(options),
+        Command::Debug => run_debug(options),
+        Command::Gfx => run_gfx(options),
+        Command::Render => run_render(options),
+    };
+}
+
+fn read_source(path: &str) -> Result<SourceFile, i32> {
+    let result = if path == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map(|_| text)
+    } else {
+        fs::read_to_string(path)
+    };
+
+    return match result {
+        Ok(text) => Ok(SourceFile::new(if path == "-" { "<stdin>" } else { path }, text)),
+        Err(error) => {
+            eprintln!("error: cannot read `{}`: {}", path, error);
+            Err(EXIT_IO)
+        }
+    };
+}
+
+fn write_output(options: &Options, content: impl AsRef<[u8]>) -> i32 {
+    let path = match &options.output {
+        Some(path) if path != "-" => path,
+        _ => {
+            if let Err(error) = io::stdout().write_all(content.as_ref()) {
+                eprintln!("error: cannot write to standard output: {}", error);
+                return EXIT_IO;
+            }
+            return EXIT_SUCCESS;
+        }
+    };
+
+    if let Err(error) = fs::write(path, content) {
+        eprintln!("error: cannot write `{}`: {}", path, error);
+        return EXIT_IO;
+    }
+
+    return EXIT_SUCCESS;
+}
+
+fn use_color(options: &Options) -> bool {
+    return match options.color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+    };
+}
+
+fn report(diagnostics: &Diagnostics, sources: &[SourceFile], options: &Options) {
+    for d in diagnostics.iter() {
+        eprint!("{}", diagnostic::render_with_color(d, diagnostic::source_for(d, sources), use_color(options)));
+    }
+}
+
+// assembler with the include paths and definitions of the command line
+fn assembler(options: &Options) -> Result<Assembler, i32> {
+    let mut assembler = Assembler::new();
+    assembler.include_paths = options.include_paths.iter().map(PathBuf::from).collect();
+    assembler.missing_files_allowed = options.missing_dependencies;
+    assembler.tests_enabled = options.command == Command::Test;
+    assembler.text_compression = options.compress_text.as_ref().map(|charmap| CompressionOptions {
+        charmap: charmap.clone(),
+        table_size: options.dictionary_size.unwrap_or(dictionary::DEFAULT_TABLE_SIZE),
+        codes: options.dictionary_codes.clone(),
+    });
+
+    for (name, value) in &options.defines {
+        if let Err(message) = assembler.define(name, value) {
+            eprintln!("error: invalid define `{}`: {}", name, message);
+            return Err(EXIT_USAGE);
+        }
+    }
+
+    return Ok(assembler);
+}
+
+fn write_dependencies(options: &Options, assembler: &Assembler) -> i32 {
+    let path = match &options.dependency_file {
+        Some(path) => path,
+        None => return EXIT_SUCCESS,
+    };
+
+    let targets = if !options.dependency_targets.is_empty() {
+        options.dependency_targets.clone()
+    } else if let Some(output) = &options.output {
+        vec![output.clone()]
+    } else {
+        vec![Path::new(&options.inputs[0]).with_extension("o").display().to_string()]
+    };
+
+    let rules = depfile::make_rules(&targets, assembler.dependencies(), options.phony_dependencies);
+    if let Err(error) = fs::write(path, rules) {
+        eprintln!("error: cannot write `{}`: {}", path, error);
+        return EXIT_IO;
+    }
+
+    return EXIT_SUCCESS;
+}
+
+fn run_format(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `fmt` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let source = match read_source(&options.inputs[0]) {
+        Ok(source) => source,
+        Err(code) => return code,
+    };
+
+    let mut diagnostics = Diagnostics::with_settings(options.warnings.clone());
+    let formatted = format::format_source(&source.text, &mut diagnostics);
+    report(&diagnostics, std::slice::from_ref(&source), options);
+
+    return match formatted {
+        Some(formatted) => write_output(options, formatted),
+        None => {
+            eprintln!("error: `{}` was not formatted because it has errors", source.name);
+            EXIT_FAILURE
+        }
+    };
+}
+
+fn run_lsp(options: &Options) -> i32 {
+    let mut server = lsp::Server::new(options.include_paths.iter().map(PathBuf::from).collect());
+
+    return match lsp::run(&mut io::stdin().lock(), &mut io::stdout().lock(), &mut server) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: language server stopped: {}", error);
+            EXIT_IO
+        }
+    };
+}
+
+fn run_rename(options: &Options) -> i32 {
+    let (name, new_name) = (&options.inputs[0], &options.inputs[1]);
+
+    let mut analyses = vec![];
+    for input in &options.inputs[2..] {
+        if input == "-" {
+            eprintln!("error: `rename` edits its input files in place and cannot read standard input");
+            return EXIT_USAGE;
+        }
+
+        let source = match read_source(input) {
+            Ok(source) => source,
+            Err(code) => return code,
+        };
+
+        let assembler = match assembler(options) {
+            Ok(assembler) => assembler,
+            Err(code) => return code,
+        };
+
+        let analysis = Analysis::new(source, assembler);
+        report(&analysis.diagnostics, &analysis.sources, options);
+        analyses.push(analysis);
+    }
+
+    let edits = match rename::rename(&analyses, name, new_name) {
+        Ok(edits) => edits,
+        Err(message) => {
+            eprintln!("error: cannot rename `{}`: {}", name, message);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let mut files: Vec<&str> = edits.iter().map(|edit| edit.file.as_str()).collect();
+    files.dedup();
+
+    for file in &files {
+        let source = analyses.iter().find_map(|analysis| analysis.source(file)).unwrap();
+        let file_edits: Vec<&rename::Edit> = edits.iter().filter(|edit| edit.file == *file).collect();
+
+        if let Err(error) = fs::write(file, rename::apply(&source.text, &file_edits)) {
+            eprintln!("error: cannot write `{}`: {}", file, error);
+            return EXIT_IO;
+        }
+    }
+
+    eprintln!("renamed {} occurrence(s) of `{}` in {} file(s)", edits.len(), name, files.len());
+
+    return EXIT_SUCCESS;
+}
+
+fn run_optimize(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `opt` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let source = match read_source(&options.inputs[0]) {
+        Ok(source) => source,
+        Err(code) => return code,
+    };
+    let assembler = match assembler(options) {
+        Ok(assembler) => assembler,
+        Err(code) => return code,
+    };
+
+    let analysis = Analysis::with_diagnostics(source, assembler, Diagnostics::with_settings(options.warnings.clone()));
+    report(&analysis.diagnostics, &analysis.sources, options);
+
+    let source = &analysis.sources[0];
+    if analysis.diagnostics.has_errors() {
+        eprintln!("error: `{}` was not optimized because it has errors", source.name);
+        return EXIT_FAILURE;
+    }
+
+    let rewrites = peephole::optimize(&analysis);
+    let (mut bytes, mut cycles) = (0, 0);
+    for rewrite in &rewrites {
+        let (line, column) = source.line_col(rewrite.span.start);
+        eprintln!("{}:{}:{}: {}, {} byte(s) and {} cycle(s) saved", source.name, line, column, rewrite.description, rewrite.bytes_saved, rewrite.cycles_saved);
+        bytes += rewrite.bytes_saved;
+        cycles += rewrite.cycles_saved;
+    }
+    eprintln!("{} rewrite(s), {} byte(s) and {} cycle(s) saved", rewrites.len(), bytes, cycles);
+
+    let edits: Vec<&rename::Edit> = rewrites.iter().flat_map(|rewrite| &rewrite.edits).collect();
+
+    return write_output(options, rename::apply(&source.text, &edits));
+}
+
+fn run_assemble(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `asm` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let (assembler, rom, sources) = match assemble_and_link(options, &options.inputs[0]) {
+        Ok(linked) => linked,
+        Err(code) => return code,
+    };
+
+    if let Some(dictionary) = &assembler.dictionary {
+        report_dictionary(dictionary);
+    }
+
+    if let Some(path) = &options.debug_info {
+        if let Err(error) = fs::write(path, DebugInfo::new(&assembler, &sources).to_string()) {
+            eprintln!("error: cannot write `{}`: {}", path, error);
+            return EXIT_IO;
+        }
+    }
+
+    return write_output(options, rom);
+}
+
+// each input assembled on its own, then linked with the others
+fn run_link(options: &Options) -> i32 {
+    let mut diagnostics = Diagnostics::with_settings(options.warnings.clone());
+    let mut modules = vec![];
+    let mut sources = vec![];
+
+    for input in &options.inputs {
+        let source = match read_source(input) {
+            Ok(source) => source,
+            Err(code) => return code,
+        };
+        let mut assembler = match assembler(options) {
+            Ok(assembler) => assembler,
+            Err(code) => return code,
+        };
+
+        // the diagnostics of a file are reported for the first one otherwise
+        let mut file_diagnostics = Diagnostics::with_settings(options.warnings.clone());
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut file_diagnostics);
+        assembler.assemble(&ast, &source, &mut file_diagnostics);
+        for diagnostic in file_diagnostics.iter() {
+            let file = diagnostic.file.clone().unwrap_or_else(|| source.name.clone());
+            diagnostics.push(diagnostic.clone().with_file(&file));
+        }
+
+        sources.push(source);
+        sources.append(&mut assembler.sources);
+        modules.push(assembler);
+    }
+
+    let mut assembler = link::merge(modules, &mut diagnostics);
+    let rom = if diagnostics.has_errors() { vec![] } else { link::link(&mut assembler, &mut diagnostics) };
+    report(&diagnostics, &sources, options);
+
+    if diagnostics.has_errors() {
+        eprintln!("error: aborting due to {} previous error(s)", diagnostics.error_count());
+        return EXIT_FAILURE;
+    }
+
+    if let Some(path) = &options.debug_info {
+        if let Err(error) = fs::write(path, DebugInfo::new(&assembler, &sources).to_string()) {
+            eprintln!("error: cannot write `{}`: {}", path, error);
+            return EXIT_IO;
+        }
+    }
+
+    return write_output(options, rom);
+}
+
+fn run_fix(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `fix` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let input = &options.inputs[0];
+    let mut rom = match fs::read(input) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("error: cannot read `{}`: {}", input, error);
+            return EXIT_IO;
+        }
+    };
+
+    if let Err(message) = header::fix(&mut rom, &options.header) {
+        eprintln!("error: cannot fix `{}`: {}", input, message);
+        return EXIT_FAILURE;
+    }
+
+    return write_output(options, rom);
+}
+
+// on stderr, the ROM may be on stdout
+fn report_dictionary(dictionary: &Dictionary) {
+    let codes = match (dictionary.entries.first(), dictionary.entries.last()) {
+        (Some(first), Some(last)) => format!(", codes ${:02X}-${:02X}", first.code, last.code),
+        _ => String::new(),
+    };
+    eprintln!("Text dictionary of charmap `{}`: {} entries{}, {} bytes", dictionary.charmap, dictionary.entries.len(), codes, dictionary.table().len());
+    for entry in &dictionary.entries {
+        eprintln!("  ${:02X} {:<20} {:>6} uses", entry.code, format!("\"{}\"", entry.text.escape_debug()), entry.uses);
+    }
+    eprintln!("Strings: {} bytes, {} compressed, {} saved with the table", dictionary.original_size, dictionary.compressed_size, dictionary.saved());
+}
+
+fn run_tests(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `test` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let (assembler, rom, sources) = match assemble_and_link(options, &options.inputs[0]) {
+        Ok(linked) => linked,
+        Err(code) => return code,
+    };
+
+    let results = testing::run_tests(&assembler, &rom);
+    let mut failures = Diagnostics::new();
+    for result in &results {
+        let status = if result.passed() { "ok" } else { "FAILED" };
+        println!("test {} ... {} ({} cycles)", result.name, status, result.cycles);
+
+        for failure in &result.failures {
+            failures.push(failure.clone());
+        }
+    }
+
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    report(&failures, &sources, options);
+    println!();
+    println!("test result: {}. {} passed; {} failed", if failed == 0 { "ok" } else { "FAILED" }, results.len() - failed, failed);
+
+    return if failed == 0 { EXIT_SUCCESS } else { EXIT_FAILURE };
+}
+
+fn is_rom_path(path: &str) -> bool {
+    return ["gb", "gbc", "sgb"].iter().any(|extension| Path::new(path).extension().is_some_and(|e| e == *extension));
+}
+
+// the symbols of the command line, or the ones next to the ROM
+fn symbol_names(options: &Options, input: &str) -> Result<Vec<SymbolName>, i32> {
+    // asm only writes the debug information, RGBLINK the .sym file
+    let symbol_file = match &options.symbol_file {
+        Some(path) => Some(PathBuf::from(path)),
+        None => ["sym", "dbg"].iter()
+            .map(|extension| Path::new(input).with_extension(extension))
+            .find(|path| input != "-" && path.is_file()),
+    };
+
+    return match symbol_file {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(text) if text.starts_with(debuginfo::HEADER) => match DebugInfo::parse(&text) {
+                Ok(info) => Ok(info.symbol_names()),
+                Err(message) => {
+                    eprintln!("error: cannot read `{}`: {}", path.display(), message);
+                    Err(EXIT_FAILURE)
+                }
+            },
+            Ok(text) => Ok(disasm::parse_symbol_file(&text)),
+            Err(error) => {
+                eprintln!("error: cannot read `{}`: {}", path.display(), error);
+                Err(EXIT_IO)
+            }
+        },
+        None => Ok(vec![]),
+    };
+}
+
+fn run_profile(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `profile` expects a single input file");
+        return EXIT_USAGE;
     }
 
-    let path = &arguments[1];
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
+    // a ROM with its .sym file, or a source file to assemble
+    let input = &options.inputs[0];
+    let (rom, labels) = if is_rom_path(input) {
+        let rom = match fs::read(input) {
+            Ok(rom) => rom,
+            Err(error) => {
+                eprintln!("error: cannot read `{}`: {}", input, error);
+                return EXIT_IO;
+            }
+        };
+        let names = match symbol_names(options, input) {
+            Ok(names) => names,
+            Err(code) => return code,
+        };
+        let labels: Vec<(String, i32, u16)> = names.into_iter()
+            .map(|name| (name.name, name.bank as i32, name.address as u16))
+            .collect();
+        (rom, labels)
+    } else {
+        let (assembler, rom, _) = match assemble_and_link(options, input) {
+            Ok(linked) => linked,
+            Err(code) => return code,
+        };
 
-    let f: Vec<String> = reader.lines().collect::<Result<_, _>>().unwrap();
+        let labels = assembler.symbols.iter().filter_map(|symbol| match &symbol.value {
+            SymbolValue::Label(location) => Some((symbol.name.clone(), location.bank?, location.address? as u16)),
+            _ => None,
+        }).collect();
+        (rom, labels)
+    };
 
-    let start = Instant::now();
-    let tokens = lexer::lex_content(f);
-    let duration = start.elapsed();
-    println!("Lex content: {:?}", duration);
+    let mut emulator = Emulator::new(rom);
+    let mut profiler = Profiler::new(labels.iter().map(|(name, bank, address)| (name.as_str(), *bank, *address)));
+    for (name, cycles) in &options.budgets {
+        if let Err(message) = profiler.set_budget(name, *cycles) {
+            eprintln!("error: {}", message);
+            return EXIT_USAGE;
+        }
+    }
+
+    if let Err(message) = profiler.run_frames(&mut emulator, options.frames) {
+        eprintln!("error: {} after {} cycles", message, profiler.cycles);
+        return EXIT_FAILURE;
+    }
+
+    let mut output = format!("Profile of {} frame(s), {} cycles\n\n", profiler.frames, profiler.cycles);
+    output += &format!("{:>10} {:>6} {:>8} {:>8} {:>8}  routine\n", "cycles", "%", "calls", "average", "longest");
+
+    let percent = |cycles: u64| 100.0 * cycles as f64 / profiler.cycles.max(1) as f64;
+    for routine in profiler.routines() {
+        // calls that never returned, like the one of a main loop, have no length
+        let (average, longest) = match routine.average_call() {
+            Some(average) => (average.to_string(), routine.longest_call.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        output += &format!(
+            "{:>10} {:>5.1}% {:>8} {:>8} {:>8}  {}\n",
+            routine.cycles, percent(routine.cycles), routine.calls, average, longest, routine.name,
+        );
+    }
+    if profiler.unlabeled_cycles > 0 {
+        output += &format!("{:>10} {:>5.1}% {:>8} {:>8} {:>8}  (unlabeled)\n", profiler.unlabeled_cycles, percent(profiler.unlabeled_cycles), "", "", "");
+    }
+
+    let code = write_output(options, output);
+    if code != EXIT_SUCCESS {
+        return code;
+    }
+
+    let over_budget = profiler.over_budget();
+    for routine in &over_budget {
+        eprintln!(
+            "error: `{}` took more than its budget of {} cycles in {} of {} call(s), up to {} cycles",
+            routine.name, routine.budget.unwrap_or(0), routine.over_budget, routine.returns, routine.longest_call,
+        );
+    }
+
+    return if over_budget.is_empty() { EXIT_SUCCESS } else { EXIT_FAILURE };
+}
+
+/// Assembles a source file and links it into a ROM, reporting the
+/// diagnostics and writing the dependency file when there is no error, and returns the assembler with the
+/// sections placed, the ROM and the source files. The error is the exit
+/// status, a success when `-MG` stopped at a missing file.
+fn assemble_and_link(options: &Options, input: &str) -> Result<(Assembler, Vec<u8>, Vec<SourceFile>), i32> {
+    let source = read_source(input)?;
+    let mut assembler = assembler(options)?;
+
+    let mut diagnostics = Diagnostics::with_settings(options.warnings.clone());
+    let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+    assembler.assemble(&ast, &source, &mut diagnostics);
+
+    // the sections are only placed once everything assembled, and like
+    // rgbasm a missing file only gives the dependencies, not a partial ROM
+    let rom = if diagnostics.has_errors() || assembler.stopped() { vec![] } else { link::link(&mut assembler, &mut diagnostics) };
+
+    let mut sources = vec![source];
+    sources.append(&mut assembler.sources);
+    report(&diagnostics, &sources, options);
+
+    if diagnostics.has_errors() {
+        eprintln!("error: aborting due to {} previous error(s)", diagnostics.error_count());
+        return Err(EXIT_FAILURE);
+    }
+
+    // make would take the rules of a failed build as up to date
+    let status = write_dependencies(options, &assembler);
+    if status != EXIT_SUCCESS {
+        return Err(status);
+    }
+
+    if assembler.stopped() {
+        return Err(EXIT_SUCCESS);
+    }
+
+    return Ok((assembler, rom, sources));
+}
+
+fn run_debug(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `debug` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    // a ROM with its debug information, or a source file to assemble
+    let input = &options.inputs[0];
+    let (rom, debug_info) = if is_rom_path(input) {
+        let rom = match fs::read(input) {
+            Ok(rom) => rom,
+            Err(error) => {
+                eprintln!("error: cannot read `{}`: {}", input, error);
+                return EXIT_IO;
+            }
+        };
+        let path = match &options.debug_info {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(Path::new(input).with_extension("dbg")).filter(|path| path.is_file()),
+        };
+        let debug_info = match path {
+            Some(path) => {
+                let info = fs::read_to_string(&path).map_err(|error| error.to_string()).and_then(|text| DebugInfo::parse(&text));
+                match info {
+                    Ok(info) => info,
+                    Err(message) => {
+                        eprintln!("error: cannot read `{}`: {}", path.display(), message);
+                        return EXIT_IO;
+                    }
+                }
+            }
+            None => {
+                eprintln!("note: no debug information for `{}`, addresses will not map to source lines", input);
+                DebugInfo::default()
+            }
+        };
+        (rom, debug_info)
+    } else {
+        match assemble_and_link(options, input) {
+            Ok((assembler, rom, sources)) => {
+                let debug_info = DebugInfo::new(&assembler, &sources);
+                (rom, debug_info)
+            }
+            Err(code) => return code,
+        }
+    };
 
-    let start_parsing = Instant::now();
-    let parsed_ast = parser::parse_ast(tokens);
-    let duration_parsing = start_parsing.elapsed();
-    println!("Parse ast: {:?}", duration_parsing);
+    let emulator = Emulator::with_bus(Watched::new(Memory::new(rom)));
+    let mut session = Session::new(emulator, debug_info);
+
+    let listener = match TcpListener::bind(("127.0.0.1", options.port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("error: cannot listen on port {}: {}", options.port, error);
+            return EXIT_IO;
+        }
+    };
+    eprintln!("Waiting for GDB on localhost:{}, connect with `target remote :{}`", options.port, options.port);
+
+    let result = listener.accept().and_then(|(mut stream, address)| {
+        eprintln!("Connected to {}", address);
+        gdb::serve(&mut session, &mut stream, &mut |message| eprintln!("{}", message))
+    });
+    if let Err(error) = result {
+        eprintln!("error: connection to GDB lost: {}", error);
+        return EXIT_IO;
+    }
+
+    return EXIT_SUCCESS;
+}
+
+fn run_gfx(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `gfx` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let input = &options.inputs[0];
+    let bytes = match fs::read(input) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("error: cannot read `{}`: {}", input, error);
+            return EXIT_IO;
+        }
+    };
+    let graphics = Image::decode_with_palette(&bytes)
+        .and_then(|(image, palette)| gfx::convert(&image, palette.as_deref(), &options.gfx));
+    let graphics = match graphics {
+        Ok(graphics) => graphics,
+        Err(message) => {
+            eprintln!("error: cannot convert `{}`: {}", input, message);
+            return EXIT_FAILURE;
+        }
+    };
+
+    // what only the attributes of the CGB tell apart
+    if options.attrmap.is_none() {
+        if graphics.palette_count > 1 {
+            eprintln!("error: `{}` needs {} palettes, which only an attribute map selects, add --attrmap", input, graphics.palette_count);
+            return EXIT_FAILURE;
+        }
+        if options.tilemap.is_some() && graphics.tile_count > 256 {
+            eprintln!("error: `{}` has {} tiles, which only a tilemap with an attribute map can address, add --attrmap", input, graphics.tile_count);
+            return EXIT_FAILURE;
+        }
+    }
+
+    let files = [(&options.tilemap, &graphics.tilemap), (&options.attrmap, &graphics.attrmap), (&options.palette, &graphics.palettes)];
+    for (path, content) in files {
+        if let Some(path) = path {
+            if let Err(error) = fs::write(path, content) {
+                eprintln!("error: cannot write `{}`: {}", path, error);
+                return EXIT_IO;
+            }
+        }
+    }
+
+    return write_output(options, &graphics.tiles);
+}
+
+fn run_render(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `render` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let mut files = vec![];
+    for path in [Some(&options.inputs[0]), options.tilemap.as_ref(), options.attrmap.as_ref(), options.palette.as_ref()] {
+        files.push(match path.map(fs::read).transpose() {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                eprintln!("error: cannot read `{}`: {}", path.unwrap(), error);
+                return EXIT_IO;
+            }
+        });
+    }
+
+    // tile data, or a ROM with its labels
+    let input = &options.inputs[0];
+    let data = files[0].as_deref().unwrap_or_default();
+    let tiles = match &options.at {
+        Some(location) => {
+            let names = match symbol_names(options, input) {
+                Ok(names) => names,
+                Err(code) => return code,
+            };
+            match gfx::rom_data(data, &names, location, options.size) {
+                Ok(tiles) => tiles,
+                Err(message) => {
+                    eprintln!("error: cannot read tiles from `{}`: {}", input, message);
+                    return EXIT_FAILURE;
+                }
+            }
+        }
+        None => data,
+    };
+
+    let render_options = RenderOptions {
+        depth: options.gfx.depth,
+        columns: options.columns,
+        tilemap: files[1].as_deref(),
+        attrmap: files[2].as_deref(),
+        palettes: files[3].as_deref(),
+    };
+    let image = match gfx::render(tiles, &render_options) {
+        Ok(image) => image,
+        Err(message) => {
+            eprintln!("error: cannot render `{}`: {}", input, message);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let ppm = options.output.as_ref().is_some_and(|path| path.ends_with(".ppm"));
+    return write_output(options, if ppm { image::encode_ppm(&image) } else { image::encode_png(&image) });
+}
+
+fn run_disassemble(options: &Options) -> i32 {
+    if options.inputs.len() > 1 {
+        eprintln!("error: `disasm` expects a single input file");
+        return EXIT_USAGE;
+    }
+
+    let input = &options.inputs[0];
+    let result = if input == "-" {
+        let mut rom = vec![];
+        io::stdin().read_to_end(&mut rom).map(|_| rom)
+    } else {
+        fs::read(input)
+    };
+    let rom = match result {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("error: cannot read `{}`: {}", input, error);
+            return EXIT_IO;
+        }
+    };
+
+    let names = match symbol_names(options, input) {
+        Ok(names) => names,
+        Err(code) => return code,
+    };
+
+    return match disasm::disassemble(&rom, &names) {
+        Ok(source) => write_output(options, source),
+        Err(message) => {
+            eprintln!("error: cannot disassemble `{}`: {}", input, message);
+            EXIT_FAILURE
+        }
+    };
+}
 
-    dbg!(parsed_ast);
+// one line per instruction with its location, size and source, and the
+// bytes saved by choosing `jr` for `jmp`
+fn listing(placements: &[Placement], sources: &[SourceFile]) -> String {
+    let mut output = String::new();
+    let (mut short, mut long) = (0, 0);
+
+    for placement in placements {
+        let source = match sources.iter().find(|source| source.name == placement.file) {
+            Some(source) => source,
+            None => continue,
+        };
+
+        let (line, _) = source.line_col(placement.span.start);
+        let text = &source.text[placement.span.start..placement.span.end];
+        let location = placement.location.to_string();
+        output += &format!("{:<20} {:>2}  {}:{:<5} {}", location, placement.size, source.name, line, text);
+
+        match placement.relaxed {
+            Some(true) => {
+                output += "  ; jr, 1 byte saved";
+                short += 1;
+            }
+            Some(false) => {
+                output += "  ; jp";
+                long += 1;
+            }
+            None => {}
+        }
+        output.push('\n');
+    }
+
+    if short + long > 0 {
+        output += &format!("; jmp: {} as jr, {} as jp, {} byte(s) saved\n", short, long, short);
+    }
+
+    return output;
+}
+
+// one line per routine with its minimum and maximum cycles, and why it has
+// no maximum
+fn cycle_report(routines: &[RoutineCycles], sources: &[SourceFile]) -> String {
+    let mut output = String::new();
+
+    for routine in routines {
+        let location = routine.definition.as_ref().and_then(|definition| {
+            let source = sources.iter().find(|source| source.name == definition.file)?;
+            Some(format!("{}:{}", source.name, source.line_col(definition.span.start).0))
+        });
+        let max = routine.max.map_or("?".to_string(), |max| max.to_string());
+
+        output += &format!("{:<24} {:>8} {:>8}  {}", routine.name, routine.min, max, location.unwrap_or_default());
+        for note in &routine.notes {
+            output += &format!("\n{:<24} ; {}", "", note);
+        }
+        output.push('\n');
+    }
+
+    return output;
+}
+
+fn run_frontend(options: &Options) -> i32 {
+    if options.command != Command::Check && options.inputs.len() > 1 {
+        eprintln!("error: `{}` expects a single input file", options.command.name());
+        return EXIT_USAGE;
+    }
+
+    let mut emits: Vec<String> = if !options.emit.is_empty() {
+        options.emit.clone()
+    } else {
+        match options.command {
+            Command::Lex => vec!["tokens".to_string()],
+            Command::Parse => vec!["ast".to_string()],
+            _ if options.format == Format::Json => vec!["diagnostics".to_string()],
+            _ => vec![],
+        }
+    };
+    if options.cycles {
+        emits.push("cycles".to_string());
+    }
+
+    let mut output = String::new();
+    let mut error_count = 0;
+
+    for input in &options.inputs {
+        let source = match read_source(input) {
+            Ok(source) => source,
+            Err(code) => return code,
+        };
+
+        let mut diagnostics = Diagnostics::with_settings(options.warnings.clone());
+        let tokens = lexer::lex_content(&source.text);
+        let parsed_ast = parser::parse_ast(tokens.clone(), &mut diagnostics);
+        let mut sources = vec![source];
+        let mut placements = vec![];
+        let mut routines = vec![];
+
+        if options.command == Command::Check {
+            let assembler = match assembler(options) {
+                Ok(assembler) => assembler,
+                Err(code) => return code,
+            };
+
+            // the analysis parses the file again, with the same diagnostics
+            let source = sources.remove(0);
+            let mut analysis = Analysis::with_diagnostics(source, assembler, Diagnostics::with_settings(options.warnings.clone()));
+
+            // undefined symbols and sections that do not fit are only found
+            // once the sections are placed, like `asm` does
+            if !analysis.diagnostics.has_errors() && !analysis.assembler.stopped() {
+                link::link(&mut analysis.assembler, &mut analysis.diagnostics);
+            }
+
+            // make would take the rules of a failed build as up to date
+            if !analysis.diagnostics.has_errors() {
+                let status = write_dependencies(options, &analysis.assembler);
+                if status != EXIT_SUCCESS {
+                    return status;
+                }
+            }
+
+            // warnings about code that does not assemble would be noise
+            if !analysis.diagnostics.has_errors() {
+                for warning in lint::lint(&analysis, &options.warnings) {
+                    analysis.diagnostics.push(warning);
+                }
+            }
+
+            // jumps and calls are only known once the sections are placed
+            if options.cycles && !analysis.diagnostics.has_errors() {
+                routines = cycles::routine_cycles(&analysis.assembler, &analysis.sources);
+            }
+
+            placements = analysis.assembler.placements;
+            diagnostics = analysis.diagnostics;
+            sources = analysis.sources;
+        }
+
+        let source = &sources[0];
+
+        if options.format == Format::Json {
+            let mut fields = vec![];
+            for kind in &emits {
+                let value = match kind.as_str() {
+                    "tokens" => emit::tokens_to_json(&tokens, source),
+                    "ast" => emit::ast_to_json(&parsed_ast, source),
+                    "listing" => emit::listing_to_json(&placements, &sources),
+                    "cycles" => emit::cycles_to_json(&routines, &sources),
+                    _ => emit::diagnostics_to_json(&diagnostics, &sources),
+                };
+                fields.push((kind.as_str(), value));
+            }
+
+            let document = emit::document(source, fields);
+            if options.inputs.len() > 1 {
+                output += &document.to_string_compact();
+            } else {
+                output += &document.to_string_pretty();
+            }
+            output.push('\n');
+        } else {
+            for kind in &emits {
+                if kind == "tokens" {
+                    for token in &tokens {
+                        let (line, column) = source.line_col(token.span.start);
+                        output += &format!("{}:{} {} {:?}\n", line, column, token.token_type.as_str(), token.literal);
+                    }
+                } else if kind == "ast" {
+                    output += &format!("{:?}", parsed_ast);
+                } else if kind == "listing" {
+                    output += &listing(&placements, &sources);
+                } else if kind == "cycles" {
+                    output += &cycle_report(&routines, &sources);
+                } else {
+                    for d in diagnostics.iter() {
+                        output += &diagnostic::render(d, diagnostic::source_for(d, &sources));
+                    }
+                }
+            }
+        }
+
+        if !emits.iter().any(|kind| kind == "diagnostics") {
+            report(&diagnostics, &sources, options);
+        }
+
+        error_count += diagnostics.error_count();
+    }
+
+    let status = write_output(options, output);
+    if status != EXIT_SUCCESS {
+        return status;
+    }
+
+    if error_count > 0 {
+        eprintln!("error: aborting due to {} previous error(s)", error_count);
+        return EXIT_FAILURE;
+    }
+
+    return EXIT_SUCCESS;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembling_with_missing_files() {
+        let directory = env::temp_dir().join(format!("gbct-missing-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).display().to_string();
+        fs::write(path("main.asm"), "SECTION \"Main\", ROM0\nINCLUDE \"generated.inc\"\n\tnop\n").unwrap();
+
+        let arguments: Vec<String> = ["asm", "-MG", "-M", &path("main.d"), "-o", &path("main.gb"), &path("main.asm")]
+            .iter().map(|argument| argument.to_string()).collect();
+        let status = run(&cli::parse_arguments(&arguments).unwrap());
+
+        assert_eq!(status, EXIT_SUCCESS);
+        assert!(fs::read_to_string(path("main.d")).unwrap().contains("generated.inc"));
+        assert!(!Path::new(&path("main.gb")).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn checking_undefined_symbols() {
+        let directory = env::temp_dir().join(format!("gbct-check-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("main.asm").display().to_string();
+        fs::write(&path, "SECTION \"Main\", ROM0\nMain: call Nowhere\n").unwrap();
+
+        let dependencies = directory.join("main.d").display().to_string();
+        let arguments: Vec<String> = ["check", "--color", "never", "-M", &dependencies, &path].iter().map(|argument| argument.to_string()).collect();
+        assert_eq!(run(&cli::parse_arguments(&arguments).unwrap()), EXIT_FAILURE);
+        assert!(!Path::new(&dependencies).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reading_names_from_the_debug_information() {
+        let directory = env::temp_dir().join(format!("gbct-names-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).display().to_string();
+        fs::write(path("main.asm"), "SECTION \"Main\", ROM0[$150]\nMain: jr Main\n").unwrap();
+
+        let arguments: Vec<String> = ["asm", "--debug-info", &path("main.dbg"), "-o", &path("main.gb"), &path("main.asm")]
+            .iter().map(|argument| argument.to_string()).collect();
+        assert_eq!(run(&cli::parse_arguments(&arguments).unwrap()), EXIT_SUCCESS);
+
+        let arguments = vec!["disasm".to_string(), path("main.gb")];
+        let names = symbol_names(&cli::parse_arguments(&arguments).unwrap(), &path("main.gb")).unwrap();
+        assert!(names.iter().any(|name| name.name == "Main" && name.address == 0x150));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
 }
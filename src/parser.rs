@@ -1,7 +1,33 @@
 use std::result::Result::Ok;
 use crate::lexer;
 use crate::ast;
-use crate::lexer::TokenType;
+use crate::cst::{self, TreeBuilder};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::expr;
+use crate::lexer::{Span, TokenType};
+use crate::sm83;
+
+const E_UNSUPPORTED_STATEMENT: &str = "E0001";
+const E_EXPECTED_STRING: &str = "E0002";
+const E_UNTERMINATED_STRING: &str = "E0003";
+const E_EXPECTED_COMMA: &str = "E0004";
+const E_EXPECTED_IDENTIFIER: &str = "E0005";
+const E_UNTERMINATED_BLOCK: &str = "E0007";
+const E_TRAILING_TOKENS: &str = "E0008";
+const E_UNMATCHED_BLOCK_END: &str = "E0009";
+const E_EXPECTED_DEFINITION: &str = "E0016";
+const E_ELSE_NOT_LAST: &str = "E0017";
+const E_EXPECTED_BRACKET: &str = "E0018";
+const E_INVALID_EXPECTATION: &str = "E0032";
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<", ">", "<=", ">="];
+
+// directives the parser does not handle yet, so they are not mistaken for macro calls
+const UNSUPPORTED_DIRECTIVES: [&str; 27] = [
+    "for", "endr", "endm", "endc", "elif", "else", "export", "purge", "assert",
+    "static_assert", "opt", "pusho", "popo", "pushs", "pops", "load", "endl", "union", "nextu",
+    "endu", "print", "println", "warn", "fail", "shift", "break", "macro",
+];
 
 pub struct Parser {
     tokens: Vec<lexer::Token>,
@@ -9,15 +35,19 @@ pub struct Parser {
     position: usize,
     read_position: usize,
     token: Option<lexer::Token>,
-}
-
-#[derive(Debug)]
-pub struct ParsingError {
-    pub error_message: &'static str,
+    // end of the last consumed token that was not a space
+    last_end: usize,
+    // errors reported inside blocks, which keep parsing after them
+    diagnostics: Vec<Diagnostic>,
+    // the concrete syntax tree, when parsing for one
+    tree: Option<TreeBuilder>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<lexer::Token>) -> Self {
+    pub fn new(mut tokens: Vec<lexer::Token>) -> Self {
+        // only carries trivia when lexed with `lex_with_trivia`
+        tokens.retain(|tok| tok.token_type != TokenType::EOF);
+
         let tokens_number = tokens.len();
         let mut p = Self {
             tokens,
@@ -25,6 +55,9 @@ impl Parser {
             position: 0,
             read_position: 0,
             token: None,
+            last_end: 0,
+            diagnostics: vec![],
+            tree: None,
         };
 
         p.next_token();
@@ -32,84 +65,327 @@ impl Parser {
         return p;
     }
 
-    pub fn next_statement(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
-        self.skip_spaces();
+    /// Skips blank lines and comments, returning true once all tokens are consumed.
+    pub fn is_finished(&mut self) -> bool {
+        self.skip_blank_lines();
 
-        if self.token.is_none() {
-            return Err(ParsingError {
-                error_message: "No token left"
-            });
+        return self.token.is_none();
+    }
+
+    /// Parses statements until the end of the input or until one of the
+    /// `terminators` keywords starts a line, recovering from every error.
+    pub fn parse_statements(&mut self, terminators: &[&str]) -> Vec<Box<dyn ast::Statement>> {
+        let mut statements = vec![];
+
+        while !self.is_finished() {
+            if self.current_keyword().is_some_and(|k| terminators.contains(&k.as_str())) {
+                break;
+            }
+
+            let start = self.position;
+            let reported = self.diagnostics.len();
+            if let Some(tree) = self.tree.as_mut() {
+                tree.start(start);
+            }
+
+            match self.next_statement() {
+                Ok(statement) => {
+                    if let Some(tree) = self.tree.as_mut() {
+                        tree.finish(self.position, statement.my_type(), statement.span());
+                    }
+                    statements.push(statement);
+                }
+                Err(diagnostic) => {
+                    // the statement is skipped as a whole, so drop what was reported inside it
+                    self.diagnostics.truncate(reported);
+
+                    let message = diagnostic.message.clone();
+                    self.diagnostics.push(*diagnostic);
+
+                    let span = self.recover(start);
+                    if let Some(tree) = self.tree.as_mut() {
+                        tree.abandon(start, self.position, span);
+                    }
+                    statements.push(Box::new(ast::ErrorStatement {
+                        message,
+                        span,
+                    }));
+                }
+            }
         }
 
-        let token = self.token.as_ref().unwrap();
+        return statements;
+    }
+
+    pub fn next_statement(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        self.skip_blank_lines();
 
-        match token.token_type {
-            TokenType::SemiColon => {
-                self.skip_comment();
-                return self.next_statement();
+        let token = match self.token.as_ref() {
+            Some(token) => token.clone(),
+            None => {
+                return Err(Box::new(Diagnostic::error(E_UNSUPPORTED_STATEMENT, "No token left", self.end_span())));
             }
+        };
+
+        let statement = match token.token_type {
             TokenType::Identifier => {
-                if token.literal.to_lowercase() == "include".to_string() {
-                    return self.parse_include();
-                } else if token.literal.to_lowercase() == "section".to_string() {
-                    return self.parse_section();
-                } else if token.literal.to_lowercase() == "if".to_string() {
-                    return self.parse_if();
-                } else if token.literal.to_lowercase() == "setcharmap".to_string() {
-                    return self.parse_set_char_map();
-                } else if token.literal.to_lowercase() == "newcharmap".to_string() {
-                    return self.parse_new_char_map();
-                } else if token.literal.to_lowercase() == "charmap".to_string() {
-                    return self.parse_char_map();
-                } else if let Some(f) = self.peek_token() {
-                    let possible_name = token.literal.clone();
-
-                    if f.literal.to_lowercase() == "equ" {
-                        return self.parse_def()
-                    } else {
-                        self.skip_spaces();
-
-                        if let Some(tok) = self.peek_token() {
-                            if tok.token_type == TokenType::Identifier && tok.literal.to_lowercase() == "macro" {
-                                self.next_token();
-                                self.next_token();
-                                return self.parse_macro(possible_name)
-                            }
-                        }
-                    }
+                let keyword = token.literal.to_lowercase();
+
+                if keyword == "include" {
+                    self.parse_include()
+                } else if keyword == "incbin" {
+                    self.parse_incbin()
+                } else if keyword == "section" {
+                    self.parse_section()
+                } else if keyword == "if" {
+                    self.parse_if()
+                } else if keyword == "rept" {
+                    self.parse_rept()
+                } else if keyword == "setcharmap" {
+                    self.parse_set_char_map()
+                } else if keyword == "newcharmap" {
+                    self.parse_new_char_map()
+                } else if keyword == "charmap" {
+                    self.parse_char_map()
+                } else if keyword == "pushc" || keyword == "popc" {
+                    self.parse_char_map_stack(keyword == "pushc")
+                } else if keyword == "rsreset" || keyword == "rsset" {
+                    self.parse_rs()
+                } else if keyword == "def" || self.is_definition() {
+                    self.parse_def()
+                } else if self.is_macro_definition() {
+                    self.parse_macro(token.literal.clone())
+                } else if self.is_label() {
+                    // a statement may follow on the same line
+                    return self.parse_label();
+                } else if keyword == "test" {
+                    self.parse_test()
+                } else if keyword == "expect" {
+                    self.parse_expect()
+                } else if ["db", "dw", "dl", "ds"].contains(&keyword.as_str()) {
+                    self.parse_data()
+                } else if sm83::is_mnemonic(&keyword) {
+                    self.parse_instruction()
+                } else if !UNSUPPORTED_DIRECTIVES.contains(&keyword.as_str()) {
+                    self.parse_macro_call()
+                } else {
+                    Err(Box::new(self.unsupported_statement(&token)))
                 }
             }
-            _ => {}
+            TokenType::Dot if self.is_label() => return self.parse_label(),
+            _ => Err(Box::new(self.unsupported_statement(&token))),
+        };
+
+        if statement.is_ok() {
+            self.expect_end_of_line()?;
         }
 
-        return Err(ParsingError {
-            error_message: "Unsupported token found"
-        });
+        return statement;
     }
 
-    fn parse_include(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
-        self.skip_spaces();
+    /// Skips past a statement that failed to parse so parsing can continue.
+    ///
+    /// Block constructs are skipped up to their matching ENDM/ENDC/ENDR, so the
+    /// body is not reported line by line; everything else resumes at the next line.
+    pub fn recover(&mut self, start: usize) -> Span {
+        let start_span = self.tokens[start].span;
+        let error_position = self.position;
+
+        if let Some((opening, closing)) = self.block_delimiters(start) {
+            match self.find_block_end(start, opening, closing) {
+                Some(end) if end >= error_position => {
+                    self.seek(end + 1);
+                    return start_span.to(self.tokens[end].span);
+                }
+                Some(_) => {}
+                None => {
+                    // the block never ends, so only drop its first line
+                    self.seek(start);
+                }
+            }
+        }
+
+        while let Some(tok) = self.token.as_ref() {
+            if tok.token_type == TokenType::LineBreak {
+                break;
+            }
+
+            self.next_token();
+        }
+
+        return self.span_from(start_span);
+    }
+
+    fn block_delimiters(&self, start: usize) -> Option<(&'static [&'static str], &'static str)> {
+        let tok = self.tokens.get(start)?;
 
-        if let Some(k) = self.token.as_ref() {
-            if k.token_type != TokenType::DoubleQuote {
-                return Err(ParsingError {
-                    error_message: "Missing \" after include"
-                });
+        if tok.token_type != TokenType::Identifier {
+            return None;
+        }
+
+        let keyword = tok.literal.to_lowercase();
+
+        if keyword == "if" {
+            return Some((&["if"], "endc"));
+        } else if keyword == "rept" || keyword == "for" {
+            return Some((&["rept", "for"], "endr"));
+        } else if keyword == "test" {
+            return Some((&["test"], "endt"));
+        }
+
+        let mut position = start + 1;
+        while let Some(tok) = self.tokens.get(position) {
+            match tok.token_type {
+                TokenType::Colon | TokenType::Space | TokenType::Tab => position += 1,
+                TokenType::Identifier if tok.literal.eq_ignore_ascii_case("macro") => {
+                    return Some((&[], "endm"));
+                }
+                _ => break,
             }
         }
 
-        let path = self.next_string();
+        return None;
+    }
+
+    fn find_block_end(&self, start: usize, opening: &[&str], closing: &str) -> Option<usize> {
+        let mut depth = 0;
+
+        for position in start + 1..self.tokens_number {
+            let tok = &self.tokens[position];
+            if tok.token_type != TokenType::Identifier {
+                continue;
+            }
+
+            let keyword = tok.literal.to_lowercase();
+
+            if opening.contains(&keyword.as_str()) {
+                depth += 1;
+            } else if keyword == closing {
+                if depth == 0 {
+                    return Some(position);
+                }
+                depth -= 1;
+            }
+        }
+
+        return None;
+    }
+
+    fn unsupported_statement(&self, token: &lexer::Token) -> Diagnostic {
+        let opening = match token.literal.to_lowercase().as_str() {
+            "endm" => Some("MACRO"),
+            "endc" | "elif" | "else" => Some("IF"),
+            "endr" => Some("REPT or FOR"),
+            "endt" => Some("TEST"),
+            _ => None,
+        };
+
+        if let Some(opening) = opening {
+            let message = format!("`{}` without a matching {}", token.literal, opening);
+
+            return Diagnostic::error(E_UNMATCHED_BLOCK_END, &message, token.span)
+                .with_label("no open block to close");
+        }
+
+        let message = format!("Unsupported token `{}` found", token.literal.escape_debug());
+
+        return Diagnostic::error(E_UNSUPPORTED_STATEMENT, &message, token.span)
+            .with_label("expected a directive or definition");
+    }
+
+    fn parse_include(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        self.next_token();
+        self.skip_spaces();
+
+        if !self.current_is(TokenType::DoubleQuote) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_STRING, "Missing \" after include", self.current_span())
+                .with_label("expected a quoted path")
+                .with_help("write the path as a string, e.g. INCLUDE \"constants.inc\"")));
+        }
+
+        let path = self.next_string()?;
 
         return Ok(Box::new(ast::IncludeStatement {
             path,
+            span: self.span_from(start),
         }));
     }
 
-    fn parse_macro(&mut self, macro_name: String) -> Result<Box<dyn ast::Statement>, ParsingError> {
+    fn parse_incbin(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        self.next_token();
+        self.skip_spaces();
+
+        if !self.current_is(TokenType::DoubleQuote) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_STRING, "Missing \" after incbin", self.current_span())
+                .with_label("expected a quoted path")
+                .with_help("write the path as a string, e.g. INCBIN \"tiles.2bpp\"")));
+        }
+
+        let path = self.next_string()?;
+
+        // optional start offset and length
+        let mut arguments = vec![];
+        while arguments.len() < 2 {
+            self.skip_spaces();
+            if !self.current_is(TokenType::Comma) {
+                break;
+            }
+
+            self.next_token();
+            arguments.push(self.parse_expression()?);
+        }
+
+        let mut arguments = arguments.into_iter();
+
+        return Ok(Box::new(ast::IncbinStatement {
+            path,
+            start: arguments.next(),
+            length: arguments.next(),
+            span: self.span_from(start),
+        }));
+    }
+
+    fn is_macro_definition(&self) -> bool {
+        let mut position = self.read_position;
+
+        if let Some(tok) = self.tokens.get(position) {
+            if tok.token_type == TokenType::Colon {
+                position += 1;
+            }
+        }
+
+        while let Some(tok) = self.tokens.get(position) {
+            if tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab {
+                return tok.token_type == TokenType::Identifier && tok.literal.eq_ignore_ascii_case("macro");
+            }
+
+            position += 1;
+        }
+
+        return false;
+    }
+
+    fn parse_macro(&mut self, macro_name: String) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let name_span = self.current_span();
+
+        // skip name, colon and spaces up to the MACRO keyword
+        while let Some(tok) = self.token.as_ref() {
+            if tok.token_type == TokenType::Identifier && tok.literal.eq_ignore_ascii_case("macro") {
+                break
+            }
+
+            self.next_token();
+        }
+
+        let macro_span = self.current_span();
+        self.next_token();
+
         let mut tokens = vec![];
 
         while let Some(tok) = self.token.as_ref() {
-            if tok.token_type == TokenType::Identifier && tok.literal.to_lowercase() == "endm" {
+            if tok.token_type == TokenType::Identifier && tok.literal.eq_ignore_ascii_case("endm") {
                 break
             }
 
@@ -117,194 +393,785 @@ impl Parser {
             self.next_token();
         }
 
+        if self.token.is_none() {
+            return Err(Box::new(Diagnostic::error(E_UNTERMINATED_BLOCK, "Macro is missing its ENDM", macro_span)
+                .with_label("macro starts here")
+                .with_secondary(name_span, "macro defined here")
+                .with_help("add ENDM after the last line of the macro body")));
+        }
+
+        // skip endm
+        self.next_token();
+
         return Ok(Box::new(ast::MacroStatement {
             name: macro_name,
             tokens,
+            span: self.span_from(name_span),
         }));
     }
 
-    fn parse_section(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
-        self.skip_spaces();
+    fn parse_rept(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let rept_span = self.current_span();
+        self.next_token();
+        let count = self.parse_expression()?;
+        self.expect_end_of_line()?;
+
+        // the body is kept as text, like a macro's, and nested loops end at
+        // their own ENDR
+        let mut tokens = vec![];
+        let mut depth = 0;
 
-        if let Some(k) = self.token.as_ref() {
-            if k.token_type != TokenType::DoubleQuote {
-                return Err(ParsingError {
-                    error_message: "Missing \" after section"
-                });
+        while let Some(tok) = self.token.as_ref() {
+            if tok.token_type == TokenType::Identifier {
+                let keyword = tok.literal.to_lowercase();
+                if keyword == "rept" || keyword == "for" {
+                    depth += 1;
+                } else if keyword == "endr" {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
             }
+
+            tokens.push(tok.clone());
+            self.next_token();
         }
 
-        let name = self.next_string();
+        if self.token.is_none() {
+            return Err(Box::new(Diagnostic::error(E_UNTERMINATED_BLOCK, "REPT block is missing its ENDR", rept_span)
+                .with_label("block starts here")
+                .with_help("add ENDR after the last line of the repeated block")));
+        }
+
+        // skip endr
+        self.next_token();
+
+        return Ok(Box::new(ast::ReptStatement {
+            count,
+            tokens,
+            span: self.span_from(rept_span),
+        }));
+    }
+
+    fn is_label(&self) -> bool {
+        let mut position = self.position;
+        let adjacent = |position: usize| {
+            position > 0 && self.tokens.get(position).is_some_and(|tok| tok.span.start == self.tokens[position - 1].span.end)
+        };
+        let is = |position: usize, token_type: TokenType| {
+            self.tokens.get(position).is_some_and(|tok| tok.token_type == token_type)
+        };
+
+        // `.local`, the colon is optional
+        if is(position, TokenType::Dot) {
+            return is(position + 1, TokenType::Identifier) && adjacent(position + 1);
+        }
 
-        if let Some(k) = self.token.as_ref() {
-            if k.token_type != TokenType::Comma {
-                return Err(ParsingError {
-                    error_message: "Missing , after include name"
-                });
+        position += 1;
+        if is(position, TokenType::Dot) && adjacent(position) {
+            if !(is(position + 1, TokenType::Identifier) && adjacent(position + 1)) {
+                return false;
             }
+            position += 2;
         }
 
-        // skip comma
-        self.next_token();
+        return is(position, TokenType::Colon) && adjacent(position);
+    }
 
-        self.skip_spaces();
+    fn parse_label(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        let mut name = String::new();
+
+        while let Some(tok) = self.token.as_ref() {
+            let continues = name.is_empty() || tok.span.start == self.last_end;
+            if !continues || !(tok.token_type == TokenType::Identifier || tok.token_type == TokenType::Dot) {
+                break;
+            }
+
+            name += &tok.literal;
+            self.next_token();
+        }
 
-        let section_type = self.token.as_ref().unwrap();
+        let mut colons = 0;
+        while colons < 2 && self.current_is(TokenType::Colon) && self.current_span().start == self.last_end {
+            colons += 1;
+            self.next_token();
+        }
 
-        return Ok(Box::new(ast::SectionStatement {
+        return Ok(Box::new(ast::LabelStatement {
             name,
-            section_type: section_type.literal.clone(),
+            exported: colons == 2,
+            span: self.span_from(start),
+        }));
+    }
+
+    fn parse_data(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        let kind = match self.current_keyword().as_deref() {
+            Some("db") => ast::DataKind::Db,
+            Some("dw") => ast::DataKind::Dw,
+            Some("dl") => ast::DataKind::Dl,
+            _ => ast::DataKind::Ds,
+        };
+
+        self.next_token();
+
+        let mut values = vec![];
+        if !self.is_end_of_line() {
+            values.push(self.parse_expression()?);
+            self.skip_spaces();
+
+            while self.current_is(TokenType::Comma) {
+                self.next_token();
+                values.push(self.parse_expression()?);
+                self.skip_spaces();
+            }
+        }
+
+        return Ok(Box::new(ast::DataStatement {
+            kind,
+            values,
+            span: self.span_from(start),
         }));
     }
 
-    fn parse_if(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
+    fn parse_instruction(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        let mnemonic = self.current_keyword().unwrap();
+
+        self.next_token();
+
+        let mut operands = vec![];
+        if !self.is_end_of_line() {
+            operands.push(self.parse_operand()?);
+            self.skip_spaces();
+
+            while self.current_is(TokenType::Comma) {
+                self.next_token();
+                operands.push(self.parse_operand()?);
+                self.skip_spaces();
+            }
+        }
+
+        return Ok(Box::new(ast::InstructionStatement {
+            mnemonic,
+            operands,
+            span: self.span_from(start),
+        }));
+    }
+
+    fn parse_operand(&mut self) -> Result<ast::Operand, Box<Diagnostic>> {
         self.skip_spaces();
+        let start = self.current_span();
 
-        while let Some(tok) = self.token.as_ref() {
-            if tok.token_type == TokenType::Identifier && tok.literal.to_lowercase() == "endc" {
-                break
+        if self.current_is(TokenType::LeftBracket) {
+            self.next_token();
+            self.skip_spaces();
+
+            let operand = match self.current_keyword() {
+                Some(register) if ["bc", "de", "hl", "hli", "hld", "c"].contains(&register.as_str()) => {
+                    self.next_token();
+                    self.skip_spaces();
+
+                    let mut register = register;
+                    if register == "hl" {
+                        match self.token.as_ref() {
+                            Some(tok) if tok.token_type == TokenType::Operator && tok.literal == "+" => register = "hli".to_string(),
+                            Some(tok) if tok.token_type == TokenType::Operator && tok.literal == "-" => register = "hld".to_string(),
+                            _ => {}
+                        }
+                        if register != "hl" {
+                            self.next_token();
+                            self.skip_spaces();
+                        }
+                    }
+
+                    register
+                }
+                _ => {
+                    let expression = self.parse_expression()?;
+                    self.skip_spaces();
+
+                    match &expression {
+                        // `[$ff00 + c]`
+                        expr::Expression::Binary { operator, left, right, .. } if operator == "+"
+                            && matches!(**left, expr::Expression::Number(0xff00, _))
+                            && matches!(&**right, expr::Expression::Symbol(name, _) if name.eq_ignore_ascii_case("c")) => {
+                            "c".to_string()
+                        }
+                        _ => {
+                            self.expect_right_bracket(start)?;
+                            return Ok(ast::Operand::Indirect(expression, self.span_from(start)));
+                        }
+                    }
+                }
+            };
+
+            self.expect_right_bracket(start)?;
+            return Ok(ast::Operand::IndirectRegister(operand, self.span_from(start)));
+        }
+
+        if let Some(register) = self.current_keyword().filter(|k| sm83::is_register(k)) {
+            let offset = self.peek_significant()
+                .filter(|tok| tok.token_type == TokenType::Operator && (tok.literal == "+" || tok.literal == "-"))
+                .map(|tok| tok.literal.clone());
+
+            if register == "sp" && offset.is_some() {
+                self.next_token();
+                self.skip_spaces();
+                self.next_token();
+
+                let value = self.parse_expression()?;
+                let value = if offset.as_deref() == Some("-") {
+                    let span = value.span();
+                    expr::Expression::Unary {
+                        operator: "-".to_string(),
+                        operand: Box::new(value),
+                        span,
+                    }
+                } else {
+                    value
+                };
+
+                return Ok(ast::Operand::StackOffset(value, self.span_from(start)));
             }
 
             self.next_token();
+            return Ok(ast::Operand::Register(register, start));
+        }
+
+        return Ok(ast::Operand::Immediate(self.parse_expression()?));
+    }
+
+    fn parse_bracketed(&mut self) -> Result<expr::Expression, Box<Diagnostic>> {
+        self.skip_spaces();
+        let start = self.current_span();
+
+        if !self.current_is(TokenType::LeftBracket) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_BRACKET, "Missing [ before value", start)
+                .with_label("expected `[`")));
         }
 
         self.next_token();
+        let expression = self.parse_expression()?;
+        self.skip_spaces();
+        self.expect_right_bracket(start)?;
 
-        return Ok(Box::new(
-            ast::IfStatement{}
-        ));
+        return Ok(expression);
     }
 
-    fn parse_set_char_map(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
+    fn expect_right_bracket(&mut self, start: Span) -> Result<(), Box<Diagnostic>> {
+        if !self.current_is(TokenType::RightBracket) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_BRACKET, "Missing ] after operand", self.current_span())
+                .with_label("expected `]`")
+                .with_secondary(start, "`[` opened here")));
+        }
+
+        self.next_token();
+        return Ok(());
+    }
+
+    fn parse_macro_call(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        let name = self.token.as_ref().unwrap().literal.clone();
+
+        self.next_token();
         self.skip_spaces();
 
-        if let Some(tok) = self.token.as_ref() {
-            if tok.token_type != TokenType::Identifier {
-                return Err(ParsingError {
-                    error_message: "No identifier after setcharmap"
-                })
+        let mut arguments = vec![];
+        let mut argument_start = self.position;
+        let mut depth = 0;
+        let mut in_string = false;
+
+        while let Some(tok) = self.token.as_ref() {
+            match tok.token_type {
+                TokenType::LineBreak => break,
+                TokenType::SemiColon if !in_string => break,
+                TokenType::DoubleQuote => in_string = !in_string,
+                TokenType::LeftParen if !in_string => depth += 1,
+                TokenType::RightParen if !in_string => depth -= 1,
+                TokenType::Comma if !in_string && depth == 0 => {
+                    arguments.push(lexer::tokens_text(&self.tokens[argument_start..self.position]).trim().to_string());
+                    argument_start = self.read_position;
+                }
+                _ => {}
             }
 
-            let char_map_name = tok.clone().literal;
+            self.next_token();
+        }
 
-            return Ok(Box::new(
-                ast::SetCharMapStatement{
-                    name: char_map_name,
-                }
-            ));
+        let end = self.position.min(self.tokens_number);
+        if end > argument_start || !arguments.is_empty() {
+            arguments.push(lexer::tokens_text(&self.tokens[argument_start..end]).trim().to_string());
         }
 
-        return Err(ParsingError {
-            error_message: "Invalid token found"
-        })
+        return Ok(Box::new(ast::MacroCallStatement {
+            name,
+            arguments,
+            span: self.span_from(start),
+        }));
     }
 
-    fn parse_new_char_map(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
+    fn parse_section(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        self.next_token();
         self.skip_spaces();
 
-        if let Some(tok) = self.token.as_ref() {
-            if tok.token_type != TokenType::Identifier {
-                return Err(ParsingError {
-                    error_message: "No identifier after newcharmap"
-                })
+        if !self.current_is(TokenType::DoubleQuote) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_STRING, "Missing \" after section", self.current_span())
+                .with_label("expected a quoted section name")));
+        }
+
+        let name = self.next_string()?;
+        self.skip_spaces();
+
+        if !self.current_is(TokenType::Comma) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_COMMA, "Missing , after section name", self.current_span())
+                .with_label("expected `,` followed by the section type")));
+        }
+
+        // skip comma
+        self.next_token();
+        self.skip_spaces();
+
+        let section_type = self.expect_identifier("Missing section type after section name")?;
+        let mut section = ast::SectionStatement {
+            name,
+            section_type,
+            address: None,
+            bank: None,
+            alignment: None,
+            span: start,
+        };
+
+        self.skip_spaces();
+        if self.current_is(TokenType::LeftBracket) {
+            section.address = Some(self.parse_bracketed()?);
+        }
+
+        // `, BANK[n]` and `, ALIGN[n]`
+        self.skip_spaces();
+        while self.current_is(TokenType::Comma) {
+            self.next_token();
+            self.skip_spaces();
+
+            let option_span = self.current_span();
+            match self.current_keyword().as_deref() {
+                Some("bank") => {
+                    self.next_token();
+                    section.bank = Some(self.parse_bracketed()?);
+                }
+                Some("align") => {
+                    self.next_token();
+                    section.alignment = Some(self.parse_bracketed()?);
+                }
+                _ => {
+                    return Err(Box::new(Diagnostic::error(E_UNSUPPORTED_STATEMENT, "Unknown section option", option_span)
+                        .with_label("expected BANK[...] or ALIGN[...]")));
+                }
             }
+            self.skip_spaces();
+        }
+
+        section.span = self.span_from(start);
+
+        return Ok(Box::new(section));
+    }
+
+    fn parse_if(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let if_span = self.current_span();
+        let mut branches = vec![];
+        let mut else_span = None;
+
+        self.next_token();
+        let mut condition = Some(self.parse_expression()?);
 
-            let char_map_name = tok.clone().literal;
-            let mut names = vec![char_map_name];
+        loop {
+            self.expect_end_of_line()?;
+            let statements = self.parse_statements(&["elif", "else", "endc"]);
 
-            while let Some(tok) = self.peek_token() {
-                if tok.token_type != TokenType::Comma && tok.token_type != TokenType::Space {
-                    break
+            branches.push(ast::ConditionalBranch {
+                condition,
+                statements,
+            });
+
+            let keyword_span = self.current_span();
+            let keyword = match self.current_keyword() {
+                Some(keyword) => keyword,
+                None => {
+                    return Err(Box::new(Diagnostic::error(E_UNTERMINATED_BLOCK, "IF block is missing its ENDC", if_span)
+                        .with_label("block starts here")
+                        .with_help("add ENDC after the last line of the conditional block")));
                 }
+            };
 
-                self.next_token();
-                self.next_token();
-                self.next_token();
+            self.next_token();
 
-                names.push(self.token.as_ref().unwrap().literal.clone())
+            if keyword == "endc" {
+                break;
             }
 
-            return Ok(Box::new(
-                ast::NewCharMapStatement{
-                    names,
-                }
-            ));
+            if let Some(else_span) = else_span {
+                let message = format!("`{}` after ELSE", keyword.to_uppercase());
+
+                return Err(Box::new(Diagnostic::error(E_ELSE_NOT_LAST, &message, keyword_span)
+                    .with_label("no branch can follow ELSE")
+                    .with_secondary(else_span, "ELSE is here")));
+            }
+
+            if keyword == "else" {
+                else_span = Some(keyword_span);
+                condition = None;
+            } else {
+                condition = Some(self.parse_expression()?);
+            }
         }
 
-        return Err(ParsingError {
-            error_message: "Invalid token found"
-        })
+        return Ok(Box::new(
+            ast::IfStatement{
+                branches,
+                span: self.span_from(if_span),
+            }
+        ));
     }
 
-    fn parse_char_map(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
+    fn parse_test(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let test_span = self.current_span();
+        self.next_token();
         self.skip_spaces();
 
-        let value = self.next_string();
+        if !self.current_is(TokenType::DoubleQuote) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_STRING, "Missing \" after test", self.current_span())
+                .with_label("expected the name of the test")
+                .with_help("name the test with a string, e.g. TEST \"multiply\"")));
+        }
+
+        let name = self.next_string()?;
+        self.expect_end_of_line()?;
+        let statements = self.parse_statements(&["endt"]);
 
-        if let Some(k) = self.token.as_ref() {
-            if k.token_type != TokenType::Comma {
-                return Err(ParsingError {
-                    error_message: "Missing , after charmap value"
-                });
-            }
+        if self.current_keyword().as_deref() != Some("endt") {
+            return Err(Box::new(Diagnostic::error(E_UNTERMINATED_BLOCK, "TEST block is missing its ENDT", test_span)
+                .with_label("block starts here")
+                .with_help("add ENDT after the last line of the test")));
         }
+        self.next_token();
 
-        // skip comma
+        return Ok(Box::new(ast::TestStatement {
+            name,
+            statements,
+            span: self.span_from(test_span),
+        }));
+    }
+
+    fn parse_expect(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
         self.next_token();
 
+        let target = self.parse_operand()?;
         self.skip_spaces();
 
-        if let Some(tok) = self.token.as_ref() {
-            if tok.token_type != TokenType::Number {
-                return Err(ParsingError {
-                    error_message: "Missing number after charmap value"
-                });
+        let comparison = match self.token.as_ref() {
+            Some(tok) if tok.token_type == TokenType::Operator && COMPARISONS.contains(&tok.literal.as_str()) => {
+                let operator = tok.literal.clone();
+                self.next_token();
+                Some((operator, self.parse_expression()?))
             }
+            _ => None,
+        };
 
-            return Ok(Box::new(
-                ast::CharMapStatement{
-                    value,
-                    number: i32::from_str_radix(tok.literal.clone().as_str(), 16).unwrap()
-                }
-            ));
+        let valid = match (&target, &comparison) {
+            (ast::Operand::Register(name, _), None) => sm83::CONDITIONS.contains(&name.as_str()),
+            (ast::Operand::Register(name, _), Some(_)) => sm83::REGISTERS.contains(&name.as_str()) || name == "sp",
+            (ast::Operand::IndirectRegister(..) | ast::Operand::Indirect(..), Some(_)) => true,
+            _ => false,
+        };
+        if !valid {
+            return Err(Box::new(Diagnostic::error(E_INVALID_EXPECTATION, "Invalid EXPECT", self.span_from(start))
+                .with_label("expected a register or memory byte compared to a value, or a condition")
+                .with_help("write e.g. EXPECT a == $0C, EXPECT [wCount] != 0 or EXPECT nz")));
         }
 
-        return Err(ParsingError {
-            error_message: "Invalid token found"
-        })
+        return Ok(Box::new(ast::ExpectStatement {
+            target,
+            comparison,
+            span: self.span_from(start),
+        }));
     }
 
-    fn parse_def(&mut self) -> Result<Box<dyn ast::Statement>, ParsingError> {
-        let def_name = self.token.as_ref().unwrap().literal.clone();
+    fn parse_rs(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        let is_set = self.current_keyword().is_some_and(|k| k == "rsset");
+
+        self.next_token();
+
+        let value = if is_set {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+
+        return Ok(Box::new(ast::RsStatement {
+            value,
+            span: self.span_from(start),
+        }));
+    }
+
+    fn parse_set_char_map(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        self.next_token();
         self.skip_spaces();
 
-        // skip equ
+        let char_map_name = self.expect_identifier("No identifier after setcharmap")?;
+
+        return Ok(Box::new(
+            ast::SetCharMapStatement{
+                name: char_map_name,
+                span: self.span_from(start),
+            }
+        ));
+    }
+
+    fn parse_new_char_map(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
         self.next_token();
         self.skip_spaces();
 
-        let mut value = String::new();
+        let char_map_name = self.expect_identifier("No identifier after newcharmap")?;
+        let mut names = vec![char_map_name];
 
-        while let Some(tok) = self.token.as_ref() {
-            if tok.token_type == TokenType::LineBreak {
-                break;
+        self.skip_spaces();
+
+        // the charmap it starts as a copy of
+        if self.current_is(TokenType::Comma) {
+            self.next_token();
+            self.skip_spaces();
+
+            names.push(self.expect_identifier("No identifier after , in newcharmap")?);
+        }
+
+        return Ok(Box::new(
+            ast::NewCharMapStatement{
+                names,
+                span: self.span_from(start),
             }
+        ));
+    }
 
-            value.push_str(tok.literal.clone().as_str());
+    fn parse_char_map(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        self.next_token();
+        self.skip_spaces();
 
+        if !self.current_is(TokenType::DoubleQuote) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_STRING, "Missing \" after charmap", self.current_span())
+                .with_label("expected the mapped characters as a string")));
+        }
+
+        let key = self.parse_expression()?;
+        self.skip_spaces();
+
+        if !self.current_is(TokenType::Comma) {
+            return Err(Box::new(Diagnostic::error(E_EXPECTED_COMMA, "Missing , after charmap value", self.current_span())
+                .with_label("expected `,` followed by the mapped values")));
+        }
+
+        let mut values = vec![];
+        while self.current_is(TokenType::Comma) {
             self.next_token();
+            values.push(self.parse_expression()?);
+            self.skip_spaces();
         }
 
+        return Ok(Box::new(
+            ast::CharMapStatement{
+                key,
+                values,
+                span: self.span_from(start),
+            }
+        ));
+    }
+
+    fn parse_char_map_stack(&mut self, push: bool) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+        self.next_token();
+
+        return Ok(Box::new(
+            ast::CharMapStackStatement{
+                push,
+                span: self.span_from(start),
+            }
+        ));
+    }
+
+    fn is_definition(&self) -> bool {
+        return match self.peek_significant() {
+            Some(tok) if tok.token_type == TokenType::Identifier => {
+                ["equ", "equs", "set", "rb", "rw", "rl"].contains(&tok.literal.to_lowercase().as_str())
+            }
+            Some(tok) => tok.token_type == TokenType::Operator && tok.literal == "=",
+            None => false,
+        };
+    }
+
+    fn parse_def(&mut self) -> Result<Box<dyn ast::Statement>, Box<Diagnostic>> {
+        let start = self.current_span();
+
+        if self.current_keyword().is_some_and(|k| k == "def") {
+            self.next_token();
+            self.skip_spaces();
+        }
+
+        let name_span = self.current_span();
+        let def_name = self.expect_identifier("Missing symbol name in definition")?;
+        self.skip_spaces();
+
+        let kind = match self.token.as_ref() {
+            Some(tok) if tok.token_type == TokenType::Operator && tok.literal == "=" => Some(ast::DefKind::Set),
+            Some(tok) if tok.token_type == TokenType::Identifier => match tok.literal.to_lowercase().as_str() {
+                "equ" => Some(ast::DefKind::Equ),
+                "equs" => Some(ast::DefKind::Equs),
+                "set" => Some(ast::DefKind::Set),
+                "rb" => Some(ast::DefKind::Rb),
+                "rw" => Some(ast::DefKind::Rw),
+                "rl" => Some(ast::DefKind::Rl),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let kind = match kind {
+            Some(kind) => kind,
+            None => {
+                return Err(Box::new(Diagnostic::error(E_EXPECTED_DEFINITION, "Expected EQU, EQUS, =, SET, RB, RW or RL after the symbol name", self.current_span())
+                    .with_label("expected the kind of definition")));
+            }
+        };
+
+        // skip equ
+        self.next_token();
+        self.skip_spaces();
+
+        let value_start = self.position;
+        let has_value = !matches!(self.token.as_ref().map(|t| &t.token_type), None | Some(TokenType::LineBreak) | Some(TokenType::SemiColon));
+
+        let expression = if has_value || !matches!(kind, ast::DefKind::Rb | ast::DefKind::Rw | ast::DefKind::Rl) {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+
+        let value = lexer::tokens_text(&self.tokens[value_start..self.position.min(self.tokens_number)]);
+
         return Ok(Box::new(ast::DefStatement {
             name: def_name,
-            value,
+            name_span,
+            kind,
+            value: value.trim_end().to_string(),
+            expression,
+            span: self.span_from(start),
         }))
     }
 
-    fn skip_spaces(&mut self) {
-        self.next_token();
+    fn parse_expression(&mut self) -> Result<expr::Expression, Box<Diagnostic>> {
+        let start = self.position.min(self.tokens_number);
+        let (expression, used) = expr::parse_expression(&self.tokens[start..])?;
+        self.seek(start + used);
+
+        return Ok(expression);
+    }
+
+    fn expect_identifier(&mut self, message: &str) -> Result<String, Box<Diagnostic>> {
+        if let Some(tok) = self.token.as_ref() {
+            if tok.token_type == TokenType::Identifier {
+                let literal = tok.literal.clone();
+                self.next_token();
+
+                return Ok(literal);
+            }
+        }
+
+        return Err(Box::new(Diagnostic::error(E_EXPECTED_IDENTIFIER, message, self.current_span())
+            .with_label("expected an identifier")));
+    }
+
+    fn expect_end_of_line(&mut self) -> Result<(), Box<Diagnostic>> {
+        self.skip_spaces();
+
+        if self.current_is(TokenType::SemiColon) {
+            self.skip_comment();
+        }
+
+        return match self.token.as_ref() {
+            None => Ok(()),
+            Some(tok) if tok.token_type == TokenType::LineBreak => Ok(()),
+            Some(tok) => {
+                let message = format!("Unexpected `{}` after statement", tok.literal.escape_debug());
+
+                Err(Box::new(Diagnostic::error(E_TRAILING_TOKENS, &message, tok.span)
+                    .with_label("expected the end of the line")))
+            }
+        };
+    }
+
+    fn is_end_of_line(&mut self) -> bool {
+        self.skip_spaces();
+
+        return matches!(self.token.as_ref().map(|tok| &tok.token_type), None | Some(TokenType::LineBreak) | Some(TokenType::SemiColon));
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        return Span {
+            start: start.start,
+            end: self.last_end.max(start.end),
+        };
+    }
+
+    fn current_is(&self, token_type: TokenType) -> bool {
+        return self.token.as_ref().is_some_and(|tok| tok.token_type == token_type);
+    }
+
+    fn current_span(&self) -> Span {
+        return match self.token.as_ref() {
+            Some(tok) => tok.span,
+            None => self.end_span(),
+        };
+    }
+
+    fn end_span(&self) -> Span {
+        let end = self.tokens.last().map(|tok| tok.span.end).unwrap_or(0);
+
+        return Span {
+            start: end,
+            end,
+        };
+    }
 
+    fn current_keyword(&self) -> Option<String> {
+        return match self.token.as_ref() {
+            Some(tok) if tok.token_type == TokenType::Identifier => Some(tok.literal.to_lowercase()),
+            _ => None,
+        };
+    }
+
+    fn peek_significant(&self) -> Option<&lexer::Token> {
+        let mut position = self.read_position;
+
+        while let Some(tok) = self.tokens.get(position) {
+            if tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab {
+                return Some(tok);
+            }
+
+            position += 1;
+        }
+
+        return None;
+    }
+
+    fn skip_spaces(&mut self) {
         while let Some(tok) = self.token.as_ref() {
-            if tok.token_type != TokenType::Space && tok.token_type != TokenType::LineBreak && tok.token_type != TokenType::Tab {
+            if tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab {
                 break;
             }
 
@@ -312,6 +1179,16 @@ impl Parser {
         }
     }
 
+    fn skip_blank_lines(&mut self) {
+        while let Some(tok) = self.token.as_ref() {
+            match tok.token_type {
+                TokenType::Space | TokenType::Tab | TokenType::LineBreak => self.next_token(),
+                TokenType::SemiColon => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
     fn skip_comment(&mut self) {
         self.next_token();
 
@@ -324,7 +1201,9 @@ impl Parser {
         }
     }
 
-    fn next_string(&mut self) -> String {
+    fn next_string(&mut self) -> Result<String, Box<Diagnostic>> {
+        let quote_span = self.current_span();
+
         // skip quote
         self.next_token();
 
@@ -335,52 +1214,209 @@ impl Parser {
                 break;
             }
 
+            if tok.token_type == TokenType::LineBreak {
+                return Err(Box::new(Diagnostic::error(E_UNTERMINATED_STRING, "Unterminated string", quote_span)
+                    .with_label("string starts here")
+                    .with_secondary(tok.span, "line ends before the closing `\"`")));
+            }
+
             data += &*tok.literal;
             self.next_token();
         }
 
+        if self.token.is_none() {
+            return Err(Box::new(Diagnostic::error(E_UNTERMINATED_STRING, "Unterminated string", quote_span)
+                .with_label("string starts here")));
+        }
+
         // skip quote
         self.next_token();
 
-        return data;
+        return Ok(data);
+    }
+
+    fn seek(&mut self, position: usize) {
+        self.token = None;
+        self.last_end = self.tokens[..position.min(self.tokens_number)].iter()
+            .rev()
+            .find(|tok| tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab)
+            .map(|tok| tok.span.end)
+            .unwrap_or(0);
+        self.read_position = position;
+        self.next_token();
     }
 
     fn next_token(&mut self) {
-        if self.read_position >= self.tokens_number {
-            self.token = None
-        } else {
-            self.token = Some(self.tokens[self.position].clone());
+        if let Some(tok) = self.token.as_ref() {
+            if tok.token_type != TokenType::Space && tok.token_type != TokenType::Tab {
+                self.last_end = tok.span.end;
+            }
         }
 
         self.position = self.read_position;
         self.read_position += 1;
-    }
 
-    fn peek_token(&self) -> Option<lexer::Token> {
-        return if self.read_position >= self.tokens_number {
-            None
+        if self.position >= self.tokens_number {
+            self.token = None
         } else {
-            Some(self.tokens[self.read_position].clone())
-        };
+            self.token = Some(self.tokens[self.position].clone());
+        }
     }
 }
 
-pub fn parse_ast(tokens: Vec<lexer::Token>) -> Result<ast::Ast, ParsingError> {
+pub fn parse_ast(tokens: Vec<lexer::Token>, diagnostics: &mut Diagnostics) -> ast::Ast {
     let mut parser = Parser::new(tokens);
-    let mut statements = vec![];
-    let mut stmt = parser.next_statement();
-    while stmt.is_ok() && parser.token.is_some() {
-        statements.push(stmt.unwrap());
-        stmt = parser.next_statement();
-    }
+    let statements = parser.parse_statements(&[]);
 
-    if stmt.is_err() {
-        println!("Error: {}", stmt.err().unwrap().error_message)
-    } else {
-        statements.push(stmt.unwrap());
+    for diagnostic in parser.diagnostics.drain(..) {
+        diagnostics.push(diagnostic);
     }
 
-    return Ok(ast::Ast {
+    return ast::Ast {
         statements,
-    });
+    };
+}
+
+/// Parses tokens lexed with `lexer::lex_with_trivia` into the AST and the
+/// concrete syntax tree holding every token.
+pub fn parse_tree(tokens: Vec<lexer::Token>, diagnostics: &mut Diagnostics) -> (ast::Ast, cst::SyntaxNode) {
+    let mut parser = Parser::new(tokens.clone());
+    parser.tree = Some(TreeBuilder::new(tokens));
+    let statements = parser.parse_statements(&[]);
+
+    for diagnostic in parser.diagnostics.drain(..) {
+        diagnostics.push(diagnostic);
+    }
+
+    let root = parser.tree.take().unwrap().build();
+
+    return (ast::Ast { statements }, root);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (ast::Ast, Diagnostics) {
+        let mut diagnostics = Diagnostics::new();
+        let ast = parse_ast(lexer::lex_content(content), &mut diagnostics);
+
+        return (ast, diagnostics);
+    }
+
+    fn statement_types(ast: &ast::Ast) -> Vec<String> {
+        return ast.statements.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn recovering_at_next_line() {
+        let (ast, diagnostics) = parse(concat!(
+            "INCLUDE foo.asm\n",
+            "SECTION \"Main\", ROM0\n",
+            "SECTION Main\n",
+            "setcharmap main\n",
+        ));
+
+        assert_eq!(diagnostics.error_count(), 2);
+        assert_eq!(statement_types(&ast), vec![
+            "Error \"Missing \" after include\"",
+            "SECTION \"Main\"",
+            "Error \"Missing \" after section\"",
+            "Set Char Map \"main\"",
+        ]);
+    }
+
+    #[test]
+    fn recovering_after_block() {
+        let (ast, diagnostics) = parse(concat!(
+            "FOR I, 3\n",
+            "  nop\n",
+            "ENDR\n",
+            "foo: MACRO\n",
+            "  ld a, b\n",
+            "ENDM extra\n",
+            "newcharmap main\n",
+            "ENDC\n",
+        ));
+
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.message.clone()).collect();
+
+        assert_eq!(messages, vec![
+            "Unsupported token `FOR` found",
+            "Unexpected `extra` after statement",
+            "`ENDC` without a matching IF",
+        ]);
+        assert_eq!(ast.statements.len(), 4);
+        assert_eq!(ast.statements[2].to_string(), "New Char Map main");
+    }
+
+    #[test]
+    fn recovering_from_unterminated_block() {
+        let (ast, diagnostics) = parse(concat!(
+            "IF DEBUG\n",
+            "setcharmap main\n",
+        ));
+
+        assert_eq!(diagnostics.error_count(), 1);
+        assert_eq!(statement_types(&ast), vec![
+            "Error \"IF block is missing its ENDC\"",
+            "Set Char Map \"main\"",
+        ]);
+    }
+
+    #[test]
+    fn parsing_labels_and_instructions() {
+        let (ast, diagnostics) = parse(concat!(
+            "Main:: ld a, [hl+]\n",
+            ".loop\tld [$FF00 + c], a\n",
+            "\tld hl, sp - 2\n",
+            "\tdb \"a, b\", 1\n",
+            "\tmy_macro 1, (2, 3)\n",
+        ));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(statement_types(&ast), vec![
+            "Label \"Main\"",
+            "Instruction \"ld\" 2 operand(s)",
+            "Label \".loop\"",
+            "Instruction \"ld\" 2 operand(s)",
+            "Instruction \"ld\" 2 operand(s)",
+            "Data \"db\" 2 value(s)",
+            "Macro call \"my_macro\" 1, (2, 3)",
+        ]);
+
+        let instruction = ast.statements[3].as_any().downcast_ref::<ast::InstructionStatement>().unwrap();
+        assert!(matches!(&instruction.operands[0], ast::Operand::IndirectRegister(register, _) if register == "c"));
+
+        let label = ast.statements[0].as_any().downcast_ref::<ast::LabelStatement>().unwrap();
+        assert!(label.exported);
+    }
+
+    #[test]
+    fn parsing_test_blocks() {
+        let (ast, diagnostics) = parse(concat!(
+            "TEST \"multiply\"\n",
+            "\tcall Multiply\n",
+            "\tEXPECT a == $0C\n",
+            "\tEXPECT [wResult + 1] != 0\n",
+            "\tEXPECT nz\n",
+            "ENDT\n",
+            "\tEXPECT 3\n",
+            "\tEXPECT nz == 1\n",
+            "TEST \"unterminated\"\n",
+        ));
+
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.message.clone()).collect();
+        assert_eq!(messages, vec!["Invalid EXPECT", "Invalid EXPECT", "TEST block is missing its ENDT"]);
+        assert_eq!(statement_types(&ast)[0], "Test \"multiply\" 4 statement(s)");
+
+        let test = ast.statements[0].as_any().downcast_ref::<ast::TestStatement>().unwrap();
+        let expect = test.statements[2].as_any().downcast_ref::<ast::ExpectStatement>().unwrap();
+        assert!(matches!(&expect.target, ast::Operand::Indirect(..)));
+        assert_eq!(expect.comparison.as_ref().unwrap().0, "!=");
+
+        let expect = test.statements[3].as_any().downcast_ref::<ast::ExpectStatement>().unwrap();
+        assert!(matches!(&expect.target, ast::Operand::Register(condition, _) if condition == "nz"));
+        assert!(expect.comparison.is_none());
+    }
 }
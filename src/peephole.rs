@@ -0,0 +1,446 @@
+//! Peephole optimizer, rewriting short runs of instructions of a source file
+//! into shorter or faster ones with the same effect.
+//!
+//! Instructions are looked at in the order they are assembled. Labels, data,
+//! definitions, macro invocations, includes and conditional blocks end a run,
+//! since code can jump there or assemble differently, and so do the lines
+//! between `; optimize: off` and `; optimize: on` comments. Rewrites only make
+//! code shorter, so a `jp` turned into `jr` stays in reach after the others.
+
+use crate::analysis::{Analysis, DeclarationKind};
+use crate::ast::{self, Operand, Statement, StatementType};
+use crate::expr::Expression;
+use crate::lexer::{self, Span, TriviaKind};
+use crate::rename::Edit;
+use crate::sm83;
+use crate::symbols::SymbolValue;
+
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    /// The rewritten instructions
+    pub span: Span,
+    pub description: String,
+    pub edits: Vec<Edit>,
+    pub bytes_saved: i32,
+    /// Machine cycles saved, when branches are taken
+    pub cycles_saved: i32,
+}
+
+enum Item<'a> {
+    Instruction(&'a ast::InstructionStatement),
+    /// Anything the optimizer cannot see through
+    Barrier,
+}
+
+/// Rewrites of the analyzed file, which must have assembled without errors.
+pub fn optimize(analysis: &Analysis) -> Vec<Rewrite> {
+    let optimizer = Optimizer {
+        analysis,
+        file: &analysis.sources[0].name,
+        text: &analysis.sources[0].text,
+        disabled: disabled_regions(&analysis.sources[0].text),
+    };
+
+    let mut items = vec![];
+    optimizer.flatten(&analysis.ast.statements, &mut items);
+
+    let mut rewrites = vec![];
+    let mut i = 0;
+    while i < items.len() {
+        let instruction = match items[i] {
+            Item::Instruction(instruction) => instruction,
+            Item::Barrier => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let next = match items.get(i + 1) {
+            Some(Item::Instruction(next)) => Some(*next),
+            _ => None,
+        };
+
+        let pair = next.and_then(|next| optimizer.tail_call(instruction, next).or_else(|| optimizer.repeated_load(instruction, next)));
+        if let Some(rewrite) = pair {
+            rewrites.push(rewrite);
+            i += 2;
+            continue;
+        }
+
+        let single = optimizer.relaxed_jump(instruction)
+            .or_else(|| optimizer.cleared_a(instruction, &items[i + 1..]))
+            .or_else(|| optimizer.useless_load(instruction));
+        rewrites.extend(single);
+        i += 1;
+    }
+
+    return rewrites;
+}
+
+// byte ranges between `; optimize: off` and `; optimize: on`
+fn disabled_regions(text: &str) -> Vec<Span> {
+    let mut regions = vec![];
+    let mut start = None;
+
+    for token in lexer::lex_with_trivia(text) {
+        for trivia in token.leading_trivia.iter().chain(&token.trailing_trivia) {
+            if trivia.kind != TriviaKind::Comment {
+                continue;
+            }
+
+            match trivia.text.trim_start_matches(';').trim() {
+                "optimize: off" if start.is_none() => start = Some(trivia.span.start),
+                "optimize: on" => {
+                    if let Some(start) = start.take() {
+                        regions.push(Span { start, end: trivia.span.end });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(start) = start {
+        regions.push(Span { start, end: text.len() });
+    }
+
+    return regions;
+}
+
+struct Optimizer<'a> {
+    analysis: &'a Analysis,
+    file: &'a str,
+    text: &'a str,
+    disabled: Vec<Span>,
+}
+
+impl<'a> Optimizer<'a> {
+    fn flatten(&self, statements: &'a [Box<dyn Statement>], items: &mut Vec<Item<'a>>) {
+        for statement in statements {
+            match statement.my_type() {
+                StatementType::Instruction => {
+                    let instruction = statement.as_any().downcast_ref::<ast::InstructionStatement>().unwrap();
+                    let disabled = self.disabled.iter().any(|region| region.start <= instruction.span.start && instruction.span.start < region.end);
+
+                    items.push(if disabled { Item::Barrier } else { Item::Instruction(instruction) });
+                }
+                StatementType::If => {
+                    let conditional = statement.as_any().downcast_ref::<ast::IfStatement>().unwrap();
+                    for branch in &conditional.branches {
+                        items.push(Item::Barrier);
+                        self.flatten(&branch.statements, items);
+                    }
+                    items.push(Item::Barrier);
+                }
+                StatementType::Test => {
+                    let test = statement.as_any().downcast_ref::<ast::TestStatement>().unwrap();
+                    items.push(Item::Barrier);
+                    self.flatten(&test.statements, items);
+                    items.push(Item::Barrier);
+                }
+                // defines no code
+                StatementType::Macro => {}
+                _ => items.push(Item::Barrier),
+            }
+        }
+    }
+
+    fn rewrite(&self, span: Span, description: &str, old: &[(&str, &[Operand])], new: &[(&str, &[Operand])], edits: Vec<Edit>) -> Rewrite {
+        let cost = |instructions: &[(&str, &[Operand])]| -> (i32, i32) {
+            let encodings = instructions.iter().filter_map(|(mnemonic, operands)| sm83::encode(mnemonic, operands).ok());
+            return encodings.fold((0, 0), |(size, cycles), e| (size + e.size() as i32, cycles + e.cycles as i32));
+        };
+        let (old_size, old_cycles) = cost(old);
+        let (new_size, new_cycles) = cost(new);
+
+        return Rewrite {
+            span,
+            description: description.to_string(),
+            edits,
+            bytes_saved: old_size - new_size,
+            cycles_saved: old_cycles - new_cycles,
+        };
+    }
+
+    // `call X` followed by `ret` becomes `jp X`, X returning to our caller
+    fn tail_call(&self, call: &ast::InstructionStatement, ret: &ast::InstructionStatement) -> Option<Rewrite> {
+        if call.mnemonic != "call" || call.operands.len() != 1 || ret.mnemonic != "ret" || !ret.operands.is_empty() {
+            return None;
+        }
+
+        let edits = vec![self.replace_mnemonic(call, "jp"), self.remove(ret)];
+        let old: [(&str, &[Operand]); 2] = [("call", &call.operands), ("ret", &[])];
+
+        return Some(self.rewrite(call.span.to(ret.span), "`call` followed by `ret` to `jp`", &old, &[("jp", &call.operands)], edits));
+    }
+
+    // a load of what is already in the register: `ld a, b` after `ld b, a`,
+    // or the same load twice
+    fn repeated_load(&self, first: &ast::InstructionStatement, second: &ast::InstructionStatement) -> Option<Rewrite> {
+        let (destination, source) = match (first.mnemonic.as_str(), first.operands.as_slice()) {
+            ("ld", [Operand::Register(destination, _), source]) => (destination, source),
+            _ => return None,
+        };
+        let (second_destination, second_source) = match (second.mnemonic.as_str(), second.operands.as_slice()) {
+            ("ld", [Operand::Register(destination, _), source]) => (destination, source),
+            _ => return None,
+        };
+
+        let is_r8 = |name: &str| ["a", "b", "c", "d", "e", "h", "l"].contains(&name);
+        let swapped = match (source, second_source) {
+            (Operand::Register(source, _), Operand::Register(second_source, _)) => {
+                is_r8(destination) && is_r8(source) && second_destination == source && second_source == destination
+            }
+            _ => false,
+        };
+        let repeated = destination == second_destination && self.same_value(source, second_source)
+            && !matches!(source, Operand::Register(source, _) if source == destination);
+
+        if !swapped && !repeated {
+            return None;
+        }
+
+        let description = format!("removed `{}` after `{}`", self.source_text(second.span), self.source_text(first.span));
+
+        return Some(self.rewrite(second.span, &description, &[("ld", &second.operands)], &[], vec![self.remove(second)]));
+    }
+
+    // registers, or values made of numbers, constants and labels only
+    fn same_value(&self, first: &Operand, second: &Operand) -> bool {
+        let normalized = |operand: &Operand| -> String {
+            return self.source_text(operand.span()).chars().filter(|c| !c.is_whitespace()).collect();
+        };
+
+        return match (first, second) {
+            (Operand::Register(first, _), Operand::Register(second, _)) => first == second,
+            (Operand::Immediate(expression), Operand::Immediate(_)) => {
+                self.is_constant(expression) && normalized(first) == normalized(second)
+            }
+            _ => false,
+        };
+    }
+
+    fn is_constant(&self, expression: &Expression) -> bool {
+        return match expression {
+            Expression::Number(..) => true,
+            Expression::String(..) => false,
+            Expression::Symbol(_, span) => {
+                let declaration = self.analysis.symbol_at(self.file, span.start).and_then(|name| self.analysis.declaration(name));
+                declaration.is_some_and(|d| d.kind == DeclarationKind::Label || d.kind == DeclarationKind::Constant)
+            }
+            Expression::Unary { operand, .. } => self.is_constant(operand),
+            Expression::Binary { left, right, .. } => self.is_constant(left) && self.is_constant(right),
+            Expression::Call { arguments, .. } => arguments.iter().all(|argument| self.is_constant(argument)),
+        };
+    }
+
+    // `jp` to a label of the same section in reach of `jr`
+    fn relaxed_jump(&self, jump: &ast::InstructionStatement) -> Option<Rewrite> {
+        if jump.mnemonic != "jp" {
+            return None;
+        }
+
+        let name = match jump.operands.last()? {
+            Operand::Immediate(Expression::Symbol(_, span)) => self.analysis.symbol_at(self.file, span.start)?,
+            _ => return None,
+        };
+        let target = match &self.analysis.assembler.symbols.get(name)?.value {
+            SymbolValue::Label(location) => location,
+            _ => return None,
+        };
+
+        let placement = self.analysis.assembler.placements.iter()
+            .find(|p| p.file == self.file && p.span == jump.span)?;
+        if placement.location.section != target.section {
+            return None;
+        }
+        if !(-128..=127).contains(&sm83::relaxed_offset(placement.location.offset, target.offset)) {
+            return None;
+        }
+
+        let edits = vec![self.replace_mnemonic(jump, "jr")];
+
+        return Some(self.rewrite(jump.span, "`jp` to `jr`", &[("jp", &jump.operands)], &[("jr", &jump.operands)], edits));
+    }
+
+    // `ld a, 0` becomes `xor a` when the flags it sets are overwritten before
+    // anything reads them
+    fn cleared_a(&self, load: &ast::InstructionStatement, rest: &[Item]) -> Option<Rewrite> {
+        let clears_a = load.mnemonic == "ld" && matches!(load.operands.as_slice(),
+            [Operand::Register(register, _), Operand::Immediate(Expression::Number(0, _))] if register == "a");
+        if !clears_a || !flags_dead(rest) {
+            return None;
+        }
+
+        let a = [Operand::Register("a".to_string(), load.span)];
+        let mnemonic = self.cased(load, "xor");
+        let edit = Edit {
+            file: self.file.to_string(),
+            span: load.span,
+            new_text: format!("{} {}", mnemonic, self.cased(load, "a")),
+        };
+
+        return Some(self.rewrite(load.span, "`ld a, 0` to `xor a`", &[("ld", &load.operands)], &[("xor", &a)], vec![edit]));
+    }
+
+    // `ld r, r` does nothing, except `ld b, b` and `ld d, d` which emulators
+    // take as a breakpoint and a debug message
+    fn useless_load(&self, load: &ast::InstructionStatement) -> Option<Rewrite> {
+        let useless = load.mnemonic == "ld" && match load.operands.as_slice() {
+            [Operand::Register(destination, _), Operand::Register(source, _)] => {
+                destination == source && ["a", "c", "e", "h", "l"].contains(&destination.as_str())
+            }
+            _ => false,
+        };
+        if !useless {
+            return None;
+        }
+
+        let description = format!("removed `{}`", self.source_text(load.span));
+
+        return Some(self.rewrite(load.span, &description, &[("ld", &load.operands)], &[], vec![self.remove(load)]));
+    }
+
+    fn source_text(&self, span: Span) -> &str {
+        return &self.text[span.start..span.end];
+    }
+
+    // `word` in the case of the mnemonic as written
+    fn cased(&self, instruction: &ast::InstructionStatement, word: &str) -> String {
+        let written = &self.text[instruction.span.start..instruction.span.start + instruction.mnemonic.len()];
+
+        return if written.chars().all(|c| c.is_uppercase()) { word.to_uppercase() } else { word.to_string() };
+    }
+
+    fn replace_mnemonic(&self, instruction: &ast::InstructionStatement, mnemonic: &str) -> Edit {
+        return Edit {
+            file: self.file.to_string(),
+            span: Span { start: instruction.span.start, end: instruction.span.start + instruction.mnemonic.len() },
+            new_text: self.cased(instruction, mnemonic),
+        };
+    }
+
+    // the whole line when nothing else is on it
+    fn remove(&self, instruction: &ast::InstructionStatement) -> Edit {
+        let span = instruction.span;
+        let line_start = self.text[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = self.text[span.end..].find('\n').map(|i| span.end + i + 1).unwrap_or(self.text.len());
+
+        let alone = self.text[line_start..span.start].trim().is_empty() && self.text[span.end..line_end].trim().is_empty();
+        // up to a comment after the instruction
+        let spaces = self.text[span.end..line_end].len() - self.text[span.end..line_end].trim_start_matches([' ', '\t']).len();
+
+        return Edit {
+            file: self.file.to_string(),
+            span: if alone { Span { start: line_start, end: line_end } } else { Span { start: span.start, end: span.end + spaces } },
+            new_text: String::new(),
+        };
+    }
+}
+
+// whether the next instructions overwrite all the flags before reading any,
+// without leaving the run
+fn flags_dead(items: &[Item]) -> bool {
+    for item in items {
+        let instruction = match item {
+            Item::Instruction(instruction) => instruction,
+            Item::Barrier => return false,
+        };
+
+        if sm83::reads_flags(&instruction.mnemonic, &instruction.operands) {
+            return false;
+        }
+        if sm83::sets_all_flags(&instruction.mnemonic, &instruction.operands) {
+            return true;
+        }
+        if ["jp", "jr", "call", "ret", "reti", "rst"].contains(&instruction.mnemonic.as_str()) {
+            return false;
+        }
+    }
+
+    return false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::diagnostic::SourceFile;
+    use crate::rename;
+    use std::time::UNIX_EPOCH;
+
+    fn optimized(text: &str) -> (String, Vec<(String, i32, i32)>) {
+        let analysis = Analysis::new(SourceFile::new("main.asm", text.to_string()), Assembler::with_time(UNIX_EPOCH));
+        assert!(!analysis.diagnostics.has_errors());
+
+        let rewrites = optimize(&analysis);
+        let edits: Vec<&Edit> = rewrites.iter().flat_map(|rewrite| &rewrite.edits).collect();
+        let report = rewrites.iter().map(|r| (r.description.clone(), r.bytes_saved, r.cycles_saved)).collect();
+
+        return (rename::apply(text, &edits), report);
+    }
+
+    #[test]
+    fn rewriting_instructions() {
+        let text = concat!(
+            "DEF SPEED EQU 2\n",
+            "SECTION \"Code\", ROM0\n",
+            "Main:\n",
+            "\tld a, 0\n",
+            "\tor b\n",
+            "\tld a, 0\n",
+            "\tjr z, Main\n",
+            "\tLD B, A\n",
+            "\tLD A, B\n",
+            "\tld c, SPEED\n",
+            "\tld c, SPEED ; again\n",
+            "\tld h, h\n",
+            "\tld b, b\n",
+            "\tJP NZ, Main\n",
+            "\tcall Main\n",
+            "\tret\n",
+            "Far:\n",
+            "\tcall Main\n",
+            "; optimize: off\n",
+            "\tret\n",
+            "\tjp Main\n",
+            "; optimize: on\n",
+            "\tjp Far\n",
+        );
+
+        let (output, report) = optimized(text);
+
+        assert_eq!(output, concat!(
+            "DEF SPEED EQU 2\n",
+            "SECTION \"Code\", ROM0\n",
+            "Main:\n",
+            "\txor a\n",
+            "\tor b\n",
+            "\tld a, 0\n",
+            "\tjr z, Main\n",
+            "\tLD B, A\n",
+            "\tld c, SPEED\n",
+            "\t; again\n",
+            "\tld b, b\n",
+            "\tJR NZ, Main\n",
+            "\tjp Main\n",
+            "Far:\n",
+            "\tcall Main\n",
+            "; optimize: off\n",
+            "\tret\n",
+            "\tjp Main\n",
+            "; optimize: on\n",
+            "\tjr Far\n",
+        ));
+
+        assert_eq!(report, vec![
+            ("`ld a, 0` to `xor a`".to_string(), 1, 1),
+            ("removed `LD A, B` after `LD B, A`".to_string(), 1, 1),
+            ("removed `ld c, SPEED` after `ld c, SPEED`".to_string(), 2, 2),
+            ("removed `ld h, h`".to_string(), 1, 1),
+            ("`jp` to `jr`".to_string(), 1, 1),
+            ("`call` followed by `ret` to `jp`".to_string(), 1, 6),
+            ("`jp` to `jr`".to_string(), 1, 1),
+        ]);
+    }
+}
@@ -0,0 +1,407 @@
+//! DMG picture processing unit.
+//!
+//! The PPU goes through the 154 lines of a frame at the speed of the
+//! hardware, 114 machine cycles a line, with the modes and interrupts of
+//! each line. Lines are drawn whole when the PPU gets to their HBlank, from
+//! the background, window and sprites as VRAM, OAM and the registers are at
+//! that point, so changes in the middle of a line take effect on the next.
+//! VRAM and OAM can always be accessed, whatever the mode.
+
+use crate::image::Image;
+
+pub const WIDTH: usize = 160;
+pub const HEIGHT: usize = 144;
+
+/// Machine cycles of a line, and of a frame of 144 drawn lines and 10 lines
+/// of VBlank.
+pub const LINE_CYCLES: u32 = 114;
+pub const FRAME_CYCLES: u32 = LINE_CYCLES * 154;
+
+const OAM_SCAN_CYCLES: u32 = 20;
+const DRAWING_CYCLES: u32 = 43;
+
+/// Bits of `IF` requested by the PPU.
+pub const INTERRUPT_VBLANK: u8 = 0x01;
+pub const INTERRUPT_STAT: u8 = 0x02;
+
+pub const LCDC: u16 = 0xFF40;
+pub const STAT: u16 = 0xFF41;
+pub const SCY: u16 = 0xFF42;
+pub const SCX: u16 = 0xFF43;
+pub const LY: u16 = 0xFF44;
+pub const LYC: u16 = 0xFF45;
+pub const DMA: u16 = 0xFF46;
+pub const BGP: u16 = 0xFF47;
+pub const OBP0: u16 = 0xFF48;
+pub const OBP1: u16 = 0xFF49;
+pub const WY: u16 = 0xFF4A;
+pub const WX: u16 = 0xFF4B;
+
+const LCDC_ENABLE: u8 = 0x80;
+const LCDC_WINDOW_MAP: u8 = 0x40;
+const LCDC_WINDOW: u8 = 0x20;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_BACKGROUND_MAP: u8 = 0x08;
+const LCDC_TALL_SPRITES: u8 = 0x04;
+const LCDC_SPRITES: u8 = 0x02;
+const LCDC_BACKGROUND: u8 = 0x01;
+
+/// The colors the 4 shades are shown as, from white to black.
+pub const SHADES: [[u8; 4]; 4] = [
+    [0xFF, 0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA, 0xFF],
+    [0x55, 0x55, 0x55, 0xFF],
+    [0x00, 0x00, 0x00, 0xFF],
+];
+
+pub struct Ppu {
+    pub lcdc: u8,
+    /// Only the interrupt sources, bits 3-6; the mode and the LYC flag are
+    /// computed when the register is read
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub dma: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    /// Machine cycles into the current line
+    cycle: u32,
+    /// The line of the window drawn next, which only advances on lines
+    /// that show it
+    window_line: u8,
+    /// Interrupts are requested when the sources of STAT go from none to
+    /// any
+    stat_line: bool,
+    /// Shades of the screen, 0 to 3 after the palettes
+    screen: Vec<u8>,
+    /// Frames that got to VBlank
+    pub frames: u64,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Ppu {
+    /// The state the boot ROM leaves the PPU in, at the start of a frame.
+    pub fn new() -> Self {
+        return Self {
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            dma: 0xFF,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            cycle: 0,
+            window_line: 0,
+            stat_line: false,
+            screen: vec![0; WIDTH * HEIGHT],
+            frames: 0,
+        };
+    }
+
+    /// 0 for HBlank, 1 for VBlank, 2 for the OAM scan and 3 for drawing.
+    pub fn mode(&self) -> u8 {
+        if self.lcdc & LCDC_ENABLE == 0 {
+            return 0;
+        }
+        if self.ly as usize >= HEIGHT {
+            return 1;
+        }
+
+        if self.cycle < OAM_SCAN_CYCLES {
+            return 2;
+        }
+        if self.cycle < OAM_SCAN_CYCLES + DRAWING_CYCLES {
+            return 3;
+        }
+        return 0;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return match address {
+            LCDC => self.lcdc,
+            STAT => 0x80 | self.stat | (((self.ly == self.lyc) as u8) << 2) | self.mode(),
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            DMA => self.dma,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            _ => 0xFF,
+        };
+    }
+
+    /// Writes a register; the OAM DMA copy on writes to DMA is up to the bus.
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            LCDC => {
+                // turning the LCD off blanks it and starts it over from the
+                // first line
+                if value & LCDC_ENABLE == 0 && self.lcdc & LCDC_ENABLE != 0 {
+                    self.ly = 0;
+                    self.cycle = 0;
+                    self.window_line = 0;
+                    self.screen.fill(0);
+                }
+                self.lcdc = value;
+            }
+            STAT => self.stat = value & 0x78,
+            SCY => self.scy = value,
+            SCX => self.scx = value,
+            LYC => self.lyc = value,
+            DMA => self.dma = value,
+            BGP => self.bgp = value,
+            OBP0 => self.obp0 = value,
+            OBP1 => self.obp1 = value,
+            WY => self.wy = value,
+            WX => self.wx = value,
+            _ => {}
+        }
+    }
+
+    /// Advances by `cycles` machine cycles with the 8 KiB of VRAM and the
+    /// 160 bytes of OAM, returning the bits of `IF` to request.
+    pub fn tick(&mut self, cycles: u32, vram: &[u8], oam: &[u8]) -> u8 {
+        if self.lcdc & LCDC_ENABLE == 0 {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+        for _ in 0..cycles {
+            self.cycle += 1;
+
+            if self.cycle == OAM_SCAN_CYCLES + DRAWING_CYCLES && (self.ly as usize) < HEIGHT {
+                self.draw_line(vram, oam);
+            }
+
+            if self.cycle == LINE_CYCLES {
+                self.cycle = 0;
+                self.ly = (self.ly + 1) % 154;
+
+                if self.ly as usize == HEIGHT {
+                    self.frames += 1;
+                    interrupts |= INTERRUPT_VBLANK;
+                }
+                if self.ly == 0 {
+                    self.window_line = 0;
+                }
+            }
+
+            let mode = self.mode();
+            let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+                || (self.stat & 0x20 != 0 && mode == 2)
+                || (self.stat & 0x10 != 0 && mode == 1)
+                || (self.stat & 0x08 != 0 && mode == 0);
+            if line && !self.stat_line {
+                interrupts |= INTERRUPT_STAT;
+            }
+            self.stat_line = line;
+        }
+
+        return interrupts;
+    }
+
+    /// Shades of the screen from the top left corner, complete once the PPU
+    /// gets to VBlank.
+    pub fn screen(&self) -> &[u8] {
+        return &self.screen;
+    }
+
+    pub fn image(&self) -> Image {
+        return Image {
+            width: WIDTH,
+            height: HEIGHT,
+            pixels: self.screen.iter().map(|shade| SHADES[*shade as usize]).collect(),
+        };
+    }
+
+    fn draw_line(&mut self, vram: &[u8], oam: &[u8]) {
+        let ly = self.ly as usize;
+        // colors of the background and window before the palette, which
+        // decide whether sprites behind them show
+        let mut colors = [0u8; WIDTH];
+
+        if self.lcdc & LCDC_BACKGROUND != 0 {
+            let map = if self.lcdc & LCDC_BACKGROUND_MAP != 0 { 0x1C00 } else { 0x1800 };
+            let y = (ly + self.scy as usize) % 256;
+            for (x, color) in colors.iter_mut().enumerate() {
+                *color = self.tile_color(vram, map, (x + self.scx as usize) % 256, y);
+            }
+
+            if self.lcdc & LCDC_WINDOW != 0 && ly >= self.wy as usize && self.wx <= 166 {
+                let map = if self.lcdc & LCDC_WINDOW_MAP != 0 { 0x1C00 } else { 0x1800 };
+                let left = self.wx as isize - 7;
+                for (x, color) in colors.iter_mut().enumerate().skip(left.max(0) as usize) {
+                    *color = self.tile_color(vram, map, (x as isize - left) as usize, self.window_line as usize);
+                }
+                self.window_line += 1;
+            }
+        }
+
+        let row = &mut self.screen[ly * WIDTH..(ly + 1) * WIDTH];
+        for (shade, color) in row.iter_mut().zip(colors) {
+            *shade = (self.bgp >> (color * 2)) & 3;
+        }
+
+        if self.lcdc & LCDC_SPRITES == 0 {
+            return;
+        }
+
+        let height = if self.lcdc & LCDC_TALL_SPRITES != 0 { 16 } else { 8 };
+        // the first 10 sprites of OAM on the line, the one further left
+        // drawn over the others, then the one first in OAM
+        let mut sprites: Vec<&[u8]> = oam.chunks(4)
+            .filter(|sprite| (0..height).contains(&(ly as isize + 16 - sprite[0] as isize)))
+            .take(10)
+            .collect();
+        sprites.sort_by_key(|sprite| sprite[1]);
+
+        let mut drawn = [false; WIDTH];
+        for sprite in sprites {
+            let (y, x, tile, attributes) = (sprite[0] as usize, sprite[1] as isize, sprite[2], sprite[3]);
+
+            let mut line = ly + 16 - y;
+            if attributes & 0x40 != 0 {
+                line = height as usize - 1 - line;
+            }
+            let tile = if height == 16 { tile & 0xFE } else { tile } as usize;
+            let address = tile * 16 + line * 2;
+            let palette = if attributes & 0x10 != 0 { self.obp1 } else { self.obp0 };
+
+            for column in 0..8 {
+                let screen_x = x - 8 + column;
+                if !(0..WIDTH as isize).contains(&screen_x) || drawn[screen_x as usize] {
+                    continue;
+                }
+
+                let bit = if attributes & 0x20 != 0 { column } else { 7 - column };
+                let color = pixel_color(vram[address], vram[address + 1], bit as u8);
+                if color == 0 {
+                    continue;
+                }
+
+                // the pixel is the sprite's even when it is hidden behind
+                // the background
+                let screen_x = screen_x as usize;
+                drawn[screen_x] = true;
+                if attributes & 0x80 != 0 && colors[screen_x] != 0 {
+                    continue;
+                }
+                row[screen_x] = (palette >> (color * 2)) & 3;
+            }
+        }
+    }
+
+    // color of a pixel of the background or window, in its 256x256 space
+    fn tile_color(&self, vram: &[u8], map: usize, x: usize, y: usize) -> u8 {
+        let tile = vram[map + (y / 8) * 32 + x / 8];
+        let address = if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as isize * 16) as usize
+        };
+
+        let address = address + (y % 8) * 2;
+        return pixel_color(vram[address], vram[address + 1], 7 - (x % 8) as u8);
+    }
+}
+
+fn pixel_color(low: u8, high: u8, bit: u8) -> u8 {
+    return (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // VRAM with tile 1 solid in color 3 and tile 2 in color 1, and OAM
+    fn memory() -> (Vec<u8>, Vec<u8>) {
+        let mut vram = vec![0u8; 0x2000];
+        vram[0x10..0x20].fill(0xFF);
+        for row in 0..8 {
+            vram[0x20 + row * 2] = 0xFF;
+        }
+        return (vram, vec![0u8; 160]);
+    }
+
+    fn frame(ppu: &mut Ppu, vram: &[u8], oam: &[u8]) -> u8 {
+        let frames = ppu.frames;
+        let mut interrupts = 0;
+        while ppu.frames == frames {
+            interrupts |= ppu.tick(1, vram, oam);
+        }
+        return interrupts;
+    }
+
+    #[test]
+    fn drawing_layers() {
+        let (mut vram, mut oam) = memory();
+        // a background of tile 1 in its top left corner, scrolled by 4
+        vram[0x1800] = 1;
+        // the window from (80, 72) with tile 2
+        vram[0x1C00] = 2;
+        // tall sprites of tiles 0 and 1 at (8, -8), and of tiles 2 and 3
+        // at (40, 40) flipped upside down
+        oam[..8].copy_from_slice(&[8, 16, 1, 0x00, 56, 48, 2, 0x40]);
+
+        let mut ppu = Ppu::new();
+        ppu.write(LCDC, 0x91 | LCDC_WINDOW | LCDC_WINDOW_MAP | LCDC_SPRITES | LCDC_TALL_SPRITES);
+        ppu.write(SCX, 4);
+        ppu.write(WY, 72);
+        ppu.write(WX, 87);
+        ppu.write(BGP, 0xE4);
+        ppu.write(OBP0, 0xE4);
+        assert_eq!(frame(&mut ppu, &vram, &oam) & INTERRUPT_VBLANK, INTERRUPT_VBLANK);
+
+        let screen = ppu.screen();
+        let shade = |x: usize, y: usize| screen[y * WIDTH + x];
+        assert_eq!((shade(0, 0), shade(3, 0), shade(4, 0)), (3, 3, 0));
+        assert_eq!((shade(8, 0), shade(15, 7), shade(16, 0), shade(8, 8)), (3, 3, 0, 0));
+        assert_eq!((shade(79, 72), shade(80, 72), shade(87, 79), shade(88, 72)), (0, 1, 1, 0));
+        assert_eq!((shade(40, 40), shade(40, 47), shade(40, 48), shade(40, 55)), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn timing_lines() {
+        let (vram, oam) = memory();
+        let mut ppu = Ppu::new();
+        ppu.write(LYC, 2);
+        ppu.write(STAT, 0x40);
+
+        assert_eq!(ppu.read(STAT) & 3, 2);
+        assert_eq!(ppu.tick(OAM_SCAN_CYCLES, &vram, &oam), 0);
+        assert_eq!(ppu.read(STAT) & 3, 3);
+        ppu.tick(DRAWING_CYCLES, &vram, &oam);
+        assert_eq!(ppu.read(STAT) & 3, 0);
+
+        let interrupts = ppu.tick(2 * LINE_CYCLES - OAM_SCAN_CYCLES - DRAWING_CYCLES, &vram, &oam);
+        assert_eq!((ppu.ly, interrupts), (2, INTERRUPT_STAT));
+        assert_eq!(ppu.read(STAT), 0x80 | 0x40 | 0x04 | 2);
+
+        assert_eq!(frame(&mut ppu, &vram, &oam), INTERRUPT_VBLANK);
+        assert_eq!((ppu.ly, ppu.read(STAT) & 3), (144, 1));
+        frame(&mut ppu, &vram, &oam);
+        assert_eq!(ppu.frames, 2);
+
+        ppu.write(LCDC, 0);
+        assert_eq!((ppu.ly, ppu.tick(FRAME_CYCLES, &vram, &oam)), (0, 0));
+    }
+}
@@ -0,0 +1,285 @@
+//! Attributing the cycles a program runs for to its routines.
+//!
+//! Each instruction's cycles go to the routine it is in: the closest label
+//! at or before it that is not a local one, in the same bank for ROMX. A
+//! routine is called by `call`, `rst` and interrupts, and by jumping to its
+//! first instruction from another routine, which returns where the routine
+//! that jumped would have. A call lasts from the instruction that follows
+//! it until the stack pointer goes above its return address, normally with
+//! a `ret` or `reti`, and its cycles include those of the routines it calls.
+
+use std::collections::BTreeMap;
+
+use crate::emulator::{Emulator, Memory};
+use crate::ppu;
+
+// opcodes of `call` and `rst`, and of the jumps that can be tail calls
+const CALLS: [u8; 13] = [0xCD, 0xC4, 0xCC, 0xD4, 0xDC, 0xC7, 0xCF, 0xD7, 0xDF, 0xE7, 0xEF, 0xF7, 0xFF];
+const JUMPS: [u8; 11] = [0xC3, 0xC2, 0xCA, 0xD2, 0xDA, 0xE9, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Routine {
+    pub name: String,
+    pub bank: i32,
+    pub address: u16,
+    pub calls: u64,
+    /// Cycles of the routine's own instructions
+    pub cycles: u64,
+    /// Cycles of the calls that returned, with the routines they called
+    pub call_cycles: u64,
+    pub returns: u64,
+    pub longest_call: u64,
+    pub budget: Option<u64>,
+    /// Calls that took longer than the budget
+    pub over_budget: u64,
+}
+
+impl Routine {
+    /// Cycles of the calls that returned on average, `None` when none did.
+    pub fn average_call(&self) -> Option<u64> {
+        return self.call_cycles.checked_div(self.returns);
+    }
+}
+
+struct Call {
+    routine: Option<usize>,
+    /// Stack pointer with the return address pushed
+    sp: u16,
+    start: u64,
+}
+
+pub struct Profiler {
+    routines: Vec<Routine>,
+    /// Routines by bank for ROMX, 0 elsewhere, and address
+    addresses: BTreeMap<(i32, u16), usize>,
+    calls: Vec<Call>,
+    /// Cycles run, and those outside of any routine
+    pub cycles: u64,
+    pub unlabeled_cycles: u64,
+    pub frames: u64,
+}
+
+impl Profiler {
+    /// A profiler for the routines starting at `labels`, given as name, bank
+    /// and address. Local labels are part of their routine.
+    pub fn new<'a>(labels: impl IntoIterator<Item = (&'a str, i32, u16)>) -> Self {
+        let mut labels: Vec<(&str, i32, u16)> = labels.into_iter().filter(|(name, _, _)| !name.contains('.')).collect();
+        // the first name of an address in alphabetical order
+        labels.sort();
+
+        let mut profiler = Self {
+            routines: vec![],
+            addresses: BTreeMap::new(),
+            calls: vec![],
+            cycles: 0,
+            unlabeled_cycles: 0,
+            frames: 0,
+        };
+
+        for (name, bank, address) in labels {
+            let key = (key_bank(bank, address), address);
+            if profiler.addresses.contains_key(&key) {
+                continue;
+            }
+
+            profiler.addresses.insert(key, profiler.routines.len());
+            profiler.routines.push(Routine {
+                name: name.to_string(),
+                bank,
+                address,
+                ..Routine::default()
+            });
+        }
+
+        return profiler;
+    }
+
+    /// Flags the calls of a routine that take longer than `cycles`.
+    pub fn set_budget(&mut self, name: &str, cycles: u64) -> Result<(), String> {
+        return match self.routines.iter_mut().find(|routine| routine.name == name) {
+            Some(routine) => {
+                routine.budget = Some(cycles);
+                Ok(())
+            }
+            None => Err(format!("Routine `{}` is not defined", name)),
+        };
+    }
+
+    /// Routines that ran, the ones that took the most cycles first.
+    pub fn routines(&self) -> Vec<&Routine> {
+        let mut routines: Vec<&Routine> = self.routines.iter().filter(|routine| routine.cycles > 0 || routine.calls > 0).collect();
+        routines.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.name.cmp(&b.name)));
+
+        return routines;
+    }
+
+    /// Routines with calls over their budget.
+    pub fn over_budget(&self) -> Vec<&Routine> {
+        return self.routines().into_iter().filter(|routine| routine.over_budget > 0).collect();
+    }
+
+    /// Runs until the PPU gets to VBlank `frames` times, like
+    /// `Emulator::run_frames`, and returns the cycles run.
+    pub fn run_frames(&mut self, emulator: &mut Emulator<Memory>, frames: u32) -> Result<u64, String> {
+        let mut elapsed = 0;
+        for _ in 0..frames {
+            let (start, counted) = (elapsed, emulator.bus.ppu.frames);
+            while emulator.bus.ppu.frames == counted && elapsed - start < ppu::FRAME_CYCLES as u64 {
+                elapsed += self.step(emulator)? as u64;
+            }
+            self.frames += 1;
+        }
+
+        return Ok(elapsed);
+    }
+
+    /// Executes an instruction, or dispatches an interrupt, and attributes
+    /// its cycles.
+    pub fn step(&mut self, emulator: &mut Emulator<Memory>) -> Result<u32, String> {
+        let (pc, sp, ime) = (emulator.cpu.registers.pc, emulator.cpu.registers.sp, emulator.cpu.ime);
+        let opcode = emulator.read(pc);
+        let routine = self.routine_at(pc, emulator.bus.rom_bank());
+
+        let cycles = emulator.step()?;
+        self.cycles += cycles as u64;
+        match routine {
+            Some(routine) => self.routines[routine].cycles += cycles as u64,
+            None => self.unlabeled_cycles += cycles as u64,
+        }
+
+        let (new_pc, new_sp) = (emulator.cpu.registers.pc, emulator.cpu.registers.sp);
+        let callee = self.routine_at(new_pc, emulator.bus.rom_bank());
+        let pushed = new_sp == sp.wrapping_sub(2);
+        let interrupted = ime && !emulator.cpu.ime && pushed;
+
+        if (CALLS.contains(&opcode) && pushed) || interrupted {
+            self.calls.push(Call {
+                routine: callee,
+                sp: new_sp,
+                start: self.cycles,
+            });
+            if let Some(callee) = callee {
+                self.routines[callee].calls += 1;
+            }
+        } else if JUMPS.contains(&opcode) && callee != routine {
+            // a tail call, when it lands on the first instruction
+            if let Some(callee) = callee.filter(|callee| self.routines[*callee].address == new_pc) {
+                self.routines[callee].calls += 1;
+                if let Some(call) = self.calls.last_mut() {
+                    call.routine = Some(callee);
+                }
+            }
+        }
+
+        while self.calls.last().is_some_and(|call| new_sp > call.sp) {
+            let call = self.calls.pop().unwrap();
+            if let Some(routine) = call.routine {
+                let routine = &mut self.routines[routine];
+                let cycles = self.cycles - call.start;
+
+                routine.returns += 1;
+                routine.call_cycles += cycles;
+                routine.longest_call = routine.longest_call.max(cycles);
+                if routine.budget.is_some_and(|budget| cycles > budget) {
+                    routine.over_budget += 1;
+                }
+            }
+        }
+
+        return Ok(cycles);
+    }
+
+    fn routine_at(&self, address: u16, rom_bank: usize) -> Option<usize> {
+        let bank = key_bank(rom_bank as i32, address);
+        let (&(_, start), &routine) = self.addresses.range(..=(bank, address)).next_back()?;
+
+        // not a routine of another bank, or of another area of memory
+        let area = |address: u16| address / 0x4000;
+        if key_bank(self.routines[routine].bank, start) != bank || area(start).min(2) != area(address).min(2) {
+            return None;
+        }
+
+        return Some(routine);
+    }
+}
+
+// only the bank of ROMX tells routines apart, since it is the only one
+// switched by `Memory`
+fn key_bank(bank: i32, address: u16) -> i32 {
+    if (0x4000..0x8000).contains(&address) {
+        return bank;
+    }
+    return 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::diagnostic::{Diagnostics, SourceFile};
+    use crate::emulator::{IE, IF};
+    use crate::{lexer, link, parser};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn profiling_routines() {
+        let source = SourceFile::new("main.asm", concat!(
+            "SECTION \"VBlank\", ROM0[$40]\n",
+            "\tjp VBlank\n",
+            "SECTION \"Entry\", ROM0[$100]\n",
+            "\tjp Main\n",
+            "SECTION \"Main\", ROM0[$150]\n",
+            "Main:\n",
+            "\tei\n",
+            ".loop:\n",
+            "\thalt\n",
+            "\tcall Short\n",
+            "\tjr .loop\n",
+            "VBlank:\n",
+            "\tld b, 100\n",
+            ".wait:\n",
+            "\tdec b\n",
+            "\tjr nz, .wait\n",
+            "\tcall Short\n",
+            "\treti\n",
+            "Short:\n",
+            "\tret\n",
+        ).to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        let rom = link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        let mut emulator = Emulator::new(rom);
+        emulator.load_symbols(&assembler.symbols);
+        emulator.write(IE, 0x01);
+        emulator.write(IF, 0);
+
+        let mut profiler = Profiler::new(emulator.labels());
+        profiler.set_budget("VBlank", 400).unwrap();
+        assert!(profiler.set_budget("Main.loop", 400).is_err());
+        profiler.run_frames(&mut emulator, 3).unwrap();
+
+        let routines = profiler.routines();
+        let names: Vec<&str> = routines.iter().map(|routine| routine.name.as_str()).collect();
+        assert_eq!(names, ["Main", "VBlank", "Short"]);
+
+        // the jump from the vector is the call of the handler, and the
+        // interrupt of the third frame is still pending
+        let vblank = routines[1];
+        assert_eq!((vblank.calls, vblank.returns), (2, 2));
+        // the loading of b, 99 loops and the last, the call and the reti,
+        // and with them the jump of the vector and the return of `Short`
+        let own = 2 + 99 * (1 + 3) + (1 + 2) + 6 + 4;
+        assert_eq!((vblank.cycles, vblank.longest_call), (2 * own, 4 + own + 4));
+        assert_eq!(vblank.over_budget, 2);
+        assert_eq!(profiler.over_budget(), [vblank]);
+
+        assert_eq!((routines[2].calls, routines[2].cycles, routines[2].average_call()), (4, 4 * 4, Some(4)));
+        // the main loop is entered from the entry point and never returns
+        assert_eq!((routines[0].calls, routines[0].average_call()), (1, None));
+        assert_eq!(profiler.cycles, routines.iter().map(|routine| routine.cycles).sum::<u64>() + profiler.unlabeled_cycles);
+    }
+}
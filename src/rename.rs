@@ -0,0 +1,293 @@
+//! Renaming a label, constant or macro everywhere it is used.
+//!
+//! The rename is computed over one or more analyses, each covering a file
+//! and everything it includes, and refused when a use of the symbol could be
+//! put together when assembling (macro arguments, `\@`, interpolations, EQUS
+//! expressions) or when the symbol is a macro argument pasted into a longer
+//! name, since such a use cannot be renamed by editing the source.
+
+use crate::analysis::{Analysis, DeclarationKind};
+use crate::cst::SyntaxTree;
+use crate::diagnostic::{Diagnostics, SourceFile};
+use crate::lexer::Span;
+use crate::{format, sm83};
+
+/// Replacement of the text at `span` in `file`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Edit {
+    pub file: String,
+    pub span: Span,
+    pub new_text: String,
+}
+
+/// Edits renaming the symbol `name` (`Parent.local` for a local label) to
+/// `new_name`, sorted by file and position. A local label can be renamed to
+/// `.new` or `Parent.new`; renaming a global label also renames the parent
+/// part of its local labels written in full.
+pub fn rename(analyses: &[Analysis], name: &str, new_name: &str) -> Result<Vec<Edit>, String> {
+    for analysis in analyses {
+        if analysis.diagnostics.has_errors() {
+            return Err(format!("`{}` has errors, fix them before renaming", analysis.sources[0].name));
+        }
+    }
+
+    let declaration = analyses.iter().find_map(|analysis| analysis.declaration(name));
+    let declaration = match declaration {
+        Some(declaration) => declaration,
+        None => return Err(undeclared(analyses, name)),
+    };
+
+    let new_name = full_name(name, new_name)?;
+    if new_name == name {
+        return Ok(vec![]);
+    }
+
+    for analysis in analyses {
+        if let Some(existing) = analysis.declaration(&new_name) {
+            let (line, _) = analysis.source(&existing.file).map(|s| s.line_col(existing.span.start)).unwrap_or((0, 0));
+            return Err(format!("`{}` is already defined at {}:{}", new_name, existing.file, line));
+        }
+        if analysis.assembler.symbols.get(&new_name).is_some() || analysis.assembler.macros.contains_key(&new_name) {
+            return Err(format!("`{}` is already defined", new_name));
+        }
+    }
+
+    // the symbol and, for a global label, its local labels
+    let is_global_label = declaration.kind == DeclarationKind::Label && !name.contains('.');
+    let prefix = format!("{}.", name);
+    let affected = |other: &str| other == name || (is_global_label && other.starts_with(&prefix));
+
+    for analysis in analyses {
+        let unresolved = analysis.templates.iter().find(|template| {
+            analysis.declarations.iter().any(|d| affected(&d.name) && template.matches(&d.name)) || template.matches(name)
+        });
+
+        if let Some(template) = unresolved {
+            let (line, _) = analysis.source(&template.file).map(|s| s.line_col(template.span.start)).unwrap_or((0, 0));
+            return Err(format!(
+                "`{}` may be used as `{}` in {} at {}:{}, which is only resolved when assembling; rename it by hand",
+                name, template.text, template.origin, template.file, line,
+            ));
+        }
+
+        if let Some(pasted) = analysis.pasted.iter().find(|pasted| affected(&pasted.name)) {
+            let (line, _) = analysis.source(&pasted.file).map(|s| s.line_col(pasted.span.start)).unwrap_or((0, 0));
+            return Err(format!(
+                "`{}` is pasted into `{}` by macro `{}` at {}:{}, which is only resolved when assembling; rename it by hand",
+                pasted.name, pasted.built, pasted.macro_name, pasted.file, line,
+            ));
+        }
+    }
+
+    let mut edits = vec![];
+    for analysis in analyses {
+        let occurrences = analysis.declarations.iter()
+            .map(|d| (&d.name, &d.file, d.span))
+            .chain(analysis.references.iter().map(|r| (&r.name, &r.file, r.span)));
+
+        for (symbol, file, span) in occurrences {
+            if !affected(symbol) {
+                continue;
+            }
+
+            let source = match analysis.source(file) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            if let Some(edit) = edit_for(source, span, name, &new_name) {
+                if !edits.contains(&edit) {
+                    edits.push(edit);
+                }
+            }
+        }
+    }
+
+    edits.sort_by(|a, b| (&a.file, a.span.start).cmp(&(&b.file, b.span.start)));
+
+    return Ok(edits);
+}
+
+/// Applies the edits of one file to the tokens of its syntax tree and prints
+/// it, keeping everything else as it was.
+pub fn apply(text: &str, edits: &[&Edit]) -> String {
+    let mut edits = edits.to_vec();
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.span.start));
+
+    let mut tree = SyntaxTree::parse(text, &mut Diagnostics::new());
+    for edit in edits {
+        let replaced = tree.replace(edit.span, &edit.new_text);
+        debug_assert!(replaced, "edit at {:?} is not in the tokens of the file", edit.span);
+    }
+
+    return tree.text();
+}
+
+fn undeclared(analyses: &[Analysis], name: &str) -> String {
+    for analysis in analyses {
+        let symbol = analysis.assembler.symbols.get(name);
+
+        if let Some(definition) = symbol.and_then(|symbol| symbol.definition.as_ref()) {
+            let (line, _) = analysis.source(&definition.file).map(|s| s.line_col(definition.span.start)).unwrap_or((0, 0));
+            return format!(
+                "`{}` is created by the macro invoked at {}:{}; rename it in the macro or its arguments",
+                name, definition.file, line,
+            );
+        }
+        if symbol.is_some() {
+            return format!("`{}` is a built-in symbol", name);
+        }
+    }
+
+    return format!("`{}` is not declared in these files", name);
+}
+
+// the new full name, keeping local labels under their parent
+fn full_name(name: &str, new_name: &str) -> Result<String, String> {
+    let new_name = match name.split_once('.') {
+        Some((parent, _)) => match new_name.split_once('.') {
+            Some(("", local)) => format!("{}.{}", parent, local),
+            Some((new_parent, _)) if new_parent == parent => new_name.to_string(),
+            Some(_) => return Err(format!("`{}` cannot be moved to another parent label", name)),
+            None => return Err(format!("`{}` is a local label, rename it to `.{}` or `{}.{}`", name, new_name, parent, new_name)),
+        },
+        None if new_name.contains('.') => return Err(format!("`{}` cannot be renamed to a local label", name)),
+        None => new_name.to_string(),
+    };
+
+    let local = new_name.rsplit('.').next().unwrap_or("");
+    let valid = new_name.split('.').all(|part| {
+        part.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '#' || c == '@')
+    });
+    if !valid {
+        return Err(format!("`{}` is not a valid symbol name", new_name));
+    }
+
+    let reserved = sm83::is_mnemonic(local) || sm83::is_register(local)
+        || format::DIRECTIVES.contains(&local.to_lowercase().as_str());
+    if reserved {
+        return Err(format!("`{}` is a reserved word", local));
+    }
+
+    return Ok(new_name);
+}
+
+// the occurrence is written as the full name, the local part or with the
+// renamed label as its parent
+fn edit_for(source: &SourceFile, span: Span, name: &str, new_name: &str) -> Option<Edit> {
+    let written = source.text.get(span.start..span.end)?;
+    let local = |name: &str| name.find('.').map(|i| name[i..].to_string());
+
+    let (span, new_text) = if written == name {
+        (span, new_name.to_string())
+    } else if written.starts_with('.') {
+        (span, local(new_name)?)
+    } else if written.starts_with(&format!("{}.", name)) {
+        (Span { start: span.start, end: span.start + name.len() }, new_name.to_string())
+    } else {
+        return None;
+    };
+
+    return Some(Edit {
+        file: source.name.clone(),
+        span,
+        new_text,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use std::time::UNIX_EPOCH;
+
+    fn analyze(text: &str) -> Analysis {
+        return Analysis::new(SourceFile::new("main.asm", text.to_string()), Assembler::with_time(UNIX_EPOCH));
+    }
+
+    fn renamed(text: &str, name: &str, new_name: &str) -> Result<String, String> {
+        let analyses = [analyze(text)];
+        let edits = rename(&analyses, name, new_name)?;
+
+        return Ok(apply(text, &edits.iter().collect::<Vec<&Edit>>()));
+    }
+
+    #[test]
+    fn renaming_labels() {
+        let text = concat!(
+            "DEF NAME EQUS \"Main\"\n",
+            "call_twice: MACRO\n",
+            "\tcall \\1\n",
+            "\tcall Main\n",
+            "ENDM\n",
+            "SECTION \"x\", ROM0[$150]\n",
+            "Main:\n",
+            ".loop: jr .loop\n",
+            "\tjp Main.loop\n",
+            "\tcall_twice Main.loop\n",
+            "\tdb \"{Main}\", NAME\n",
+        );
+
+        assert_eq!(renamed(text, "Main", "Start").unwrap(), concat!(
+            "DEF NAME EQUS \"Start\"\n",
+            "call_twice: MACRO\n",
+            "\tcall \\1\n",
+            "\tcall Start\n",
+            "ENDM\n",
+            "SECTION \"x\", ROM0[$150]\n",
+            "Start:\n",
+            ".loop: jr .loop\n",
+            "\tjp Start.loop\n",
+            "\tcall_twice Start.loop\n",
+            "\tdb \"{Start}\", NAME\n",
+        ));
+
+        let renamed_local = renamed(text, "Main.loop", ".wait").unwrap();
+        assert!(renamed_local.contains(".wait: jr .wait\n\tjp Main.wait\n\tcall_twice Main.wait\n"));
+
+        assert_eq!(renamed(text, "Main.loop", "Other.wait"), Err("`Main.loop` cannot be moved to another parent label".to_string()));
+        assert_eq!(renamed(text, "Main", "call_twice"), Err("`call_twice` is already defined at main.asm:2".to_string()));
+        assert_eq!(renamed(text, "Main", "ld"), Err("`ld` is a reserved word".to_string()));
+        assert_eq!(renamed(text, "NAME", "TITLE").unwrap(), text.replace("NAME", "TITLE"));
+    }
+
+    #[test]
+    fn refusing_names_built_when_assembling() {
+        let text = concat!(
+            "wait: MACRO\n",
+            "\\1_Loop\\@: jr \\1_Loop\\@\n",
+            "ENDM\n",
+            "DEF PREFIX EQUS STRCAT(\"Ti\", \"tle\")\n",
+            "SECTION \"x\", ROM0[$150]\n",
+            "Title_Loop:\n",
+            "Title:\n",
+            "\twait Player\n",
+            "Helper: nop\n",
+        );
+
+        assert_eq!(renamed(text, "Title_Loop", "Loop"), Err(
+            "`Title_Loop` may be used as `\\1_Loop\\@` in macro `wait` at main.asm:2, which is only resolved when assembling; rename it by hand".to_string()));
+        assert_eq!(renamed(text, "Title", "Start"), Err(
+            "`Title` may be used as `Title` in EQUS `PREFIX` at main.asm:4, which is only resolved when assembling; rename it by hand".to_string()));
+        assert_eq!(renamed(text, "Player_Loop_u1", "X"), Err(
+            "`Player_Loop_u1` is created by the macro invoked at main.asm:8; rename it in the macro or its arguments".to_string()));
+        assert!(renamed(text, "Helper", "Assist").is_ok());
+    }
+
+    #[test]
+    fn refusing_arguments_pasted_into_names() {
+        let text = concat!(
+            "mk: MACRO\n",
+            "\\1_x: ret\n",
+            "ENDM\n",
+            "SECTION \"x\", ROM0[$150]\n",
+            "Helper: nop\n",
+            "\tmk Helper\n",
+            "\tcall Helper_x\n",
+        );
+
+        assert_eq!(renamed(text, "Helper", "Assist"), Err(
+            "`Helper` is pasted into `Helper_x` by macro `mk` at main.asm:6, which is only resolved when assembling; rename it by hand".to_string()));
+    }
+}
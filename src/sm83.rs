@@ -0,0 +1,571 @@
+//! Instruction set of the Game Boy CPU (Sharp SM83).
+
+use std::sync::OnceLock;
+
+use crate::ast::Operand;
+use crate::expr::Expression;
+use crate::lexer::Span;
+
+pub const MNEMONICS: [&str; 46] = [
+    "adc", "add", "and", "bit", "call", "ccf", "cp", "cpl", "daa", "dec", "di", "ei",
+    "halt", "inc", "jp", "jr", "ld", "ldd", "ldh", "ldi", "nop", "or", "pop", "push",
+    "res", "ret", "reti", "rl", "rla", "rlc", "rlca", "rr", "rra", "rrc", "rrca", "rst",
+    "sbc", "scf", "set", "sla", "sra", "srl", "stop", "sub", "swap", "xor",
+];
+
+pub const REGISTERS: [&str; 11] = ["a", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl"];
+
+pub const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+
+/// `jmp` assembles to `jr` when the target is in reach and to `jp` otherwise.
+pub const PSEUDO_MNEMONICS: [&str; 1] = ["jmp"];
+
+pub fn is_mnemonic(name: &str) -> bool {
+    let name = name.to_lowercase();
+
+    return MNEMONICS.contains(&name.as_str()) || PSEUDO_MNEMONICS.contains(&name.as_str());
+}
+
+/// Registers, `sp` and the condition codes, which are reserved names.
+pub fn is_register(name: &str) -> bool {
+    let name = name.to_lowercase();
+
+    return REGISTERS.contains(&name.as_str()) || CONDITIONS.contains(&name.as_str()) || name == "sp";
+}
+
+/// Value that follows (or is merged into) the opcode once it is known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Immediate<'a> {
+    None,
+    /// 8 bit value
+    Byte(&'a Expression),
+    /// 16 bit little endian value
+    Word(&'a Expression),
+    /// Signed 8 bit value (`add sp, e8`, `ld hl, sp + e8`)
+    Signed(&'a Expression),
+    /// Jump target of `jr`, stored relative to the next instruction
+    Relative(&'a Expression),
+    /// Address in $FF00-$FFFF of `ldh`, stored as its low byte
+    HighPage(&'a Expression),
+    /// Bit number of `bit`, `res` and `set`, merged into the opcode
+    Bit(&'a Expression),
+    /// Vector of `rst`, merged into the opcode
+    Vector(&'a Expression),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoding<'a> {
+    /// Opcode bytes, with the `$CB` prefix for bit operations
+    pub opcode: Vec<u8>,
+    pub immediate: Immediate<'a>,
+    /// Duration in machine cycles (4 clock ticks), for conditional
+    /// instructions when the branch is taken
+    pub cycles: u8,
+    /// Duration when the condition is false
+    pub cycles_not_taken: u8,
+}
+
+impl Encoding<'_> {
+    pub fn size(&self) -> usize {
+        let immediate = match self.immediate {
+            Immediate::None | Immediate::Bit(_) | Immediate::Vector(_) => 0,
+            Immediate::Word(_) => 2,
+            _ => 1,
+        };
+
+        return self.opcode.len() + immediate;
+    }
+}
+
+// operand as far as the encoding is concerned
+#[derive(Clone, Copy)]
+enum Shape<'a> {
+    /// b, c, d, e, h, l, [hl], a by their 3 bit index
+    R8(u8),
+    /// bc, de, hl, sp by their 2 bit index
+    R16(u8),
+    Af,
+    IndirectBc,
+    IndirectDe,
+    IndirectHlIncrement,
+    IndirectHlDecrement,
+    IndirectC,
+    Indirect(&'a Expression),
+    StackOffset(&'a Expression),
+    Value(&'a Expression),
+    Condition(u8),
+}
+
+const R8_A: u8 = 7;
+const R8_HL: u8 = 6;
+const R16_HL: u8 = 2;
+const R16_SP: u8 = 3;
+
+fn shape(operand: &Operand, is_condition: bool) -> Option<Shape<'_>> {
+    return match operand {
+        Operand::Register(name, _) => {
+            if is_condition {
+                return CONDITIONS.iter().position(|c| c == name).map(|i| Shape::Condition(i as u8));
+            }
+
+            match name.as_str() {
+                "b" => Some(Shape::R8(0)),
+                "c" => Some(Shape::R8(1)),
+                "d" => Some(Shape::R8(2)),
+                "e" => Some(Shape::R8(3)),
+                "h" => Some(Shape::R8(4)),
+                "l" => Some(Shape::R8(5)),
+                "a" => Some(Shape::R8(R8_A)),
+                "bc" => Some(Shape::R16(0)),
+                "de" => Some(Shape::R16(1)),
+                "hl" => Some(Shape::R16(R16_HL)),
+                "sp" => Some(Shape::R16(R16_SP)),
+                "af" => Some(Shape::Af),
+                _ => None,
+            }
+        }
+        Operand::IndirectRegister(name, _) => match name.as_str() {
+            "hl" => Some(Shape::R8(R8_HL)),
+            "bc" => Some(Shape::IndirectBc),
+            "de" => Some(Shape::IndirectDe),
+            "hli" => Some(Shape::IndirectHlIncrement),
+            "hld" => Some(Shape::IndirectHlDecrement),
+            "c" => Some(Shape::IndirectC),
+            _ => None,
+        },
+        Operand::Indirect(expression, _) => Some(Shape::Indirect(expression)),
+        Operand::StackOffset(expression, _) => Some(Shape::StackOffset(expression)),
+        Operand::Immediate(expression) => Some(Shape::Value(expression)),
+    };
+}
+
+fn op(opcode: u8, immediate: Immediate<'_>, cycles: u8) -> Encoding<'_> {
+    return Encoding {
+        opcode: vec![opcode],
+        immediate,
+        cycles,
+        cycles_not_taken: cycles,
+    };
+}
+
+fn branch(opcode: u8, immediate: Immediate<'_>, cycles: u8, cycles_not_taken: u8) -> Encoding<'_> {
+    return Encoding {
+        opcode: vec![opcode],
+        immediate,
+        cycles,
+        cycles_not_taken,
+    };
+}
+
+fn prefixed(opcode: u8, immediate: Immediate<'_>, cycles: u8) -> Encoding<'_> {
+    return Encoding {
+        opcode: vec![0xCB, opcode],
+        immediate,
+        cycles,
+        cycles_not_taken: cycles,
+    };
+}
+
+/// Chooses the opcode for an instruction, or explains why the operands are
+/// not valid for the mnemonic.
+pub fn encode<'a>(mnemonic: &str, operands: &'a [Operand]) -> Result<Encoding<'a>, String> {
+    let is_branch = ["jp", "jr", "call", "ret"].contains(&mnemonic);
+    let shapes: Vec<Shape> = operands.iter()
+        .enumerate()
+        .map(|(i, operand)| shape(operand, is_branch && i == 0 && operands.len() + (mnemonic == "ret") as usize == 2))
+        .collect::<Option<Vec<Shape>>>()
+        .ok_or_else(|| invalid(mnemonic))?;
+
+    // `add b` is short for `add a, b`, the same for the other 8 bit operations
+    let alu = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+    let shapes = match shapes.as_slice() {
+        [Shape::R8(R8_A), value] if alu.contains(&mnemonic) && !matches!(value, Shape::R16(_)) => vec![*value],
+        _ => shapes,
+    };
+
+    let encoding = match (mnemonic, shapes.as_slice()) {
+        ("nop", []) => op(0x00, Immediate::None, 1),
+        ("stop", []) => Encoding {
+            opcode: vec![0x10, 0x00],
+            immediate: Immediate::None,
+            cycles: 1,
+            cycles_not_taken: 1,
+        },
+        ("halt", []) => op(0x76, Immediate::None, 1),
+        ("di", []) => op(0xF3, Immediate::None, 1),
+        ("ei", []) => op(0xFB, Immediate::None, 1),
+        ("daa", []) => op(0x27, Immediate::None, 1),
+        ("cpl", []) | ("cpl", [Shape::R8(R8_A)]) => op(0x2F, Immediate::None, 1),
+        ("scf", []) => op(0x37, Immediate::None, 1),
+        ("ccf", []) => op(0x3F, Immediate::None, 1),
+        ("rlca", []) => op(0x07, Immediate::None, 1),
+        ("rrca", []) => op(0x0F, Immediate::None, 1),
+        ("rla", []) => op(0x17, Immediate::None, 1),
+        ("rra", []) => op(0x1F, Immediate::None, 1),
+        ("reti", []) => op(0xD9, Immediate::None, 4),
+        ("ret", []) => op(0xC9, Immediate::None, 4),
+        ("ret", [Shape::Condition(cc)]) => branch(0xC0 | cc << 3, Immediate::None, 5, 2),
+
+        ("ld", [Shape::R8(R8_HL), Shape::R8(R8_HL)]) => return Err(invalid(mnemonic)),
+        ("ld", [Shape::R8(to), Shape::R8(from)]) => op(0x40 | to << 3 | from, Immediate::None, if *to == R8_HL || *from == R8_HL { 2 } else { 1 }),
+        ("ld", [Shape::R8(to), Shape::Value(value)]) => op(0x06 | to << 3, Immediate::Byte(value), if *to == R8_HL { 3 } else { 2 }),
+        ("ld", [Shape::R16(rr), Shape::Value(value)]) => op(0x01 | rr << 4, Immediate::Word(value), 3),
+        ("ld", [Shape::IndirectBc, Shape::R8(R8_A)]) => op(0x02, Immediate::None, 2),
+        ("ld", [Shape::IndirectDe, Shape::R8(R8_A)]) => op(0x12, Immediate::None, 2),
+        ("ld", [Shape::IndirectHlIncrement, Shape::R8(R8_A)]) | ("ldi", [Shape::R8(R8_HL), Shape::R8(R8_A)]) => op(0x22, Immediate::None, 2),
+        ("ld", [Shape::IndirectHlDecrement, Shape::R8(R8_A)]) | ("ldd", [Shape::R8(R8_HL), Shape::R8(R8_A)]) => op(0x32, Immediate::None, 2),
+        ("ld", [Shape::R8(R8_A), Shape::IndirectBc]) => op(0x0A, Immediate::None, 2),
+        ("ld", [Shape::R8(R8_A), Shape::IndirectDe]) => op(0x1A, Immediate::None, 2),
+        ("ld", [Shape::R8(R8_A), Shape::IndirectHlIncrement]) | ("ldi", [Shape::R8(R8_A), Shape::R8(R8_HL)]) => op(0x2A, Immediate::None, 2),
+        ("ld", [Shape::R8(R8_A), Shape::IndirectHlDecrement]) | ("ldd", [Shape::R8(R8_A), Shape::R8(R8_HL)]) => op(0x3A, Immediate::None, 2),
+        ("ld", [Shape::Indirect(address), Shape::R8(R8_A)]) => op(0xEA, Immediate::Word(address), 4),
+        ("ld", [Shape::R8(R8_A), Shape::Indirect(address)]) => op(0xFA, Immediate::Word(address), 4),
+        ("ld", [Shape::Indirect(address), Shape::R16(R16_SP)]) => op(0x08, Immediate::Word(address), 5),
+        ("ld", [Shape::IndirectC, Shape::R8(R8_A)]) | ("ldh", [Shape::IndirectC, Shape::R8(R8_A)]) => op(0xE2, Immediate::None, 2),
+        ("ld", [Shape::R8(R8_A), Shape::IndirectC]) | ("ldh", [Shape::R8(R8_A), Shape::IndirectC]) => op(0xF2, Immediate::None, 2),
+        ("ld", [Shape::R16(R16_SP), Shape::R16(R16_HL)]) => op(0xF9, Immediate::None, 2),
+        ("ld", [Shape::R16(R16_HL), Shape::StackOffset(offset)]) => op(0xF8, Immediate::Signed(offset), 3),
+        ("ldh", [Shape::Indirect(address), Shape::R8(R8_A)]) => op(0xE0, Immediate::HighPage(address), 3),
+        ("ldh", [Shape::R8(R8_A), Shape::Indirect(address)]) => op(0xF0, Immediate::HighPage(address), 3),
+
+        ("push", [Shape::R16(rr)]) if *rr != R16_SP => op(0xC5 | rr << 4, Immediate::None, 4),
+        ("push", [Shape::Af]) => op(0xF5, Immediate::None, 4),
+        ("pop", [Shape::R16(rr)]) if *rr != R16_SP => op(0xC1 | rr << 4, Immediate::None, 3),
+        ("pop", [Shape::Af]) => op(0xF1, Immediate::None, 3),
+
+        ("add", [Shape::R16(R16_HL), Shape::R16(rr)]) => op(0x09 | rr << 4, Immediate::None, 2),
+        ("add", [Shape::R16(R16_SP), Shape::Value(offset)]) => op(0xE8, Immediate::Signed(offset), 4),
+        (_, [Shape::R8(r)]) if alu.contains(&mnemonic) => {
+            let base = 0x80 | (alu.iter().position(|m| *m == mnemonic).unwrap() as u8) << 3;
+            op(base | r, Immediate::None, if *r == R8_HL { 2 } else { 1 })
+        }
+        (_, [Shape::Value(value)]) if alu.contains(&mnemonic) => {
+            let base = 0xC6 | (alu.iter().position(|m| *m == mnemonic).unwrap() as u8) << 3;
+            op(base, Immediate::Byte(value), 2)
+        }
+
+        ("inc", [Shape::R8(r)]) => op(0x04 | r << 3, Immediate::None, if *r == R8_HL { 3 } else { 1 }),
+        ("dec", [Shape::R8(r)]) => op(0x05 | r << 3, Immediate::None, if *r == R8_HL { 3 } else { 1 }),
+        ("inc", [Shape::R16(rr)]) => op(0x03 | rr << 4, Immediate::None, 2),
+        ("dec", [Shape::R16(rr)]) => op(0x0B | rr << 4, Immediate::None, 2),
+
+        ("jp", [Shape::R16(R16_HL)]) => op(0xE9, Immediate::None, 1),
+        ("jp", [Shape::Value(target)]) => op(0xC3, Immediate::Word(target), 4),
+        ("jp", [Shape::Condition(cc), Shape::Value(target)]) => branch(0xC2 | cc << 3, Immediate::Word(target), 4, 3),
+        ("jr", [Shape::Value(target)]) => op(0x18, Immediate::Relative(target), 3),
+        ("jr", [Shape::Condition(cc), Shape::Value(target)]) => branch(0x20 | cc << 3, Immediate::Relative(target), 3, 2),
+        ("call", [Shape::Value(target)]) => op(0xCD, Immediate::Word(target), 6),
+        ("call", [Shape::Condition(cc), Shape::Value(target)]) => branch(0xC4 | cc << 3, Immediate::Word(target), 6, 3),
+        ("rst", [Shape::Value(vector)]) => op(0xC7, Immediate::Vector(vector), 4),
+
+        ("rlc" | "rrc" | "rl" | "rr" | "sla" | "sra" | "swap" | "srl", [Shape::R8(r)]) => {
+            let index = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"].iter().position(|m| *m == mnemonic).unwrap() as u8;
+            prefixed(index << 3 | r, Immediate::None, if *r == R8_HL { 4 } else { 2 })
+        }
+        ("bit", [Shape::Value(bit), Shape::R8(r)]) => prefixed(0x40 | r, Immediate::Bit(bit), if *r == R8_HL { 3 } else { 2 }),
+        ("res", [Shape::Value(bit), Shape::R8(r)]) => prefixed(0x80 | r, Immediate::Bit(bit), if *r == R8_HL { 4 } else { 2 }),
+        ("set", [Shape::Value(bit), Shape::R8(r)]) => prefixed(0xC0 | r, Immediate::Bit(bit), if *r == R8_HL { 4 } else { 2 }),
+
+        _ => return Err(invalid(mnemonic)),
+    };
+
+    return Ok(encoding);
+}
+
+/// Whether the instruction depends on the flags: conditional jumps, calls
+/// and returns, and the instructions using the carry or the flags of the
+/// previous operation.
+pub fn reads_flags(mnemonic: &str, operands: &[Operand]) -> bool {
+    let is_register = |operand: &Operand, name: &str| matches!(operand, Operand::Register(register, _) if register == name);
+
+    return match mnemonic {
+        "jp" | "jr" | "call" => operands.len() == 2,
+        "ret" => operands.len() == 1,
+        "adc" | "sbc" | "rla" | "rra" | "rl" | "rr" | "daa" | "ccf" => true,
+        "push" => operands.first().is_some_and(|operand| is_register(operand, "af")),
+        _ => false,
+    };
+}
+
+/// Whether the instruction sets all of Z, N, H and C without reading them.
+pub fn sets_all_flags(mnemonic: &str, operands: &[Operand]) -> bool {
+    let is_register = |operand: &Operand, name: &str| matches!(operand, Operand::Register(register, _) if register == name);
+
+    return match mnemonic {
+        "sub" | "and" | "or" | "xor" | "cp" | "rlca" | "rrca" | "rlc" | "rrc" | "sla" | "sra" | "srl" | "swap" => true,
+        // `add hl, r16` leaves Z alone
+        "add" => !operands.first().is_some_and(|operand| is_register(operand, "hl")),
+        "pop" => operands.first().is_some_and(|operand| is_register(operand, "af")),
+        _ => false,
+    };
+}
+
+/// Offset stored by a `jr` that replaces the 3 byte `jp` at `jump`, going to
+/// `target` in the same section: a target after the jump moves one byte
+/// closer.
+pub fn relaxed_offset(jump: i32, target: i32) -> i32 {
+    return if target > jump { target - (jump + 3) } else { target - (jump + 2) };
+}
+
+/// Instruction decoded from its bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub mnemonic: &'static str,
+    /// Operands as the parser builds them, with the immediate value as a
+    /// number: the stored offset for `jr`, the full address for `ldh`
+    pub operands: Vec<Operand>,
+    pub size: usize,
+    pub cycles: u8,
+    pub cycles_not_taken: u8,
+}
+
+// value stored after the opcode
+#[derive(Clone, Copy)]
+enum Stored {
+    None,
+    Byte,
+    Word,
+    Signed,
+    HighPage,
+}
+
+struct Template {
+    mnemonic: &'static str,
+    opcode: Vec<u8>,
+    operands: Vec<Operand>,
+    /// Operand holding the stored value
+    immediate: usize,
+    stored: Stored,
+    cycles: u8,
+    cycles_not_taken: u8,
+}
+
+/// Decodes the instruction at the start of `bytes`, `None` for the opcodes
+/// no instruction has or when `bytes` ends before the instruction.
+pub fn decode(bytes: &[u8]) -> Option<Decoded> {
+    let index = match bytes {
+        [0xCB, opcode, ..] => 0x100 + *opcode as usize,
+        [opcode, ..] => *opcode as usize,
+        [] => return None,
+    };
+    let template = decoding_table()[index].as_ref()?;
+
+    let size = template.opcode.len() + match template.stored {
+        Stored::None => 0,
+        Stored::Word => 2,
+        _ => 1,
+    };
+    if bytes.len() < size || bytes[..template.opcode.len()] != template.opcode[..] {
+        return None;
+    }
+
+    let stored = &bytes[template.opcode.len()..size];
+    let value = match template.stored {
+        Stored::None => None,
+        Stored::Byte => Some(stored[0] as i32),
+        Stored::Word => Some(u16::from_le_bytes([stored[0], stored[1]]) as i32),
+        Stored::Signed => Some(stored[0] as i8 as i32),
+        Stored::HighPage => Some(0xFF00 | stored[0] as i32),
+    };
+
+    let mut operands = template.operands.clone();
+    if let Some(value) = value {
+        let number = Expression::Number(value, Span::default());
+        match &mut operands[template.immediate] {
+            Operand::Indirect(expression, _) | Operand::StackOffset(expression, _) | Operand::Immediate(expression) => *expression = number,
+            _ => {}
+        }
+    }
+
+    return Some(Decoded {
+        mnemonic: template.mnemonic,
+        operands,
+        size,
+        cycles: template.cycles,
+        cycles_not_taken: template.cycles_not_taken,
+    });
+}
+
+// the instruction of each opcode, then of each opcode after $CB, found by
+// encoding every combination of operands so both directions agree; the first
+// spelling found is kept, e.g. `ld [hl+], a` rather than `ldi [hl], a` and
+// `add b` rather than `add a, b`
+fn decoding_table() -> &'static [Option<Template>] {
+    static TABLE: OnceLock<Vec<Option<Template>>> = OnceLock::new();
+
+    return TABLE.get_or_init(|| {
+        let span = Span::default();
+        let value = || Expression::Number(0, span);
+
+        let mut candidates: Vec<Operand> = vec![];
+        for name in REGISTERS.iter().chain(&["sp", "nz", "z", "nc"]) {
+            candidates.push(Operand::Register(name.to_string(), span));
+        }
+        for name in ["hl", "bc", "de", "hli", "hld", "c"] {
+            candidates.push(Operand::IndirectRegister(name.to_string(), span));
+        }
+        candidates.push(Operand::Indirect(value(), span));
+        candidates.push(Operand::StackOffset(value(), span));
+        candidates.push(Operand::Immediate(value()));
+
+        let mut operand_lists: Vec<Vec<Operand>> = vec![vec![]];
+        operand_lists.extend(candidates.iter().map(|operand| vec![operand.clone()]));
+        for first in &candidates {
+            operand_lists.extend(candidates.iter().map(|second| vec![first.clone(), second.clone()]));
+        }
+
+        let mut table: Vec<Option<Template>> = (0..0x200).map(|_| None).collect();
+        for mnemonic in MNEMONICS {
+            for operands in &operand_lists {
+                let encoding = match encode(mnemonic, operands) {
+                    Ok(encoding) => encoding,
+                    Err(_) => continue,
+                };
+                let immediate = operands.iter().position(|operand| {
+                    matches!(operand, Operand::Indirect(..) | Operand::StackOffset(..) | Operand::Immediate(_))
+                }).unwrap_or(0);
+
+                // bit numbers and vectors are part of the opcode
+                let (stored, merged) = match encoding.immediate {
+                    Immediate::None => (Stored::None, vec![(0, None)]),
+                    Immediate::Byte(_) => (Stored::Byte, vec![(0, None)]),
+                    Immediate::Word(_) => (Stored::Word, vec![(0, None)]),
+                    Immediate::Signed(_) | Immediate::Relative(_) => (Stored::Signed, vec![(0, None)]),
+                    Immediate::HighPage(_) => (Stored::HighPage, vec![(0, None)]),
+                    Immediate::Bit(_) => (Stored::None, (0..8).map(|bit| (bit << 3, Some(bit as i32))).collect()),
+                    Immediate::Vector(_) => (Stored::None, (0..8).map(|vector| (vector << 3, Some(vector as i32 * 8))).collect()),
+                };
+
+                for (bits, number) in merged {
+                    let mut opcode = encoding.opcode.clone();
+                    *opcode.last_mut().unwrap() |= bits;
+
+                    let index = if opcode[0] == 0xCB { 0x100 + opcode[1] as usize } else { opcode[0] as usize };
+                    if table[index].is_some() {
+                        continue;
+                    }
+
+                    let mut operands = operands.clone();
+                    if let Some(number) = number {
+                        operands[immediate] = Operand::Immediate(Expression::Number(number, span));
+                    }
+
+                    table[index] = Some(Template {
+                        mnemonic,
+                        opcode,
+                        operands,
+                        immediate,
+                        stored,
+                        cycles: encoding.cycles,
+                        cycles_not_taken: encoding.cycles_not_taken,
+                    });
+                }
+            }
+        }
+
+        return table;
+    });
+}
+
+fn invalid(mnemonic: &str) -> String {
+    return format!("Invalid operands for `{}`", mnemonic);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{InstructionStatement, Statement};
+    use crate::diagnostic::Diagnostics;
+    use crate::{lexer, parser};
+
+    fn encoding(line: &str) -> Result<(Vec<u8>, usize, u8), String> {
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(line), &mut diagnostics);
+        let statement: &dyn Statement = ast.statements[0].as_ref();
+        let instruction = statement.as_any().downcast_ref::<InstructionStatement>().unwrap();
+
+        return encode(&instruction.mnemonic, &instruction.operands)
+            .map(|encoding| (encoding.opcode.clone(), encoding.size(), encoding.cycles));
+    }
+
+    #[test]
+    fn encoding_instructions() {
+        assert_eq!(encoding("nop"), Ok((vec![0x00], 1, 1)));
+        assert_eq!(encoding("ld a, [hl+]"), Ok((vec![0x2A], 1, 2)));
+        assert_eq!(encoding("ld [hl], b"), Ok((vec![0x70], 1, 2)));
+        assert_eq!(encoding("ld hl, $C000"), Ok((vec![0x21], 3, 3)));
+        assert_eq!(encoding("ld [wFoo], a"), Ok((vec![0xEA], 3, 4)));
+        assert_eq!(encoding("ldh [$FF40], a"), Ok((vec![0xE0], 2, 3)));
+        assert_eq!(encoding("ld [$ff00+c], a"), Ok((vec![0xE2], 1, 2)));
+        assert_eq!(encoding("ld hl, sp + 2"), Ok((vec![0xF8], 2, 3)));
+        assert_eq!(encoding("add a, [hl]"), Ok((vec![0x86], 1, 2)));
+        assert_eq!(encoding("xor a"), Ok((vec![0xAF], 1, 1)));
+        assert_eq!(encoding("cp 0"), Ok((vec![0xFE], 2, 2)));
+        assert_eq!(encoding("add hl, de"), Ok((vec![0x19], 1, 2)));
+        assert_eq!(encoding("jr c, Label"), Ok((vec![0x38], 2, 3)));
+        assert_eq!(encoding("ret nz"), Ok((vec![0xC0], 1, 5)));
+        assert_eq!(encoding("push af"), Ok((vec![0xF5], 1, 4)));
+        assert_eq!(encoding("bit 7, h"), Ok((vec![0xCB, 0x44], 2, 2)));
+        assert_eq!(encoding("swap [hl]"), Ok((vec![0xCB, 0x36], 2, 4)));
+        assert_eq!(encoding("rst $38"), Ok((vec![0xC7], 1, 4)));
+
+        assert_eq!(encoding("ld [hl], [hl]"), Err("Invalid operands for `ld`".to_string()));
+        assert_eq!(encoding("push sp"), Err("Invalid operands for `push`".to_string()));
+        assert_eq!(encoding("jp nz"), Err("Invalid operands for `jp`".to_string()));
+    }
+
+    #[test]
+    fn decoding_instructions() {
+        let decoded = |bytes: &[u8]| decode(bytes).map(|decoded| (decoded.mnemonic, decoded.operands.len(), decoded.size, decoded.cycles));
+
+        assert_eq!(decoded(&[0x00]), Some(("nop", 0, 1, 1)));
+        assert_eq!(decoded(&[0x2A]), Some(("ld", 2, 1, 2)));
+        assert_eq!(decoded(&[0xE2]), Some(("ld", 2, 1, 2)));
+        assert_eq!(decoded(&[0xF0, 0x44]), Some(("ldh", 2, 2, 3)));
+        assert_eq!(decoded(&[0xC2, 0x50, 0x01]), Some(("jp", 2, 3, 4)));
+        assert_eq!(decoded(&[0xCB, 0x7C]), Some(("bit", 2, 2, 2)));
+        assert_eq!(decoded(&[0xFF]), Some(("rst", 1, 1, 4)));
+        assert_eq!(decoded(&[0x10, 0x00]), Some(("stop", 0, 2, 1)));
+        assert_eq!(decoded(&[0x10, 0x01]), None);
+        assert_eq!(decoded(&[0xD3]), None);
+        assert_eq!(decoded(&[0x21, 0x00]), None);
+
+        let values = |bytes: &[u8]| decode(bytes).unwrap().operands.iter().filter_map(|operand| match operand {
+            Operand::Immediate(Expression::Number(value, _)) | Operand::Indirect(Expression::Number(value, _), _)
+                | Operand::StackOffset(Expression::Number(value, _), _) => Some(*value),
+            _ => None,
+        }).collect::<Vec<i32>>();
+        assert_eq!(values(&[0xF0, 0x44]), vec![0xFF44]);
+        assert_eq!(values(&[0x18, 0xFE]), vec![-2]);
+        assert_eq!(values(&[0xF8, 0x80]), vec![-128]);
+        assert_eq!(values(&[0xCB, 0x7C]), vec![7]);
+        assert_eq!(values(&[0xEF]), vec![0x28]);
+
+        // every opcode but the 11 unused ones, each encoded back to itself
+        let mut count = 0;
+        for index in 0..0x200 {
+            let bytes = if index < 0x100 { vec![index as u8, 0, 0] } else { vec![0xCB, index as u8] };
+            let decoded = match decode(&bytes) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            count += 1;
+
+            let encoding = encode(decoded.mnemonic, &decoded.operands).unwrap();
+            assert_eq!(encoding.size(), decoded.size);
+            if !matches!(encoding.immediate, Immediate::Bit(_) | Immediate::Vector(_)) {
+                assert_eq!(encoding.opcode, bytes[..encoding.opcode.len()]);
+            }
+        }
+        assert_eq!(count, 245 + 256);
+    }
+
+    #[test]
+    fn relaxing_jumps() {
+        // `jr` to itself
+        assert_eq!(relaxed_offset(4, 4), -2);
+        // `jp` followed by its target
+        assert_eq!(relaxed_offset(4, 7), 0);
+        assert_eq!(relaxed_offset(0, 130), 127);
+    }
+}
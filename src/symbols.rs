@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::charmap::CharMaps;
+use crate::lexer::Span;
+
+// version of the RGBDS language this toolchain follows
+pub const RGBDS_MAJOR: i32 = 0;
+pub const RGBDS_MINOR: i32 = 7;
+pub const RGBDS_PATCH: i32 = 0;
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolValue {
+    Number(i32),
+    String(String),
+    Label(LabelLocation),
+}
+
+/// Where a label points to. Only labels in sections at a fixed address have a
+/// value before linking.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelLocation {
+    pub section: String,
+    pub section_type: String,
+    pub bank: Option<i32>,
+    /// Offset from the start of the section
+    pub offset: i32,
+    pub address: Option<i32>,
+}
+
+impl std::fmt::Display for LabelLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bank = match self.bank {
+            Some(bank) => format!("${:02X}", bank),
+            None => "??".to_string(),
+        };
+
+        return match self.address {
+            Some(address) => write!(f, "{}:${:04X}", bank, address),
+            None => write!(f, "{}:\"{}\"+${:X}", bank, self.section, self.offset),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SymbolKind {
+    /// `EQU` constant
+    Constant,
+    /// `=` or `SET` variable, may be redefined
+    Variable,
+    /// `EQUS` string, also used for command line definitions
+    String,
+    /// Predefined by the assembler
+    Builtin,
+    Label,
+}
+
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub file: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub value: SymbolValue,
+    /// Where the symbol was defined, `None` for built-in and command line symbols.
+    pub definition: Option<Definition>,
+}
+
+#[derive(Debug)]
+pub struct SymbolError {
+    pub message: String,
+    pub previous: Option<Definition>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+    file: String,
+    line: usize,
+    macro_arguments: Vec<usize>,
+    /// Location of the statement being assembled, the value of `@`
+    here: Option<LabelLocation>,
+    /// How strings are encoded, for CHARLEN and CHARSUB as well as `db`
+    pub charmaps: CharMaps,
+}
+
+impl SymbolTable {
+    /// An empty table without any predefined symbol.
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// A table holding the symbols RGBDS predefines, with the date and time
+    /// symbols set from `now` (in UTC).
+    pub fn with_builtins(now: SystemTime) -> Self {
+        let mut table = Self::new();
+
+        let seconds = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+        let time = seconds.rem_euclid(86400);
+        let (hour, minute, second) = (time / 3600, time / 60 % 60, time % 60);
+
+        let date = format!("{:02} {} {}", day, MONTHS[month as usize - 1], year);
+        let clock = format!("{:02}:{:02}:{:02}", hour, minute, second);
+        let iso = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second);
+
+        let numbers = [
+            ("__RGBDS_MAJOR__", RGBDS_MAJOR),
+            ("__RGBDS_MINOR__", RGBDS_MINOR),
+            ("__RGBDS_PATCH__", RGBDS_PATCH),
+            ("__UTC_YEAR__", year as i32),
+            ("__UTC_MONTH__", month as i32),
+            ("__UTC_DAY__", day as i32),
+            ("__UTC_HOUR__", hour as i32),
+            ("__UTC_MINUTE__", minute as i32),
+            ("__UTC_SECOND__", second as i32),
+            ("_RS", 0),
+        ];
+        for (name, value) in numbers {
+            table.insert(name, SymbolKind::Builtin, SymbolValue::Number(value));
+        }
+
+        // string built-ins hold their quotes like in RGBDS, so they expand to string literals
+        let strings = [
+            ("__RGBDS_VERSION__", format!("\"{}.{}.{}\"", RGBDS_MAJOR, RGBDS_MINOR, RGBDS_PATCH)),
+            ("__DATE__", format!("\"{}\"", date)),
+            ("__TIME__", format!("\"{}\"", clock)),
+            ("__ISO_8601_UTC__", format!("\"{}\"", iso)),
+            ("__ISO_8601_LOCAL__", format!("\"{}\"", iso)),
+        ];
+        for (name, value) in strings {
+            table.insert(name, SymbolKind::Builtin, SymbolValue::String(value));
+        }
+
+        return table;
+    }
+
+    fn insert(&mut self, name: &str, kind: SymbolKind, value: SymbolValue) {
+        self.symbols.insert(name.to_string(), Symbol {
+            name: name.to_string(),
+            kind,
+            value,
+            definition: None,
+        });
+    }
+
+    /// Defines a symbol from the command line (`-D NAME=VALUE`), which is the
+    /// same as `DEF NAME EQUS "VALUE"`.
+    pub fn define_command_line(&mut self, name: &str, value: &str) -> Result<(), SymbolError> {
+        return self.define(name, SymbolKind::String, SymbolValue::String(value.to_string()), None);
+    }
+
+    pub fn define(&mut self, name: &str, kind: SymbolKind, value: SymbolValue, definition: Option<Definition>) -> Result<(), SymbolError> {
+        if is_dynamic(name) {
+            return Err(SymbolError {
+                message: format!("`{}` is a built-in symbol and cannot be redefined", name),
+                previous: None,
+            });
+        }
+
+        if let Some(existing) = self.symbols.get(name) {
+            let redefinable = existing.kind == SymbolKind::Variable && kind == SymbolKind::Variable;
+
+            if !redefinable {
+                let message = if existing.kind == SymbolKind::Builtin {
+                    format!("`{}` is a built-in symbol and cannot be redefined", name)
+                } else if existing.kind == SymbolKind::Variable || kind == SymbolKind::Variable {
+                    format!("`{}` is already defined as a different kind of symbol", name)
+                } else {
+                    format!("`{}` is already defined", name)
+                };
+
+                return Err(SymbolError {
+                    message,
+                    previous: existing.definition.clone(),
+                });
+            }
+        }
+
+        self.symbols.insert(name.to_string(), Symbol {
+            name: name.to_string(),
+            kind,
+            value,
+            definition,
+        });
+
+        return Ok(());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        return self.symbols.get(name);
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        return self.value(name).is_ok();
+    }
+
+    /// Current value of a symbol, including the ones that depend on where the
+    /// assembler currently is (`__LINE__`, `__FILE__` and `_NARG`).
+    pub fn value(&self, name: &str) -> Result<SymbolValue, String> {
+        match name {
+            "__LINE__" => return Ok(SymbolValue::Number(self.line as i32)),
+            "__FILE__" => return Ok(SymbolValue::String(format!("\"{}\"", self.file.escape_debug()))),
+            "@" => {
+                return match &self.here {
+                    Some(location) => Ok(SymbolValue::Label(location.clone())),
+                    None => Err("`@` is only defined inside a SECTION".to_string()),
+                };
+            }
+            "_NARG" => {
+                return match self.macro_arguments.last() {
+                    Some(count) => Ok(SymbolValue::Number(*count as i32)),
+                    None => Err("`_NARG` is only defined inside a macro".to_string()),
+                };
+            }
+            _ => {}
+        }
+
+        return match self.symbols.get(name) {
+            Some(symbol) => Ok(symbol.value.clone()),
+            None => Err(format!("Symbol `{}` is not defined", name)),
+        };
+    }
+
+    pub fn set_location(&mut self, file: &str, line: usize) {
+        if self.file != file {
+            self.file = file.to_string();
+        }
+        self.line = line;
+    }
+
+    pub fn set_here(&mut self, location: Option<LabelLocation>) {
+        self.here = location;
+    }
+
+    pub fn enter_macro(&mut self, argument_count: usize) {
+        self.macro_arguments.push(argument_count);
+    }
+
+    pub fn leave_macro(&mut self) {
+        self.macro_arguments.pop();
+    }
+
+    /// Value of the `_RS` counter used by RSRESET, RSSET, RB, RW and RL.
+    pub fn rs(&self) -> i32 {
+        return match self.symbols.get("_RS").map(|s| &s.value) {
+            Some(SymbolValue::Number(n)) => *n,
+            _ => 0,
+        };
+    }
+
+    pub fn set_rs(&mut self, value: i32) {
+        self.insert("_RS", SymbolKind::Builtin, SymbolValue::Number(value));
+    }
+
+    /// Gives the labels of a section placed when linking their bank and
+    /// address.
+    pub fn place_section(&mut self, section: &str, bank: i32, address: i32) {
+        for symbol in self.symbols.values_mut() {
+            if let SymbolValue::Label(location) = &mut symbol.value {
+                if location.section == section {
+                    location.bank = Some(bank);
+                    location.address = Some(address + location.offset);
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        return self.symbols.values();
+    }
+}
+
+fn is_dynamic(name: &str) -> bool {
+    return name == "__LINE__" || name == "__FILE__" || name == "_NARG" || name == "@";
+}
+
+// days since 1970-01-01 to (year, month, day), see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    return (year, month, day);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn predefined_symbols() {
+        // 2026-10-18 12:34:56 UTC
+        let mut table = SymbolTable::with_builtins(UNIX_EPOCH + Duration::from_secs(1792326896));
+        table.set_location("main.asm", 12);
+
+        assert_eq!(table.value("__DATE__"), Ok(SymbolValue::String("\"18 October 2026\"".to_string())));
+        assert_eq!(table.value("__ISO_8601_UTC__"), Ok(SymbolValue::String("\"2026-10-18T12:34:56Z\"".to_string())));
+        assert_eq!(table.value("__UTC_MONTH__"), Ok(SymbolValue::Number(10)));
+        assert_eq!(table.value("__RGBDS_MAJOR__"), Ok(SymbolValue::Number(RGBDS_MAJOR)));
+        assert_eq!(table.value("__LINE__"), Ok(SymbolValue::Number(12)));
+        assert_eq!(table.value("__FILE__"), Ok(SymbolValue::String("\"main.asm\"".to_string())));
+        assert!(table.value("_NARG").is_err());
+
+        table.enter_macro(2);
+        assert_eq!(table.value("_NARG"), Ok(SymbolValue::Number(2)));
+    }
+
+    #[test]
+    fn redefining_symbols() {
+        let mut table = SymbolTable::with_builtins(UNIX_EPOCH);
+
+        table.define_command_line("DEBUG", "1").unwrap();
+        assert!(table.define_command_line("DEBUG", "0").is_err());
+        assert!(table.define("_RS", SymbolKind::Constant, SymbolValue::Number(1), None).is_err());
+        assert!(table.define("__LINE__", SymbolKind::Variable, SymbolValue::Number(1), None).is_err());
+
+        table.define("counter", SymbolKind::Variable, SymbolValue::Number(1), None).unwrap();
+        table.define("counter", SymbolKind::Variable, SymbolValue::Number(2), None).unwrap();
+        assert_eq!(table.value("counter"), Ok(SymbolValue::Number(2)));
+        assert!(table.define("counter", SymbolKind::Constant, SymbolValue::Number(3), None).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Running the TEST blocks of a file in the emulator.
+//!
+//! Each test runs on its own from the state the boot ROM leaves the CPU in,
+//! with the ROM linked from the file and its tests, from the start of the
+//! test's section to its end. The EXPECTs are checked whenever the CPU gets
+//! to the instruction that follows them.
+
+use crate::assembler::{Assembler, Expectation, Test};
+use crate::ast::Operand;
+use crate::diagnostic::Diagnostic;
+use crate::emulator::{Emulator, FLAG_C, FLAG_Z};
+use crate::expr::{self, Expression};
+use crate::symbols::SymbolTable;
+
+const E_TEST_FAILED: &str = "E0034";
+
+/// Cycles a test may run for, a second of Game Boy time.
+pub const CYCLE_LIMIT: u64 = 1 << 20;
+
+pub struct TestResult {
+    pub name: String,
+    /// Machine cycles from the start of the test to its end, or to where it
+    /// stopped
+    pub cycles: u64,
+    /// The EXPECTs that failed or were never checked, or why the test did not
+    /// get to its end
+    pub failures: Vec<Diagnostic>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        return self.failures.is_empty();
+    }
+}
+
+/// Runs every test of `assembler`, whose sections `rom` was linked from.
+pub fn run_tests(assembler: &Assembler, rom: &[u8]) -> Vec<TestResult> {
+    return assembler.tests.iter().map(|test| run_test(assembler, test, rom)).collect();
+}
+
+fn run_test(assembler: &Assembler, test: &Test, rom: &[u8]) -> TestResult {
+    let in_file = |diagnostic: Diagnostic, file: &str| {
+        if file != assembler.main_file {
+            return diagnostic.with_file(file);
+        }
+        return diagnostic;
+    };
+
+    let section = &assembler.sections()[test.section];
+    let bank = section.bank.unwrap_or(1);
+    let start = section.address.unwrap_or(0x4000) as u16;
+    let end = start + section.data.len() as u16;
+
+    let mut emulator = Emulator::new(rom.to_vec());
+    emulator.write(0x2000, bank as u8);
+    emulator.write(0x3000, (bank >> 8) as u8);
+    emulator.cpu.registers.pc = start;
+
+    let mut failures = vec![];
+    // whether each EXPECT was checked, and whether it failed
+    let mut checked = vec![false; test.expectations.len()];
+    let mut failed = vec![false; test.expectations.len()];
+    let mut cycles = 0;
+
+    loop {
+        let pc = emulator.cpu.registers.pc;
+
+        if !emulator.cpu.halted {
+            for (i, expectation) in test.expectations.iter().enumerate() {
+                if start + expectation.offset as u16 != pc || failed[i] {
+                    continue;
+                }
+
+                checked[i] = true;
+                if let Err(diagnostic) = check(expectation, &emulator, &assembler.symbols) {
+                    failures.push(in_file(*diagnostic, &expectation.file));
+                    failed[i] = true;
+                }
+            }
+        }
+
+        if pc == end {
+            break;
+        }
+
+        let stop = if cycles >= CYCLE_LIMIT {
+            Some(format!("Test `{}` did not finish within {} cycles", test.name, CYCLE_LIMIT))
+        } else {
+            match emulator.step() {
+                Ok(step) => {
+                    cycles += step as u64;
+                    None
+                }
+                Err(message) => Some(message),
+            }
+        };
+
+        if let Some(message) = stop {
+            let diagnostic = Diagnostic::error(E_TEST_FAILED, &message, test.definition.span)
+                .with_label("in this test");
+            failures.push(in_file(diagnostic, &test.definition.file));
+
+            return TestResult {
+                name: test.name.clone(),
+                cycles,
+                failures,
+            };
+        }
+    }
+
+    for (expectation, _) in test.expectations.iter().zip(&checked).filter(|(_, checked)| !**checked) {
+        let diagnostic = Diagnostic::error(E_TEST_FAILED, "EXPECT was never checked", expectation.span)
+            .with_label("the test did not get here");
+        failures.push(in_file(diagnostic, &expectation.file));
+    }
+
+    return TestResult {
+        name: test.name.clone(),
+        cycles,
+        failures,
+    };
+}
+
+fn check(expectation: &Expectation, emulator: &Emulator, symbols: &SymbolTable) -> Result<(), Box<Diagnostic>> {
+    let registers = &emulator.cpu.registers;
+
+    let (operator, value) = match &expectation.comparison {
+        Some(comparison) => comparison,
+        None => {
+            let condition = match &expectation.target {
+                Operand::Register(condition, _) => condition.as_str(),
+                _ => unreachable!("the parser only allows conditions without a value"),
+            };
+            let (flag, name) = if condition.ends_with('z') { (FLAG_Z, "Z") } else { (FLAG_C, "C") };
+            let set = registers.flag(flag);
+
+            if set == condition.starts_with('n') {
+                let state = if set { "set" } else { "clear" };
+                return Err(Box::new(Diagnostic::error(E_TEST_FAILED, &format!("Expected `{}`", condition), expectation.span)
+                    .with_label(&format!("{} is {}", name, state))));
+            }
+            return Ok(());
+        }
+    };
+
+    let (target, actual, bits) = match &expectation.target {
+        Operand::Register(name, _) => {
+            let bits = if name.len() == 1 { 8 } else { 16 };
+            (format!("`{}`", name), registers.get(name).unwrap_or(0), bits)
+        }
+        Operand::IndirectRegister(name, _) => {
+            let address = match name.as_str() {
+                "bc" => registers.bc(),
+                "de" => registers.de(),
+                "c" => 0xFF00 | registers.c as u16,
+                _ => registers.hl(),
+            };
+            (format!("`[{}]` at ${:04X}", name, address), emulator.read(address) as u16, 8)
+        }
+        Operand::Indirect(address, _) => {
+            let address = evaluate(address, symbols, expectation)? as u16;
+            (format!("`[${:04X}]`", address), emulator.read(address) as u16, 8)
+        }
+        _ => unreachable!("the parser only allows registers and memory"),
+    };
+
+    let mut expected = evaluate(value, symbols, expectation)?;
+    // negative values as they are stored
+    if (-(1 << (bits - 1))..0).contains(&expected) {
+        expected &= (1 << bits) - 1;
+    }
+
+    let actual = actual as i32;
+    let holds = match operator.as_str() {
+        "==" => actual == expected,
+        "!=" => actual != expected,
+        "<" => actual < expected,
+        ">" => actual > expected,
+        "<=" => actual <= expected,
+        _ => actual >= expected,
+    };
+
+    if !holds {
+        let digits = bits / 4;
+        return Err(Box::new(Diagnostic::error(E_TEST_FAILED, "EXPECT failed", expectation.span)
+            .with_label(&format!("{} is ${:0digits$X}", target, actual, digits = digits))));
+    }
+
+    return Ok(());
+}
+
+fn evaluate(expression: &Expression, symbols: &SymbolTable, expectation: &Expectation) -> Result<i32, Box<Diagnostic>> {
+    // a failure points at the EXPECT, since the expression may come from a
+    // macro expansion
+    return expr::evaluate_number(expression, symbols).map_err(|diagnostic| {
+        Box::new(Diagnostic::error(E_TEST_FAILED, &diagnostic.message, expectation.span)
+            .with_label("cannot be checked"))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::{Diagnostics, SourceFile};
+    use crate::{lexer, link, parser};
+    use std::time::UNIX_EPOCH;
+
+    fn run(text: &str) -> Vec<TestResult> {
+        let source = SourceFile::new("main.asm", text.to_string());
+        let mut diagnostics = Diagnostics::new();
+        let ast = parser::parse_ast(lexer::lex_content(&source.text), &mut diagnostics);
+
+        let mut assembler = Assembler::with_time(UNIX_EPOCH);
+        assembler.tests_enabled = true;
+        assembler.assemble(&ast, &source, &mut diagnostics);
+        let rom = link::link(&mut assembler, &mut diagnostics);
+        assert!(!diagnostics.has_errors(), "{:?}", diagnostics);
+
+        return run_tests(&assembler, &rom);
+    }
+
+    #[test]
+    fn running_tests() {
+        let results = run(concat!(
+            "SECTION \"Math\", ROM0\n",
+            "Multiply:\n",
+            "\txor a\n",
+            "\tinc c\n",
+            ".loop:\n",
+            "\tdec c\n",
+            "\tret z\n",
+            "\tadd b\n",
+            "\tjr .loop\n",
+            "TEST \"multiply\"\n",
+            "\tld b, 3\n",
+            "\tld c, 4\n",
+            "\tcall Multiply\n",
+            "\tEXPECT a == $0C\n",
+            "\tEXPECT z\n",
+            "\tld [wResult], a\n",
+            "\tEXPECT [wResult] == 12\n",
+            "\tld hl, wResult\n",
+            "\tEXPECT [hl] >= 10\n",
+            "\tEXPECT hl == wResult\n",
+            "ENDT\n",
+            "TEST \"failing\"\n",
+            "\tld a, -1\n",
+            "\tEXPECT a == -1\n",
+            "\tEXPECT a < $80\n",
+            "\tEXPECT nc\n",
+            "\tjr .skip\n",
+            "\tEXPECT a == 0\n",
+            "\tnop\n",
+            ".skip:\n",
+            "ENDT\n",
+            "TEST \"stuck\"\n",
+            ".forever: jr .forever\n",
+            "ENDT\n",
+            "SECTION \"Work\", WRAM0\n",
+            "wResult: ds 1\n",
+        ));
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].name, "multiply");
+        assert!(results[0].passed(), "{:?}", results[0].failures);
+        // two loads, the call, the multiplication, a store and a load
+        assert_eq!(results[0].cycles, 2 + 2 + 6 + 36 + 4 + 3);
+
+        let messages: Vec<String> = results[1].failures.iter()
+            .map(|failure| format!("{}: {}", failure.message, failure.primary.message))
+            .collect();
+        assert_eq!(messages, [
+            "EXPECT failed: `a` is $FF",
+            "Expected `nc`: C is set",
+            "EXPECT was never checked: the test did not get here",
+        ]);
+
+        assert_eq!(results[2].failures[0].message, format!("Test `stuck` did not finish within {} cycles", CYCLE_LIMIT));
+    }
+}